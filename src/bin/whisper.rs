@@ -8,7 +8,7 @@ extern crate time;
 extern crate whisper;
 
 use docopt::Docopt;
-use whisper::{ WhisperFile, Point, Schema };
+use whisper::{ WhisperFile, Point, Schema, AggregationType };
 
 use std::path::Path;
 
@@ -21,7 +21,7 @@ Usage:
     whisper update <file> <timestamp> <value>
     whisper mark <file> <value>
     whisper thrash <file> <value> <times>
-    whisper create <file> <timespec>...
+    whisper create [options] <file> <timespec>...
 
 Options:
     --xff <x_files_factor>
@@ -42,7 +42,10 @@ struct Args {
     arg_value: String,
     arg_times: String,
 
-    arg_timespec: Vec<String>
+    arg_timespec: Vec<String>,
+
+    flag_xff: Option<String>,
+    flag_aggregation_method: Option<String>
 }
 
 
@@ -78,52 +81,59 @@ pub fn main(){
 
 fn cmd_info<P>(path: P)
   where P: AsRef<Path> {
-    let whisper_file = WhisperFile::open(path);
-    // TODO: used to simpler of Display, not Debug
-    println!("{:?}", whisper_file);
+    let whisper_file = WhisperFile::open_readonly(path).unwrap();
+    print!("{}", whisper_file.info());
 }
 
 fn cmd_dump<P>(path: P)
   where P: AsRef<Path> {
-    let whisper_file = WhisperFile::open(path);
+    let whisper_file = WhisperFile::open_readonly(path).unwrap();
     println!("{:?}", whisper_file);
 }
 
-#[allow(unused_variables)] /*TODO: Remove once we reenable writing current_time*/
 fn cmd_update<P>(args: Args, path: P, current_time: u64)
   where P: AsRef<Path> {
-    let mut file = WhisperFile::open(path);
+    let mut file = WhisperFile::open(path).unwrap();
     let point = Point(args.arg_timestamp.parse::<u32>().unwrap(),
         					args.arg_value.parse::<f64>().unwrap());
     debug!("Updating TS: {} with value: {}", point.0, point.1);
 
-    file.write(/*current_time, TODO: reenable */ &point);
+    file.write_at(current_time as u32, &point).unwrap();
 }
 
 fn cmd_mark<P>(args: Args, path: P, current_time: u64)
   where P: AsRef<Path> {
-    let mut file = WhisperFile::open(path);
+    let mut file = WhisperFile::open(path).unwrap();
     let point = Point(current_time as u32, args.arg_value.parse::<f64>().unwrap());
 
-    file.write(/*current_time, TODO: reenable */ &point);
+    file.write_at(current_time as u32, &point).unwrap();
 }
 
 fn cmd_thrash<P>(args: Args, path: P, current_time: u64)
   where P: AsRef<Path> {
     let times = args.arg_times.parse::<u32>().unwrap();
-    let mut file = WhisperFile::open(path);
+    let mut file = WhisperFile::open(path).unwrap();
     for index in 1..times {
-        let point = Point(current_time as u32+index,
+        // Walk backwards from now, points in the future are rejected
+        let point = Point(current_time as u32 - index,
         				  args.arg_value.parse::<f64>().unwrap());
 
-        file.write(&point);
+        file.write_at(current_time as u32, &point).unwrap();
     }
 }
 
 fn cmd_create<P>(args: Args, path: P)
   where P: AsRef<Path> {
     let schema = Schema::new_from_retention_specs(args.arg_timespec).unwrap();
-    let new_result = WhisperFile::new(path, &schema);
+    let aggregation_type = match args.flag_aggregation_method {
+        Some(ref method) => method.parse::<AggregationType>().unwrap(),
+        None => AggregationType::Average
+    };
+    let x_files_factor = match args.flag_xff {
+        Some(ref xff) => xff.parse::<f32>().unwrap(),
+        None => 0.5
+    };
+    let new_result = WhisperFile::new_with_options(path, &schema, aggregation_type, x_files_factor);
     match new_result {
     	// TODO change to Display
         Ok(whisper_file) => println!("Success! {:?}", whisper_file),
@@ -0,0 +1,566 @@
+use std::collections::{ BTreeMap, HashMap, HashSet };
+use std::fmt;
+use std::fs::File;
+use std::io::{ self, Read };
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::{ Arc, Mutex };
+use std::thread;
+use std::time::Duration;
+
+use regex::Regex;
+
+use whisper::{ WhisperCache, NamedPoint, current_time };
+use whisper::{ WhisperError, RewriteRules, RewriteStage };
+
+// How long carbon keeps the intervals of an aggregated metric around, in
+// multiples of its frequency, for late points to still be counted in
+pub const MAX_AGGREGATION_INTERVALS : u32 = 5;
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum AggregateMethod {
+	Sum,
+	Average,
+	Min,
+	Max,
+	Count,
+	// p50, p90, p999, ... as a fraction from 0 to 1
+	Percentile(f64)
+}
+
+impl AggregateMethod {
+	// NaN and infinities, which collectd sends, are left out. A percentile
+	// factor outside 0 to 1 aggregates to None.
+	pub fn aggregate(&self, values: &[f64]) -> Option<f64> {
+		if let AggregateMethod::Percentile(factor) = *self {
+			if !(factor >= 0.0 && factor <= 1.0) {
+				return None;
+			}
+		}
+		let values : Vec<f64> = values.iter().cloned().filter(|value| value.is_finite()).collect();
+		if values.is_empty() {
+			return None;
+		}
+		let value = match *self {
+			AggregateMethod::Sum => values.iter().sum(),
+			AggregateMethod::Average => values.iter().sum::<f64>() / values.len() as f64,
+			AggregateMethod::Min => values.iter().cloned().fold(values[0], f64::min),
+			AggregateMethod::Max => values.iter().cloned().fold(values[0], f64::max),
+			AggregateMethod::Count => values.len() as f64,
+			// Interpolated between the closest ranks, same as carbon
+			AggregateMethod::Percentile(factor) => {
+				let mut sorted = values.clone();
+				sorted.sort_by(|a, b| a.total_cmp(b));
+				let rank = factor * (sorted.len() - 1) as f64;
+				let (left, right) = (rank.floor(), rank.ceil());
+				if left == right {
+					sorted[left as usize]
+				} else {
+					sorted[left as usize] * (right - rank) + sorted[right as usize] * (rank - left)
+				}
+			}
+		};
+		Some(value)
+	}
+}
+
+impl FromStr for AggregateMethod {
+	type Err = String;
+
+	fn from_str(method: &str) -> Result<AggregateMethod, String> {
+		match method {
+			"sum" => Ok(AggregateMethod::Sum),
+			"avg" => Ok(AggregateMethod::Average),
+			"min" => Ok(AggregateMethod::Min),
+			"max" => Ok(AggregateMethod::Max),
+			"count" => Ok(AggregateMethod::Count),
+			_ if method.len() > 1 && method.starts_with('p') && method[1..].bytes().all(|byte| byte.is_ascii_digit()) => {
+				Ok(AggregateMethod::Percentile(format!("0.{}", &method[1..]).parse().unwrap()))
+			},
+			_ => Err(format!("unknown aggregation method {}", method))
+		}
+	}
+}
+
+impl fmt::Display for AggregateMethod {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match *self {
+			AggregateMethod::Sum => write!(f, "sum"),
+			AggregateMethod::Average => write!(f, "avg"),
+			AggregateMethod::Min => write!(f, "min"),
+			AggregateMethod::Max => write!(f, "max"),
+			AggregateMethod::Count => write!(f, "count"),
+			// The digits after `0.`, which is what the parser takes. A factor
+			// it can't give, 1 or one out of range, is shown as it is.
+			AggregateMethod::Percentile(factor) if factor >= 0.0 && factor < 1.0 => {
+				let digits = factor.to_string();
+				match digits.find('.') {
+					Some(dot) => write!(f, "p{}", &digits[dot + 1 ..]),
+					None => write!(f, "p0")
+				}
+			},
+			AggregateMethod::Percentile(factor) => write!(f, "percentile({})", factor)
+		}
+	}
+}
+
+// A line of aggregation-rules.conf: `output_template (frequency) = method input_pattern`.
+// In the input pattern `<field>` captures a node, `<<field>>` any number of
+// them, and `*` matches within a node. The output template puts fields back
+// in by the same `<field>`.
+#[derive(Debug, Clone)]
+pub struct AggregatorRule {
+	pub output_template: String,
+	pub frequency: u32,
+	pub method: AggregateMethod,
+	pub input_pattern: String,
+	regex: Regex,
+	fields: Vec<String>
+}
+
+impl AggregatorRule {
+	pub fn parse(line: &str) -> Result<AggregatorRule, String> {
+		let invalid = || format!("invalid rule `{}`, not `output_template (frequency) = method input_pattern`", line);
+
+		let (output_template, rest) = try!( split_word(line).ok_or_else(&invalid) );
+		let (frequency, rest) = try!( split_word(rest).ok_or_else(&invalid) );
+		if !(frequency.starts_with('(') && frequency.ends_with(')')) {
+			return Err(invalid());
+		}
+		let frequency = try!( frequency[1..frequency.len()-1].parse::<u32>().map_err(|_| format!("invalid frequency {}", frequency)) );
+		if frequency == 0 {
+			return Err("the frequency has to be at least a second".to_string());
+		}
+		let (equals, rest) = try!( split_word(rest).ok_or_else(&invalid) );
+		let (method, rest) = try!( split_word(rest).ok_or_else(&invalid) );
+		let (input_pattern, rest) = try!( split_word(rest).ok_or_else(&invalid) );
+		if equals != "=" || !rest.trim().is_empty() {
+			return Err(invalid());
+		}
+
+		let (regex, fields) = try!( AggregatorRule::build_regex(input_pattern) );
+		Ok(AggregatorRule {
+			output_template: output_template.to_string(),
+			frequency: frequency,
+			method: try!( method.parse() ),
+			input_pattern: input_pattern.to_string(),
+			regex: regex,
+			fields: fields
+		})
+	}
+
+	// The aggregated metric `metric` goes into, if any. Fields the input
+	// pattern doesn't capture leave the output without one.
+	pub fn output_for(&self, metric: &str) -> Option<String> {
+		let captures = match self.regex.captures(metric) {
+			Some(captures) => captures,
+			None => return None
+		};
+
+		let mut output = String::new();
+		let mut rest = &self.output_template[..];
+		while let Some(start) = rest.find('<') {
+			let end = match rest[start..].find('>') {
+				Some(end) => start + end,
+				None => break
+			};
+			let field = &rest[start+1..end];
+			if !self.fields.iter().any(|known| known == field) {
+				return None;
+			}
+			output.push_str(&rest[..start]);
+			output.push_str(captures.name(field).map(|value| value.as_str()).unwrap_or(""));
+			rest = &rest[end+1..];
+		}
+		output.push_str(rest);
+		Some(output)
+	}
+
+	// Node by node the way carbon builds it, anchored at both ends
+	fn build_regex(input_pattern: &str) -> Result<(Regex, Vec<String>), String> {
+		let mut fields = vec![];
+		let mut parts = vec![];
+		for part in input_pattern.split('.') {
+			let (open, close) = (part.find("<<"), part.find(">>"));
+			let regex_part = match (open, close) {
+				(Some(open), Some(close)) if close > open => {
+					let field = &part[open+2..close];
+					fields.push(field.to_string());
+					format!("{}(?P<{}>.+?){}", &part[..open], field, &part[close+2..])
+				},
+				_ => match (part.find('<'), part.find('>')) {
+					(Some(open), Some(close)) if close > open => {
+						let field = &part[open+1..close];
+						fields.push(field.to_string());
+						format!("{}(?P<{}>[^.]+?){}", &part[..open], field, &part[close+1..])
+					},
+					_ if part == "*" => "[^.]+".to_string(),
+					_ => part.replace("*", "[^.]*")
+				}
+			};
+			parts.push(regex_part);
+		}
+
+		let regex = try!( Regex::new(&format!("^{}$", parts.join("\\."))).map_err(|err| format!("invalid input pattern {}: {}", input_pattern, err)) );
+		Ok((regex, fields))
+	}
+}
+
+fn split_word(line: &str) -> Option<(&str, &str)> {
+	let line = line.trim_left();
+	if line.is_empty() {
+		return None;
+	}
+	let end = line.find(char::is_whitespace).unwrap_or(line.len());
+	Some((&line[..end], &line[end..]))
+}
+
+// Same as carbon's aggregation-rules.conf: every rule a metric matches gets
+// its points, blank lines and `#` comments aside.
+#[derive(Debug, Clone)]
+pub struct AggregatorRules {
+	rules: Vec<AggregatorRule>
+}
+
+impl AggregatorRules {
+	pub fn new(rules: Vec<AggregatorRule>) -> AggregatorRules {
+		AggregatorRules { rules: rules }
+	}
+
+	pub fn open<P>(path: P) -> io::Result<AggregatorRules>
+		where P: AsRef<Path> {
+		let mut contents = String::new();
+		try!( try!( File::open(path) ).read_to_string(&mut contents) );
+		AggregatorRules::parse(&contents).map_err(|reason| io::Error::new(io::ErrorKind::InvalidData, reason))
+	}
+
+	pub fn parse(contents: &str) -> Result<AggregatorRules, String> {
+		let mut rules = vec![];
+		for (index, line) in contents.lines().enumerate() {
+			let line = line.trim();
+			if line.is_empty() || line.starts_with('#') {
+				continue;
+			}
+			rules.push(try!( AggregatorRule::parse(line).map_err(|reason| format!("line {}: {}", index + 1, reason)) ));
+		}
+		Ok(AggregatorRules::new(rules))
+	}
+
+	pub fn rules(&self) -> &[AggregatorRule] {
+		&self.rules
+	}
+
+	// Every aggregated metric `metric` goes into, with the rule making it
+	pub fn outputs_for(&self, metric: &str) -> Vec<(String, &AggregatorRule)> {
+		self.rules.iter().filter_map(|rule| rule.output_for(metric).map(|output| (output, rule))).collect()
+	}
+}
+
+#[derive(Debug, Default, PartialEq, Clone)]
+pub struct AggregatorStats {
+	pub points_received: u64,
+	// Received points that matched at least one rule
+	pub points_aggregated: u64,
+	pub aggregates_written: u64,
+	// Points too old for the intervals still kept
+	pub points_expired: u64,
+	pub write_errors: u64
+}
+
+// The values of one interval of an aggregated metric. Only intervals that got
+// points since the last flush are written again.
+struct IntervalBuffer {
+	values: Vec<f64>,
+	active: bool
+}
+
+struct Buffers {
+	frequency: u32,
+	method: AggregateMethod,
+	intervals: BTreeMap<u32, IntervalBuffer>
+}
+
+// carbon-aggregator in front of a cache: points matching a rule are buffered by
+// interval of its frequency, and every flush the intervals that changed are
+// written as the aggregated metric, at the start of the interval. Values
+// written before an interval ends are overwritten by later flushes, the way
+// carbon does it.
+//
+// With `forward_all`, the default, the points themselves are written too,
+// unless they are named like one of the aggregated metrics.
+//
+// Given rewrite rules, the aggregator runs them where carbon does: `[pre]` on
+// the points it receives, `[post]` on the points it writes. The cache is
+// better left without rules of its own then.
+pub struct CarbonAggregator {
+	rules: AggregatorRules,
+	cache: Arc<WhisperCache>,
+	rewrite_rules: Option< Arc<RewriteRules> >,
+	forward_all: bool,
+	max_intervals: u32,
+	buffers: Mutex< HashMap<String, Buffers> >,
+	stats: Mutex<AggregatorStats>
+}
+
+impl CarbonAggregator {
+	pub fn new(cache: Arc<WhisperCache>, rules: AggregatorRules) -> CarbonAggregator {
+		CarbonAggregator {
+			rules: rules,
+			cache: cache,
+			rewrite_rules: None,
+			forward_all: true,
+			max_intervals: MAX_AGGREGATION_INTERVALS,
+			buffers: Mutex::new(HashMap::new()),
+			stats: Mutex::new(AggregatorStats::default())
+		}
+	}
+
+	pub fn forward_all(mut self, forward_all: bool) -> CarbonAggregator {
+		self.forward_all = forward_all;
+		self
+	}
+
+	pub fn rewrite_rules(mut self, rewrite_rules: Arc<RewriteRules>) -> CarbonAggregator {
+		self.rewrite_rules = Some(rewrite_rules);
+		self
+	}
+
+	pub fn max_intervals(mut self, max_intervals: u32) -> CarbonAggregator {
+		self.max_intervals = max_intervals;
+		self
+	}
+
+	pub fn rules(&self) -> &AggregatorRules {
+		&self.rules
+	}
+
+	pub fn stats(&self) -> AggregatorStats {
+		self.stats.lock().unwrap().clone()
+	}
+
+	pub fn write(&self, named_point: NamedPoint) -> Result<(), WhisperError> {
+		self.write_at(current_time(), named_point)
+	}
+
+	pub fn write_at(&self, now: u32, named_point: NamedPoint) -> Result<(), WhisperError> {
+		let named_point = self.rewrite(RewriteStage::Pre, named_point);
+		let outputs = self.rules.outputs_for(named_point.metric_name());
+		let (expired, is_output) = {
+			let mut buffers = self.buffers.lock().unwrap();
+			let is_output = buffers.contains_key(named_point.metric_name());
+			let mut expired = 0;
+			for &(ref output, rule) in outputs.iter() {
+				let timestamp = named_point.point().0;
+				let interval = timestamp - timestamp % rule.frequency;
+				if interval.saturating_add(rule.frequency.saturating_mul(self.max_intervals)) <= now {
+					expired = expired + 1;
+					continue;
+				}
+				let buffers = buffers.entry(output.clone()).or_insert_with(|| Buffers {
+					frequency: rule.frequency,
+					method: rule.method,
+					intervals: BTreeMap::new()
+				});
+				let buffer = buffers.intervals.entry(interval).or_insert_with(|| IntervalBuffer { values: vec![], active: true });
+				buffer.values.push(named_point.point().1);
+				buffer.active = true;
+			}
+			(expired, is_output)
+		};
+		{
+			let mut stats = self.stats.lock().unwrap();
+			stats.points_received = stats.points_received + 1;
+			if !outputs.is_empty() {
+				stats.points_aggregated = stats.points_aggregated + 1;
+			}
+			stats.points_expired = stats.points_expired + expired;
+		}
+
+		if self.forward_all && !is_output {
+			self.cache.write(self.rewrite(RewriteStage::Post, named_point))
+		} else {
+			Ok(())
+		}
+	}
+
+	pub fn flush(&self) {
+		self.flush_at(current_time())
+	}
+
+	// Writes the intervals that got points since the last flush, and forgets
+	// the ones older than `max_intervals` of their frequency
+	pub fn flush_at(&self, now: u32) {
+		let mut aggregates = vec![];
+		{
+			let mut buffers = self.buffers.lock().unwrap();
+			for (metric, buffers) in buffers.iter_mut() {
+				let horizon = now.saturating_sub(buffers.frequency.saturating_mul(self.max_intervals));
+				buffers.intervals = buffers.intervals.split_off(&horizon);
+				for (interval, buffer) in buffers.intervals.iter_mut().filter(|&(_, ref buffer)| buffer.active) {
+					buffer.active = false;
+					if let Some(value) = buffers.method.aggregate(&buffer.values) {
+						aggregates.push(NamedPoint::new(metric.clone(), *interval, value));
+					}
+				}
+			}
+			buffers.retain(|_, buffers| !buffers.intervals.is_empty());
+		}
+
+		let (mut written, mut failed) = (0, 0);
+		for named_point in aggregates {
+			match self.cache.write(self.rewrite(RewriteStage::Post, named_point)) {
+				Ok(()) => written = written + 1,
+				Err(err) => {
+					warn!("could not write an aggregated point: {}", err);
+					failed = failed + 1;
+				}
+			}
+		}
+
+		let mut stats = self.stats.lock().unwrap();
+		stats.aggregates_written = stats.aggregates_written + written;
+		stats.write_errors = stats.write_errors + failed;
+	}
+
+	fn rewrite(&self, stage: RewriteStage, named_point: NamedPoint) -> NamedPoint {
+		match self.rewrite_rules {
+			Some(ref rewrite_rules) => {
+				let name = rewrite_rules.rewrite(stage, named_point.metric_name());
+				NamedPoint::new(name, named_point.point().0, named_point.point().1)
+			},
+			None => named_point
+		}
+	}
+
+	// Flushes as often as the most frequent rule, forever
+	pub fn run(&self) {
+		let frequencies : HashSet<u32> = self.rules.rules().iter().map(|rule| rule.frequency).collect();
+		let tick = frequencies.into_iter().min().unwrap_or(60);
+		loop {
+			thread::sleep(Duration::from_secs(tick as u64));
+			self.flush();
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use whisper::{ Schema, temp_path };
+	use std::fs;
+
+	const AGGREGATION_RULES : &'static str = "
+# requests of every web server
+<env>.applications.<app>.all.requests (60) = sum <env>.applications.<app>.*.requests
+<env>.applications.<app>.all.latency.p90 (60) = p90 <env>.applications.<app>.*.latency
+
+servers.<host>.load.avg (10) = avg servers.<<host>>.cpu*.load
+";
+
+	#[test]
+	fn test_parse() {
+		let rules = AggregatorRules::parse(AGGREGATION_RULES).unwrap();
+		assert_eq!(rules.rules().len(), 3);
+		assert_eq!(rules.rules()[1].frequency, 60);
+		assert_eq!(rules.rules()[1].method, AggregateMethod::Percentile(0.9));
+
+		assert_eq!(rules.outputs_for("prod.applications.shop.web01.requests").iter().map(|output| output.0.clone()).collect::<Vec<_>>(), vec!["prod.applications.shop.all.requests"]);
+		assert_eq!(rules.rules()[2].output_for("servers.dc1.web01.cpu0.load"), Some("servers.dc1.web01.load.avg".to_string()));
+		assert_eq!(rules.rules()[2].output_for("servers.web01.mem.load"), None);
+		assert!(rules.outputs_for("prod.applications.shop.web01.requests.count").is_empty());
+
+		assert!(AggregatorRules::parse("a.b (60) = median a.*").unwrap_err().starts_with("line 1: unknown aggregation method"));
+		assert!(AggregatorRules::parse("\na.b 60 = sum a.*").unwrap_err().starts_with("line 2: invalid rule"));
+		assert!(AggregatorRule::parse("a.b (0) = sum a.*").is_err());
+		assert_eq!(AggregatorRule::parse("a.<missing> (60) = sum a.*").unwrap().output_for("a.b"), None);
+	}
+
+	#[test]
+	fn test_aggregate() {
+		let values = [3.0, 1.0, 4.0, 1.0, 5.0];
+		assert_eq!(AggregateMethod::Sum.aggregate(&values), Some(14.0));
+		assert_eq!(AggregateMethod::Average.aggregate(&values), Some(2.8));
+		assert_eq!(AggregateMethod::Min.aggregate(&values), Some(1.0));
+		assert_eq!(AggregateMethod::Max.aggregate(&values), Some(5.0));
+		assert_eq!(AggregateMethod::Count.aggregate(&values), Some(5.0));
+		assert_eq!(AggregateMethod::Percentile(0.5).aggregate(&values), Some(3.0));
+		assert_eq!(AggregateMethod::Percentile(0.9).aggregate(&values), Some(4.6));
+		assert_eq!(AggregateMethod::Sum.aggregate(&[]), None);
+		assert_eq!(AggregateMethod::Percentile(0.5).aggregate(&[2.0, ::std::f64::NAN, 1.0, ::std::f64::INFINITY, 3.0]), Some(2.0));
+		assert_eq!(AggregateMethod::Count.aggregate(&[::std::f64::NAN]), None);
+		assert_eq!(AggregateMethod::Percentile(0.0).aggregate(&values), Some(1.0));
+		assert_eq!(AggregateMethod::Percentile(1.0).aggregate(&values), Some(5.0));
+		assert_eq!(AggregateMethod::Percentile(1.5).aggregate(&values), None);
+		assert_eq!(AggregateMethod::Percentile(::std::f64::NAN).aggregate(&values), None);
+		assert_eq!("p999".parse::<AggregateMethod>().unwrap().to_string(), "p999");
+		assert_eq!("p0".parse::<AggregateMethod>().unwrap().to_string(), "p0");
+		assert_eq!("p05".parse::<AggregateMethod>().unwrap().to_string(), "p05");
+		assert_eq!(AggregateMethod::Percentile(1.0).to_string(), "percentile(1)");
+	}
+
+	#[test]
+	fn test_carbon_aggregator() {
+		let base_path = temp_path("test_carbon_aggregator");
+		fs::remove_dir_all(&base_path).ok();
+		let cache = Arc::new(WhisperCache::new(&base_path, 10, Schema::parse("10s:10m").unwrap()));
+		let aggregator = CarbonAggregator::new(cache.clone(), AggregatorRules::parse(AGGREGATION_RULES).unwrap());
+		let now = current_time();
+		let interval = now - now % 60 - 60;
+
+		for &(host, value) in &[("web01", 10.0), ("web02", 5.0), ("web01", 1.0)] {
+			aggregator.write_at(now, NamedPoint::new(format!("prod.applications.shop.{}.requests", host), interval + 5, value)).unwrap();
+		}
+		aggregator.write_at(now, NamedPoint::new("prod.applications.shop.web01.requests".to_string(), interval - 400, 1.0)).unwrap();
+		aggregator.write_at(now, NamedPoint::new("prod.other".to_string(), interval, 1.0)).unwrap();
+		aggregator.flush_at(now);
+
+		let fetched = cache.fetch("prod.applications.shop.all.requests", interval - 10, interval).unwrap();
+		assert_eq!(fetched.values, vec![Some(16.0)]);
+		assert_eq!(cache.fetch("prod.applications.shop.web02.requests", interval - 10, interval).unwrap().values, vec![Some(5.0)]);
+
+		// A late point rewrites its interval, an unchanged one isn't written again
+		aggregator.write_at(now, NamedPoint::new("prod.applications.shop.web03.requests".to_string(), interval + 30, 4.0)).unwrap();
+		aggregator.flush_at(now);
+		assert_eq!(cache.fetch("prod.applications.shop.all.requests", interval - 10, interval).unwrap().values, vec![Some(20.0)]);
+		aggregator.flush_at(now);
+
+		// A NaN is no part of the aggregate, and breaks nothing for later points
+		aggregator.write_at(now, NamedPoint::new("prod.applications.shop.web01.latency".to_string(), interval, ::std::f64::NAN)).unwrap();
+		aggregator.write_at(now, NamedPoint::new("prod.applications.shop.web02.latency".to_string(), interval, 2.0)).unwrap();
+		aggregator.flush_at(now);
+		assert_eq!(cache.fetch("prod.applications.shop.all.latency.p90", interval - 10, interval).unwrap().values, vec![Some(2.0)]);
+
+		assert_eq!(aggregator.stats(), AggregatorStats {
+			points_received: 8,
+			points_aggregated: 7,
+			aggregates_written: 3,
+			points_expired: 1,
+			write_errors: 0
+		});
+
+		// Intervals near the end of time don't overflow
+		let aggregator = CarbonAggregator::new(cache.clone(), AggregatorRules::parse(AGGREGATION_RULES).unwrap()).forward_all(false);
+		aggregator.write_at(u32::MAX - 1, NamedPoint::new("prod.applications.shop.web01.requests".to_string(), u32::MAX - 1, 1.0)).unwrap();
+		aggregator.flush_at(u32::MAX - 1);
+		assert_eq!(aggregator.stats().points_expired, 0);
+	}
+
+	#[test]
+	fn test_rewrite_rules() {
+		let base_path = temp_path("test_carbon_aggregator_rewrite_rules");
+		fs::remove_dir_all(&base_path).ok();
+		let cache = Arc::new(WhisperCache::new(&base_path, 10, Schema::parse("10s:10m").unwrap()));
+		let rewrite_rules = RewriteRules::parse("[pre]\n^collectd_([a-z0-9]+)\\. = servers.\\1.\n[post]\n\\.all\\. = .total.\n").unwrap();
+		let rules = AggregatorRules::parse("servers.all.<metric> (60) = sum servers.*.<metric>").unwrap();
+		let aggregator = CarbonAggregator::new(cache.clone(), rules).rewrite_rules(Arc::new(rewrite_rules));
+		let now = current_time();
+		let interval = now - now % 60 - 60;
+
+		aggregator.write_at(now, NamedPoint::new("collectd_web01.load".to_string(), interval, 1.0)).unwrap();
+		aggregator.write_at(now, NamedPoint::new("collectd_web02.load".to_string(), interval, 2.0)).unwrap();
+		aggregator.flush_at(now);
+
+		assert_eq!(cache.fetch("servers.total.load", interval - 10, interval).unwrap().values, vec![Some(3.0)]);
+		assert_eq!(cache.fetch("servers.web01.load", interval - 10, interval).unwrap().values, vec![Some(1.0)]);
+	}
+}
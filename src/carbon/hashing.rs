@@ -0,0 +1,191 @@
+use super::md5;
+
+// How keys are placed on the ring, carbon's HASH_TYPE setting
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum HashType {
+	// carbon_ch: the first 16 bits of the key's md5
+	Carbon,
+	// fnv1a_ch: 32 bit FNV-1a folded to 16 bits. Replicas only hash the
+	// instance, so destinations need distinct instances.
+	Fnv1a
+}
+
+// A carbon-cache a relay sends to. Only the server and instance place it on
+// the ring, the same cache listening on another port stays put.
+#[derive(Debug, PartialEq, Eq, Hash, Clone)]
+pub struct Destination {
+	pub server: String,
+	pub port: u16,
+	pub instance: Option<String>
+}
+
+impl Destination {
+	// What carbon formats as the node key, the repr of a (server, instance) tuple
+	fn key(&self) -> String {
+		match self.instance {
+			Some(ref instance) => format!("('{}', '{}')", self.server, instance),
+			None => format!("('{}', None)", self.server)
+		}
+	}
+}
+
+// carbon's ConsistentHashRing, placing the same metrics on the same
+// destinations as carbon-relay given the same destinations and replica count
+pub struct ConsistentHashRing {
+	hash_type: HashType,
+	replica_count: usize,
+	// (position, index in destinations), by position
+	ring: Vec<(u32, usize)>,
+	destinations: Vec<Destination>
+}
+
+impl ConsistentHashRing {
+	// carbon-relay uses 100 replicas
+	pub fn new(hash_type: HashType, replica_count: usize) -> ConsistentHashRing {
+		ConsistentHashRing {
+			hash_type: hash_type,
+			replica_count: replica_count,
+			ring: vec![],
+			destinations: vec![]
+		}
+	}
+
+	pub fn with_destinations(hash_type: HashType, replica_count: usize, destinations: Vec<Destination>) -> ConsistentHashRing {
+		let mut ring = ConsistentHashRing::new(hash_type, replica_count);
+		for destination in destinations {
+			ring.add_destination(destination);
+		}
+		ring
+	}
+
+	pub fn destinations(&self) -> &[Destination] {
+		&self.destinations
+	}
+
+	// Adding one that's already there does nothing
+	pub fn add_destination(&mut self, destination: Destination) {
+		if self.destinations.contains(&destination) {
+			return;
+		}
+		let index = self.destinations.len();
+		for replica in 0..self.replica_count {
+			let replica_key = match self.hash_type {
+				HashType::Carbon => format!("{}:{}", destination.key(), replica),
+				HashType::Fnv1a => format!("{}-{}", replica, destination.instance.as_ref().map_or("None", |instance| instance.as_str()))
+			};
+			// Taken positions push the replica along, same as carbon
+			let mut position = self.position(&replica_key);
+			while self.ring.iter().any(|&(taken, _)| taken == position) {
+				position = position + 1;
+			}
+			let at = self.ring.binary_search_by_key(&position, |&(position, _)| position).unwrap_err();
+			self.ring.insert(at, (position, index));
+		}
+		self.destinations.push(destination);
+	}
+
+	// Replicas of the others keep their positions, so only its metrics move
+	pub fn remove_destination(&mut self, destination: &Destination) {
+		let mut remaining = ConsistentHashRing::new(self.hash_type, self.replica_count);
+		for other in self.destinations.iter().filter(|&other| other != destination) {
+			remaining.add_destination(other.clone());
+		}
+		*self = remaining;
+	}
+
+	// Where `key`, usually a metric name, goes. None on an empty ring.
+	pub fn get_destination(&self, key: &str) -> Option<&Destination> {
+		self.get_destinations(key).into_iter().next()
+	}
+
+	// Every destination, in the order met walking the ring from `key`'s
+	// position. A relay with a replication factor of n sends to the first n.
+	pub fn get_destinations(&self, key: &str) -> Vec<&Destination> {
+		if self.ring.is_empty() {
+			return vec![];
+		}
+
+		let position = self.position(key);
+		let start = match self.ring.binary_search_by_key(&position, |&(position, _)| position) {
+			Ok(index) | Err(index) => index
+		};
+		let mut found : Vec<usize> = vec![];
+		for offset in 0..self.ring.len() {
+			let index = self.ring[(start + offset) % self.ring.len()].1;
+			if !found.contains(&index) {
+				found.push(index);
+				if found.len() == self.destinations.len() {
+					break;
+				}
+			}
+		}
+		found.into_iter().map(|index| &self.destinations[index]).collect()
+	}
+
+	fn position(&self, key: &str) -> u32 {
+		match self.hash_type {
+			HashType::Carbon => {
+				let digest = md5::digest(key.as_bytes());
+				(digest[0] as u32) << 8 | digest[1] as u32
+			},
+			HashType::Fnv1a => {
+				let hash = key.bytes().fold(0x811c9dc5u32, |hash, byte| (hash ^ byte as u32).wrapping_mul(0x01000193));
+				(hash >> 16) ^ (hash & 0xffff)
+			}
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn destinations() -> Vec<Destination> {
+		vec![
+			Destination { server: "127.0.0.1".to_string(), port: 2004, instance: Some("a".to_string()) },
+			Destination { server: "127.0.0.1".to_string(), port: 2104, instance: Some("b".to_string()) },
+			Destination { server: "10.0.0.2".to_string(), port: 2004, instance: Some("a".to_string()) }
+		]
+	}
+
+	// The expected destinations come from carbon's own ConsistentHashRing
+	#[test]
+	fn test_carbon_ch() {
+		let ring = ConsistentHashRing::with_destinations(HashType::Carbon, 100, destinations());
+		assert_eq!(ring.position("servers.web01.cpu.user"), 28773);
+		assert_eq!(&ring.ring[..3], &[(398, 0), (474, 0), (1448, 2)]);
+
+		let destinations = destinations();
+		assert_eq!(ring.get_destination("carbon.agents.host.cache.size"), Some(&destinations[0]));
+		assert_eq!(ring.get_destination("servers.web01.cpu.user"), Some(&destinations[2]));
+		assert_eq!(ring.get_destination("a"), Some(&destinations[2]));
+		assert_eq!(ring.get_destination("collectd.host.load"), Some(&destinations[1]));
+		assert_eq!(ring.get_destinations("a").len(), 3);
+	}
+
+	#[test]
+	fn test_fnv1a_ch() {
+		let ring = ConsistentHashRing::with_destinations(HashType::Fnv1a, 100, destinations());
+		assert_eq!(ring.position("servers.web01.cpu.user"), 22683);
+		assert_eq!(&ring.ring[..3], &[(218, 1), (536, 1), (611, 0)]);
+
+		let destinations = destinations();
+		assert_eq!(ring.get_destination("carbon.agents.host.cache.size"), Some(&destinations[1]));
+		assert_eq!(ring.get_destination("a"), Some(&destinations[0]));
+		assert_eq!(ring.get_destination("collectd.host.load"), Some(&destinations[0]));
+	}
+
+	#[test]
+	fn test_remove_destination() {
+		let mut ring = ConsistentHashRing::with_destinations(HashType::Carbon, 100, destinations());
+		let destinations = destinations();
+		ring.remove_destination(&destinations[2]);
+
+		assert_eq!(ring.destinations().len(), 2);
+		// Metrics of the others stay where they were
+		assert_eq!(ring.get_destination("carbon.agents.host.cache.size"), Some(&destinations[0]));
+		assert_eq!(ring.get_destination("collectd.host.load"), Some(&destinations[1]));
+		assert!(ring.get_destination("servers.web01.cpu.user") != Some(&destinations[2]));
+		assert_eq!(ConsistentHashRing::new(HashType::Carbon, 100).get_destination("a"), None);
+	}
+}
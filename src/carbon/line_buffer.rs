@@ -0,0 +1,103 @@
+use std::mem;
+
+// Splits a stream of bytes into lines, holding on to a partial last line until
+// the rest of it arrives. Lines longer than `max_line_length` are dropped up to
+// their newline, so a client that never sends one can't grow the buffer.
+pub struct LineBuffer {
+	max_line_length: usize,
+	partial: Vec<u8>,
+	// Dropping the rest of an oversized line
+	skipping: bool,
+	oversized_lines: u64
+}
+
+impl LineBuffer {
+	pub fn new(max_line_length: usize) -> LineBuffer {
+		LineBuffer {
+			max_line_length: max_line_length,
+			partial: vec![],
+			skipping: false,
+			oversized_lines: 0
+		}
+	}
+
+	// The lines completed by `data`, without their line endings
+	pub fn push(&mut self, data: &[u8]) -> Vec<Vec<u8>> {
+		let mut lines = vec![];
+		let mut rest = data;
+		loop {
+			match rest.iter().position(|&byte| byte == b'\n') {
+				Some(newline) => {
+					self.append(&rest[..newline]);
+					if let Some(line) = self.end_line() {
+						lines.push(line);
+					}
+					rest = &rest[newline + 1 ..];
+				},
+				None => {
+					self.append(rest);
+					return lines;
+				}
+			}
+		}
+	}
+
+	// Whatever is left once the stream ends, a last line without a newline
+	pub fn finish(&mut self) -> Option<Vec<u8>> {
+		match self.end_line() {
+			Some(ref line) if line.is_empty() => None,
+			line => line
+		}
+	}
+
+	pub fn oversized_lines(&self) -> u64 {
+		self.oversized_lines
+	}
+
+	fn append(&mut self, bytes: &[u8]) {
+		if self.skipping {
+			return;
+		}
+		self.partial.extend_from_slice(bytes);
+		if self.partial.len() > self.max_line_length {
+			self.partial.clear();
+			self.skipping = true;
+			self.oversized_lines = self.oversized_lines + 1;
+		}
+	}
+
+	fn end_line(&mut self) -> Option<Vec<u8>> {
+		let mut line = mem::replace(&mut self.partial, vec![]);
+		if mem::replace(&mut self.skipping, false) {
+			return None;
+		}
+		if line.last() == Some(&b'\r') {
+			line.pop();
+		}
+		Some(line)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_partial_lines() {
+		let mut buffer = LineBuffer::new(100);
+		assert_eq!(buffer.push(b"a.b 1 1440392100\na.c 2 14403"), vec![b"a.b 1 1440392100".to_vec()]);
+		assert_eq!(buffer.push(b"92100\r\n\n"), vec![b"a.c 2 1440392100".to_vec(), vec![]]);
+		assert_eq!(buffer.push(b"a.d 3"), Vec::<Vec<u8>>::new());
+		assert_eq!(buffer.finish(), Some(b"a.d 3".to_vec()));
+		assert_eq!(buffer.finish(), None);
+	}
+
+	#[test]
+	fn test_oversized_lines() {
+		let mut buffer = LineBuffer::new(8);
+		assert_eq!(buffer.push(b"0123456789"), Vec::<Vec<u8>>::new());
+		// The rest of the long line is dropped, the next one is kept
+		assert_eq!(buffer.push(b"abc\nok 1 2\n"), vec![b"ok 1 2".to_vec()]);
+		assert_eq!(buffer.oversized_lines(), 1);
+	}
+}
@@ -0,0 +1,18 @@
+// Receivers speaking carbon's protocols, feeding what they get to a WhisperCache
+// the way carbon-cache does
+
+mod line_buffer;
+mod md5;
+mod hashing;
+mod stats;
+mod tcp;
+mod udp;
+mod aggregator;
+
+pub use self::line_buffer::LineBuffer;
+pub use self::hashing::{ ConsistentHashRing, Destination, HashType };
+pub use self::stats::ReceiverStats;
+pub use self::tcp::{ TcpReceiver, ConnectionLimits };
+pub(crate) use self::tcp::accept_connections;
+pub use self::udp::UdpReceiver;
+pub use self::aggregator::{ CarbonAggregator, AggregatorRules, AggregatorRule, AggregateMethod, AggregatorStats, MAX_AGGREGATION_INTERVALS };
@@ -0,0 +1,20 @@
+// What a receiver has been up to since it started
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct ReceiverStats {
+	pub connections: u64,
+	// Turned away for being over ConnectionLimits::max_connections
+	pub rejected_connections: u64,
+	pub open_connections: usize,
+	pub lines_received: u64,
+	// Written to the cache
+	pub points_received: u64,
+	// Not a `metric value timestamp` line
+	pub invalid_lines: u64,
+	// Longer than ConnectionLimits::max_line_length, dropped
+	pub oversized_lines: u64,
+	// Parsed, but the cache failed to write them
+	pub write_errors: u64,
+	pub datagrams_received: u64,
+	// Dropped whole for holding at least one invalid line
+	pub invalid_datagrams: u64
+}
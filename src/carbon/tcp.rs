@@ -0,0 +1,220 @@
+use std::io::{ self, Read };
+use std::net::{ TcpListener, TcpStream, ToSocketAddrs, SocketAddr };
+use std::str;
+use std::sync::{ Arc, Mutex };
+use std::thread;
+use std::time::Duration;
+
+use whisper::{ WhisperCache, WhisperError, NamedPoint };
+use super::{ LineBuffer, ReceiverStats, CarbonAggregator };
+
+// How long accepting waits after it fails, so running out of descriptors
+// doesn't spin a core until a connection closes
+const ACCEPT_BACKOFF_MILLIS : u64 = 100;
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct ConnectionLimits {
+	pub max_connections: usize,
+	pub max_line_length: usize,
+	// Connections that send nothing for this long are closed. None waits forever.
+	pub idle_timeout: Option<Duration>
+}
+
+impl Default for ConnectionLimits {
+	fn default() -> ConnectionLimits {
+		ConnectionLimits {
+			max_connections: 1024,
+			max_line_length: 4096,
+			idle_timeout: None
+		}
+	}
+}
+
+// carbon's plaintext protocol: `metric value timestamp` lines over TCP, a
+// thread per connection, every point written to the cache as it's parsed
+pub struct TcpReceiver {
+	listener: TcpListener,
+	cache: Arc<WhisperCache>,
+	aggregator: Option< Arc<CarbonAggregator> >,
+	limits: ConnectionLimits,
+	stats: Arc< Mutex<ReceiverStats> >
+}
+
+impl TcpReceiver {
+	pub fn bind<A>(address: A, cache: Arc<WhisperCache>, limits: ConnectionLimits) -> io::Result<TcpReceiver>
+		where A: ToSocketAddrs {
+		Ok(TcpReceiver {
+			listener: try!( TcpListener::bind(address) ),
+			cache: cache,
+			aggregator: None,
+			limits: limits,
+			stats: Arc::new(Mutex::new(ReceiverStats::default()))
+		})
+	}
+
+	// Points go through `aggregator` on their way to the cache
+	pub fn with_aggregator(mut self, aggregator: Arc<CarbonAggregator>) -> TcpReceiver {
+		self.aggregator = Some(aggregator);
+		self
+	}
+
+	pub fn local_addr(&self) -> io::Result<SocketAddr> {
+		self.listener.local_addr()
+	}
+
+	pub fn stats(&self) -> ReceiverStats {
+		self.stats.lock().unwrap().clone()
+	}
+
+	// Accepts connections for good, failing to accept one is only logged
+	pub fn run(&self) -> io::Result<()> {
+		accept_connections(&self.listener, |stream| {
+			{
+				let mut stats = self.stats.lock().unwrap();
+				if stats.open_connections >= self.limits.max_connections {
+					debug!("turning away {:?}, {} connections open", stream.peer_addr(), stats.open_connections);
+					stats.rejected_connections = stats.rejected_connections + 1;
+					return;
+				}
+				stats.connections = stats.connections + 1;
+				stats.open_connections = stats.open_connections + 1;
+			}
+
+			let cache = self.cache.clone();
+			let aggregator = self.aggregator.clone();
+			let stats = self.stats.clone();
+			let limits = self.limits;
+			thread::spawn(move || {
+				serve_connection(stream, &cache, aggregator.as_ref().map(|aggregator| &**aggregator), &stats, &limits);
+				let mut stats = stats.lock().unwrap();
+				stats.open_connections = stats.open_connections - 1;
+			});
+		});
+		Ok(())
+	}
+}
+
+// Hands every connection `listener` accepts to `handle`, for good. A
+// connection reset before it was accepted or running out of descriptors
+// ends that connection, not the server, and is waited out a little.
+pub(crate) fn accept_connections<F>(listener: &TcpListener, mut handle: F)
+	where F: FnMut(TcpStream) {
+	for stream in listener.incoming() {
+		match stream {
+			Ok(stream) => handle(stream),
+			Err(err) => {
+				warn!("could not accept a connection: {}", err);
+				thread::sleep(Duration::from_millis(ACCEPT_BACKOFF_MILLIS));
+			}
+		}
+	}
+}
+
+fn serve_connection(mut stream: TcpStream, cache: &WhisperCache, aggregator: Option<&CarbonAggregator>, stats: &Mutex<ReceiverStats>, limits: &ConnectionLimits) {
+	if let Err(err) = stream.set_read_timeout(limits.idle_timeout) {
+		warn!("could not set the idle timeout: {}", err);
+	}
+
+	let mut lines = LineBuffer::new(limits.max_line_length);
+	let mut read_buffer = [0u8; 8192];
+	loop {
+		match stream.read(&mut read_buffer) {
+			Ok(0) => break,
+			Ok(size) => receive_lines(lines.push(&read_buffer[..size]), cache, aggregator, stats),
+			Err(ref err) if err.kind() == io::ErrorKind::Interrupted => continue,
+			Err(err) => {
+				debug!("closing {:?}: {}", stream.peer_addr(), err);
+				break;
+			}
+		}
+	}
+
+	receive_lines(lines.finish().into_iter().collect(), cache, aggregator, stats);
+	let mut stats = stats.lock().unwrap();
+	stats.oversized_lines = stats.oversized_lines + lines.oversized_lines();
+}
+
+// Shared with the other receivers. Empty lines are skipped.
+pub fn receive_lines(lines: Vec<Vec<u8>>, cache: &WhisperCache, aggregator: Option<&CarbonAggregator>, stats: &Mutex<ReceiverStats>) {
+	let (mut received, mut written, mut invalid, mut failed) = (0, 0, 0, 0);
+	for line in lines.iter().filter(|line| !line.is_empty()) {
+		received = received + 1;
+		let named_point = match str::from_utf8(line).map_err(|_| "invalid utf8 character".to_string()).and_then(|line| NamedPoint::parse_line(line.trim())) {
+			Ok(named_point) => named_point,
+			Err(reason) => {
+				debug!("dropping invalid line: {}", reason);
+				invalid = invalid + 1;
+				continue;
+			}
+		};
+		match write_point(cache, aggregator, named_point) {
+			Ok(()) => written = written + 1,
+			Err(err) => {
+				warn!("could not write a received point: {}", err);
+				failed = failed + 1;
+			}
+		}
+	}
+
+	let mut stats = stats.lock().unwrap();
+	stats.lines_received = stats.lines_received + received;
+	stats.points_received = stats.points_received + written;
+	stats.invalid_lines = stats.invalid_lines + invalid;
+	stats.write_errors = stats.write_errors + failed;
+}
+
+pub fn write_point(cache: &WhisperCache, aggregator: Option<&CarbonAggregator>, named_point: NamedPoint) -> Result<(), WhisperError> {
+	match aggregator {
+		Some(aggregator) => aggregator.write(named_point),
+		None => cache.write(named_point)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use whisper::temp_cache;
+	use std::io::Write;
+
+	fn wait_for<F>(condition: F) where F: Fn() -> bool {
+		for _ in 0..200 {
+			if condition() {
+				return;
+			}
+			thread::sleep(Duration::from_millis(10));
+		}
+		panic!("timed out");
+	}
+
+	#[test]
+	fn test_tcp_receiver() {
+		let (cache, now) = temp_cache("test_carbon_tcp_receiver");
+		let cache = Arc::new(cache);
+
+		let receiver = Arc::new(TcpReceiver::bind("127.0.0.1:0", cache.clone(), ConnectionLimits { max_line_length: 64, .. ConnectionLimits::default() }).unwrap());
+		let address = receiver.local_addr().unwrap();
+		{
+			let receiver = receiver.clone();
+			thread::spawn(move || receiver.run());
+		}
+
+		let mut client = TcpStream::connect(address).unwrap();
+		// A line split over two writes, an invalid one and one too long
+		let timestamp = now.to_string();
+		let (head, tail) = timestamp.split_at(5);
+		write!(client, "tcp.a 1 {}\ntcp.b 2 {}", now, head).unwrap();
+		client.flush().unwrap();
+		thread::sleep(Duration::from_millis(20));
+		write!(client, "{}\nnot a point\n{} 1 {}\r\n\ntcp.a 3 {}", tail, "x".repeat(100), now, now - 10).unwrap();
+		drop(client);
+
+		wait_for(|| receiver.stats().open_connections == 0 && receiver.stats().connections == 1);
+		let stats = receiver.stats();
+		assert_eq!(stats.lines_received, 4);
+		assert_eq!(stats.points_received, 3);
+		assert_eq!(stats.invalid_lines, 1);
+		assert_eq!(stats.oversized_lines, 1);
+		assert_eq!(cache.fetch("tcp.a", now - 20, now).unwrap().values, vec![Some(3.0), Some(1.0)]);
+		assert_eq!(cache.fetch("tcp.b", now - 10, now).unwrap().values, vec![Some(2.0)]);
+	}
+}
@@ -0,0 +1,124 @@
+use std::io;
+use std::net::{ UdpSocket, ToSocketAddrs, SocketAddr };
+use std::sync::{ Arc, Mutex };
+
+use whisper::{ WhisperCache, NamedPoint };
+use super::{ ReceiverStats, CarbonAggregator };
+use super::tcp::write_point;
+
+// The largest payload a UDP datagram can carry
+const MAX_DATAGRAM_SIZE : usize = 65535;
+
+// carbon's plaintext protocol over UDP, any number of lines per datagram.
+// Datagrams stand alone: the last line needs no newline, and none is carried
+// over to the next one. Like NamedPoint::from_datagram, a datagram with an
+// invalid line is dropped whole.
+pub struct UdpReceiver {
+	socket: UdpSocket,
+	cache: Arc<WhisperCache>,
+	aggregator: Option< Arc<CarbonAggregator> >,
+	stats: Mutex<ReceiverStats>
+}
+
+impl UdpReceiver {
+	pub fn bind<A>(address: A, cache: Arc<WhisperCache>) -> io::Result<UdpReceiver>
+		where A: ToSocketAddrs {
+		Ok(UdpReceiver {
+			socket: try!( UdpSocket::bind(address) ),
+			cache: cache,
+			aggregator: None,
+			stats: Mutex::new(ReceiverStats::default())
+		})
+	}
+
+	// Points go through `aggregator` on their way to the cache
+	pub fn with_aggregator(mut self, aggregator: Arc<CarbonAggregator>) -> UdpReceiver {
+		self.aggregator = Some(aggregator);
+		self
+	}
+
+	pub fn local_addr(&self) -> io::Result<SocketAddr> {
+		self.socket.local_addr()
+	}
+
+	pub fn stats(&self) -> ReceiverStats {
+		self.stats.lock().unwrap().clone()
+	}
+
+	// Receives datagrams until the socket fails
+	pub fn run(&self) -> io::Result<()> {
+		let mut datagram = vec![0u8; MAX_DATAGRAM_SIZE];
+		loop {
+			let (size, _) = match self.socket.recv_from(&mut datagram) {
+				Ok(received) => received,
+				Err(ref err) if err.kind() == io::ErrorKind::Interrupted => continue,
+				Err(err) => return Err(err)
+			};
+			self.receive_datagram(&datagram[..size]);
+		}
+	}
+
+	fn receive_datagram(&self, datagram: &[u8]) {
+		let named_points = NamedPoint::from_datagram(datagram);
+
+		let mut stats = self.stats.lock().unwrap();
+		stats.datagrams_received = stats.datagrams_received + 1;
+		let named_points = match named_points {
+			Ok(named_points) => named_points,
+			Err(reason) => {
+				debug!("dropping datagram: {}", reason);
+				stats.invalid_datagrams = stats.invalid_datagrams + 1;
+				return;
+			}
+		};
+
+		stats.lines_received = stats.lines_received + named_points.len() as u64;
+		for named_point in named_points {
+			match write_point(&self.cache, self.aggregator.as_ref().map(|aggregator| &**aggregator), named_point) {
+				Ok(()) => stats.points_received = stats.points_received + 1,
+				Err(err) => {
+					warn!("could not write a received point: {}", err);
+					stats.write_errors = stats.write_errors + 1;
+				}
+			}
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use whisper::temp_cache;
+	use std::thread;
+	use std::time::Duration;
+
+	#[test]
+	fn test_udp_receiver() {
+		let (cache, now) = temp_cache("test_carbon_udp_receiver");
+		let cache = Arc::new(cache);
+
+		let receiver = Arc::new(UdpReceiver::bind("127.0.0.1:0", cache.clone()).unwrap());
+		let address = receiver.local_addr().unwrap();
+		{
+			let receiver = receiver.clone();
+			thread::spawn(move || receiver.run());
+		}
+
+		let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+		client.send_to(format!("udp.a 1 {}\nudp.b 2 {}", now - 10, now).as_bytes(), address).unwrap();
+		client.send_to(format!("udp.a 5 {}\nnot a point\n", now).as_bytes(), address).unwrap();
+		client.send_to(format!("udp.a 3 {}\n", now).as_bytes(), address).unwrap();
+
+		for _ in 0..200 {
+			if receiver.stats().datagrams_received == 3 {
+				break;
+			}
+			thread::sleep(Duration::from_millis(10));
+		}
+		let stats = receiver.stats();
+		assert_eq!(stats.datagrams_received, 3);
+		assert_eq!(stats.invalid_datagrams, 1);
+		assert_eq!(stats.points_received, 3);
+		assert_eq!(cache.fetch("udp.a", now - 20, now).unwrap().values, vec![Some(1.0), Some(3.0)]);
+	}
+}
@@ -0,0 +1,123 @@
+use std::fs;
+use std::path::Path;
+
+use whisper::{ WhisperFile, Schema, Point, current_time };
+use super::{ CeresNode, CeresError };
+
+impl CeresNode {
+	// The whisper schema of the node's retentions metadata, None without it
+	pub fn schema(&self) -> Option<Result<Schema, CeresError>> {
+		if self.retentions.is_empty() {
+			return None;
+		}
+		let specs = self.retentions.iter().map(|&(precision, points)| format!("{}s:{}s", precision, precision * points)).collect();
+		Some(Schema::new_from_retention_specs(specs).map_err(|err| CeresError::Whisper(err.into())))
+	}
+
+	pub fn convert<P>(&self, path: P, schema: &Schema) -> Result<WhisperFile, CeresError>
+		where P: AsRef<Path> {
+		self.convert_at(current_time(), path, schema)
+	}
+
+	// Creates a whisper file at `path` with the node's aggregation method and
+	// xFilesFactor and writes every slice into it, coarsest time step first so
+	// the finer data wins where they overlap. What's older than the schema
+	// retains as of `now` is dropped, the same as any other write.
+	pub fn convert_at<P>(&self, now: u32, path: P, schema: &Schema) -> Result<WhisperFile, CeresError>
+		where P: AsRef<Path> {
+		let mut file = try!( WhisperFile::new_with_options(path, schema, self.aggregation_type.clone(), self.x_files_factor) );
+
+		let mut time_steps : Vec<u32> = self.slices.iter().map(|slice| slice.time_step).collect();
+		time_steps.sort();
+		time_steps.dedup();
+		for &time_step in time_steps.iter().rev() {
+			let mut points : Vec<Point> = vec![];
+			for slice in self.slices.iter().filter(|slice| slice.time_step == time_step) {
+				points.extend(try!( slice.read() ));
+			}
+			try!( file.update_many_at(now, &points) );
+		}
+		Ok(file)
+	}
+}
+
+// Converts every node under `ceres_root` into a file under `whisper_root`,
+// `a/b/c` to `a/b/c.wsp`, laid out by its retentions metadata or
+// `default_schema` when it has none. Returns how many nodes were converted.
+pub fn convert_tree<P, Q>(ceres_root: P, whisper_root: Q, default_schema: &Schema) -> Result<usize, CeresError>
+	where P: AsRef<Path>, Q: AsRef<Path> {
+	convert_directory(ceres_root.as_ref(), whisper_root.as_ref(), default_schema, current_time())
+}
+
+fn convert_directory(directory: &Path, whisper_directory: &Path, default_schema: &Schema, now: u32) -> Result<usize, CeresError> {
+	if CeresNode::is_node(directory) {
+		let node = try!( CeresNode::open(directory) );
+		let schema = match node.schema() {
+			Some(schema) => try!( schema ),
+			None => default_schema.clone()
+		};
+		if let Some(parent) = whisper_directory.parent() {
+			try!( fs::create_dir_all(parent) );
+		}
+		try!( node.convert_at(now, whisper_directory.with_extension("wsp"), &schema) );
+		return Ok(1);
+	}
+
+	let mut converted = 0;
+	for entry in try!( fs::read_dir(directory) ) {
+		let path = try!( entry ).path();
+		let name = match path.file_name().and_then(|name| name.to_str()) {
+			// .ceres-tree and the like
+			Some(name) if !name.starts_with('.') => name.to_string(),
+			_ => continue
+		};
+		if path.is_dir() {
+			converted = converted + try!( convert_directory(&path, &whisper_directory.join(name), default_schema, now) );
+		}
+	}
+	Ok(converted)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use ceres::node::tests::{ build_node, write_slice };
+	use whisper::{ AggregationType, temp_path };
+	use std::path::PathBuf;
+
+	#[test]
+	fn test_convert() {
+		let path = PathBuf::from(temp_path("test_ceres_convert"));
+		build_node(&path, "{\"timeStep\": 10, \"retentions\": [[10, 6], [60, 10]], \"aggregationMethod\": \"sum\", \"xFilesFactor\": 0}");
+		let now = 1440392100;
+		write_slice(&path, now - 50, 10, &[1.0, 2.0, 3.0]);
+		write_slice(&path, now - 300, 60, &[9.0, 7.0]);
+
+		let node = CeresNode::open(&path).unwrap();
+		let schema = node.schema().unwrap().unwrap();
+		let whisper_path = temp_path("test_ceres_convert.wsp");
+		fs::remove_file(&whisper_path).ok();
+		let file = node.convert_at(now, &whisper_path, &schema).unwrap();
+
+		assert_eq!(file.info().aggregation_type, AggregationType::Sum);
+		assert_eq!(file.info().max_retention, 600);
+		assert_eq!(file.fetch_at(now, now - 60, now).unwrap().values, vec![Some(1.0), Some(2.0), Some(3.0), None, None, None]);
+		let series = file.fetch_at(now, now - 360, now - 180).unwrap();
+		assert_eq!(series.values, vec![Some(9.0), Some(7.0), None]);
+	}
+
+	#[test]
+	fn test_convert_tree() {
+		let ceres_root = PathBuf::from(temp_path("test_ceres_tree"));
+		fs::remove_dir_all(&ceres_root).ok();
+		fs::create_dir_all(ceres_root.join(".ceres-tree")).unwrap();
+		build_node(&ceres_root.join("servers/web01/load"), "{\"timeStep\": 10}");
+		build_node(&ceres_root.join("servers/web02/load"), "{\"timeStep\": 10, \"retentions\": [[10, 60]]}");
+		let whisper_root = PathBuf::from(temp_path("test_ceres_tree_whisper"));
+		fs::remove_dir_all(&whisper_root).ok();
+
+		assert_eq!(convert_tree(&ceres_root, &whisper_root, &Schema::parse("10s:60s").unwrap()).unwrap(), 2);
+		assert_eq!(WhisperFile::open(whisper_root.join("servers/web01/load.wsp")).unwrap().info().max_retention, 60);
+		assert_eq!(WhisperFile::open(whisper_root.join("servers/web02/load.wsp")).unwrap().info().max_retention, 600);
+	}
+}
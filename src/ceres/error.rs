@@ -0,0 +1,57 @@
+use std::error;
+use std::fmt;
+use std::io;
+
+use whisper::WhisperError;
+
+#[derive(Debug)]
+pub enum CeresError {
+	Io(io::Error),
+	// The .ceres-node file of a node
+	InvalidMetadata(String),
+	// A file named like a slice that isn't one
+	InvalidSlice(String),
+	Whisper(WhisperError)
+}
+
+impl fmt::Display for CeresError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match *self {
+			CeresError::Io(ref err) => write!(f, "io error: {}", err),
+			CeresError::InvalidMetadata(ref reason) => write!(f, "invalid node metadata: {}", reason),
+			CeresError::InvalidSlice(ref reason) => write!(f, "invalid slice: {}", reason),
+			CeresError::Whisper(ref err) => write!(f, "whisper error: {}", err)
+		}
+	}
+}
+
+impl error::Error for CeresError {
+	fn description(&self) -> &str {
+		match *self {
+			CeresError::Io(ref err) => err.description(),
+			CeresError::InvalidMetadata(_) => "invalid node metadata",
+			CeresError::InvalidSlice(_) => "invalid slice",
+			CeresError::Whisper(ref err) => err.description()
+		}
+	}
+
+	fn cause(&self) -> Option<&error::Error> {
+		match *self {
+			CeresError::Io(ref err) => Some(err),
+			CeresError::Whisper(ref err) => Some(err),
+			_ => None
+		}
+	}
+}
+
+impl From<io::Error> for CeresError {
+	fn from(err: io::Error) -> CeresError {
+		CeresError::Io(err)
+	}
+}
+
+impl From<WhisperError> for CeresError {
+	fn from(err: WhisperError) -> CeresError {
+		CeresError::Whisper(err)
+	}
+}
@@ -0,0 +1,10 @@
+// Reading graphite's Ceres format, a directory per metric node holding its
+// metadata and slices of consecutive values, and converting it to whisper
+
+mod error;
+mod node;
+mod convert;
+
+pub use self::error::CeresError;
+pub use self::node::{ CeresNode, CeresSlice };
+pub use self::convert::convert_tree;
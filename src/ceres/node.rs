@@ -0,0 +1,240 @@
+use std::cmp;
+use std::fs::{ self, File };
+use std::io::Read;
+use std::path::{ Path, PathBuf };
+
+use byteorder::{ ByteOrder, BigEndian };
+
+use whisper::{ Point, TimeSeries, AggregationType, JsonValue };
+use super::CeresError;
+
+pub const NODE_METADATA_FILE : &'static str = ".ceres-node";
+
+// ceres.py's defaults for metadata a node leaves out
+const DEFAULT_TIME_STEP : u32 = 60;
+const DEFAULT_X_FILES_FACTOR : f32 = 0.5;
+
+// A file of consecutive big endian doubles, the first at `start_time`, NaN
+// where nothing was written. Named `<start_time>@<time_step>.slice`.
+#[derive(Debug, PartialEq, Clone)]
+pub struct CeresSlice {
+	pub start_time: u32,
+	pub time_step: u32,
+	pub points: usize,
+	path: PathBuf
+}
+
+impl CeresSlice {
+	fn open(path: PathBuf) -> Result<Option<CeresSlice>, CeresError> {
+		let (start_time, time_step) = {
+			let name = match path.file_name().and_then(|name| name.to_str()) {
+				Some(name) if name.ends_with(".slice") => &name[..name.len() - 6],
+				_ => return Ok(None)
+			};
+			let mut parts = name.splitn(2, '@');
+			match (parts.next().map(|part| part.parse::<u32>()), parts.next().map(|part| part.parse::<u32>())) {
+				(Some(Ok(start_time)), Some(Ok(time_step))) if time_step > 0 => (start_time, time_step),
+				_ => return Err(CeresError::InvalidSlice(format!("can't tell start and step from '{}'", path.display())))
+			}
+		};
+		let length = try!( fs::metadata(&path) ).len() as usize;
+		let points = length / 8;
+		if start_time as u64 + time_step as u64 * points as u64 > u32::MAX as u64 {
+			return Err(CeresError::InvalidSlice(format!("'{}' runs past the last u32 timestamp", path.display())));
+		}
+		Ok(Some(CeresSlice { start_time: start_time, time_step: time_step, points: points, path: path }))
+	}
+
+	// Where the slice stops, exclusive, the last u32 timestamp for one that
+	// would run past it
+	#[inline]
+	pub fn end_time(&self) -> u32 {
+		cmp::min(self.start_time as u64 + self.time_step as u64 * self.points as u64, u32::MAX as u64) as u32
+	}
+
+	// The values that are there, oldest first
+	pub fn read(&self) -> Result<Vec<Point>, CeresError> {
+		let mut data = vec![];
+		try!( try!( File::open(&self.path) ).read_to_end(&mut data) );
+		let mut points = vec![];
+		for (index, bytes) in data.chunks(8).enumerate() {
+			// Grown past the last u32 timestamp since it was opened
+			let timestamp = self.start_time as u64 + self.time_step as u64 * index as u64;
+			if bytes.len() < 8 || timestamp > u32::MAX as u64 {
+				break;
+			}
+			let value = BigEndian::read_f64(bytes);
+			if !value.is_nan() {
+				points.push(Point(timestamp as u32, value));
+			}
+		}
+		Ok(points)
+	}
+}
+
+// One metric of a Ceres tree: the directory holding its .ceres-node and slices
+#[derive(Debug, PartialEq, Clone)]
+pub struct CeresNode {
+	pub path: PathBuf,
+	pub time_step: u32,
+	// (seconds per point, points), when the metadata has them
+	pub retentions: Vec<(u32, u32)>,
+	pub aggregation_type: AggregationType,
+	pub x_files_factor: f32,
+	// Oldest first
+	pub slices: Vec<CeresSlice>
+}
+
+impl CeresNode {
+	pub fn is_node<P>(path: P) -> bool
+		where P: AsRef<Path> {
+		path.as_ref().join(NODE_METADATA_FILE).is_file()
+	}
+
+	pub fn open<P>(path: P) -> Result<CeresNode, CeresError>
+		where P: AsRef<Path> {
+		let path = path.as_ref().to_path_buf();
+		let mut text = String::new();
+		try!( try!( File::open(path.join(NODE_METADATA_FILE)) ).read_to_string(&mut text) );
+		let metadata = try!( JsonValue::parse(&text).map_err(CeresError::InvalidMetadata) );
+
+		let time_step = match metadata.get("timeStep") {
+			Some(&JsonValue::Number(time_step)) if time_step >= 1.0 => time_step as u32,
+			None => DEFAULT_TIME_STEP,
+			_ => return Err(CeresError::InvalidMetadata("timeStep isn't a positive number".to_string()))
+		};
+		let retentions = match metadata.get("retentions") {
+			Some(&JsonValue::Array(ref retentions)) => {
+				let mut pairs = vec![];
+				for retention in retentions {
+					match *retention {
+						JsonValue::Array(ref pair) if pair.len() == 2 => match (&pair[0], &pair[1]) {
+							(&JsonValue::Number(precision), &JsonValue::Number(points)) if precision >= 1.0 && points >= 1.0 => pairs.push((precision as u32, points as u32)),
+							_ => return Err(CeresError::InvalidMetadata("retentions aren't [precision, points] pairs".to_string()))
+						},
+						_ => return Err(CeresError::InvalidMetadata("retentions aren't [precision, points] pairs".to_string()))
+					}
+				}
+				pairs
+			},
+			None => vec![],
+			_ => return Err(CeresError::InvalidMetadata("retentions isn't a list".to_string()))
+		};
+		let aggregation_type = match metadata.get("aggregationMethod") {
+			Some(&JsonValue::String(ref method)) => try!( method.parse().map_err(CeresError::InvalidMetadata) ),
+			None => AggregationType::Average,
+			_ => return Err(CeresError::InvalidMetadata("aggregationMethod isn't a string".to_string()))
+		};
+		let x_files_factor = match metadata.get("xFilesFactor") {
+			Some(&JsonValue::Number(x_files_factor)) if x_files_factor >= 0.0 && x_files_factor <= 1.0 => x_files_factor as f32,
+			None => DEFAULT_X_FILES_FACTOR,
+			_ => return Err(CeresError::InvalidMetadata("xFilesFactor isn't between 0 and 1".to_string()))
+		};
+
+		let mut slices = vec![];
+		for entry in try!( fs::read_dir(&path) ) {
+			if let Some(slice) = try!( CeresSlice::open(try!( entry ).path()) ) {
+				slices.push(slice);
+			}
+		}
+		slices.sort_by_key(|slice| (slice.start_time, slice.time_step));
+
+		Ok(CeresNode {
+			path: path,
+			time_step: time_step,
+			retentions: retentions,
+			aggregation_type: aggregation_type,
+			x_files_factor: x_files_factor,
+			slices: slices
+		})
+	}
+
+	// Same as ceres.py's read: the intervals of the node's time step from the
+	// one holding `from` up to the one holding `until`, exclusive, filled in from
+	// the slices of that step. Slices left at other steps aren't read.
+	pub fn read(&self, from: u32, until: u32) -> Result<TimeSeries, CeresError> {
+		let step = self.time_step;
+		let from = from - from % step;
+		let until = until - until % step;
+		let mut values = vec![None; (until.saturating_sub(from) / step) as usize];
+
+		for slice in self.slices.iter().filter(|slice| slice.time_step == step) {
+			if slice.end_time() <= from || slice.start_time >= until {
+				continue;
+			}
+			for point in try!( slice.read() ) {
+				if point.0 >= from && point.0 < until {
+					values[((point.0 - from) / step) as usize] = Some(point.1);
+				}
+			}
+		}
+
+		Ok(TimeSeries { from: from, until: cmp::max(from, until), step: step, values: values })
+	}
+}
+
+#[cfg(test)]
+pub mod tests {
+	use super::*;
+	use std::io::Write;
+	use std::f64;
+
+	pub fn write_slice(node: &Path, start_time: u32, time_step: u32, values: &[f64]) {
+		let mut data = vec![0; values.len() * 8];
+		for (index, value) in values.iter().enumerate() {
+			BigEndian::write_f64(&mut data[index*8..index*8 + 8], *value);
+		}
+		File::create(node.join(format!("{}@{}.slice", start_time, time_step))).unwrap().write_all(&data).unwrap();
+	}
+
+	pub fn build_node(path: &Path, metadata: &str) {
+		fs::remove_dir_all(path).ok();
+		fs::create_dir_all(path).unwrap();
+		File::create(path.join(NODE_METADATA_FILE)).unwrap().write_all(metadata.as_bytes()).unwrap();
+	}
+
+	#[test]
+	fn test_open_and_read() {
+		let path = PathBuf::from(::whisper::temp_path("test_ceres_node"));
+		build_node(&path, "{\"timeStep\": 10, \"retentions\": [[10, 6], [60, 10]], \"aggregationMethod\": \"max\", \"xFilesFactor\": 0.25}");
+		write_slice(&path, 1440392050, 10, &[1.0, f64::NAN, 3.0]);
+		write_slice(&path, 1440392090, 10, &[4.0]);
+		write_slice(&path, 1440391800, 60, &[9.0]);
+
+		let node = CeresNode::open(&path).unwrap();
+		assert_eq!(node.time_step, 10);
+		assert_eq!(node.retentions, vec![(10, 6), (60, 10)]);
+		assert_eq!(node.aggregation_type, AggregationType::Max);
+		assert_eq!(node.x_files_factor, 0.25);
+		assert_eq!(node.slices.len(), 3);
+		assert_eq!(node.slices[0].end_time(), 1440391860);
+		assert_eq!(node.slices[2].read().unwrap(), vec![Point(1440392090, 4.0)]);
+
+		let series = node.read(1440392045, 1440392100).unwrap();
+		assert_eq!(series, TimeSeries { from: 1440392040, until: 1440392100, step: 10, values: vec![None, Some(1.0), None, Some(3.0), None, Some(4.0)] });
+		assert!(CeresNode::is_node(&path));
+	}
+
+	#[test]
+	fn test_defaults_and_invalid_metadata() {
+		let path = PathBuf::from(::whisper::temp_path("test_ceres_node_defaults"));
+		build_node(&path, "{}");
+		let node = CeresNode::open(&path).unwrap();
+		assert_eq!((node.time_step, node.aggregation_type, node.x_files_factor), (60, AggregationType::Average, 0.5));
+		assert!(node.retentions.is_empty());
+
+		build_node(&path, "{\"aggregationMethod\": \"median\"}");
+		assert!(CeresNode::open(&path).is_err());
+		build_node(&path, "{\"timeStep\": 60}");
+		write_slice(&path, 1440392050, 10, &[1.0]);
+		File::create(path.join("latest@.slice")).unwrap();
+		assert!(CeresNode::open(&path).is_err());
+
+		build_node(&path, "{}");
+		write_slice(&path, 4294967200, 60, &[1.0, 2.0]);
+		match CeresNode::open(&path) {
+			Err(CeresError::InvalidSlice(_)) => (),
+			other => panic!("expected InvalidSlice, got {:?}", other)
+		}
+	}
+}
@@ -0,0 +1,204 @@
+// Whisper data as Arrow record batches of (metric, timestamp, value) rows and
+// Parquet files of them, for Spark, DuckDB and the like. Every file is read
+// at the best precision it holds for each stretch of time, see
+// WhisperFile::history.
+
+use std::error;
+use std::fmt;
+use std::fs;
+use std::io::{ self, Write };
+use std::path::Path;
+use std::sync::Arc;
+
+use arrow::array::{ ArrayRef, StringArray, TimestampSecondArray, Float64Array };
+use arrow::datatypes::{ DataType, Field, Schema, SchemaRef, TimeUnit };
+use arrow::error::ArrowError;
+use arrow::record_batch::RecordBatch;
+use parquet::arrow::ArrowWriter;
+use parquet::errors::ParquetError;
+
+use whisper::{ WhisperFile, WhisperError, Point };
+
+#[derive(Debug)]
+pub enum ExportError {
+	Io(io::Error),
+	Whisper(WhisperError),
+	Arrow(ArrowError),
+	Parquet(ParquetError)
+}
+
+impl fmt::Display for ExportError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match *self {
+			ExportError::Io(ref err) => write!(f, "io error: {}", err),
+			ExportError::Whisper(ref err) => write!(f, "whisper error: {}", err),
+			ExportError::Arrow(ref err) => write!(f, "arrow error: {}", err),
+			ExportError::Parquet(ref err) => write!(f, "parquet error: {}", err)
+		}
+	}
+}
+
+impl error::Error for ExportError {
+	fn description(&self) -> &str {
+		match *self {
+			ExportError::Io(_) => "io error",
+			ExportError::Whisper(_) => "whisper error",
+			ExportError::Arrow(_) => "arrow error",
+			ExportError::Parquet(_) => "parquet error"
+		}
+	}
+
+	fn cause(&self) -> Option<&error::Error> {
+		match *self {
+			ExportError::Io(ref err) => Some(err),
+			ExportError::Whisper(ref err) => Some(err),
+			ExportError::Arrow(ref err) => Some(err),
+			ExportError::Parquet(ref err) => Some(err)
+		}
+	}
+}
+
+impl From<io::Error> for ExportError {
+	fn from(err: io::Error) -> ExportError {
+		ExportError::Io(err)
+	}
+}
+
+impl From<WhisperError> for ExportError {
+	fn from(err: WhisperError) -> ExportError {
+		ExportError::Whisper(err)
+	}
+}
+
+impl From<ArrowError> for ExportError {
+	fn from(err: ArrowError) -> ExportError {
+		ExportError::Arrow(err)
+	}
+}
+
+impl From<ParquetError> for ExportError {
+	fn from(err: ParquetError) -> ExportError {
+		ExportError::Parquet(err)
+	}
+}
+
+// metric: utf8, timestamp: seconds since the epoch, value: float64, none nullable
+pub fn arrow_schema() -> SchemaRef {
+	Arc::new(Schema::new(vec![
+		Field::new("metric", DataType::Utf8, false),
+		Field::new("timestamp", DataType::Timestamp(TimeUnit::Second, None), false),
+		Field::new("value", DataType::Float64, false)
+	]))
+}
+
+// The points of one metric as a batch of rows
+pub fn record_batch(metric_name: &str, points: &[Point]) -> Result<RecordBatch, ExportError> {
+	let metrics : ArrayRef = Arc::new(StringArray::from(vec![metric_name; points.len()]));
+	let timestamps : ArrayRef = Arc::new(TimestampSecondArray::from(points.iter().map(|point| point.0 as i64).collect::<Vec<i64>>()));
+	let values : ArrayRef = Arc::new(Float64Array::from(points.iter().map(|point| point.1).collect::<Vec<f64>>()));
+	Ok(try!( RecordBatch::try_new(arrow_schema(), vec![metrics, timestamps, values]) ))
+}
+
+// Writes the rows of whisper files to a Parquet file, a row group or more per
+// batch. close() finishes the file, without it the footer is missing.
+pub struct ParquetExport<W> where W: Write + Send {
+	writer: ArrowWriter<W>,
+	rows: usize
+}
+
+impl<W> ParquetExport<W> where W: Write + Send {
+	pub fn new(writer: W) -> Result<ParquetExport<W>, ExportError> {
+		Ok(ParquetExport {
+			writer: try!( ArrowWriter::try_new(writer, arrow_schema(), None) ),
+			rows: 0
+		})
+	}
+
+	// The rows written
+	pub fn write_file(&mut self, metric_name: &str, file: &WhisperFile) -> Result<usize, ExportError> {
+		let points = try!( file.history() );
+		if points.is_empty() {
+			return Ok(0);
+		}
+		try!( self.writer.write(&try!( record_batch(metric_name, &points) )) );
+		self.rows = self.rows + points.len();
+		Ok(points.len())
+	}
+
+	// Every file under `data_root`, named by their path below it the way
+	// graphite does. Returns the rows written.
+	pub fn write_tree(&mut self, data_root: &Path) -> Result<usize, ExportError> {
+		let rows = self.rows;
+		try!( self.write_directory(data_root, "") );
+		Ok(self.rows - rows)
+	}
+
+	fn write_directory(&mut self, directory: &Path, prefix: &str) -> Result<(), ExportError> {
+		let mut entries = vec![];
+		for entry in try!( fs::read_dir(directory) ) {
+			entries.push(try!( entry ).path());
+		}
+		entries.sort();
+
+		for path in entries {
+			let name = match path.file_name().and_then(|name| name.to_str()) {
+				Some(name) if !name.starts_with('.') => name.to_string(),
+				_ => continue
+			};
+			if path.is_dir() {
+				try!( self.write_directory(&path, &format!("{}{}.", prefix, name)) );
+			} else if name.ends_with(".wsp") {
+				let file = try!( WhisperFile::open_readonly(&path) );
+				try!( self.write_file(&format!("{}{}", prefix, &name[..name.len() - 4]), &file) );
+			}
+		}
+		Ok(())
+	}
+
+	// The rows written in all
+	pub fn close(self) -> Result<usize, ExportError> {
+		try!( self.writer.close() );
+		Ok(self.rows)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use whisper::{ Schema, current_time, temp_path };
+	use arrow::array::Array;
+	use parquet::file::reader::{ FileReader, SerializedFileReader };
+	use std::fs::File;
+	use std::path::PathBuf;
+
+	#[test]
+	fn test_record_batch() {
+		let batch = record_batch("servers.load", &[Point(1440392090, 1.0), Point(1440392100, 2.5)]).unwrap();
+		assert_eq!(batch.num_rows(), 2);
+		assert_eq!(batch.schema(), arrow_schema());
+		let values = batch.column(2).as_any().downcast_ref::<Float64Array>().unwrap();
+		assert_eq!(values.value(1), 2.5);
+	}
+
+	#[test]
+	fn test_parquet_export() {
+		let data_root = PathBuf::from(temp_path("test_parquet_export"));
+		fs::remove_dir_all(&data_root).ok();
+		fs::create_dir_all(data_root.join("servers")).unwrap();
+		let now = current_time();
+		let now = now - now % 10;
+		let schema = Schema::parse("10s:60s").unwrap();
+		let mut file = WhisperFile::new(data_root.join("servers/load.wsp"), &schema).unwrap();
+		file.update_many(&[Point(now - 10, 1.0), Point(now, 2.0)]).unwrap();
+		let mut file = WhisperFile::new(data_root.join("servers/users.wsp"), &schema).unwrap();
+		file.update_many(&[Point(now, 3.0)]).unwrap();
+
+		let path = temp_path("test_parquet_export.parquet");
+		let mut export = ParquetExport::new(File::create(&path).unwrap()).unwrap();
+		assert_eq!(export.write_tree(&data_root).unwrap(), 3);
+		assert_eq!(export.close().unwrap(), 3);
+
+		let reader = SerializedFileReader::new(File::open(&path).unwrap()).unwrap();
+		assert_eq!(reader.metadata().file_metadata().num_rows(), 3);
+	}
+}
@@ -0,0 +1,65 @@
+use whisper::{ MetricPattern, MetricNode, DataRoot };
+use super::{ Request, Response };
+use whisper::json_string;
+
+// graphite-web's /metrics/find, in its `treejson` format or the `completer`
+// one of the composer's autocompletion
+pub fn find(request: &Request, data_root: &DataRoot) -> Response {
+	let query = match request.param("query") {
+		Some(query) => query,
+		None => return Response::error(400, "missing query")
+	};
+	let pattern = match MetricPattern::parse(query) {
+		Ok(pattern) => pattern,
+		Err(reason) => return Response::error(400, &reason)
+	};
+	let nodes = match data_root.find(&pattern) {
+		Ok(nodes) => nodes,
+		Err(err) => {
+			warn!("could not find {}: {}", query, err);
+			return Response::error(500, &err.to_string());
+		}
+	};
+
+	match request.param("format").unwrap_or("treejson") {
+		"treejson" => Response::json(tree_json(&nodes)),
+		"completer" => Response::json(completer_json(&nodes)),
+		format => Response::error(400, &format!("unsupported format `{}`", format))
+	}
+}
+
+pub fn tree_json(nodes: &[MetricNode]) -> String {
+	let nodes : Vec<String> = nodes.iter().map(|node| {
+		let branch = if node.is_leaf { 0 } else { 1 };
+		format!("{{\"allowChildren\": {}, \"context\": {{}}, \"expandable\": {}, \"id\": {}, \"leaf\": {}, \"text\": {}}}",
+			branch, branch, json_string(&node.path), 1 - branch, json_string(node.name()))
+	}).collect();
+	format!("[{}]", nodes.join(", "))
+}
+
+// Branch paths end in a dot, ready for the next node to be typed
+pub fn completer_json(nodes: &[MetricNode]) -> String {
+	let nodes : Vec<String> = nodes.iter().map(|node| {
+		let path = if node.is_leaf { node.path.clone() } else { format!("{}.", node.path) };
+		format!("{{\"is_leaf\": \"{}\", \"name\": {}, \"path\": {}}}", if node.is_leaf { 1 } else { 0 }, json_string(node.name()), json_string(&path))
+	}).collect();
+	format!("{{\"metrics\": [{}]}}", nodes.join(", "))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_find_json() {
+		let nodes = vec![
+			MetricNode { path: "servers.web01".to_string(), is_leaf: false },
+			MetricNode { path: "servers.load".to_string(), is_leaf: true }
+		];
+		assert_eq!(tree_json(&nodes), "[{\"allowChildren\": 1, \"context\": {}, \"expandable\": 1, \"id\": \"servers.web01\", \"leaf\": 0, \"text\": \"web01\"}, \
+			{\"allowChildren\": 0, \"context\": {}, \"expandable\": 0, \"id\": \"servers.load\", \"leaf\": 1, \"text\": \"load\"}]");
+		assert_eq!(completer_json(&nodes), "{\"metrics\": [{\"is_leaf\": \"0\", \"name\": \"web01\", \"path\": \"servers.web01.\"}, \
+			{\"is_leaf\": \"1\", \"name\": \"load\", \"path\": \"servers.load\"}]}");
+		assert_eq!(tree_json(&[]), "[]");
+	}
+}
@@ -0,0 +1,59 @@
+use influx::{ InfluxWrite, Precision };
+use super::{ Request, Response };
+use whisper::json_string;
+
+// InfluxDB 1.x's /write. The `db` and `rp` parameters are ignored, the naming
+// scheme decides where points go.
+pub fn write(request: &Request, influx: &InfluxWrite) -> Response {
+	if request.method != "POST" {
+		return Response::error(405, "writes take a POST");
+	}
+	if request.header("content-encoding").map_or(false, |encoding| encoding != "identity") {
+		return Response::error(400, "compressed bodies are not supported");
+	}
+	let precision = match request.param("precision").map(|precision| precision.parse::<Precision>()) {
+		Some(Ok(precision)) => precision,
+		Some(Err(reason)) => return Response::error(400, &reason),
+		None => Precision::default()
+	};
+
+	match influx.write(&request.body, precision) {
+		Ok(()) => Response::no_content(),
+		// The error document InfluxDB's clients look for
+		Err(reason) => Response { status: 400, .. Response::json(format!("{{\"error\": {}}}", json_string(&reason))) }
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use influx::NamingScheme;
+	use whisper::{ WhisperCache, Schema, temp_path };
+	use std::fs;
+	use std::sync::Arc;
+
+	fn request(query: &[(&str, &str)], body: &str) -> Request {
+		Request {
+			method: "POST".to_string(),
+			path: "/write".to_string(),
+			params: query.iter().map(|&(name, value)| (name.to_string(), value.to_string())).collect(),
+			headers: vec![],
+			body: body.as_bytes().to_vec()
+		}
+	}
+
+	#[test]
+	fn test_write() {
+		let base_path = temp_path("test_http_influx_write");
+		fs::remove_dir_all(&base_path).ok();
+		let cache = Arc::new(WhisperCache::new(&base_path, 10, Schema::parse("10s:60s").unwrap()));
+		let influx = InfluxWrite::new(cache, NamingScheme::default());
+
+		assert_eq!(write(&request(&[("db", "telegraf"), ("precision", "s")], "cpu,host=a user=1"), &influx).status, 204);
+		assert_eq!(write(&request(&[("precision", "d")], "cpu,host=a user=1"), &influx).status, 400);
+		let response = write(&request(&[], "cpu,host=a"), &influx);
+		assert_eq!(response.status, 400);
+		assert!(String::from_utf8(response.body).unwrap().starts_with("{\"error\": \"partial write"));
+		assert_eq!(influx.stats().points_written, 1);
+	}
+}
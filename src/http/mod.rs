@@ -0,0 +1,14 @@
+// A small read-only HTTP API over a directory of whisper files, answering the
+// parts of graphite-web's that dashboards use
+
+mod request;
+mod response;
+mod render;
+mod find;
+mod influx;
+mod server;
+
+pub use self::request::Request;
+pub use self::response::Response;
+pub use self::render::parse_time;
+pub use self::server::HttpServer;
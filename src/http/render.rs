@@ -0,0 +1,145 @@
+use whisper::{ WhisperFile, WhisperError, DataRoot, AggregationType, MetricPattern, render_json };
+use super::{ Request, Response };
+
+// graphite-web's /render with format=json, for metric names and globs of them
+// but not functions. Targets without a file are left out of the result like
+// graphite-web does. With maxDataPoints the series are consolidated by
+// average, its default.
+pub fn render(request: &Request, data_root: &DataRoot, now: u32) -> Response {
+	let format = request.param("format").unwrap_or("json");
+	if format != "json" {
+		return Response::error(400, &format!("unsupported format `{}`", format));
+	}
+
+	let from = match parse_time(request.param("from").unwrap_or("-1d"), now) {
+		Ok(from) => from,
+		Err(reason) => return Response::error(400, &reason)
+	};
+	let until = match parse_time(request.param("until").unwrap_or("now"), now) {
+		Ok(until) => until,
+		Err(reason) => return Response::error(400, &reason)
+	};
+	let max_points = match request.param("maxDataPoints") {
+		Some(value) => match value.parse::<usize>() {
+			Ok(max_points) => max_points,
+			Err(_) => return Response::error(400, &format!("invalid maxDataPoints `{}`", value))
+		},
+		None => 0
+	};
+
+	let mut results = vec![];
+	for target in request.params_named("target") {
+		let pattern = match MetricPattern::parse(target) {
+			Ok(pattern) => pattern,
+			Err(reason) => return Response::error(400, &reason)
+		};
+		let leaves = match data_root.find(&pattern) {
+			Ok(nodes) => nodes.into_iter().filter(|node| node.is_leaf),
+			Err(err) => {
+				warn!("could not find {}: {}", target, err);
+				return Response::error(500, &err.to_string());
+			}
+		};
+
+		for leaf in leaves {
+			let series = data_root.resolve(&leaf.path).and_then(WhisperFile::open_readonly).and_then(|file| file.fetch_consolidated_at(now, from, until, max_points, &AggregationType::Average));
+			match series {
+				Ok(series) => results.push((leaf.path, series)),
+				Err(err @ WhisperError::InvalidTimeInterval(_, _)) => return Response::error(400, &err.to_string()),
+				Err(err) => {
+					warn!("could not render {}: {}", leaf.path, err);
+					return Response::error(500, &err.to_string());
+				}
+			}
+		}
+	}
+
+	Response::json(render_json(&results))
+}
+
+// graphite's from/until: `now`, a unix timestamp, or an offset from now such as
+// `-2h`, `-30min` or `-1week`. Months count 30 days and years 365.
+pub fn parse_time(value: &str, now: u32) -> Result<u32, String> {
+	if value == "now" {
+		return Ok(now);
+	}
+	if let Ok(timestamp) = value.parse::<u32>() {
+		return Ok(timestamp);
+	}
+
+	let invalid = || format!("invalid time `{}`", value);
+	let (backwards, offset) = if value.starts_with('-') {
+		(true, &value[1..])
+	} else if value.starts_with('+') {
+		(false, &value[1..])
+	} else {
+		return Err(invalid());
+	};
+	let digits = offset.chars().take_while(|c| c.is_digit(10)).count();
+	let count = try!( offset[..digits].parse::<u32>().map_err(|_| invalid()) );
+	let unit_seconds = match &offset[digits..] {
+		"s" | "sec" | "secs" | "second" | "seconds" => 1,
+		"min" | "mins" | "minute" | "minutes" => 60,
+		"h" | "hour" | "hours" => 3600,
+		"d" | "day" | "days" => 86400,
+		"w" | "week" | "weeks" => 7 * 86400,
+		"mon" | "month" | "months" => 30 * 86400,
+		"y" | "year" | "years" => 365 * 86400,
+		_ => return Err(invalid())
+	};
+
+	let seconds = count.saturating_mul(unit_seconds);
+	Ok(if backwards { now.saturating_sub(seconds) } else { now.saturating_add(seconds) })
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use whisper::{ Schema, Point, temp_path };
+	use std::fs;
+	use std::path::PathBuf;
+
+	fn request(query: &[(&str, &str)]) -> Request {
+		Request {
+			method: "GET".to_string(),
+			path: "/render".to_string(),
+			params: query.iter().map(|&(name, value)| (name.to_string(), value.to_string())).collect(),
+			headers: vec![],
+			body: vec![]
+		}
+	}
+
+	#[test]
+	fn test_parse_time() {
+		let now = 1440392100;
+		assert_eq!(parse_time("now", now), Ok(now));
+		assert_eq!(parse_time("1440000000", now), Ok(1440000000));
+		assert_eq!(parse_time("-1h", now), Ok(now - 3600));
+		assert_eq!(parse_time("-30min", now), Ok(now - 1800));
+		assert_eq!(parse_time("-2days", now), Ok(now - 2 * 86400));
+		assert_eq!(parse_time("+10s", now), Ok(now + 10));
+		assert!(parse_time("-1fortnight", now).is_err());
+		assert!(parse_time("-h", now).is_err());
+		assert!(parse_time("yesterday", now).is_err());
+	}
+
+	#[test]
+	fn test_render() {
+		let data_root = temp_path("test_http_render");
+		fs::remove_dir_all(&data_root).ok();
+		fs::create_dir_all(PathBuf::from(&data_root).join("servers")).unwrap();
+		let now = 1440392100;
+		let mut file = WhisperFile::new(PathBuf::from(&data_root).join("servers/load.wsp"), &Schema::parse("10s:60s").unwrap()).unwrap();
+		file.update_many_at(now, &[Point(now - 20, 1.0), Point(now, 3.0)]).unwrap();
+
+		let response = render(&request(&[("target", "servers.l*"), ("target", "servers.missing"), ("from", "-30s")]), &DataRoot::new(&data_root), now);
+		assert_eq!(response.status, 200);
+		assert_eq!(String::from_utf8(response.body).unwrap(),
+			format!("[{{\"target\": \"servers.load\", \"tags\": {{\"name\": \"servers.load\"}}, \"datapoints\": [[1.0, {}], [null, {}], [3.0, {}]]}}]", now - 20, now - 10, now));
+
+		let response = render(&request(&[("target", "/etc/passwd")]), &DataRoot::new(&data_root), now);
+		assert_eq!(response.status, 400);
+		let response = render(&request(&[("target", "servers.load"), ("format", "png")]), &DataRoot::new(&data_root), now);
+		assert_eq!(response.status, 400);
+	}
+}
@@ -0,0 +1,164 @@
+use std::io::{ BufRead, Read };
+use std::str;
+
+// Anything longer than this isn't a dashboard asking for graphs
+const MAX_HEAD_SIZE : usize = 65536;
+
+// One HTTP/1.x request, read whole. Parameters of the query string come
+// first, then those of an application/x-www-form-urlencoded body, the way
+// graphite-web merges GET and POST.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Request {
+	pub method: String,
+	pub path: String,
+	pub params: Vec<(String, String)>,
+	// Names lowercased
+	pub headers: Vec<(String, String)>,
+	pub body: Vec<u8>
+}
+
+impl Request {
+	// Bodies longer than `max_body_size` are refused
+	pub fn read_from<R>(reader: &mut R, max_body_size: usize) -> Result<Request, String>
+		where R: BufRead {
+		let request_line = try!( read_head_line(reader) );
+		let mut parts = request_line.split(' ');
+		let (method, target) = match (parts.next(), parts.next(), parts.next()) {
+			(Some(method), Some(target), Some(version)) if version.starts_with("HTTP/1.") => (method.to_string(), target),
+			_ => return Err(format!("invalid request line `{}`", request_line))
+		};
+
+		let (path, query) = match target.find('?') {
+			Some(index) => (&target[..index], &target[index+1..]),
+			None => (target, "")
+		};
+		let mut request = Request {
+			method: method,
+			path: percent_decode(path, false),
+			params: parse_params(query),
+			headers: vec![],
+			body: vec![]
+		};
+
+		let mut head_size = request_line.len();
+		loop {
+			let line = try!( read_head_line(reader) );
+			if line.is_empty() {
+				break;
+			}
+			head_size = head_size + line.len();
+			if head_size > MAX_HEAD_SIZE {
+				return Err("request head too long".to_string());
+			}
+			match line.find(':') {
+				Some(index) => request.headers.push((line[..index].trim().to_lowercase(), line[index+1..].trim().to_string())),
+				None => return Err(format!("invalid header `{}`", line))
+			}
+		}
+
+		let content_length = match request.header("content-length") {
+			Some(value) => try!( value.parse::<usize>().map_err(|_| format!("invalid content length `{}`", value)) ),
+			None => 0
+		};
+		if content_length > max_body_size {
+			return Err(format!("body of {} bytes is over the limit of {}", content_length, max_body_size));
+		}
+		request.body = vec![0u8; content_length];
+		try!( reader.read_exact(&mut request.body).map_err(|err| format!("could not read the body: {}", err)) );
+
+		if request.header("content-type").map_or(false, |content_type| content_type.starts_with("application/x-www-form-urlencoded")) {
+			let form_params = parse_params(&String::from_utf8_lossy(&request.body));
+			request.params.extend(form_params);
+		}
+
+		Ok(request)
+	}
+
+	pub fn header(&self, name: &str) -> Option<&str> {
+		self.headers.iter().find(|header| header.0 == name).map(|header| header.1.as_str())
+	}
+
+	// The first value given for `name`
+	pub fn param(&self, name: &str) -> Option<&str> {
+		self.params.iter().find(|param| param.0 == name).map(|param| param.1.as_str())
+	}
+
+	// Every value given for `name`, e.g. each `target` of a render
+	pub fn params_named(&self, name: &str) -> Vec<&str> {
+		self.params.iter().filter(|param| param.0 == name).map(|param| param.1.as_str()).collect()
+	}
+}
+
+fn read_head_line<R>(reader: &mut R) -> Result<String, String>
+	where R: BufRead {
+	let mut line = vec![];
+	try!( reader.take(MAX_HEAD_SIZE as u64).read_until(b'\n', &mut line).map_err(|err| format!("could not read the request: {}", err)) );
+	if line.last() != Some(&b'\n') {
+		return Err("request ended early".to_string());
+	}
+	while line.last() == Some(&b'\n') || line.last() == Some(&b'\r') {
+		line.pop();
+	}
+	String::from_utf8(line).map_err(|_| "invalid utf8 character in the request head".to_string())
+}
+
+fn parse_params(query: &str) -> Vec<(String, String)> {
+	query.split('&').filter(|pair| !pair.is_empty()).map(|pair| {
+		match pair.find('=') {
+			Some(index) => (percent_decode(&pair[..index], true), percent_decode(&pair[index+1..], true)),
+			None => (percent_decode(pair, true), String::new())
+		}
+	}).collect()
+}
+
+// Invalid escapes are kept as they are
+fn percent_decode(encoded: &str, plus_as_space: bool) -> String {
+	let bytes = encoded.as_bytes();
+	let mut decoded = Vec::with_capacity(bytes.len());
+	let mut index = 0;
+	while index < bytes.len() {
+		let escaped = if bytes[index] == b'%' && index + 2 < bytes.len() {
+			str::from_utf8(&bytes[index+1..index+3]).ok().and_then(|hex| u8::from_str_radix(hex, 16).ok())
+		} else {
+			None
+		};
+		match escaped {
+			Some(byte) => {
+				decoded.push(byte);
+				index = index + 3;
+			},
+			None => {
+				decoded.push(if plus_as_space && bytes[index] == b'+' { b' ' } else { bytes[index] });
+				index = index + 1;
+			}
+		}
+	}
+	String::from_utf8_lossy(&decoded).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_read_request() {
+		let raw = "POST /render?target=a.b&from=-1h HTTP/1.1\r\nHost: localhost\r\nContent-Type: application/x-www-form-urlencoded\r\nContent-Length: 27\r\n\r\ntarget=sum%28c.%2A%29&x=a+b";
+		let request = Request::read_from(&mut raw.as_bytes(), 1024).unwrap();
+
+		assert_eq!(request.method, "POST");
+		assert_eq!(request.path, "/render");
+		assert_eq!(request.header("host"), Some("localhost"));
+		assert_eq!(request.params_named("target"), vec!["a.b", "sum(c.*)"]);
+		assert_eq!(request.param("from"), Some("-1h"));
+		assert_eq!(request.param("x"), Some("a b"));
+		assert_eq!(request.param("until"), None);
+	}
+
+	#[test]
+	fn test_invalid_requests() {
+		assert!(Request::read_from(&mut &b"GET /render\r\n\r\n"[..], 1024).is_err());
+		assert!(Request::read_from(&mut &b"GET /render HTTP/1.1\r\nHost"[..], 1024).is_err());
+		assert!(Request::read_from(&mut &b"POST / HTTP/1.1\r\nContent-Length: 2000\r\n\r\n"[..], 1024).is_err());
+		assert_eq!(percent_decode("100%25%zz%4", false), "100%%zz%4");
+	}
+}
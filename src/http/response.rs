@@ -0,0 +1,51 @@
+use std::io::{ self, Write };
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Response {
+	pub status: u16,
+	pub content_type: &'static str,
+	// Besides Content-Type, Content-Length and Connection
+	pub headers: Vec<(&'static str, String)>,
+	pub body: Vec<u8>
+}
+
+impl Response {
+	pub fn json(body: String) -> Response {
+		Response { status: 200, content_type: "application/json", headers: vec![], body: body.into_bytes() }
+	}
+
+	pub fn no_content() -> Response {
+		Response { status: 204, content_type: "text/plain; charset=utf-8", headers: vec![], body: vec![] }
+	}
+
+	// The reason as plain text
+	pub fn error(status: u16, message: &str) -> Response {
+		Response { status: status, content_type: "text/plain; charset=utf-8", headers: vec![], body: message.as_bytes().to_vec() }
+	}
+
+	// Every response closes its connection, so there's no keep-alive to track
+	pub fn write_to<W>(&self, writer: &mut W) -> io::Result<()>
+		where W: Write {
+		try!( write!(writer, "HTTP/1.1 {} {}\r\n", self.status, reason_phrase(self.status)) );
+		try!( write!(writer, "Content-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n", self.content_type, self.body.len()) );
+		for &(name, ref value) in &self.headers {
+			try!( write!(writer, "{}: {}\r\n", name, value) );
+		}
+		try!( writer.write_all(b"\r\n") );
+		try!( writer.write_all(&self.body) );
+		writer.flush()
+	}
+}
+
+fn reason_phrase(status: u16) -> &'static str {
+	match status {
+		200 => "OK",
+		204 => "No Content",
+		400 => "Bad Request",
+		404 => "Not Found",
+		405 => "Method Not Allowed",
+		413 => "Payload Too Large",
+		500 => "Internal Server Error",
+		_ => ""
+	}
+}
@@ -0,0 +1,231 @@
+use std::io::{ self, BufReader };
+use std::net::{ TcpListener, TcpStream, ToSocketAddrs, SocketAddr };
+use std::path::Path;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use whisper::{ DataRoot, current_time };
+use carbon::accept_connections;
+use influx::InfluxWrite;
+#[cfg(feature = "prometheus")]
+use prometheus::{ RemoteWrite, RemoteRead };
+#[cfg(feature = "otlp")]
+use otlp::OtlpWrite;
+use super::{ Request, Response };
+use super::render::render;
+use super::find::find;
+use super::influx;
+
+// Prometheus' remote write batches stay well under this
+const MAX_BODY_SIZE : usize = 16 << 20;
+// Clients that stall mid request are dropped after this long
+const READ_TIMEOUT_SECS : u64 = 30;
+
+// Serves the files under `data_root`, laid out the way WhisperCache writes
+// them, a thread per connection and one request per connection. A cache with
+// a PathMapping of its own or symlink targets has its files found through
+// `with_data_root(cache.data_root())`.
+pub struct HttpServer {
+	listener: TcpListener,
+	routes: Routes
+}
+
+// What the connection threads answer requests with
+#[derive(Clone)]
+struct Routes {
+	data_root: Arc<DataRoot>,
+	influx_write: Option<Arc<InfluxWrite>>,
+	#[cfg(feature = "prometheus")]
+	remote_write: Option<Arc<RemoteWrite>>,
+	#[cfg(feature = "prometheus")]
+	remote_read: Option<Arc<RemoteRead>>,
+	#[cfg(feature = "otlp")]
+	otlp_write: Option<Arc<OtlpWrite>>
+}
+
+impl HttpServer {
+	pub fn bind<A, P>(address: A, data_root: P) -> io::Result<HttpServer>
+		where A: ToSocketAddrs, P: AsRef<Path> {
+		Ok(HttpServer {
+			listener: try!( TcpListener::bind(address) ),
+			routes: Routes {
+				data_root: Arc::new(DataRoot::new(data_root)),
+				influx_write: None,
+				#[cfg(feature = "prometheus")]
+				remote_write: None,
+				#[cfg(feature = "prometheus")]
+				remote_read: None,
+				#[cfg(feature = "otlp")]
+				otlp_write: None
+			}
+		})
+	}
+
+	// Replaces the plain root given to bind
+	pub fn with_data_root(mut self, data_root: DataRoot) -> HttpServer {
+		self.routes.data_root = Arc::new(data_root);
+		self
+	}
+
+	// Takes InfluxDB line protocol at /write
+	pub fn with_influx_write(mut self, influx_write: Arc<InfluxWrite>) -> HttpServer {
+		self.routes.influx_write = Some(influx_write);
+		self
+	}
+
+	// Takes Prometheus' remote writes at /api/v1/write
+	#[cfg(feature = "prometheus")]
+	pub fn with_remote_write(mut self, remote_write: Arc<RemoteWrite>) -> HttpServer {
+		self.routes.remote_write = Some(remote_write);
+		self
+	}
+
+	// Answers Prometheus' remote reads at /api/v1/read
+	#[cfg(feature = "prometheus")]
+	pub fn with_remote_read(mut self, remote_read: Arc<RemoteRead>) -> HttpServer {
+		self.routes.remote_read = Some(remote_read);
+		self
+	}
+
+	// Takes OTLP/HTTP metric exports at /v1/metrics
+	#[cfg(feature = "otlp")]
+	pub fn with_otlp_write(mut self, otlp_write: Arc<OtlpWrite>) -> HttpServer {
+		self.routes.otlp_write = Some(otlp_write);
+		self
+	}
+
+	pub fn local_addr(&self) -> io::Result<SocketAddr> {
+		self.listener.local_addr()
+	}
+
+	// Accepts connections for good, failing to accept one is only logged
+	pub fn run(&self) -> io::Result<()> {
+		accept_connections(&self.listener, |stream| {
+			let routes = self.routes.clone();
+			thread::spawn(move || serve_connection(stream, &routes));
+		});
+		Ok(())
+	}
+}
+
+fn serve_connection(stream: TcpStream, routes: &Routes) {
+	if let Err(err) = stream.set_read_timeout(Some(Duration::from_secs(READ_TIMEOUT_SECS))) {
+		warn!("could not set the read timeout: {}", err);
+	}
+
+	let mut reader = BufReader::new(&stream);
+	let response = match Request::read_from(&mut reader, MAX_BODY_SIZE) {
+		Ok(request) => respond(&request, routes),
+		Err(reason) => {
+			debug!("bad request from {:?}: {}", stream.peer_addr(), reason);
+			Response::error(400, &reason)
+		}
+	};
+	if let Err(err) = response.write_to(&mut &stream) {
+		debug!("could not respond to {:?}: {}", stream.peer_addr(), err);
+	}
+}
+
+fn respond(request: &Request, routes: &Routes) -> Response {
+	if request.method != "GET" && request.method != "POST" {
+		return Response::error(405, "only GET and POST are supported");
+	}
+	match request.path.as_str() {
+		"/render" => render(request, &routes.data_root, current_time()),
+		"/metrics/find" | "/metrics/find/" => find(request, &routes.data_root),
+		"/write" if routes.influx_write.is_some() => influx::write(request, routes.influx_write.as_ref().unwrap()),
+		#[cfg(feature = "prometheus")]
+		"/api/v1/write" if routes.remote_write.is_some() => routes.remote_write.as_ref().unwrap().respond(request),
+		#[cfg(feature = "prometheus")]
+		"/api/v1/read" if routes.remote_read.is_some() => routes.remote_read.as_ref().unwrap().respond(request),
+		#[cfg(feature = "otlp")]
+		"/v1/metrics" if routes.otlp_write.is_some() => routes.otlp_write.as_ref().unwrap().respond(request),
+		_ => Response::error(404, "not found")
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use whisper::{ WhisperCache, WhisperFile, NamedPoint, PathMapping, Schema, Point, temp_path };
+	use std::fs;
+	use std::path::PathBuf;
+	use std::io::{ Read, Write };
+
+	fn get(address: SocketAddr, target: &str) -> String {
+		let mut client = TcpStream::connect(address).unwrap();
+		write!(client, "GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", target).unwrap();
+		let mut response = String::new();
+		client.read_to_string(&mut response).unwrap();
+		response
+	}
+
+	#[test]
+	fn test_http_server() {
+		let data_root = temp_path("test_http_server");
+		fs::remove_dir_all(&data_root).ok();
+		fs::create_dir_all(&data_root).unwrap();
+		let now = current_time();
+		let now = now - now % 10;
+		let mut file = WhisperFile::new(PathBuf::from(&data_root).join("load.wsp"), &Schema::parse("10s:60s").unwrap()).unwrap();
+		file.write(&Point(now - 10, 2.0)).unwrap();
+
+		let server = HttpServer::bind("127.0.0.1:0", &data_root).unwrap();
+		let address = server.local_addr().unwrap();
+		thread::spawn(move || server.run());
+
+		let response = get(address, &format!("/render?target=load&from={}&until={}&format=json", now - 20, now - 10));
+		assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+		assert!(response.contains("Content-Type: application/json\r\n"));
+		assert!(response.ends_with(&format!("\"datapoints\": [[2.0, {}]]}}]", now - 10)));
+
+		let response = get(address, "/metrics/find?query=l%2A");
+		assert!(response.ends_with("[{\"allowChildren\": 0, \"context\": {}, \"expandable\": 0, \"id\": \"load\", \"leaf\": 1, \"text\": \"load\"}]"));
+
+		assert!(get(address, "/dashboard").starts_with("HTTP/1.1 404 Not Found\r\n"));
+		assert!(get(address, "/render?target=load&from=soon").starts_with("HTTP/1.1 400 Bad Request\r\n"));
+	}
+
+	#[test]
+	fn test_hashed_data_root() {
+		let data_root = temp_path("test_http_server_hashed");
+		fs::remove_dir_all(&data_root).ok();
+		let now = current_time();
+		let now = now - now % 10;
+		let cache = WhisperCache::new(&data_root, 10, Schema::parse("10s:60s").unwrap());
+		cache.set_path_mapping(PathMapping::hashed());
+		cache.write(NamedPoint::new("servers.web01.load".to_string(), now - 10, 2.0)).unwrap();
+		cache.flush_all().unwrap();
+
+		let server = HttpServer::bind("127.0.0.1:0", &data_root).unwrap().with_data_root(cache.data_root());
+		let address = server.local_addr().unwrap();
+		thread::spawn(move || server.run());
+
+		let response = get(address, &format!("/render?target=servers.*.load&from={}&until={}", now - 20, now - 10));
+		assert!(response.ends_with(&format!("{{\"target\": \"servers.web01.load\", \"tags\": {{\"name\": \"servers.web01.load\"}}, \"datapoints\": [[2.0, {}]]}}]", now - 10)));
+		let response = get(address, "/metrics/find?query=servers.%2A");
+		assert!(response.ends_with("[{\"allowChildren\": 1, \"context\": {}, \"expandable\": 1, \"id\": \"servers.web01\", \"leaf\": 0, \"text\": \"web01\"}]"));
+	}
+
+	#[test]
+	fn test_escaped_names() {
+		let data_root = temp_path("test_http_server_escaped");
+		fs::remove_dir_all(&data_root).ok();
+		let now = current_time();
+		let now = now - now % 10;
+		let cache = WhisperCache::new(&data_root, 10, Schema::parse("10s:60s").unwrap());
+		cache.set_path_mapping(PathMapping::escaping());
+		cache.write(NamedPoint::new("disk.100%".to_string(), now - 10, 2.0)).unwrap();
+		assert!(PathBuf::from(&data_root).join("disk/100%25.wsp").is_file());
+
+		let server = HttpServer::bind("127.0.0.1:0", &data_root).unwrap().with_data_root(cache.data_root());
+		let address = server.local_addr().unwrap();
+		thread::spawn(move || server.run());
+
+		let response = get(address, "/metrics/find?query=disk.%2A&format=completer");
+		assert!(response.ends_with("{\"metrics\": [{\"is_leaf\": \"1\", \"name\": \"100%\", \"path\": \"disk.100%\"}]}"));
+		let response = get(address, &format!("/render?target=disk.100%25&from={}&until={}", now - 20, now - 10));
+		assert!(response.ends_with(&format!("\"datapoints\": [[2.0, {}]]}}]", now - 10)));
+	}
+}
@@ -0,0 +1,255 @@
+use std::str::FromStr;
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum FieldValue {
+	Float(f64),
+	Integer(i64),
+	Unsigned(u64),
+	Boolean(bool),
+	String(String)
+}
+
+impl FieldValue {
+	// What a whisper file can hold: booleans as 1 and 0, strings not at all
+	pub fn as_f64(&self) -> Option<f64> {
+		match *self {
+			FieldValue::Float(value) => Some(value),
+			FieldValue::Integer(value) => Some(value as f64),
+			FieldValue::Unsigned(value) => Some(value as f64),
+			FieldValue::Boolean(value) => Some(if value { 1.0 } else { 0.0 }),
+			FieldValue::String(_) => None
+		}
+	}
+}
+
+// The unit of line timestamps, the `precision` parameter of InfluxDB's /write
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Precision {
+	Nanoseconds,
+	Microseconds,
+	Milliseconds,
+	Seconds,
+	Minutes,
+	Hours
+}
+
+impl Default for Precision {
+	fn default() -> Precision {
+		Precision::Nanoseconds
+	}
+}
+
+impl FromStr for Precision {
+	type Err = String;
+
+	fn from_str(precision: &str) -> Result<Precision, String> {
+		match precision {
+			"n" | "ns" => Ok(Precision::Nanoseconds),
+			"u" | "us" => Ok(Precision::Microseconds),
+			"ms" => Ok(Precision::Milliseconds),
+			"s" => Ok(Precision::Seconds),
+			"m" => Ok(Precision::Minutes),
+			"h" => Ok(Precision::Hours),
+			_ => Err(format!("unknown precision `{}`", precision))
+		}
+	}
+}
+
+impl Precision {
+	// Whole seconds since the epoch, None outside of what whisper holds
+	pub fn to_seconds(&self, timestamp: i64) -> Option<u32> {
+		let seconds = match *self {
+			Precision::Nanoseconds => timestamp / 1000000000,
+			Precision::Microseconds => timestamp / 1000000,
+			Precision::Milliseconds => timestamp / 1000,
+			Precision::Seconds => timestamp,
+			Precision::Minutes => timestamp.saturating_mul(60),
+			Precision::Hours => timestamp.saturating_mul(3600)
+		};
+		if seconds < 0 || seconds > u32::max_value() as i64 {
+			None
+		} else {
+			Some(seconds as u32)
+		}
+	}
+}
+
+// One line of `measurement,tag=value field=1.0,other=2i timestamp`
+#[derive(Debug, PartialEq, Clone)]
+pub struct Line {
+	pub measurement: String,
+	pub tags: Vec<(String, String)>,
+	pub fields: Vec<(String, FieldValue)>,
+	// In the request's precision, None when the server's clock is to be used
+	pub timestamp: Option<i64>
+}
+
+impl Line {
+	pub fn tag(&self, key: &str) -> Option<&str> {
+		self.tags.iter().find(|tag| tag.0 == key).map(|tag| tag.1.as_str())
+	}
+
+	// Comments and blank lines are Ok(None)
+	pub fn parse(line: &str) -> Result<Option<Line>, String> {
+		let line = line.trim();
+		if line.is_empty() || line.starts_with('#') {
+			return Ok(None);
+		}
+
+		let (series, rest) = split_unescaped(line, b' ', false);
+		let rest = try!( rest.ok_or_else(|| format!("line `{}` has no fields", line)) );
+		let (fields, timestamp) = split_unescaped(rest.trim_left(), b' ', true);
+
+		let mut series_parts = split_all(series, b',', false).into_iter();
+		let measurement = unescape(series_parts.next().unwrap());
+		if measurement.is_empty() {
+			return Err(format!("line `{}` has no measurement", line));
+		}
+		let mut tags = vec![];
+		for tag in series_parts {
+			match split_unescaped(tag, b'=', false) {
+				(key, Some(value)) if !key.is_empty() && !value.is_empty() => tags.push((unescape(key), unescape(value))),
+				_ => return Err(format!("invalid tag `{}`", tag))
+			}
+		}
+
+		let mut parsed_fields = vec![];
+		for field in split_all(fields, b',', true) {
+			match split_unescaped(field, b'=', false) {
+				(key, Some(value)) if !key.is_empty() => parsed_fields.push((unescape(key), try!( parse_field_value(value) ))),
+				_ => return Err(format!("invalid field `{}`", field))
+			}
+		}
+		if parsed_fields.is_empty() {
+			return Err(format!("line `{}` has no fields", line));
+		}
+
+		let timestamp = match timestamp.map(|timestamp| timestamp.trim()) {
+			Some(timestamp) if !timestamp.is_empty() => Some(try!( timestamp.parse::<i64>().map_err(|_| format!("invalid timestamp `{}`", timestamp)) )),
+			_ => None
+		};
+
+		Ok(Some(Line { measurement: measurement, tags: tags, fields: parsed_fields, timestamp: timestamp }))
+	}
+}
+
+fn parse_field_value(value: &str) -> Result<FieldValue, String> {
+	let invalid = || format!("invalid field value `{}`", value);
+	if value.len() >= 2 && value.starts_with('"') && value.ends_with('"') {
+		return Ok(FieldValue::String(unescape(&value[1..value.len() - 1])));
+	}
+	match value {
+		"t" | "T" | "true" | "True" | "TRUE" => return Ok(FieldValue::Boolean(true)),
+		"f" | "F" | "false" | "False" | "FALSE" => return Ok(FieldValue::Boolean(false)),
+		_ => {}
+	}
+	if value.ends_with('i') {
+		return value[..value.len() - 1].parse::<i64>().map(FieldValue::Integer).map_err(|_| invalid());
+	}
+	if value.ends_with('u') {
+		return value[..value.len() - 1].parse::<u64>().map(FieldValue::Unsigned).map_err(|_| invalid());
+	}
+	// Rust would take `nan` and `inf`, InfluxDB doesn't
+	if value.chars().any(|c| c.is_alphabetic() && c != 'e' && c != 'E') {
+		return Err(invalid());
+	}
+	value.parse::<f64>().map(FieldValue::Float).map_err(|_| invalid())
+}
+
+// Splits at the first `separator` that isn't escaped with a backslash, or
+// within a quoted string when `quotes` is set
+fn split_unescaped(text: &str, separator: u8, quotes: bool) -> (&str, Option<&str>) {
+	let bytes = text.as_bytes();
+	let (mut index, mut quoted) = (0, false);
+	while index < bytes.len() {
+		match bytes[index] {
+			b'\\' => index = index + 1,
+			b'"' if quotes => quoted = !quoted,
+			byte if byte == separator && !quoted => return (&text[..index], Some(&text[index+1..])),
+			_ => {}
+		}
+		index = index + 1;
+	}
+	(text, None)
+}
+
+fn split_all(text: &str, separator: u8, quotes: bool) -> Vec<&str> {
+	let mut parts = vec![];
+	let mut rest = Some(text);
+	while let Some(remaining) = rest {
+		let (part, next) = split_unescaped(remaining, separator, quotes);
+		parts.push(part);
+		rest = next;
+	}
+	parts
+}
+
+// Drops the backslash of `\,`, `\=`, `\ `, `\"` and `\\`, others are kept
+fn unescape(text: &str) -> String {
+	let mut unescaped = String::with_capacity(text.len());
+	let mut chars = text.chars().peekable();
+	while let Some(c) = chars.next() {
+		if c == '\\' {
+			if let Some(&next) = chars.peek() {
+				if next == ',' || next == '=' || next == ' ' || next == '"' || next == '\\' {
+					unescaped.push(next);
+					chars.next();
+					continue;
+				}
+			}
+		}
+		unescaped.push(c);
+	}
+	unescaped
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_parse() {
+		let line = Line::parse("cpu,host=web01,region=us\\ west usage_user=1.5,usage_idle=90i,up=t,note=\"a, b=c\" 1440392100000000000").unwrap().unwrap();
+		assert_eq!(line, Line {
+			measurement: "cpu".to_string(),
+			tags: vec![("host".to_string(), "web01".to_string()), ("region".to_string(), "us west".to_string())],
+			fields: vec![
+				("usage_user".to_string(), FieldValue::Float(1.5)),
+				("usage_idle".to_string(), FieldValue::Integer(90)),
+				("up".to_string(), FieldValue::Boolean(true)),
+				("note".to_string(), FieldValue::String("a, b=c".to_string()))
+			],
+			timestamp: Some(1440392100000000000)
+		});
+		assert_eq!(line.tag("region"), Some("us west"));
+
+		let line = Line::parse("disk\\,io free=2e3,used=-1,inodes=7u").unwrap().unwrap();
+		assert_eq!(line.measurement, "disk,io");
+		assert_eq!(line.fields.iter().map(|field| field.1.as_f64()).collect::<Vec<_>>(), vec![Some(2000.0), Some(-1.0), Some(7.0)]);
+		assert_eq!(line.timestamp, None);
+
+		assert_eq!(Line::parse("# a comment"), Ok(None));
+		assert_eq!(Line::parse("   "), Ok(None));
+	}
+
+	#[test]
+	fn test_invalid() {
+		assert!(Line::parse("cpu").is_err());
+		assert!(Line::parse("cpu,host usage=1").is_err());
+		assert!(Line::parse("cpu usage=").is_err());
+		assert!(Line::parse("cpu usage=nan").is_err());
+		assert!(Line::parse("cpu usage=1x").is_err());
+		assert!(Line::parse("cpu usage=1 soon").is_err());
+		assert!(Line::parse(",host=a usage=1").is_err());
+	}
+
+	#[test]
+	fn test_precision() {
+		assert_eq!("ms".parse::<Precision>(), Ok(Precision::Milliseconds));
+		assert!("d".parse::<Precision>().is_err());
+		assert_eq!(Precision::Nanoseconds.to_seconds(1440392100123456789), Some(1440392100));
+		assert_eq!(Precision::Minutes.to_seconds(24006535), Some(1440392100));
+		assert_eq!(Precision::Seconds.to_seconds(-1), None);
+		assert_eq!(Precision::Seconds.to_seconds(1 << 40), None);
+	}
+}
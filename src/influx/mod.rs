@@ -0,0 +1,10 @@
+// InfluxDB's line protocol, flattened into whisper metrics for the agents
+// (telegraf and friends) that speak it rather than carbon's
+
+mod line;
+mod naming;
+mod write;
+
+pub use self::line::{ Line, FieldValue, Precision };
+pub use self::naming::NamingScheme;
+pub use self::write::{ InfluxWrite, InfluxStats };
@@ -0,0 +1,103 @@
+use template::{ self, Piece, sanitize };
+use super::Line;
+
+// How a line's fields become metric names, e.g. `telegraf.{host}.{measurement}.{field}`:
+// `{measurement}`, `{field}`, a tag by its key, and `{tags}` for the values of
+// the tags not named otherwise, by key. Values become nodes through
+// template::sanitize. Like telegraf's graphite output a field named `value`
+// is left out, and so are empty nodes.
+#[derive(Debug, PartialEq, Clone)]
+pub struct NamingScheme {
+	parts: Vec<Part>
+}
+
+#[derive(Debug, PartialEq, Clone)]
+enum Part {
+	Literal(String),
+	Measurement,
+	Field,
+	Tag(String),
+	OtherTags
+}
+
+impl Default for NamingScheme {
+	// The tags' values between the measurement and field
+	fn default() -> NamingScheme {
+		NamingScheme::parse("{measurement}.{tags}.{field}").unwrap()
+	}
+}
+
+impl NamingScheme {
+	pub fn parse(scheme: &str) -> Result<NamingScheme, String> {
+		let parts : Vec<Part> = try!( template::parse(scheme, "naming scheme") ).into_iter().map(|piece| match piece {
+			Piece::Literal(literal) => Part::Literal(literal.to_string()),
+			Piece::Placeholder("measurement") => Part::Measurement,
+			Piece::Placeholder("field") => Part::Field,
+			Piece::Placeholder("tags") => Part::OtherTags,
+			Piece::Placeholder(tag) => Part::Tag(tag.to_string())
+		}).collect();
+
+		if !parts.contains(&Part::Field) {
+			return Err(format!("naming scheme `{}` leaves out the field, fields of a line would share a file", scheme));
+		}
+		Ok(NamingScheme { parts: parts })
+	}
+
+	// None when the line lacks a tag the scheme names
+	pub fn metric_name(&self, line: &Line, field: &str) -> Option<String> {
+		let mut name = String::new();
+		for part in &self.parts {
+			match *part {
+				Part::Literal(ref literal) => name.push_str(literal),
+				Part::Measurement => name.push_str(&sanitize(&line.measurement)),
+				Part::Field => if field != "value" {
+					name.push_str(&sanitize(field));
+				},
+				Part::Tag(ref key) => match line.tag(key) {
+					Some(value) => name.push_str(&sanitize(value)),
+					None => return None
+				},
+				Part::OtherTags => {
+					let mut tags : Vec<&(String, String)> = line.tags.iter().filter(|tag| !self.parts.contains(&Part::Tag(tag.0.clone()))).collect();
+					tags.sort();
+					let values : Vec<String> = tags.iter().map(|tag| sanitize(&tag.1)).collect();
+					name.push_str(&values.join("."));
+				}
+			}
+		}
+
+		let nodes : Vec<&str> = name.split('.').filter(|node| !node.is_empty()).collect();
+		if nodes.is_empty() {
+			None
+		} else {
+			Some(nodes.join("."))
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_metric_name() {
+		let line = Line::parse("cpu,region=us-west,host=web01.example.com usage_user=1.5,value=2").unwrap().unwrap();
+
+		let scheme = NamingScheme::default();
+		assert_eq!(scheme.metric_name(&line, "usage_user"), Some("cpu.web01_example_com.us-west.usage_user".to_string()));
+		assert_eq!(scheme.metric_name(&line, "value"), Some("cpu.web01_example_com.us-west".to_string()));
+
+		let scheme = NamingScheme::parse("telegraf.{host}.{measurement}.{tags}.{field}").unwrap();
+		assert_eq!(scheme.metric_name(&line, "usage_user"), Some("telegraf.web01_example_com.cpu.us-west.usage_user".to_string()));
+		let scheme = NamingScheme::parse("telegraf.{dc}.{measurement}.{field}").unwrap();
+		assert_eq!(scheme.metric_name(&line, "usage_user"), None);
+	}
+
+	#[test]
+	fn test_invalid_schemes() {
+		assert!(NamingScheme::parse("{measurement}").is_err());
+		assert!(NamingScheme::parse("{measurement}.{field").is_err());
+		assert!(NamingScheme::parse("{}.{field}").is_err());
+		assert!(NamingScheme::parse("/etc/{field}").is_err());
+	}
+}
@@ -0,0 +1,146 @@
+use std::str;
+use std::sync::{ Arc, Mutex };
+
+use whisper::{ WhisperCache, NamedPoint, current_time };
+use super::{ Line, NamingScheme, Precision };
+
+#[derive(Debug, Default, PartialEq, Clone)]
+pub struct InfluxStats {
+	pub lines_received: usize,
+	pub points_written: usize,
+	pub invalid_lines: usize,
+	// Lines missing a tag the naming scheme names
+	pub unmapped_lines: usize,
+	// String fields, which whisper can't hold
+	pub skipped_fields: usize,
+	// Timestamps whisper can't hold and points the cache refused
+	pub write_errors: usize
+}
+
+// Writes the numeric fields of line protocol bodies through a WhisperCache
+pub struct InfluxWrite {
+	cache: Arc<WhisperCache>,
+	scheme: NamingScheme,
+	stats: Mutex<InfluxStats>
+}
+
+impl InfluxWrite {
+	pub fn new(cache: Arc<WhisperCache>, scheme: NamingScheme) -> InfluxWrite {
+		InfluxWrite {
+			cache: cache,
+			scheme: scheme,
+			stats: Mutex::new(InfluxStats::default())
+		}
+	}
+
+	pub fn stats(&self) -> InfluxStats {
+		self.stats.lock().unwrap().clone()
+	}
+
+	// Like InfluxDB, the valid lines are written even when others aren't. The
+	// error names the first invalid one.
+	pub fn write(&self, body: &[u8], precision: Precision) -> Result<(), String> {
+		let now = current_time();
+		let (mut received, mut written, mut invalid, mut unmapped, mut skipped, mut failed) = (0, 0, 0, 0, 0, 0);
+		let mut first_error = None;
+
+		for line in body.split(|&byte| byte == b'\n') {
+			let line = match str::from_utf8(line).map_err(|_| "invalid utf8 character".to_string()).and_then(|line| Line::parse(line)) {
+				Ok(Some(line)) => line,
+				Ok(None) => continue,
+				Err(reason) => {
+					received = received + 1;
+					invalid = invalid + 1;
+					first_error = first_error.or(Some(reason));
+					continue;
+				}
+			};
+			received = received + 1;
+
+			let timestamp = match line.timestamp {
+				Some(timestamp) => precision.to_seconds(timestamp),
+				None => Some(now)
+			};
+			let timestamp = match timestamp {
+				Some(timestamp) => timestamp,
+				None => {
+					failed = failed + line.fields.len();
+					continue;
+				}
+			};
+
+			for &(ref field, ref value) in &line.fields {
+				let value = match value.as_f64() {
+					Some(value) => value,
+					None => {
+						skipped = skipped + 1;
+						continue;
+					}
+				};
+				let metric_name = match self.scheme.metric_name(&line, field) {
+					Some(metric_name) => metric_name,
+					None => {
+						unmapped = unmapped + 1;
+						break;
+					}
+				};
+				match self.cache.write(NamedPoint::new(metric_name, timestamp, value)) {
+					Ok(()) => written = written + 1,
+					Err(err) => {
+						warn!("could not write field {} of {}: {}", field, line.measurement, err);
+						failed = failed + 1;
+					}
+				}
+			}
+		}
+
+		let mut stats = self.stats.lock().unwrap();
+		stats.lines_received = stats.lines_received + received;
+		stats.points_written = stats.points_written + written;
+		stats.invalid_lines = stats.invalid_lines + invalid;
+		stats.unmapped_lines = stats.unmapped_lines + unmapped;
+		stats.skipped_fields = stats.skipped_fields + skipped;
+		stats.write_errors = stats.write_errors + failed;
+
+		match first_error {
+			Some(reason) => Err(format!("partial write: {} of {} lines invalid, the first: {}", invalid, received, reason)),
+			None => Ok(())
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use whisper::temp_cache;
+
+	#[test]
+	fn test_influx_write() {
+		let (cache, now) = temp_cache("test_influx_write");
+		let cache = Arc::new(cache);
+
+		let influx = InfluxWrite::new(cache.clone(), NamingScheme::parse("telegraf.{host}.{measurement}.{field}").unwrap());
+		let body = format!("cpu,host=web01 user=1.5,idle=90i,note=\"busy\" {}\n\
+			# a comment\n\
+			cpu,host=web01 user=2.5 {}\n\
+			cpu user=3\n\
+			cpu,host=web01 user=oops {}\n", now - 10, now, now);
+		let result = influx.write(body.as_bytes(), Precision::Seconds);
+
+		assert!(result.unwrap_err().starts_with("partial write: 1 of 4 lines invalid"));
+		assert_eq!(influx.stats(), InfluxStats {
+			lines_received: 4,
+			points_written: 3,
+			invalid_lines: 1,
+			unmapped_lines: 1,
+			skipped_fields: 1,
+			write_errors: 0
+		});
+		assert_eq!(cache.fetch("telegraf.web01.cpu.user", now - 20, now).unwrap().values, vec![Some(1.5), Some(2.5)]);
+		assert_eq!(cache.fetch("telegraf.web01.cpu.idle", now - 20, now).unwrap().values, vec![Some(90.0), None]);
+
+		let body = format!("cpu,host=web01 user=4 {}000", now);
+		assert_eq!(influx.write(body.as_bytes(), Precision::Milliseconds), Ok(()));
+		assert_eq!(cache.fetch("telegraf.web01.cpu.user", now - 10, now).unwrap().values, vec![Some(4.0)]);
+	}
+}
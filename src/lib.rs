@@ -9,7 +9,44 @@ extern crate lru_cache;
 
 #[macro_use]
 extern crate log;
+#[cfg(feature = "serde")]
+#[macro_use]
+extern crate serde;
+#[cfg(feature = "serde")]
+extern crate toml;
+#[cfg(feature = "tokio")]
+extern crate tokio;
+#[cfg(any(feature = "prometheus", feature = "otlp"))]
+extern crate prost;
+// The code prost derives names ::core
+#[cfg(any(feature = "prometheus", feature = "otlp"))]
+extern crate core;
+#[cfg(feature = "columnar")]
+extern crate arrow;
+#[cfg(feature = "columnar")]
+extern crate parquet;
 
 mod whisper;
+pub mod carbon;
+pub mod influx;
+pub mod statsd;
+pub mod rrd;
+pub mod ceres;
+#[cfg(feature = "http")]
+pub mod http;
+#[cfg(feature = "prometheus")]
+pub mod prometheus;
+#[cfg(feature = "otlp")]
+pub mod otlp;
+mod template;
+#[cfg(feature = "columnar")]
+pub mod columnar;
 
-pub use self::whisper::{ WhisperFile, Point, Schema, WhisperCache, NamedPoint };
+pub use self::whisper::{ WhisperFile, WhisperBuilder, TimeSeries, MetricPattern, MetricNode, PathMapping, UnsafeNames, PathLayout, DataRoot, render_json, ArchiveSelection, FuturePolicy, NonFinitePolicy, OverwritePolicy, Allocation, AggregationType, FileInfo, ArchiveInfo, FileStats, ArchiveStats, ArchiveDiff, SchemaDiff, ImportReport, FileDefect, WhisperError, Point, Schema, SchemaError, SchemaRules, SchemaRule, AggregationRules, AggregationRule, DiskUsagePlan, PrefixUsage, SchemaResolver, StorageRules, RewriteRules, RewriteRule, RewriteStage, RegexList, MetricFilter, FilterStats, TaggedSeries, TagIndex, TagExpression, WhisperTree, TreeEntry, Walk, DeleteReport, DiskUsage, SubtreeUsage, StalePolicy, StaleAction, StaleMetric, StaleReport, AuditReport, FileAudit, ProvisionProgress, ProvisionReport, MetricIndex, WhisperCache, NamedPoint, CacheStats, CacheMetrics, WriteBack, Flusher, IngestQueue, CacheWriter, QueueError, QueryCaching, Replication, Replica, ReplicaProtocol, ReplicaConfig, ReplicaStats, read_framed };
+#[cfg(feature = "tokio")]
+pub use self::whisper::{ AsyncWhisperCache, Blocking };
+#[cfg(all(feature = "watch", target_os = "linux"))]
+pub use self::whisper::{ TreeWatcher, WatchStats };
+#[cfg(feature = "serde")]
+pub use self::whisper::config;
+pub use self::whisper::presets;
@@ -0,0 +1,11 @@
+// OpenTelemetry metrics over OTLP/HTTP, so SDKs can export straight into
+// whisper files. Requests are read as uncompressed protobuf, gzip and OTLP's
+// JSON encoding aren't.
+
+mod proto;
+mod naming;
+mod write;
+
+pub use self::proto::{ ExportRequest, ResourceMetrics, Metric, MetricData, NumberPoint, HistogramPoint, SummaryPoint };
+pub use self::naming::OtlpNaming;
+pub use self::write::{ OtlpWrite, OtlpStats };
@@ -0,0 +1,105 @@
+use template::{ self, Piece, sanitize };
+
+// How OTLP data points become metric names, e.g. `otel.{service.name}.{name}.{attributes}`:
+// `{name}` for the metric's name, its dots kept as separators, an attribute
+// by its key, looked up on the data point and then on the resource, and
+// `{attributes}` for the values of the point's attributes not named otherwise,
+// by key. Values become nodes through template::sanitize, and empty nodes
+// are left out. Histograms and summaries append their own nodes, `.count`,
+// `.sum` and so on, after the name.
+#[derive(Debug, PartialEq, Clone)]
+pub struct OtlpNaming {
+	parts: Vec<Part>
+}
+
+#[derive(Debug, PartialEq, Clone)]
+enum Part {
+	Literal(String),
+	Name,
+	Attribute(String),
+	OtherAttributes
+}
+
+impl Default for OtlpNaming {
+	// service.name is set on every resource the SDKs export
+	fn default() -> OtlpNaming {
+		OtlpNaming::parse("{service.name}.{name}.{attributes}").unwrap()
+	}
+}
+
+impl OtlpNaming {
+	pub fn parse(scheme: &str) -> Result<OtlpNaming, String> {
+		let parts : Vec<Part> = try!( template::parse(scheme, "naming scheme") ).into_iter().map(|piece| match piece {
+			Piece::Literal(literal) => Part::Literal(literal.to_string()),
+			Piece::Placeholder("name") => Part::Name,
+			Piece::Placeholder("attributes") => Part::OtherAttributes,
+			Piece::Placeholder(key) => Part::Attribute(key.to_string())
+		}).collect();
+
+		if !parts.contains(&Part::Name) {
+			return Err(format!("naming scheme `{}` leaves out the name, metrics would share a file", scheme));
+		}
+		Ok(OtlpNaming { parts: parts })
+	}
+
+	// None when neither the point nor its resource has an attribute the scheme names
+	pub fn metric_name(&self, name: &str, attributes: &[(String, String)], resource_attributes: &[(String, String)]) -> Option<String> {
+		let mut metric_name = String::new();
+		for part in &self.parts {
+			match *part {
+				Part::Literal(ref literal) => metric_name.push_str(literal),
+				Part::Name => {
+					let nodes : Vec<String> = name.split('.').map(sanitize).collect();
+					metric_name.push_str(&nodes.join("."));
+				},
+				Part::Attribute(ref key) => {
+					let value = attributes.iter().chain(resource_attributes).find(|attribute| &attribute.0 == key);
+					match value {
+						Some(attribute) => metric_name.push_str(&sanitize(&attribute.1)),
+						None => return None
+					}
+				},
+				Part::OtherAttributes => {
+					let mut others : Vec<&(String, String)> = attributes.iter().filter(|attribute| !self.parts.contains(&Part::Attribute(attribute.0.clone()))).collect();
+					others.sort();
+					let values : Vec<String> = others.iter().map(|attribute| sanitize(&attribute.1)).collect();
+					metric_name.push_str(&values.join("."));
+				}
+			}
+		}
+
+		let nodes : Vec<&str> = metric_name.split('.').filter(|node| !node.is_empty()).collect();
+		Some(nodes.join("."))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn attributes(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+		pairs.iter().map(|&(key, value)| (key.to_string(), value.to_string())).collect()
+	}
+
+	#[test]
+	fn test_metric_name() {
+		let resource = attributes(&[("service.name", "checkout"), ("host.name", "web01.example.com")]);
+		let point = attributes(&[("status", "200"), ("method", "GET")]);
+
+		let naming = OtlpNaming::default();
+		assert_eq!(naming.metric_name("http.server.requests", &point, &resource), Some("checkout.http.server.requests.GET.200".to_string()));
+		assert_eq!(naming.metric_name("queue size", &[], &resource), Some("checkout.queue_size".to_string()));
+		assert_eq!(naming.metric_name("queue", &[], &[]), None);
+
+		let naming = OtlpNaming::parse("otel.{host.name}.{name}.{status}.{attributes}").unwrap();
+		assert_eq!(naming.metric_name("http.server.requests", &point, &resource), Some("otel.web01_example_com.http.server.requests.200.GET".to_string()));
+	}
+
+	#[test]
+	fn test_invalid_schemes() {
+		assert!(OtlpNaming::parse("{service.name}").is_err());
+		assert!(OtlpNaming::parse("{service.name}.{name").is_err());
+		assert!(OtlpNaming::parse("{}.{name}").is_err());
+		assert!(OtlpNaming::parse("/etc/{name}").is_err());
+	}
+}
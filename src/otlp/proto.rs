@@ -0,0 +1,393 @@
+use prost::Message;
+
+// The parts of opentelemetry-proto's ExportMetricsServiceRequest that become
+// whisper points, decoded by prost. Attribute values are kept as strings,
+// arrays and maps of them are dropped.
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct ExportRequest {
+	pub resource_metrics: Vec<ResourceMetrics>
+}
+
+// The metrics of every scope of a resource
+#[derive(Debug, PartialEq, Clone)]
+pub struct ResourceMetrics {
+	pub attributes: Vec<(String, String)>,
+	pub metrics: Vec<Metric>
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Metric {
+	pub name: String,
+	pub data: MetricData
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum MetricData {
+	Gauge(Vec<NumberPoint>),
+	// Cumulative or delta, monotonic or not, written as they come
+	Sum(Vec<NumberPoint>),
+	Histogram(Vec<HistogramPoint>),
+	Summary(Vec<SummaryPoint>),
+	// Exponential histograms and whatever comes next
+	Unsupported
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct NumberPoint {
+	pub attributes: Vec<(String, String)>,
+	pub time_unix_nano: u64,
+	pub value: f64
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct HistogramPoint {
+	pub attributes: Vec<(String, String)>,
+	pub time_unix_nano: u64,
+	pub count: u64,
+	pub sum: Option<f64>,
+	// One more count than bounds, the last for everything above them
+	pub bucket_counts: Vec<u64>,
+	pub explicit_bounds: Vec<f64>,
+	pub min: Option<f64>,
+	pub max: Option<f64>
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct SummaryPoint {
+	pub attributes: Vec<(String, String)>,
+	pub time_unix_nano: u64,
+	pub count: u64,
+	pub sum: f64,
+	// (quantile, value) pairs
+	pub quantiles: Vec<(f64, f64)>
+}
+
+impl ExportRequest {
+	pub fn decode(data: &[u8]) -> Result<ExportRequest, String> {
+		let request = try!( wire::ExportRequest::decode(data).map_err(|err| format!("invalid protobuf message: {}", err)) );
+		let mut resource_metrics = Vec::with_capacity(request.resource_metrics.len());
+		for resource in request.resource_metrics {
+			resource_metrics.push(try!( from_wire_resource_metrics(resource) ));
+		}
+		Ok(ExportRequest { resource_metrics: resource_metrics })
+	}
+}
+
+fn from_wire_resource_metrics(resource_metrics: wire::ResourceMetrics) -> Result<ResourceMetrics, String> {
+	let attributes = resource_metrics.resource.map_or(vec![], |resource| attributes(resource.attributes));
+	let mut metrics = vec![];
+	for scope_metrics in resource_metrics.scope_metrics {
+		for metric in scope_metrics.metrics {
+			metrics.push(Metric { name: metric.name, data: try!( from_wire_data(metric.data) ) });
+		}
+	}
+	Ok(ResourceMetrics { attributes: attributes, metrics: metrics })
+}
+
+fn from_wire_data(data: Option<wire::Data>) -> Result<MetricData, String> {
+	Ok(match data {
+		Some(wire::Data::Gauge(gauge)) => MetricData::Gauge(gauge.data_points.into_iter().map(number_point).collect()),
+		Some(wire::Data::Sum(sum)) => MetricData::Sum(sum.data_points.into_iter().map(number_point).collect()),
+		Some(wire::Data::Histogram(histogram)) => {
+			let mut points = Vec::with_capacity(histogram.data_points.len());
+			for point in histogram.data_points {
+				if !point.bucket_counts.is_empty() && point.bucket_counts.len() != point.explicit_bounds.len() + 1 {
+					return Err("histogram bucket counts don't fit its bounds".to_string());
+				}
+				points.push(HistogramPoint {
+					attributes: attributes(point.attributes),
+					time_unix_nano: point.time_unix_nano,
+					count: point.count,
+					sum: point.sum,
+					bucket_counts: point.bucket_counts,
+					explicit_bounds: point.explicit_bounds,
+					min: point.min,
+					max: point.max
+				});
+			}
+			MetricData::Histogram(points)
+		},
+		Some(wire::Data::Summary(summary)) => MetricData::Summary(summary.data_points.into_iter().map(|point| SummaryPoint {
+			attributes: attributes(point.attributes),
+			time_unix_nano: point.time_unix_nano,
+			count: point.count,
+			sum: point.sum,
+			quantiles: point.quantile_values.iter().map(|quantile| (quantile.quantile, quantile.value)).collect()
+		}).collect()),
+		None => MetricData::Unsupported
+	})
+}
+
+fn number_point(point: wire::NumberDataPoint) -> NumberPoint {
+	NumberPoint {
+		attributes: attributes(point.attributes),
+		time_unix_nano: point.time_unix_nano,
+		value: match point.value {
+			Some(wire::NumberValue::AsDouble(value)) => value,
+			Some(wire::NumberValue::AsInt(value)) => value as f64,
+			None => 0.0
+		}
+	}
+}
+
+// Leaving out values that aren't a string, bool, int or double
+fn attributes(key_values: Vec<wire::KeyValue>) -> Vec<(String, String)> {
+	key_values.into_iter().filter_map(|key_value| {
+		let value = match key_value.value.and_then(|value| value.value) {
+			Some(wire::Value::String(value)) => value,
+			Some(wire::Value::Bool(value)) => value.to_string(),
+			Some(wire::Value::Int(value)) => value.to_string(),
+			Some(wire::Value::Double(value)) => value.to_string(),
+			None => return None
+		};
+		Some((key_value.key, value))
+	}).collect()
+}
+
+// opentelemetry-proto's messages, down to the fields read here. Exponential
+// histograms and array or map attribute values are skipped as unknown fields.
+pub mod wire {
+	use prost::{ Message, Oneof };
+
+	#[derive(PartialEq, Clone, Message)]
+	pub struct ExportRequest {
+		#[prost(message, repeated, tag = "1")]
+		pub resource_metrics: Vec<ResourceMetrics>
+	}
+
+	#[derive(PartialEq, Clone, Message)]
+	pub struct ResourceMetrics {
+		#[prost(message, optional, tag = "1")]
+		pub resource: Option<Resource>,
+		#[prost(message, repeated, tag = "2")]
+		pub scope_metrics: Vec<ScopeMetrics>
+	}
+
+	#[derive(PartialEq, Clone, Message)]
+	pub struct Resource {
+		#[prost(message, repeated, tag = "1")]
+		pub attributes: Vec<KeyValue>
+	}
+
+	#[derive(PartialEq, Clone, Message)]
+	pub struct ScopeMetrics {
+		#[prost(message, repeated, tag = "2")]
+		pub metrics: Vec<Metric>
+	}
+
+	#[derive(PartialEq, Clone, Message)]
+	pub struct Metric {
+		#[prost(string, tag = "1")]
+		pub name: String,
+		#[prost(oneof = "Data", tags = "5, 7, 9, 11")]
+		pub data: Option<Data>
+	}
+
+	#[derive(PartialEq, Clone, Oneof)]
+	pub enum Data {
+		#[prost(message, tag = "5")]
+		Gauge(Gauge),
+		#[prost(message, tag = "7")]
+		Sum(Sum),
+		#[prost(message, tag = "9")]
+		Histogram(Histogram),
+		#[prost(message, tag = "11")]
+		Summary(Summary)
+	}
+
+	#[derive(PartialEq, Clone, Message)]
+	pub struct Gauge {
+		#[prost(message, repeated, tag = "1")]
+		pub data_points: Vec<NumberDataPoint>
+	}
+
+	// Its temporality and monotonicity aren't read
+	#[derive(PartialEq, Clone, Message)]
+	pub struct Sum {
+		#[prost(message, repeated, tag = "1")]
+		pub data_points: Vec<NumberDataPoint>
+	}
+
+	#[derive(PartialEq, Clone, Message)]
+	pub struct Histogram {
+		#[prost(message, repeated, tag = "1")]
+		pub data_points: Vec<HistogramDataPoint>
+	}
+
+	#[derive(PartialEq, Clone, Message)]
+	pub struct Summary {
+		#[prost(message, repeated, tag = "1")]
+		pub data_points: Vec<SummaryDataPoint>
+	}
+
+	#[derive(PartialEq, Clone, Message)]
+	pub struct NumberDataPoint {
+		#[prost(fixed64, tag = "3")]
+		pub time_unix_nano: u64,
+		#[prost(oneof = "NumberValue", tags = "4, 6")]
+		pub value: Option<NumberValue>,
+		#[prost(message, repeated, tag = "7")]
+		pub attributes: Vec<KeyValue>
+	}
+
+	#[derive(PartialEq, Clone, Oneof)]
+	pub enum NumberValue {
+		#[prost(double, tag = "4")]
+		AsDouble(f64),
+		#[prost(sfixed64, tag = "6")]
+		AsInt(i64)
+	}
+
+	#[derive(PartialEq, Clone, Message)]
+	pub struct HistogramDataPoint {
+		#[prost(fixed64, tag = "3")]
+		pub time_unix_nano: u64,
+		#[prost(fixed64, tag = "4")]
+		pub count: u64,
+		#[prost(double, optional, tag = "5")]
+		pub sum: Option<f64>,
+		#[prost(fixed64, repeated, tag = "6")]
+		pub bucket_counts: Vec<u64>,
+		#[prost(double, repeated, tag = "7")]
+		pub explicit_bounds: Vec<f64>,
+		#[prost(message, repeated, tag = "9")]
+		pub attributes: Vec<KeyValue>,
+		#[prost(double, optional, tag = "11")]
+		pub min: Option<f64>,
+		#[prost(double, optional, tag = "12")]
+		pub max: Option<f64>
+	}
+
+	#[derive(PartialEq, Clone, Message)]
+	pub struct SummaryDataPoint {
+		#[prost(fixed64, tag = "3")]
+		pub time_unix_nano: u64,
+		#[prost(fixed64, tag = "4")]
+		pub count: u64,
+		#[prost(double, tag = "5")]
+		pub sum: f64,
+		#[prost(message, repeated, tag = "6")]
+		pub quantile_values: Vec<ValueAtQuantile>,
+		#[prost(message, repeated, tag = "7")]
+		pub attributes: Vec<KeyValue>
+	}
+
+	#[derive(PartialEq, Clone, Message)]
+	pub struct ValueAtQuantile {
+		#[prost(double, tag = "1")]
+		pub quantile: f64,
+		#[prost(double, tag = "2")]
+		pub value: f64
+	}
+
+	#[derive(PartialEq, Clone, Message)]
+	pub struct KeyValue {
+		#[prost(string, tag = "1")]
+		pub key: String,
+		#[prost(message, optional, tag = "2")]
+		pub value: Option<AnyValue>
+	}
+
+	#[derive(PartialEq, Clone, Message)]
+	pub struct AnyValue {
+		#[prost(oneof = "Value", tags = "1, 2, 3, 4")]
+		pub value: Option<Value>
+	}
+
+	#[derive(PartialEq, Clone, Oneof)]
+	pub enum Value {
+		#[prost(string, tag = "1")]
+		String(String),
+		#[prost(bool, tag = "2")]
+		Bool(bool),
+		#[prost(int64, tag = "3")]
+		Int(i64),
+		#[prost(double, tag = "4")]
+		Double(f64)
+	}
+}
+
+#[cfg(test)]
+pub mod tests {
+	use super::*;
+
+	// The messages the tests send
+
+	pub fn attribute(key: &str, value: &str) -> wire::KeyValue {
+		wire::KeyValue { key: key.to_string(), value: Some(wire::AnyValue { value: Some(wire::Value::String(value.to_string())) }) }
+	}
+
+	pub fn number_point(attributes: &[(&str, &str)], time_unix_nano: u64, value: f64) -> wire::NumberDataPoint {
+		wire::NumberDataPoint {
+			time_unix_nano: time_unix_nano,
+			value: Some(wire::NumberValue::AsDouble(value)),
+			attributes: attributes.iter().map(|&(key, value)| attribute(key, value)).collect()
+		}
+	}
+
+	pub fn histogram_point(time_unix_nano: u64, count: u64, sum: f64, bucket_counts: &[u64], bounds: &[f64]) -> wire::HistogramDataPoint {
+		wire::HistogramDataPoint {
+			time_unix_nano: time_unix_nano,
+			count: count,
+			sum: Some(sum),
+			bucket_counts: bucket_counts.to_vec(),
+			explicit_bounds: bounds.to_vec(),
+			.. wire::HistogramDataPoint::default()
+		}
+	}
+
+	pub fn gauge(name: &str, points: Vec<wire::NumberDataPoint>) -> wire::Metric {
+		wire::Metric { name: name.to_string(), data: Some(wire::Data::Gauge(wire::Gauge { data_points: points })) }
+	}
+
+	pub fn sum(name: &str, points: Vec<wire::NumberDataPoint>) -> wire::Metric {
+		wire::Metric { name: name.to_string(), data: Some(wire::Data::Sum(wire::Sum { data_points: points })) }
+	}
+
+	pub fn histogram(name: &str, points: Vec<wire::HistogramDataPoint>) -> wire::Metric {
+		wire::Metric { name: name.to_string(), data: Some(wire::Data::Histogram(wire::Histogram { data_points: points })) }
+	}
+
+	// An exponential histogram or anything else not read
+	pub fn unsupported(name: &str) -> wire::Metric {
+		wire::Metric { name: name.to_string(), data: None }
+	}
+
+	pub fn request(resource_attributes: &[(&str, &str)], metrics: Vec<wire::Metric>) -> Vec<u8> {
+		wire::ExportRequest { resource_metrics: vec![wire::ResourceMetrics {
+			resource: Some(wire::Resource { attributes: resource_attributes.iter().map(|&(key, value)| attribute(key, value)).collect() }),
+			scope_metrics: vec![wire::ScopeMetrics { metrics: metrics }]
+		}] }.encode_to_vec()
+	}
+
+	#[test]
+	fn test_decode() {
+		let encoded = request(&[("service.name", "checkout")], vec![
+			sum("http.requests", vec![number_point(&[("method", "GET")], 1440392100000000000, 12.0)]),
+			histogram("http.duration", vec![histogram_point(1440392100000000000, 3, 0.6, &[1, 2, 0], &[0.1, 0.5])])
+		]);
+		let request_message = ExportRequest::decode(&encoded).unwrap();
+
+		assert_eq!(request_message.resource_metrics.len(), 1);
+		let resource_metrics = &request_message.resource_metrics[0];
+		assert_eq!(resource_metrics.attributes, vec![("service.name".to_string(), "checkout".to_string())]);
+		assert_eq!(resource_metrics.metrics[0], Metric {
+			name: "http.requests".to_string(),
+			data: MetricData::Sum(vec![NumberPoint { attributes: vec![("method".to_string(), "GET".to_string())], time_unix_nano: 1440392100000000000, value: 12.0 }])
+		});
+		assert_eq!(resource_metrics.metrics[1].data, MetricData::Histogram(vec![HistogramPoint {
+			attributes: vec![],
+			time_unix_nano: 1440392100000000000,
+			count: 3,
+			sum: Some(0.6),
+			bucket_counts: vec![1, 2, 0],
+			explicit_bounds: vec![0.1, 0.5],
+			min: None,
+			max: None
+		}]));
+
+		assert!(ExportRequest::decode(&request(&[], vec![histogram("bad", vec![histogram_point(0, 1, 1.0, &[1], &[0.1])])])).is_err());
+		assert!(ExportRequest::decode(&[0x0a, 0x05]).is_err());
+	}
+}
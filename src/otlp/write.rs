@@ -0,0 +1,230 @@
+use std::sync::{ Arc, Mutex };
+
+use whisper::{ WhisperCache, NamedPoint };
+use http::{ Request, Response };
+use super::{ ExportRequest, MetricData, OtlpNaming };
+
+#[derive(Debug, Default, PartialEq, Clone)]
+pub struct OtlpStats {
+	pub requests: usize,
+	// Bodies that could not be decoded, or came compressed or as JSON
+	pub invalid_requests: usize,
+	pub data_points_received: usize,
+	pub points_written: usize,
+	// Data points missing an attribute the naming scheme names
+	pub unmapped_data_points: usize,
+	// Metrics of a type there's no mapping for, exponential histograms so far
+	pub unsupported_metrics: usize,
+	// Timestamps whisper can't hold and points the cache refused
+	pub write_errors: usize
+}
+
+// Writes the data points of OTLP export requests through a WhisperCache.
+// Gauges and sums are written as they are. A histogram point becomes
+// `.count`, `.sum`, `.min` and `.max` points and the cumulative count of
+// each bucket, `.bucket.le_<bound>` up to `.bucket.le_inf`, a summary point
+// `.count`, `.sum` and a `.p<quantile>` per quantile, e.g. `.p99`.
+// Timestamps are cut down to seconds.
+pub struct OtlpWrite {
+	cache: Arc<WhisperCache>,
+	naming: OtlpNaming,
+	stats: Mutex<OtlpStats>
+}
+
+impl OtlpWrite {
+	pub fn new(cache: Arc<WhisperCache>, naming: OtlpNaming) -> OtlpWrite {
+		OtlpWrite {
+			cache: cache,
+			naming: naming,
+			stats: Mutex::new(OtlpStats::default())
+		}
+	}
+
+	pub fn stats(&self) -> OtlpStats {
+		self.stats.lock().unwrap().clone()
+	}
+
+	// A protobuf ExportMetricsServiceRequest
+	pub fn ingest(&self, body: &[u8]) -> Result<(), String> {
+		let request = match ExportRequest::decode(body) {
+			Ok(request) => request,
+			Err(err) => {
+				self.count_invalid_request();
+				return Err(err);
+			}
+		};
+
+		let (mut received, mut written, mut unmapped, mut unsupported, mut failed) = (0, 0, 0, 0, 0);
+		for resource_metrics in &request.resource_metrics {
+			for metric in &resource_metrics.metrics {
+				// (attributes, time, [(suffix, value)]) of each data point
+				let data_points : Vec<(&[(String, String)], u64, Vec<(String, f64)>)> = match metric.data {
+					MetricData::Gauge(ref points) | MetricData::Sum(ref points) => points.iter().map(|point| {
+						(&point.attributes[..], point.time_unix_nano, vec![(String::new(), point.value)])
+					}).collect(),
+					MetricData::Histogram(ref points) => points.iter().map(|point| {
+						let mut values = vec![(".count".to_string(), point.count as f64)];
+						values.extend(point.sum.map(|sum| (".sum".to_string(), sum)));
+						values.extend(point.min.map(|min| (".min".to_string(), min)));
+						values.extend(point.max.map(|max| (".max".to_string(), max)));
+						let mut cumulative = 0;
+						for (index, count) in point.bucket_counts.iter().enumerate() {
+							cumulative = cumulative + count;
+							let bound = match point.explicit_bounds.get(index) {
+								Some(bound) => sanitize_number(*bound),
+								None => "inf".to_string()
+							};
+							values.push((format!(".bucket.le_{}", bound), cumulative as f64));
+						}
+						(&point.attributes[..], point.time_unix_nano, values)
+					}).collect(),
+					MetricData::Summary(ref points) => points.iter().map(|point| {
+						let mut values = vec![(".count".to_string(), point.count as f64), (".sum".to_string(), point.sum)];
+						for &(quantile, value) in &point.quantiles {
+							values.push((format!(".p{}", sanitize_number(quantile * 100.0)), value));
+						}
+						(&point.attributes[..], point.time_unix_nano, values)
+					}).collect(),
+					MetricData::Unsupported => {
+						unsupported = unsupported + 1;
+						continue;
+					}
+				};
+
+				for (attributes, time_unix_nano, values) in data_points {
+					received = received + 1;
+					let metric_name = match self.naming.metric_name(&metric.name, attributes, &resource_metrics.attributes) {
+						Some(metric_name) => metric_name,
+						None => {
+							unmapped = unmapped + 1;
+							continue;
+						}
+					};
+
+					let timestamp = time_unix_nano / 1_000_000_000;
+					if timestamp > u32::max_value() as u64 {
+						failed = failed + values.len();
+						continue;
+					}
+					for (suffix, value) in values {
+						let name = format!("{}{}", metric_name, suffix);
+						match self.cache.write(NamedPoint::new(name.clone(), timestamp as u32, value)) {
+							Ok(()) => written = written + 1,
+							Err(err) => {
+								warn!("could not write a point of {}: {}", name, err);
+								failed = failed + 1;
+							}
+						}
+					}
+				}
+			}
+		}
+
+		let mut stats = self.stats.lock().unwrap();
+		stats.requests = stats.requests + 1;
+		stats.data_points_received = stats.data_points_received + received;
+		stats.points_written = stats.points_written + written;
+		stats.unmapped_data_points = stats.unmapped_data_points + unmapped;
+		stats.unsupported_metrics = stats.unsupported_metrics + unsupported;
+		stats.write_errors = stats.write_errors + failed;
+		Ok(())
+	}
+
+	// An empty ExportMetricsServiceResponse once the points are in the cache.
+	// OTLP's JSON encoding and compressed bodies get a 415, which exporters
+	// don't retry. The collector's otlphttp exporter gzips by default, so it
+	// needs `compression: none`, the SDKs send uncompressed bodies unless told.
+	pub fn respond(&self, request: &Request) -> Response {
+		if request.method != "POST" {
+			return Response::error(405, "OTLP export takes a POST");
+		}
+		if request.header("content-type").map_or(false, |content_type| content_type.starts_with("application/json")) {
+			self.count_invalid_request();
+			return Response::error(415, "only protobuf encoded requests are supported");
+		}
+		if request.header("content-encoding").map_or(false, |encoding| encoding != "identity") {
+			self.count_invalid_request();
+			return Response::error(415, "compressed requests are not supported, export with compression set to none");
+		}
+
+		match self.ingest(&request.body) {
+			Ok(()) => Response { status: 200, content_type: "application/x-protobuf", headers: vec![], body: vec![] },
+			Err(err) => Response::error(400, &err)
+		}
+	}
+
+	fn count_invalid_request(&self) {
+		let mut stats = self.stats.lock().unwrap();
+		stats.requests = stats.requests + 1;
+		stats.invalid_requests = stats.invalid_requests + 1;
+	}
+}
+
+// 0.5 as `0_5`, 99.9 as `99_9`
+fn sanitize_number(value: f64) -> String {
+	value.to_string().replace('.', "_")
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use otlp::proto::tests::{ request, gauge, sum, histogram, unsupported, number_point, histogram_point };
+	use whisper::temp_cache;
+
+	#[test]
+	fn test_otlp_write() {
+		let (cache, now) = temp_cache("test_otlp_write");
+		let (cache, now) = (Arc::new(cache), now as u64);
+		let nanos = |seconds: u64| seconds * 1_000_000_000 + 250;
+
+		let otlp_write = OtlpWrite::new(cache.clone(), OtlpNaming::default());
+		let body = request(&[("service.name", "checkout")], vec![
+			sum("http.requests", vec![
+				number_point(&[("method", "GET")], nanos(now - 10), 3.0),
+				number_point(&[("method", "GET")], nanos(now), 5.0)
+			]),
+			histogram("http.duration", vec![histogram_point(nanos(now), 3, 0.6, &[1, 2, 0], &[0.1, 0.5])]),
+			// An exponential histogram
+			unsupported("http.size")
+		]);
+		otlp_write.ingest(&body).unwrap();
+
+		assert_eq!(otlp_write.stats(), OtlpStats {
+			requests: 1,
+			invalid_requests: 0,
+			data_points_received: 3,
+			points_written: 7,
+			unmapped_data_points: 0,
+			unsupported_metrics: 1,
+			write_errors: 0
+		});
+		let now = now as u32;
+		assert_eq!(cache.fetch("checkout.http.requests.GET", now - 20, now).unwrap().values, vec![Some(3.0), Some(5.0)]);
+		assert_eq!(cache.fetch("checkout.http.duration.count", now - 10, now).unwrap().values, vec![Some(3.0)]);
+		assert_eq!(cache.fetch("checkout.http.duration.bucket.le_0_5", now - 10, now).unwrap().values, vec![Some(3.0)]);
+		assert_eq!(cache.fetch("checkout.http.duration.bucket.le_inf", now - 10, now).unwrap().values, vec![Some(3.0)]);
+
+		otlp_write.ingest(&request(&[], vec![gauge("up", vec![number_point(&[], nanos(now as u64), 1.0)])])).unwrap();
+		assert_eq!(otlp_write.stats().unmapped_data_points, 1);
+	}
+
+	#[test]
+	fn test_respond() {
+		let (cache, _) = temp_cache("test_otlp_respond");
+		let cache = Arc::new(cache);
+		let otlp_write = OtlpWrite::new(cache, OtlpNaming::default());
+
+		let request = Request { method: "POST".to_string(), path: "/v1/metrics".to_string(), params: vec![], headers: vec![], body: vec![] };
+		let response = otlp_write.respond(&request);
+		assert_eq!(response.status, 200);
+		assert_eq!(response.content_type, "application/x-protobuf");
+
+		let invalid = Request { body: vec![0x0a, 0x05], .. request.clone() };
+		assert_eq!(otlp_write.respond(&invalid).status, 400);
+		let json = Request { headers: vec![("content-type".to_string(), "application/json".to_string())], .. request.clone() };
+		assert_eq!(otlp_write.respond(&json).status, 415);
+		let gzip = Request { headers: vec![("content-encoding".to_string(), "gzip".to_string())], .. request.clone() };
+		assert_eq!(otlp_write.respond(&gzip).status, 415);
+		assert_eq!(otlp_write.stats().invalid_requests, 3);
+	}
+}
@@ -0,0 +1,77 @@
+use std::error;
+use std::fmt;
+
+use whisper::WhisperError;
+
+// Why a request body could not be read
+#[derive(Debug, PartialEq, Clone)]
+pub enum DecodeError {
+	Snappy(&'static str),
+	Protobuf(String)
+}
+
+impl fmt::Display for DecodeError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match *self {
+			DecodeError::Snappy(reason) => write!(f, "invalid snappy data: {}", reason),
+			DecodeError::Protobuf(ref reason) => write!(f, "invalid protobuf message: {}", reason)
+		}
+	}
+}
+
+impl error::Error for DecodeError {
+	fn description(&self) -> &str {
+		match *self {
+			DecodeError::Snappy(_) => "invalid snappy data",
+			DecodeError::Protobuf(_) => "invalid protobuf message"
+		}
+	}
+}
+
+#[derive(Debug)]
+pub enum RemoteReadError {
+	Decode(DecodeError),
+	// A regex matcher that doesn't compile
+	InvalidMatcher(String),
+	Whisper(WhisperError)
+}
+
+impl fmt::Display for RemoteReadError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match *self {
+			RemoteReadError::Decode(ref err) => write!(f, "{}", err),
+			RemoteReadError::InvalidMatcher(ref reason) => write!(f, "invalid matcher: {}", reason),
+			RemoteReadError::Whisper(ref err) => write!(f, "{}", err)
+		}
+	}
+}
+
+impl error::Error for RemoteReadError {
+	fn description(&self) -> &str {
+		match *self {
+			RemoteReadError::Decode(ref err) => err.description(),
+			RemoteReadError::InvalidMatcher(_) => "invalid matcher",
+			RemoteReadError::Whisper(ref err) => err.description()
+		}
+	}
+
+	fn cause(&self) -> Option<&error::Error> {
+		match *self {
+			RemoteReadError::Decode(ref err) => Some(err),
+			RemoteReadError::Whisper(ref err) => Some(err),
+			_ => None
+		}
+	}
+}
+
+impl From<DecodeError> for RemoteReadError {
+	fn from(err: DecodeError) -> RemoteReadError {
+		RemoteReadError::Decode(err)
+	}
+}
+
+impl From<WhisperError> for RemoteReadError {
+	fn from(err: WhisperError) -> RemoteReadError {
+		RemoteReadError::Whisper(err)
+	}
+}
@@ -0,0 +1,17 @@
+// Prometheus' remote storage protocol, so whisper can keep a Prometheus
+// fleet's samples long term and answer its queries for them. Snappy is
+// decoded here, the protobuf messages by prost.
+
+mod error;
+mod snappy;
+mod proto;
+mod template;
+mod remote_write;
+mod remote_read;
+
+pub use self::error::{ DecodeError, RemoteReadError };
+pub use self::proto::{ WriteRequest, ReadRequest, ReadResponse, Query, QueryResult, Series, Label, Sample, LabelMatcher, MatchType };
+pub use self::template::MetricTemplate;
+pub use self::remote_write::{ RemoteWrite, RemoteWriteStats };
+pub use self::remote_read::RemoteRead;
+pub use self::snappy::{ compress, decompress };
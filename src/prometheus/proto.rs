@@ -0,0 +1,248 @@
+use prost::Message;
+
+use super::DecodeError;
+
+// The protobuf messages of remote storage, as in Prometheus' prompb package,
+// encoded and decoded by prost. Fields this side doesn't know are skipped.
+
+#[derive(PartialEq, Clone, Message)]
+pub struct Label {
+	#[prost(string, tag = "1")]
+	pub name: String,
+	#[prost(string, tag = "2")]
+	pub value: String
+}
+
+#[derive(PartialEq, Clone, Copy, Message)]
+pub struct Sample {
+	#[prost(double, tag = "1")]
+	pub value: f64,
+	// Milliseconds since the epoch
+	#[prost(int64, tag = "2")]
+	pub timestamp: i64
+}
+
+#[derive(PartialEq, Clone, Message)]
+pub struct Series {
+	#[prost(message, repeated, tag = "1")]
+	pub labels: Vec<Label>,
+	#[prost(message, repeated, tag = "2")]
+	pub samples: Vec<Sample>
+}
+
+#[derive(PartialEq, Clone, Message)]
+pub struct WriteRequest {
+	#[prost(message, repeated, tag = "1")]
+	pub timeseries: Vec<Series>
+}
+
+impl Series {
+	pub fn label(&self, name: &str) -> Option<&str> {
+		self.labels.iter().find(|label| label.name == name).map(|label| label.value.as_str())
+	}
+}
+
+impl WriteRequest {
+	// The protobuf message, once decompressed
+	pub fn decode(data: &[u8]) -> Result<WriteRequest, DecodeError> {
+		<WriteRequest as Message>::decode(data).map_err(invalid)
+	}
+
+	pub fn encode(&self) -> Vec<u8> {
+		self.encode_to_vec()
+	}
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum MatchType {
+	Equal,
+	NotEqual,
+	RegexMatch,
+	RegexNoMatch
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct LabelMatcher {
+	pub match_type: MatchType,
+	pub name: String,
+	pub value: String
+}
+
+// Series matching all the matchers, samples in [start_timestamp, end_timestamp]
+#[derive(Debug, PartialEq, Clone)]
+pub struct Query {
+	pub start_timestamp: i64,
+	pub end_timestamp: i64,
+	pub matchers: Vec<LabelMatcher>
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct ReadRequest {
+	pub queries: Vec<Query>
+}
+
+#[derive(PartialEq, Clone, Message)]
+pub struct QueryResult {
+	#[prost(message, repeated, tag = "1")]
+	pub timeseries: Vec<Series>
+}
+
+// A result per query, in the same order
+#[derive(PartialEq, Clone, Message)]
+pub struct ReadResponse {
+	#[prost(message, repeated, tag = "1")]
+	pub results: Vec<QueryResult>
+}
+
+impl MatchType {
+	fn from_wire(match_type: i32) -> Result<MatchType, DecodeError> {
+		match match_type {
+			0 => Ok(MatchType::Equal),
+			1 => Ok(MatchType::NotEqual),
+			2 => Ok(MatchType::RegexMatch),
+			3 => Ok(MatchType::RegexNoMatch),
+			_ => Err(DecodeError::Protobuf("unknown matcher type".to_string()))
+		}
+	}
+
+	fn to_wire(&self) -> i32 {
+		match *self {
+			MatchType::Equal => 0,
+			MatchType::NotEqual => 1,
+			MatchType::RegexMatch => 2,
+			MatchType::RegexNoMatch => 3
+		}
+	}
+}
+
+impl ReadRequest {
+	// The protobuf message, once decompressed. Only the samples response type
+	// is answered, which Prometheus always accepts.
+	pub fn decode(data: &[u8]) -> Result<ReadRequest, DecodeError> {
+		let request = try!( wire::ReadRequest::decode(data).map_err(invalid) );
+		let mut queries = Vec::with_capacity(request.queries.len());
+		for query in request.queries {
+			let mut matchers = Vec::with_capacity(query.matchers.len());
+			for matcher in query.matchers {
+				matchers.push(LabelMatcher { match_type: try!( MatchType::from_wire(matcher.match_type) ), name: matcher.name, value: matcher.value });
+			}
+			queries.push(Query { start_timestamp: query.start_timestamp_ms, end_timestamp: query.end_timestamp_ms, matchers: matchers });
+		}
+		Ok(ReadRequest { queries: queries })
+	}
+
+	pub fn encode(&self) -> Vec<u8> {
+		wire::ReadRequest {
+			queries: self.queries.iter().map(|query| wire::Query {
+				start_timestamp_ms: query.start_timestamp,
+				end_timestamp_ms: query.end_timestamp,
+				matchers: query.matchers.iter().map(|matcher| wire::LabelMatcher { match_type: matcher.match_type.to_wire(), name: matcher.name.clone(), value: matcher.value.clone() }).collect()
+			}).collect()
+		}.encode_to_vec()
+	}
+}
+
+impl ReadResponse {
+	pub fn decode(data: &[u8]) -> Result<ReadResponse, DecodeError> {
+		<ReadResponse as Message>::decode(data).map_err(invalid)
+	}
+
+	pub fn encode(&self) -> Vec<u8> {
+		self.encode_to_vec()
+	}
+}
+
+fn invalid(err: ::prost::DecodeError) -> DecodeError {
+	DecodeError::Protobuf(err.to_string())
+}
+
+// The read request as it is sent, matcher types and all
+mod wire {
+	use prost::Message;
+
+	#[derive(PartialEq, Clone, Message)]
+	pub struct LabelMatcher {
+		#[prost(int32, tag = "1")]
+		pub match_type: i32,
+		#[prost(string, tag = "2")]
+		pub name: String,
+		#[prost(string, tag = "3")]
+		pub value: String
+	}
+
+	#[derive(PartialEq, Clone, Message)]
+	pub struct Query {
+		#[prost(int64, tag = "1")]
+		pub start_timestamp_ms: i64,
+		#[prost(int64, tag = "2")]
+		pub end_timestamp_ms: i64,
+		#[prost(message, repeated, tag = "3")]
+		pub matchers: Vec<LabelMatcher>
+	}
+
+	#[derive(PartialEq, Clone, Message)]
+	pub struct ReadRequest {
+		#[prost(message, repeated, tag = "1")]
+		pub queries: Vec<Query>
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_decode() {
+		// A series with one label and one sample, as Prometheus encodes it,
+		// followed by a metadata field (3) that gets skipped
+		let encoded = [
+			0x0a, 0x1e,
+				0x0a, 0x0e, 0x0a, 0x08, b'_', b'_', b'n', b'a', b'm', b'e', b'_', b'_', 0x12, 0x02, b'u', b'p',
+				0x12, 0x0c, 0x09, 0, 0, 0, 0, 0, 0, 0xf0, 0x3f, 0x10, 0xe8, 0x07,
+			0x1a, 0x02, 0x08, 0x01
+		];
+		let request = WriteRequest::decode(&encoded).unwrap();
+		assert_eq!(request.timeseries, vec![Series {
+			labels: vec![Label { name: "__name__".to_string(), value: "up".to_string() }],
+			samples: vec![Sample { value: 1.0, timestamp: 1000 }]
+		}]);
+		assert_eq!(request.timeseries[0].label("__name__"), Some("up"));
+		assert_eq!(request.encode(), encoded[..32].to_vec());
+	}
+
+	#[test]
+	fn test_roundtrip() {
+		let request = WriteRequest { timeseries: vec![Series {
+			labels: vec![Label { name: "job".to_string(), value: "node".to_string() }, Label { name: "instance".to_string(), value: "web01:9100".to_string() }],
+			samples: vec![Sample { value: -2.5, timestamp: 1440392100000 }, Sample { value: 3.0, timestamp: -1 }]
+		}] };
+		assert_eq!(WriteRequest::decode(&request.encode()).unwrap(), request);
+	}
+
+	#[test]
+	fn test_read_roundtrip() {
+		let request = ReadRequest { queries: vec![Query {
+			start_timestamp: 1440388500000,
+			end_timestamp: 1440392100000,
+			matchers: vec![
+				LabelMatcher { match_type: MatchType::Equal, name: "__name__".to_string(), value: "up".to_string() },
+				LabelMatcher { match_type: MatchType::RegexNoMatch, name: "job".to_string(), value: "node|push".to_string() }
+			]
+		}] };
+		assert_eq!(ReadRequest::decode(&request.encode()).unwrap(), request);
+
+		let response = ReadResponse { results: vec![
+			QueryResult { timeseries: vec![Series { labels: vec![Label { name: "__name__".to_string(), value: "up".to_string() }], samples: vec![Sample { value: 1.0, timestamp: 1440392100000 }] }] },
+			QueryResult { timeseries: vec![] }
+		] };
+		assert_eq!(ReadResponse::decode(&response.encode()).unwrap(), response);
+	}
+
+	#[test]
+	fn test_invalid() {
+		assert!(WriteRequest::decode(&[0x0a, 0x05, 0x00]).is_err());
+		assert!(WriteRequest::decode(&[0x08]).is_err());
+		assert!(WriteRequest::decode(&[0x0b]).is_err());
+		assert!(WriteRequest::decode(&[0x0a, 0x04, 0x0a, 0x02, 0x0a, 0xff]).is_err());
+	}
+}
@@ -0,0 +1,174 @@
+use std::sync::Arc;
+
+use regex::Regex;
+
+use whisper::{ WhisperCache, MetricPattern };
+use http::{ Request, Response };
+use super::{ RemoteReadError, ReadRequest, ReadResponse, Query, QueryResult, Series, Sample, LabelMatcher, MatchType, MetricTemplate, compress, decompress };
+use template::sanitize;
+
+// Answers Prometheus' remote reads from the files a RemoteWrite with the same
+// template wrote. Series are found by globbing the template with the
+// equality matchers filled in, their labels read back from the metric name.
+// Matchers are held against those labels, so they see values the way the
+// template sanitized them.
+pub struct RemoteRead {
+	cache: Arc<WhisperCache>,
+	template: MetricTemplate
+}
+
+enum Matcher {
+	Equal(String, String),
+	NotEqual(String, String),
+	Regex(String, Regex, bool)
+}
+
+impl Matcher {
+	fn compile(matcher: &LabelMatcher) -> Result<Matcher, RemoteReadError> {
+		let name = matcher.name.clone();
+		let regex = || Regex::new(&format!("^(?:{})$", matcher.value)).map_err(|err| RemoteReadError::InvalidMatcher(err.to_string()));
+		Ok(match matcher.match_type {
+			MatchType::Equal => Matcher::Equal(name, sanitize(&matcher.value)),
+			MatchType::NotEqual => Matcher::NotEqual(name, sanitize(&matcher.value)),
+			MatchType::RegexMatch => Matcher::Regex(name, try!( regex() ), true),
+			MatchType::RegexNoMatch => Matcher::Regex(name, try!( regex() ), false)
+		})
+	}
+
+	// A missing label is an empty one, as in Prometheus
+	fn matches(&self, series: &Series) -> bool {
+		match *self {
+			Matcher::Equal(ref name, ref value) => series.label(name).unwrap_or("") == value,
+			Matcher::NotEqual(ref name, ref value) => series.label(name).unwrap_or("") != value,
+			Matcher::Regex(ref name, ref regex, should_match) => regex.is_match(series.label(name).unwrap_or("")) == should_match
+		}
+	}
+}
+
+impl RemoteRead {
+	pub fn new(cache: Arc<WhisperCache>, template: MetricTemplate) -> RemoteRead {
+		RemoteRead {
+			cache: cache,
+			template: template
+		}
+	}
+
+	pub fn query(&self, query: &Query) -> Result<QueryResult, RemoteReadError> {
+		let matchers : Vec<Matcher> = try!( query.matchers.iter().map(Matcher::compile).collect() );
+		let from = seconds(query.start_timestamp);
+		let until = seconds(query.end_timestamp);
+		if from > until {
+			return Ok(QueryResult { timeseries: vec![] });
+		}
+
+		// Sanitized values always make a valid pattern, the template checked the rest
+		let pattern = MetricPattern::parse(&self.template.pattern(&query.matchers)).unwrap();
+		let leaves = try!( self.cache.data_root().find(&pattern).map_err(|err| RemoteReadError::Whisper(err.into())) );
+
+		let mut timeseries = vec![];
+		for leaf in leaves.into_iter().filter(|node| node.is_leaf) {
+			let mut series = match self.template.labels(&leaf.path) {
+				Some(labels) => Series { labels: labels, samples: vec![] },
+				None => continue
+			};
+			if !matchers.iter().all(|matcher| matcher.matches(&series)) {
+				continue;
+			}
+
+			let fetched = try!( self.cache.fetch(&leaf.path, from, until) );
+			series.samples = fetched.points().into_iter().map(|point| Sample { value: point.1, timestamp: point.0 as i64 * 1000 }).collect();
+			if !series.samples.is_empty() {
+				timeseries.push(series);
+			}
+		}
+		Ok(QueryResult { timeseries: timeseries })
+	}
+
+	// A snappy compressed ReadRequest in, a snappy compressed ReadResponse out
+	pub fn read(&self, body: &[u8]) -> Result<Vec<u8>, RemoteReadError> {
+		let request = try!( decompress(body).and_then(|decompressed| ReadRequest::decode(&decompressed)) );
+		let mut response = ReadResponse { results: vec![] };
+		for query in &request.queries {
+			response.results.push(try!( self.query(query) ));
+		}
+		Ok(compress(&response.encode()))
+	}
+
+	pub fn respond(&self, request: &Request) -> Response {
+		if request.method != "POST" {
+			return Response::error(405, "remote read takes a POST");
+		}
+		match self.read(&request.body) {
+			Ok(body) => Response {
+				status: 200,
+				content_type: "application/x-protobuf",
+				headers: vec![("Content-Encoding", "snappy".to_string())],
+				body: body
+			},
+			Err(err @ RemoteReadError::Whisper(_)) => {
+				warn!("could not answer a remote read: {}", err);
+				Response::error(500, &err.to_string())
+			},
+			Err(err) => Response::error(400, &err.to_string())
+		}
+	}
+}
+
+// Milliseconds to whisper's seconds, clamped to what a u32 holds
+fn seconds(timestamp: i64) -> u32 {
+	let seconds = timestamp / 1000;
+	if seconds < 0 {
+		0
+	} else if seconds > u32::max_value() as i64 {
+		u32::max_value()
+	} else {
+		seconds as u32
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use prometheus::Label;
+	use whisper::{ NamedPoint, temp_cache };
+
+	fn matcher(match_type: MatchType, name: &str, value: &str) -> LabelMatcher {
+		LabelMatcher { match_type: match_type, name: name.to_string(), value: value.to_string() }
+	}
+
+	fn label(name: &str, value: &str) -> Label {
+		Label { name: name.to_string(), value: value.to_string() }
+	}
+
+	#[test]
+	fn test_remote_read() {
+		let (cache, now) = temp_cache("test_prometheus_remote_read");
+		let cache = Arc::new(cache);
+		for &(metric, value) in &[("prometheus.node.web01_9100.up", 1.0), ("prometheus.node.web02_9100.up", 0.0), ("prometheus.push.web01_9100.up", 1.0), ("prometheus.node.web01_9100.load", 0.5)] {
+			cache.write(NamedPoint::new(metric.to_string(), now - 10, value)).unwrap();
+		}
+
+		let remote_read = RemoteRead::new(cache.clone(), MetricTemplate::parse("prometheus.{job}.{instance}.{__name__}").unwrap());
+		let query = |matchers: Vec<LabelMatcher>| Query { start_timestamp: (now as i64 - 30) * 1000, end_timestamp: now as i64 * 1000, matchers: matchers };
+
+		let result = remote_read.query(&query(vec![matcher(MatchType::Equal, "__name__", "up"), matcher(MatchType::RegexMatch, "instance", "web01.*")])).unwrap();
+		assert_eq!(result.timeseries, vec![
+			Series { labels: vec![label("__name__", "up"), label("instance", "web01_9100"), label("job", "node")], samples: vec![Sample { value: 1.0, timestamp: (now as i64 - 10) * 1000 }] },
+			Series { labels: vec![label("__name__", "up"), label("instance", "web01_9100"), label("job", "push")], samples: vec![Sample { value: 1.0, timestamp: (now as i64 - 10) * 1000 }] }
+		]);
+
+		let result = remote_read.query(&query(vec![matcher(MatchType::Equal, "job", "node"), matcher(MatchType::NotEqual, "__name__", "load"), matcher(MatchType::Equal, "env", "")])).unwrap();
+		assert_eq!(result.timeseries.len(), 2);
+		let result = remote_read.query(&query(vec![matcher(MatchType::RegexNoMatch, "job", "node|push")])).unwrap();
+		assert_eq!(result.timeseries, vec![]);
+		assert!(remote_read.query(&query(vec![matcher(MatchType::RegexMatch, "job", "(")])).is_err());
+
+		let body = compress(&ReadRequest { queries: vec![query(vec![matcher(MatchType::Equal, "__name__", "load")]), query(vec![])] }.encode());
+		let response = remote_read.respond(&Request { method: "POST".to_string(), path: "/api/v1/read".to_string(), params: vec![], headers: vec![], body: body });
+		assert_eq!(response.status, 200);
+		let response = ReadResponse::decode(&decompress(&response.body).unwrap()).unwrap();
+		assert_eq!(response.results.len(), 2);
+		assert_eq!(response.results[0].timeseries[0].samples, vec![Sample { value: 0.5, timestamp: (now as i64 - 10) * 1000 }]);
+		assert_eq!(response.results[1].timeseries.len(), 4);
+	}
+}
@@ -0,0 +1,176 @@
+use std::sync::{ Arc, Mutex };
+
+use whisper::{ WhisperCache, NamedPoint };
+use http::{ Request, Response };
+use super::{ DecodeError, WriteRequest, MetricTemplate, decompress };
+
+// The NaN Prometheus writes when a series goes stale, not a measurement
+const STALE_NAN_BITS : u64 = 0x7ff0000000000002;
+
+#[derive(Debug, Default, PartialEq, Clone)]
+pub struct RemoteWriteStats {
+	pub requests: usize,
+	// Bodies that could not be decoded
+	pub invalid_requests: usize,
+	pub samples_received: usize,
+	pub samples_written: usize,
+	// Samples of series missing a label the template names
+	pub unmapped_samples: usize,
+	pub stale_samples: usize,
+	// Timestamps whisper can't hold and points the cache refused
+	pub write_errors: usize
+}
+
+// Writes the samples of Prometheus' remote write requests through a
+// WhisperCache. Timestamps are cut down to seconds, so the last sample of a
+// second wins.
+pub struct RemoteWrite {
+	cache: Arc<WhisperCache>,
+	template: MetricTemplate,
+	stats: Mutex<RemoteWriteStats>
+}
+
+impl RemoteWrite {
+	pub fn new(cache: Arc<WhisperCache>, template: MetricTemplate) -> RemoteWrite {
+		RemoteWrite {
+			cache: cache,
+			template: template,
+			stats: Mutex::new(RemoteWriteStats::default())
+		}
+	}
+
+	pub fn stats(&self) -> RemoteWriteStats {
+		self.stats.lock().unwrap().clone()
+	}
+
+	// A snappy compressed WriteRequest, the body Prometheus POSTs
+	pub fn ingest(&self, body: &[u8]) -> Result<(), DecodeError> {
+		let request = decompress(body).and_then(|decompressed| WriteRequest::decode(&decompressed));
+		let request = match request {
+			Ok(request) => request,
+			Err(err) => {
+				let mut stats = self.stats.lock().unwrap();
+				stats.requests = stats.requests + 1;
+				stats.invalid_requests = stats.invalid_requests + 1;
+				return Err(err);
+			}
+		};
+
+		let (mut received, mut written, mut unmapped, mut stale, mut failed) = (0, 0, 0, 0, 0);
+		for series in &request.timeseries {
+			received = received + series.samples.len();
+			let metric_name = match self.template.metric_name(series) {
+				Some(metric_name) => metric_name,
+				None => {
+					unmapped = unmapped + series.samples.len();
+					continue;
+				}
+			};
+
+			for sample in &series.samples {
+				if sample.value.to_bits() == STALE_NAN_BITS {
+					stale = stale + 1;
+					continue;
+				}
+				let timestamp = sample.timestamp / 1000;
+				if timestamp < 0 || timestamp > u32::max_value() as i64 {
+					failed = failed + 1;
+					continue;
+				}
+				match self.cache.write(NamedPoint::new(metric_name.clone(), timestamp as u32, sample.value)) {
+					Ok(()) => written = written + 1,
+					Err(err) => {
+						warn!("could not write a sample of {}: {}", metric_name, err);
+						failed = failed + 1;
+					}
+				}
+			}
+		}
+
+		let mut stats = self.stats.lock().unwrap();
+		stats.requests = stats.requests + 1;
+		stats.samples_received = stats.samples_received + received;
+		stats.samples_written = stats.samples_written + written;
+		stats.unmapped_samples = stats.unmapped_samples + unmapped;
+		stats.stale_samples = stats.stale_samples + stale;
+		stats.write_errors = stats.write_errors + failed;
+		Ok(())
+	}
+
+	// 204 once the samples are in the cache. Bodies that can't be decoded get a
+	// 400, which Prometheus doesn't retry.
+	pub fn respond(&self, request: &Request) -> Response {
+		if request.method != "POST" {
+			return Response::error(405, "remote write takes a POST");
+		}
+		match self.ingest(&request.body) {
+			Ok(()) => Response::no_content(),
+			Err(err) => Response::error(400, &err.to_string())
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use prometheus::{ Series, Label, Sample };
+	use whisper::temp_cache;
+
+	// Snappy data of nothing but literals, which any decoder takes
+	fn compress_literals(data: &[u8]) -> Vec<u8> {
+		let mut compressed = vec![];
+		let mut length = data.len();
+		while length >= 0x80 {
+			compressed.push(length as u8 | 0x80);
+			length = length >> 7;
+		}
+		compressed.push(length as u8);
+		for chunk in data.chunks(60) {
+			compressed.push(((chunk.len() - 1) << 2) as u8);
+			compressed.extend_from_slice(chunk);
+		}
+		compressed
+	}
+
+	fn series(labels: &[(&str, &str)], samples: Vec<Sample>) -> Series {
+		Series {
+			labels: labels.iter().map(|&(name, value)| Label { name: name.to_string(), value: value.to_string() }).collect(),
+			samples: samples
+		}
+	}
+
+	#[test]
+	fn test_remote_write() {
+		let (cache, now) = temp_cache("test_prometheus_remote_write");
+		let (cache, now) = (Arc::new(cache), now as i64);
+
+		let remote_write = RemoteWrite::new(cache.clone(), MetricTemplate::parse("prometheus.{job}.{__name__}").unwrap());
+		let request = WriteRequest { timeseries: vec![
+			series(&[("__name__", "up"), ("job", "node")], vec![
+				Sample { value: 1.0, timestamp: (now - 10) * 1000 + 500 },
+				Sample { value: 0.0, timestamp: now * 1000 },
+				Sample { value: f64::from_bits(STALE_NAN_BITS), timestamp: now * 1000 }
+			]),
+			series(&[("__name__", "up")], vec![Sample { value: 1.0, timestamp: now * 1000 }]),
+			series(&[("__name__", "load"), ("job", "node")], vec![Sample { value: 2.0, timestamp: -1000 }])
+		] };
+		remote_write.ingest(&compress_literals(&request.encode())).unwrap();
+
+		assert_eq!(remote_write.stats(), RemoteWriteStats {
+			requests: 1,
+			invalid_requests: 0,
+			samples_received: 5,
+			samples_written: 2,
+			unmapped_samples: 1,
+			stale_samples: 1,
+			write_errors: 1
+		});
+		assert_eq!(cache.fetch("prometheus.node.up", now as u32 - 20, now as u32).unwrap().values, vec![Some(1.0), Some(0.0)]);
+
+		let request = Request { method: "POST".to_string(), path: "/api/v1/write".to_string(), params: vec![], headers: vec![], body: vec![0x05, 0x00] };
+		assert_eq!(remote_write.respond(&request).status, 400);
+		assert_eq!(remote_write.stats().invalid_requests, 1);
+		let request = Request { body: compress_literals(&[]), .. request };
+		assert_eq!(remote_write.respond(&request).status, 204);
+	}
+}
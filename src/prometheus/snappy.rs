@@ -0,0 +1,212 @@
+use super::DecodeError;
+
+// Nothing Prometheus sends comes close, it keeps a bad length from allocating
+// the machine away
+const MAX_DECOMPRESSED_SIZE : usize = 256 << 20;
+// The most a compressed byte can turn into, a 3 byte copy of 64 bytes, rounded
+// up. A body stating a longer length than this allows is cut short.
+const MAX_EXPANSION : usize = 22;
+// Size of the compressor's table of 4 byte runs
+const HASH_BITS : u32 = 14;
+
+// Snappy's block format, the one remote storage bodies use: the decompressed
+// length as a varint, then literals and back references into the output
+pub fn decompress(compressed: &[u8]) -> Result<Vec<u8>, DecodeError> {
+	let (length, mut index) = try!( read_varint(compressed) );
+	if length > MAX_DECOMPRESSED_SIZE as u64 {
+		return Err(DecodeError::Snappy("decompressed length too large"));
+	}
+	if length > (compressed.len() - index).saturating_mul(MAX_EXPANSION) as u64 {
+		return Err(DecodeError::Snappy("shorter than its stated length"));
+	}
+	let length = length as usize;
+	let mut decompressed = Vec::with_capacity(length);
+
+	while index < compressed.len() {
+		let tag = compressed[index];
+		index = index + 1;
+		match tag & 0b11 {
+			0 => {
+				let mut literal_length = (tag >> 2) as usize;
+				if literal_length >= 60 {
+					// The length minus one follows in 1 to 4 little endian bytes
+					let length_bytes = literal_length - 59;
+					literal_length = try!( read_le(compressed, index, length_bytes) );
+					index = index + length_bytes;
+				}
+				let literal_length = literal_length + 1;
+				if compressed.len() - index < literal_length {
+					return Err(DecodeError::Snappy("literal runs past the end"));
+				}
+				decompressed.extend_from_slice(&compressed[index..index + literal_length]);
+				index = index + literal_length;
+			},
+			kind => {
+				let (copy_length, offset) = match kind {
+					1 => {
+						let low = try!( read_le(compressed, index, 1) );
+						index = index + 1;
+						(4 + ((tag >> 2) & 0b111) as usize, ((tag >> 5) as usize) << 8 | low)
+					},
+					2 => {
+						let offset = try!( read_le(compressed, index, 2) );
+						index = index + 2;
+						(1 + (tag >> 2) as usize, offset)
+					},
+					_ => {
+						let offset = try!( read_le(compressed, index, 4) );
+						index = index + 4;
+						(1 + (tag >> 2) as usize, offset)
+					}
+				};
+				if offset == 0 || offset > decompressed.len() {
+					return Err(DecodeError::Snappy("copy offset out of range"));
+				}
+				// Copies may overlap what they write, so byte by byte
+				let start = decompressed.len() - offset;
+				for copy_index in 0..copy_length {
+					let byte = decompressed[start + copy_index];
+					decompressed.push(byte);
+				}
+			}
+		}
+		if decompressed.len() > length {
+			return Err(DecodeError::Snappy("longer than its stated length"));
+		}
+	}
+
+	if decompressed.len() != length {
+		return Err(DecodeError::Snappy("shorter than its stated length"));
+	}
+	Ok(decompressed)
+}
+
+// A greedy compressor, hashing 4 byte runs to find earlier copies of them not
+// further back than a 2 byte offset reaches
+pub fn compress(data: &[u8]) -> Vec<u8> {
+	let mut compressed = Vec::with_capacity(data.len() / 2 + 16);
+	let mut length = data.len() as u64;
+	while length >= 0x80 {
+		compressed.push(length as u8 | 0x80);
+		length = length >> 7;
+	}
+	compressed.push(length as u8);
+
+	// One past the last position each hash was seen at
+	let mut table = vec![0usize; 1 << HASH_BITS];
+	let (mut literal_start, mut index) = (0, 0);
+	while index + 4 <= data.len() {
+		let hash = (read_le(data, index, 4).unwrap() as u32).wrapping_mul(0x1e35a7bd) >> (32 - HASH_BITS);
+		let candidate = table[hash as usize];
+		table[hash as usize] = index + 1;
+
+		if candidate == 0 || index - (candidate - 1) > 0xffff || data[candidate - 1..candidate + 3] != data[index..index + 4] {
+			index = index + 1;
+			continue;
+		}
+		let start = candidate - 1;
+		let mut copy_length = 4;
+		while index + copy_length < data.len() && data[start + copy_length] == data[index + copy_length] {
+			copy_length = copy_length + 1;
+		}
+		write_literal(&mut compressed, &data[literal_start..index]);
+		write_copy(&mut compressed, index - start, copy_length);
+		index = index + copy_length;
+		literal_start = index;
+	}
+	write_literal(&mut compressed, &data[literal_start..]);
+	compressed
+}
+
+fn write_literal(compressed: &mut Vec<u8>, literal: &[u8]) {
+	if literal.is_empty() {
+		return;
+	}
+	let length = literal.len() - 1;
+	if length < 60 {
+		compressed.push((length << 2) as u8);
+	} else {
+		let length_bytes = (0..4).find(|&bytes| length >> (8 * (bytes + 1)) == 0).unwrap_or(3) + 1;
+		compressed.push(((59 + length_bytes) << 2) as u8);
+		for byte in 0..length_bytes {
+			compressed.push((length >> (8 * byte)) as u8);
+		}
+	}
+	compressed.extend_from_slice(literal);
+}
+
+// As copies with 2 byte offsets, 64 bytes at most each
+fn write_copy(compressed: &mut Vec<u8>, offset: usize, mut length: usize) {
+	while length > 0 {
+		let chunk = if length > 64 { 64 } else { length };
+		compressed.push((((chunk - 1) << 2) | 2) as u8);
+		compressed.push(offset as u8);
+		compressed.push((offset >> 8) as u8);
+		length = length - chunk;
+	}
+}
+
+fn read_varint(data: &[u8]) -> Result<(u64, usize), DecodeError> {
+	let mut value = 0u64;
+	for (index, &byte) in data.iter().enumerate().take(10) {
+		value = value | ((byte & 0x7f) as u64) << (7 * index);
+		if byte & 0x80 == 0 {
+			return Ok((value, index + 1));
+		}
+	}
+	Err(DecodeError::Snappy("invalid length"))
+}
+
+fn read_le(data: &[u8], index: usize, bytes: usize) -> Result<usize, DecodeError> {
+	if data.len() < index + bytes {
+		return Err(DecodeError::Snappy("tag runs past the end"));
+	}
+	Ok(data[index..index + bytes].iter().rev().fold(0, |value, &byte| value << 8 | byte as usize))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_decompress() {
+		// A literal, a 2 byte offset copy overlapping itself and another literal
+		let compressed = [0x1a, 0x0c, b'a', b'b', b'c', b'd', 0x46, 0x04, 0x00, 0x0c, b'x', b'y', b'z', b'!'];
+		assert_eq!(decompress(&compressed).unwrap(), b"abcdabcdabcdabcdabcdabxyz!".to_vec());
+
+		// A long literal, a 1 byte offset copy and a 4 byte offset one
+		let mut compressed = vec![0x48, 0xf0, 0x3f];
+		compressed.extend((0..64).map(|i| i as u8));
+		compressed.extend_from_slice(&[0x01, 0x40, 0x0f, 0x02, 0x00, 0x00, 0x00]);
+		let decompressed = decompress(&compressed).unwrap();
+		assert_eq!(decompressed.len(), 72);
+		assert_eq!(&decompressed[64..68], &[0, 1, 2, 3]);
+		assert_eq!(&decompressed[68..], &[2, 3, 2, 3]);
+
+		assert_eq!(decompress(&[0x00]).unwrap(), vec![]);
+	}
+
+	#[test]
+	fn test_compress() {
+		let repetitive : Vec<u8> = b"prometheus.node.web01.up ".iter().cycle().take(10000).cloned().collect();
+		let compressed = compress(&repetitive);
+		assert!(compressed.len() < 1000);
+		assert_eq!(decompress(&compressed).unwrap(), repetitive);
+
+		let noise : Vec<u8> = (0..70000u32).map(|i| (i.wrapping_mul(2654435761) >> 13) as u8).collect();
+		assert_eq!(decompress(&compress(&noise)).unwrap(), noise);
+		assert_eq!(decompress(&compress(b"abc")).unwrap(), b"abc".to_vec());
+		assert_eq!(compress(&[]), vec![0x00]);
+	}
+
+	#[test]
+	fn test_invalid() {
+		assert!(decompress(&[]).is_err());
+		assert!(decompress(&[0x05, 0x0c, b'a']).is_err());
+		assert!(decompress(&[0x05, 0x05, 0x00]).is_err());
+		assert!(decompress(&[0x01, 0x04, b'a', b'b']).is_err());
+		assert!(decompress(&[0x04, 0x00, b'a', b'b']).is_err());
+		// 200MB stated, 4 bytes given
+		assert_eq!(decompress(&[0x80, 0x80, 0x80, 0x64, 0x00, b'a']), Err(DecodeError::Snappy("shorter than its stated length")));
+	}
+}
@@ -0,0 +1,160 @@
+use regex::{ self, Regex };
+
+use whisper::MetricPattern;
+use template::{ self, Piece, sanitize };
+use super::{ Series, Label, LabelMatcher, MatchType };
+
+// Where a series' samples are written: a metric name with `{label}`
+// placeholders, e.g. `prometheus.{job}.{instance}.{__name__}`. Each label
+// value becomes a single node through template::sanitize. Labels the
+// template doesn't name are not kept, so it must name every label that tells
+// two series apart.
+#[derive(Debug, Clone)]
+pub struct MetricTemplate {
+	parts: Vec<Part>,
+	// Matches the names it makes, a group per label
+	names: Regex
+}
+
+#[derive(Debug, PartialEq, Clone)]
+enum Part {
+	Literal(String),
+	Label(String)
+}
+
+impl MetricTemplate {
+	pub fn parse(template: &str) -> Result<MetricTemplate, String> {
+		let parts : Vec<Part> = try!( template::parse(template, "template") ).into_iter().map(|piece| match piece {
+			Piece::Literal(literal) => Part::Literal(literal.to_string()),
+			Piece::Placeholder(label) => Part::Label(label.to_string())
+		}).collect();
+
+		if !parts.iter().any(|part| if let Part::Label(_) = *part { true } else { false }) {
+			return Err(format!("template `{}` names no label", template));
+		}
+		let mut names = String::from("^");
+		for part in &parts {
+			match *part {
+				Part::Literal(ref literal) => names.push_str(&regex::escape(literal)),
+				Part::Label(_) => names.push_str("([A-Za-z0-9_-]+)")
+			}
+		}
+		names.push('$');
+
+		let metric_template = MetricTemplate { parts: parts, names: Regex::new(&names).unwrap() };
+		// Empty nodes and the like
+		try!( MetricPattern::parse(&metric_template.pattern(&[])).map_err(|_| format!("template `{}` makes invalid metric names", template)) );
+		Ok(metric_template)
+	}
+
+	// None when the series lacks a label the template names
+	pub fn metric_name(&self, series: &Series) -> Option<String> {
+		let mut metric_name = String::new();
+		for part in &self.parts {
+			match *part {
+				Part::Literal(ref literal) => metric_name.push_str(literal),
+				Part::Label(ref label) => match series.label(label) {
+					Some(value) if !value.is_empty() => metric_name.push_str(&sanitize(value)),
+					_ => return None
+				}
+			}
+		}
+		Some(metric_name)
+	}
+
+	// A glob of the metric names series matching `matchers` went to. Labels
+	// with an equality matcher are filled in, the others left as `*`.
+	pub fn pattern(&self, matchers: &[LabelMatcher]) -> String {
+		let mut pattern = String::new();
+		for part in &self.parts {
+			match *part {
+				Part::Literal(ref literal) => pattern.push_str(literal),
+				Part::Label(ref label) => match matchers.iter().find(|matcher| matcher.match_type == MatchType::Equal && &matcher.name == label) {
+					Some(matcher) if !matcher.value.is_empty() => pattern.push_str(&sanitize(&matcher.value)),
+					_ => pattern.push('*')
+				}
+			}
+		}
+		pattern
+	}
+
+	// The labels of a metric name this template made, sanitized values and
+	// all. None for names it can't have made.
+	pub fn labels(&self, metric_name: &str) -> Option<Vec<Label>> {
+		let captures = match self.names.captures(metric_name) {
+			Some(captures) => captures,
+			None => return None
+		};
+
+		let mut labels : Vec<Label> = vec![];
+		let label_names = self.parts.iter().filter_map(|part| if let Part::Label(ref label) = *part { Some(label) } else { None });
+		for (index, name) in label_names.enumerate() {
+			if labels.iter().any(|label| &label.name == name) {
+				continue;
+			}
+			labels.push(Label { name: name.clone(), value: captures.get(index + 1).unwrap().as_str().to_string() });
+		}
+		labels.sort_by(|a, b| a.name.cmp(&b.name));
+		Some(labels)
+	}
+}
+
+// The regex follows from the parts
+impl PartialEq for MetricTemplate {
+	fn eq(&self, other: &MetricTemplate) -> bool {
+		self.parts == other.parts
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use prometheus::{ Label, LabelMatcher, MatchType };
+
+	fn series(labels: &[(&str, &str)]) -> Series {
+		Series {
+			labels: labels.iter().map(|&(name, value)| Label { name: name.to_string(), value: value.to_string() }).collect(),
+			samples: vec![]
+		}
+	}
+
+	#[test]
+	fn test_metric_name() {
+		let template = MetricTemplate::parse("prometheus.{job}.{instance}.{__name__}").unwrap();
+		let up = series(&[("__name__", "up"), ("job", "node"), ("instance", "web01.example.com:9100")]);
+		assert_eq!(template.metric_name(&up), Some("prometheus.node.web01_example_com_9100.up".to_string()));
+		assert_eq!(template.metric_name(&series(&[("__name__", "up"), ("job", "node")])), None);
+		assert_eq!(template.metric_name(&series(&[("__name__", "up"), ("job", "node"), ("instance", "")])), None);
+
+		assert_eq!(MetricTemplate::parse("{__name__}_total").unwrap().metric_name(&up), Some("up_total".to_string()));
+	}
+
+	#[test]
+	fn test_reverse() {
+		let template = MetricTemplate::parse("prometheus.{job}.{instance}.{__name__}").unwrap();
+		let matchers = vec![
+			LabelMatcher { match_type: MatchType::Equal, name: "__name__".to_string(), value: "up".to_string() },
+			LabelMatcher { match_type: MatchType::RegexMatch, name: "job".to_string(), value: "node".to_string() },
+			LabelMatcher { match_type: MatchType::Equal, name: "instance".to_string(), value: "web01:9100".to_string() }
+		];
+		assert_eq!(template.pattern(&matchers), "prometheus.*.web01_9100.up");
+		assert_eq!(template.pattern(&[]), "prometheus.*.*.*");
+
+		let labels = template.labels("prometheus.node.web01_9100.up").unwrap();
+		assert_eq!(labels.iter().map(|label| (label.name.as_str(), label.value.as_str())).collect::<Vec<_>>(),
+			vec![("__name__", "up"), ("instance", "web01_9100"), ("job", "node")]);
+		assert_eq!(template.labels("prometheus.node.up"), None);
+		assert_eq!(template.labels("graphite.node.web01_9100.up"), None);
+	}
+
+	#[test]
+	fn test_invalid_templates() {
+		assert!(MetricTemplate::parse("prometheus.{job").is_err());
+		assert!(MetricTemplate::parse("prometheus.{}").is_err());
+		assert!(MetricTemplate::parse("prometheus.up").is_err());
+		assert!(MetricTemplate::parse("../{job}").is_err());
+		assert!(MetricTemplate::parse("*.{job}").is_err());
+		assert!(MetricTemplate::parse("prometheus..{job}").is_err());
+		assert!(MetricTemplate::parse("").is_err());
+	}
+}
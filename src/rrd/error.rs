@@ -0,0 +1,64 @@
+use std::error;
+use std::fmt;
+use std::io;
+
+use whisper::WhisperError;
+
+#[derive(Debug)]
+pub enum RrdError {
+	Io(io::Error),
+	InvalidFile(String),
+	// A data source name the file doesn't have
+	UnknownDataSource(String),
+	// A consolidation function none of the file's archives use
+	NoArchives(String),
+	// One of the Holt-Winters functions, which whisper has no aggregation for
+	UnsupportedConsolidation(String),
+	Whisper(WhisperError)
+}
+
+impl fmt::Display for RrdError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match *self {
+			RrdError::Io(ref err) => write!(f, "io error: {}", err),
+			RrdError::InvalidFile(ref reason) => write!(f, "invalid rrd file: {}", reason),
+			RrdError::UnknownDataSource(ref name) => write!(f, "no data source '{}'", name),
+			RrdError::NoArchives(ref consolidation) => write!(f, "no {} archives", consolidation),
+			RrdError::UnsupportedConsolidation(ref consolidation) => write!(f, "consolidation function {} can't be imported", consolidation),
+			RrdError::Whisper(ref err) => write!(f, "whisper error: {}", err)
+		}
+	}
+}
+
+impl error::Error for RrdError {
+	fn description(&self) -> &str {
+		match *self {
+			RrdError::Io(ref err) => err.description(),
+			RrdError::InvalidFile(_) => "invalid rrd file",
+			RrdError::UnknownDataSource(_) => "no such data source",
+			RrdError::NoArchives(_) => "no archives of that consolidation function",
+			RrdError::UnsupportedConsolidation(_) => "unsupported consolidation function",
+			RrdError::Whisper(ref err) => err.description()
+		}
+	}
+
+	fn cause(&self) -> Option<&error::Error> {
+		match *self {
+			RrdError::Io(ref err) => Some(err),
+			RrdError::Whisper(ref err) => Some(err),
+			_ => None
+		}
+	}
+}
+
+impl From<io::Error> for RrdError {
+	fn from(err: io::Error) -> RrdError {
+		RrdError::Io(err)
+	}
+}
+
+impl From<WhisperError> for RrdError {
+	fn from(err: WhisperError) -> RrdError {
+		RrdError::Whisper(err)
+	}
+}
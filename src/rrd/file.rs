@@ -0,0 +1,334 @@
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+use byteorder::{ ByteOrder, BigEndian, LittleEndian };
+
+use whisper::Point;
+use super::RrdError;
+
+// rrdtool writes this double after the version to tell layouts apart
+const FLOAT_COOKIE : f64 = 8.642135E130;
+
+// Sizes of rrdtool's structs as a 64 bit build lays them out
+const STAT_HEAD_SIZE : usize = 128;
+const DS_DEF_SIZE : usize = 120;
+const RRA_DEF_SIZE : usize = 120;
+const PDP_PREP_SIZE : usize = 112;
+const CDP_PREP_SIZE : usize = 80;
+const RRA_PTR_SIZE : usize = 8;
+const NAME_SIZE : usize = 20;
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum Endian {
+	Little,
+	Big
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct DataSource {
+	pub name: String,
+	// GAUGE, COUNTER, DERIVE, ABSOLUTE or COMPUTE
+	pub kind: String
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct RoundRobinArchive {
+	// AVERAGE, MIN, MAX, LAST, or one of the Holt-Winters functions
+	pub consolidation: String,
+	pub rows: usize,
+	pub pdp_per_row: u32,
+	pub x_files_factor: f64,
+	current_row: usize,
+	data_offset: usize
+}
+
+// An RRD file as rrdtool 1.x writes it on 64 bit machines, either byte order.
+// The whole file is read in, RRDs are small.
+#[derive(Debug)]
+pub struct RrdFile {
+	pub step: u32,
+	pub last_update: u32,
+	pub data_sources: Vec<DataSource>,
+	pub archives: Vec<RoundRobinArchive>,
+	endian: Endian,
+	data: Vec<u8>
+}
+
+impl RrdFile {
+	pub fn open<P>(path: P) -> Result<RrdFile, RrdError>
+		where P: AsRef<Path> {
+		let mut data = vec![];
+		try!( try!( File::open(path) ).read_to_end(&mut data) );
+		RrdFile::parse(data)
+	}
+
+	pub fn parse(data: Vec<u8>) -> Result<RrdFile, RrdError> {
+		let invalid = |reason: &str| Err(RrdError::InvalidFile(reason.to_string()));
+		if data.len() < STAT_HEAD_SIZE || &data[0..4] != b"RRD\0" {
+			return invalid("no RRD cookie");
+		}
+		let version = read_name(&data[4..9]);
+		if !["0001", "0002", "0003", "0004", "0005"].contains(&&version[..]) {
+			return Err(RrdError::InvalidFile(format!("unsupported version '{}'", version)));
+		}
+		let endian = if LittleEndian::read_f64(&data[16..24]) == FLOAT_COOKIE {
+			Endian::Little
+		} else if BigEndian::read_f64(&data[16..24]) == FLOAT_COOKIE {
+			Endian::Big
+		} else {
+			return invalid("float cookie doesn't match, only 64 bit layouts are supported");
+		};
+
+		let mut file = RrdFile { step: 0, last_update: 0, data_sources: vec![], archives: vec![], endian: endian, data: vec![] };
+		let ds_count = file.read_u64(&data, 24);
+		let rra_count = file.read_u64(&data, 32);
+		let step = file.read_u64(&data, 40);
+		if ds_count == 0 || rra_count == 0 || step == 0 || step > u32::max_value() as u64 {
+			return invalid("no data sources, archives or step");
+		}
+		file.step = step as u32;
+
+		// Counts straight from the file, checked so a crafted one can't overflow
+		// the offsets. Once they're within the file they fit a usize too.
+		let live_head_size = if version.as_str() >= "0003" { 16 } else { 8 };
+		let layout = header_layout(ds_count, rra_count, live_head_size);
+		let (rra_start, live_head_start, rra_ptr_start, data_start) = match layout {
+			Some(layout) if layout.3 <= data.len() as u64 => (layout.0 as usize, layout.1 as usize, layout.2 as usize, layout.3 as usize),
+			_ => return invalid("header runs past the end")
+		};
+		let (ds_count, rra_count) = (ds_count as usize, rra_count as usize);
+
+		for index in 0..ds_count {
+			let definition = &data[STAT_HEAD_SIZE + DS_DEF_SIZE * index..];
+			file.data_sources.push(DataSource {
+				name: read_name(&definition[0..NAME_SIZE]),
+				kind: read_name(&definition[NAME_SIZE..2*NAME_SIZE])
+			});
+		}
+
+		let mut data_offset = data_start as u64;
+		for index in 0..rra_count {
+			let definition_start = rra_start + RRA_DEF_SIZE * index;
+			let rows = file.read_u64(&data, definition_start + 24);
+			let current_row = file.read_u64(&data, rra_ptr_start + RRA_PTR_SIZE * index);
+			if rows == 0 || current_row >= rows {
+				return invalid("archive without rows or with its current row out of range");
+			}
+			let pdp_per_row = file.read_u64(&data, definition_start + 32);
+			// Retentions have to fit the u32 timestamps of whisper
+			let seconds_per_row = if pdp_per_row <= u32::max_value() as u64 { file.step.checked_mul(pdp_per_row as u32) } else { None };
+			let retention = seconds_per_row.and_then(|seconds_per_row| if rows <= u32::max_value() as u64 { seconds_per_row.checked_mul(rows as u32) } else { None });
+			if pdp_per_row == 0 || retention.is_none() {
+				return invalid("archive with no or too many primary data points per row");
+			}
+			let data_end = rows.checked_mul(ds_count as u64).and_then(|values| values.checked_mul(8)).and_then(|size| data_offset.checked_add(size));
+			let data_end = match data_end {
+				Some(data_end) if data_end <= data.len() as u64 => data_end,
+				_ => return invalid("archives run past the end")
+			};
+			file.archives.push(RoundRobinArchive {
+				consolidation: read_name(&data[definition_start..definition_start + NAME_SIZE]),
+				rows: rows as usize,
+				pdp_per_row: pdp_per_row as u32,
+				x_files_factor: file.read_f64(&data, definition_start + 40),
+				current_row: current_row as usize,
+				data_offset: data_offset as usize
+			});
+			data_offset = data_end;
+		}
+
+		file.last_update = file.read_u64(&data, live_head_start) as u32;
+		file.data = data;
+		Ok(file)
+	}
+
+	pub fn data_source_index(&self, name: &str) -> Result<usize, RrdError> {
+		self.data_sources.iter().position(|data_source| data_source.name == name).ok_or_else(|| RrdError::UnknownDataSource(name.to_string()))
+	}
+
+	#[inline]
+	pub fn seconds_per_row(&self, archive_index: usize) -> u32 {
+		self.step * self.archives[archive_index].pdp_per_row
+	}
+
+	// The known values of one data source in one archive, oldest first. A row's
+	// value covers the step up to its timestamp in rrdtool, whisper points
+	// start their interval, so each point is one step before its row.
+	pub fn points(&self, data_source_index: usize, archive_index: usize) -> Vec<Point> {
+		let archive = &self.archives[archive_index];
+		let step = self.seconds_per_row(archive_index);
+		let last_row_end = self.last_update - self.last_update % step;
+
+		let mut points = vec![];
+		for age in (0..archive.rows).rev() {
+			let row = (archive.current_row + archive.rows - age) % archive.rows;
+			let offset = archive.data_offset + (row * self.data_sources.len() + data_source_index) * 8;
+			let value = self.read_f64(&self.data, offset);
+			let row_end = last_row_end as i64 - age as i64 * step as i64;
+			if !value.is_nan() && row_end >= step as i64 {
+				points.push(Point(row_end as u32 - step, value));
+			}
+		}
+		points
+	}
+
+	fn read_u64(&self, data: &[u8], offset: usize) -> u64 {
+		match self.endian {
+			Endian::Little => LittleEndian::read_u64(&data[offset..offset + 8]),
+			Endian::Big => BigEndian::read_u64(&data[offset..offset + 8])
+		}
+	}
+
+	fn read_f64(&self, data: &[u8], offset: usize) -> f64 {
+		match self.endian {
+			Endian::Little => LittleEndian::read_f64(&data[offset..offset + 8]),
+			Endian::Big => BigEndian::read_f64(&data[offset..offset + 8])
+		}
+	}
+}
+
+// (rra definitions, live header, rra pointers, data) offsets, None when they
+// overflow
+fn header_layout(ds_count: u64, rra_count: u64, live_head_size: usize) -> Option<(u64, u64, u64, u64)> {
+	let rra_start = match (DS_DEF_SIZE as u64).checked_mul(ds_count).and_then(|size| size.checked_add(STAT_HEAD_SIZE as u64)) {
+		Some(rra_start) => rra_start,
+		None => return None
+	};
+	let live_head_start = match (RRA_DEF_SIZE as u64).checked_mul(rra_count).and_then(|size| size.checked_add(rra_start)) {
+		Some(live_head_start) => live_head_start,
+		None => return None
+	};
+	let prep_size = (PDP_PREP_SIZE as u64).checked_mul(ds_count)
+		.and_then(|pdp_size| (CDP_PREP_SIZE as u64).checked_mul(ds_count).and_then(|size| size.checked_mul(rra_count)).and_then(|cdp_size| cdp_size.checked_add(pdp_size)));
+	let rra_ptr_start = match prep_size.and_then(|size| size.checked_add(live_head_size as u64)).and_then(|size| size.checked_add(live_head_start)) {
+		Some(rra_ptr_start) => rra_ptr_start,
+		None => return None
+	};
+	(RRA_PTR_SIZE as u64).checked_mul(rra_count).and_then(|size| size.checked_add(rra_ptr_start))
+		.map(|data_start| (rra_start, live_head_start, rra_ptr_start, data_start))
+}
+
+// A NUL padded C string
+fn read_name(bytes: &[u8]) -> String {
+	let end = bytes.iter().position(|&byte| byte == 0).unwrap_or(bytes.len());
+	String::from_utf8_lossy(&bytes[..end]).into_owned()
+}
+
+#[cfg(test)]
+pub mod tests {
+	use super::*;
+	use std::f64;
+
+	// An RRD as rrdtool 1.4 on x86_64 writes it, with one data source. Each
+	// archive is (consolidation, pdp per row, current row, its rows' values).
+	pub fn build_rrd(step: u64, last_update: u64, archives: &[(&str, u64, u64, Vec<f64>)]) -> Vec<u8> {
+		fn name(data: &mut Vec<u8>, name: &str) {
+			let mut bytes = name.as_bytes().to_vec();
+			bytes.resize(NAME_SIZE, 0);
+			data.extend(bytes);
+		}
+		fn u64_le(data: &mut Vec<u8>, value: u64) {
+			let mut bytes = [0; 8];
+			LittleEndian::write_u64(&mut bytes, value);
+			data.extend_from_slice(&bytes);
+		}
+		fn f64_le(data: &mut Vec<u8>, value: f64) {
+			let mut bytes = [0; 8];
+			LittleEndian::write_f64(&mut bytes, value);
+			data.extend_from_slice(&bytes);
+		}
+
+		let mut data = b"RRD\x000003\x00".to_vec();
+		data.resize(16, 0);
+		f64_le(&mut data, FLOAT_COOKIE);
+		u64_le(&mut data, 1);
+		u64_le(&mut data, archives.len() as u64);
+		u64_le(&mut data, step);
+		data.resize(STAT_HEAD_SIZE, 0);
+
+		name(&mut data, "value");
+		name(&mut data, "GAUGE");
+		data.resize(STAT_HEAD_SIZE + DS_DEF_SIZE, 0);
+
+		for &(consolidation, pdp_per_row, _, ref values) in archives {
+			let start = data.len();
+			name(&mut data, consolidation);
+			data.resize(start + 24, 0);
+			u64_le(&mut data, values.len() as u64);
+			u64_le(&mut data, pdp_per_row);
+			f64_le(&mut data, 0.5);
+			data.resize(start + RRA_DEF_SIZE, 0);
+		}
+
+		u64_le(&mut data, last_update);
+		u64_le(&mut data, 0);
+		let length = data.len() + PDP_PREP_SIZE + CDP_PREP_SIZE * archives.len();
+		data.resize(length, 0);
+		for &(_, _, current_row, _) in archives {
+			u64_le(&mut data, current_row);
+		}
+		for &(_, _, _, ref values) in archives {
+			for value in values {
+				f64_le(&mut data, *value);
+			}
+		}
+		data
+	}
+
+	#[test]
+	fn test_parse() {
+		let nan = f64::NAN;
+		let rrd = RrdFile::parse(build_rrd(10, 1440392105, &[
+			("AVERAGE", 1, 1, vec![3.0, 4.0, 1.0, nan]),
+			("MAX", 3, 0, vec![7.0, nan])
+		])).unwrap();
+
+		assert_eq!(rrd.step, 10);
+		assert_eq!(rrd.last_update, 1440392105);
+		assert_eq!(rrd.data_sources, vec![DataSource { name: "value".to_string(), kind: "GAUGE".to_string() }]);
+		assert_eq!(rrd.archives[1].consolidation, "MAX");
+		assert_eq!(rrd.archives[1].x_files_factor, 0.5);
+		assert_eq!(rrd.seconds_per_row(1), 30);
+		assert_eq!(rrd.data_source_index("value").unwrap(), 0);
+		assert!(rrd.data_source_index("load").is_err());
+
+		// Row 1 is the newest, ending at 1440392100, row 2 the oldest
+		assert_eq!(rrd.points(0, 0), vec![Point(1440392060, 1.0), Point(1440392080, 3.0), Point(1440392090, 4.0)]);
+		assert_eq!(rrd.points(0, 1), vec![Point(1440392070, 7.0)]);
+	}
+
+	#[test]
+	fn test_parse_invalid() {
+		assert!(RrdFile::parse(b"WSP".to_vec()).is_err());
+		let mut data = build_rrd(10, 1440392105, &[("AVERAGE", 1, 0, vec![1.0])]);
+		data[4..8].copy_from_slice(b"0009");
+		assert!(RrdFile::parse(data).is_err());
+		let data = build_rrd(10, 1440392105, &[("AVERAGE", 1, 0, vec![1.0])]);
+		assert!(RrdFile::parse(data[..data.len() - 4].to_vec()).is_err());
+		for length in 0..data.len() {
+			assert!(RrdFile::parse(data[..length].to_vec()).is_err());
+		}
+	}
+
+	#[test]
+	fn test_parse_malicious() {
+		let rra_start = STAT_HEAD_SIZE + DS_DEF_SIZE;
+		// (offset, u64 put there) of header fields
+		let fields = [
+			// Data source and archive counts
+			(24, u64::max_value()), (24, 1 << 61), (32, u64::max_value()), (32, 1 << 60),
+			// Step
+			(40, 1 << 40),
+			// Rows, overflowing the data size with the data source count
+			(rra_start + 24, u64::max_value()), (rra_start + 24, 1 << 62),
+			// Primary data points per row, none or overflowing the step
+			(rra_start + 32, 0), (rra_start + 32, 1 << 31), (rra_start + 32, 1 << 33)
+		];
+		for &(offset, value) in &fields {
+			let mut data = build_rrd(10, 1440392105, &[("AVERAGE", 1, 0, vec![1.0, 2.0])]);
+			LittleEndian::write_u64(&mut data[offset..offset + 8], value);
+			assert!(RrdFile::parse(data).is_err(), "{} at {}", value, offset);
+		}
+	}
+}
@@ -0,0 +1,104 @@
+use std::path::Path;
+
+use whisper::{ WhisperFile, Schema, AggregationType };
+use super::{ RrdFile, RrdError };
+
+impl RrdFile {
+	// The consolidation functions of the file's archives, in the order they first appear
+	pub fn consolidations(&self) -> Vec<&str> {
+		let mut consolidations : Vec<&str> = vec![];
+		for archive in &self.archives {
+			if !consolidations.contains(&&archive.consolidation[..]) {
+				consolidations.push(&archive.consolidation);
+			}
+		}
+		consolidations
+	}
+
+	// A retention policy per archive of `consolidation`, at its seconds per row
+	// for as many rows as it has
+	pub fn schema(&self, consolidation: &str) -> Result<Schema, RrdError> {
+		let specs : Vec<String> = self.archive_indexes(consolidation).into_iter().map(|index| {
+			format!("{}s:{}s", self.seconds_per_row(index), self.seconds_per_row(index) * self.archives[index].rows as u32)
+		}).collect();
+		if specs.is_empty() {
+			return Err(RrdError::NoArchives(consolidation.to_string()));
+		}
+		Schema::new_from_retention_specs(specs).map_err(|err| RrdError::Whisper(err.into()))
+	}
+
+	// Same as whisper's rrd2whisper.py: creates a file at `path` from the archives
+	// of one data source and consolidation function, AVERAGE, MIN, MAX or LAST,
+	// aggregating the same way and with the first archive's xFilesFactor. The
+	// archives are written coarsest first so the finer data wins where they overlap.
+	pub fn import<P>(&self, data_source: &str, consolidation: &str, path: P) -> Result<WhisperFile, RrdError>
+		where P: AsRef<Path> {
+		let data_source_index = try!( self.data_source_index(data_source) );
+		let aggregation_type = match consolidation {
+			"AVERAGE" => AggregationType::Average,
+			"MIN" => AggregationType::Min,
+			"MAX" => AggregationType::Max,
+			"LAST" => AggregationType::Last,
+			_ => return Err(RrdError::UnsupportedConsolidation(consolidation.to_string()))
+		};
+		let schema = try!( self.schema(consolidation) );
+		let mut archive_indexes = self.archive_indexes(consolidation);
+		let x_files_factor = self.archives[archive_indexes[0]].x_files_factor as f32;
+
+		let mut file = try!( WhisperFile::new_with_options(path, &schema, aggregation_type, x_files_factor) );
+		archive_indexes.sort_by_key(|&index| self.seconds_per_row(index));
+		for &index in archive_indexes.iter().rev() {
+			// Ages count from the start of the archive's last row, where its
+			// newest point is, so its oldest row still fits
+			let step = self.seconds_per_row(index);
+			try!( file.update_many_at((self.last_update - self.last_update % step).saturating_sub(step), &self.points(data_source_index, index)) );
+		}
+		Ok(file)
+	}
+
+	fn archive_indexes(&self, consolidation: &str) -> Vec<usize> {
+		(0..self.archives.len()).filter(|&index| self.archives[index].consolidation == consolidation).collect()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use rrd::file::tests::build_rrd;
+	use whisper::temp_path;
+	use std::f64;
+	use std::fs;
+
+	#[test]
+	fn test_import() {
+		let nan = f64::NAN;
+		let last_update = 1440392105;
+		let rrd = RrdFile::parse(build_rrd(10, last_update, &[
+			("AVERAGE", 1, 5, vec![1.0, 2.0, 3.0, 4.0, 5.0, 6.0]),
+			("AVERAGE", 6, 1, vec![40.0, 10.0, nan, nan, nan, nan, nan, nan, nan, nan]),
+			("MAX", 1, 0, vec![9.0, nan, nan, nan, nan, nan])
+		])).unwrap();
+
+		assert_eq!(rrd.consolidations(), vec!["AVERAGE", "MAX"]);
+		let policies : Vec<(u32, u32)> = rrd.schema("AVERAGE").unwrap().retention_policies.iter().map(|policy| (policy.precision, policy.retention)).collect();
+		assert_eq!(policies, vec![(10, 60), (60, 600)]);
+		assert!(rrd.schema("LAST").is_err());
+
+		let path = temp_path("test_rrd_import.wsp");
+		fs::remove_file(&path).ok();
+		let file = rrd.import("value", "AVERAGE", &path).unwrap();
+		let info = file.info();
+		assert_eq!(info.aggregation_type, AggregationType::Average);
+		assert_eq!(info.x_files_factor, 0.5);
+
+		let now = 1440392090;
+		assert_eq!(file.fetch_at(now, now - 60, now).unwrap().values, vec![Some(1.0), Some(2.0), Some(3.0), Some(4.0), Some(5.0), Some(6.0)]);
+		// The minute before comes from the coarse archive, the last one is
+		// rolled up from the fine rows rather than taken from its own
+		let series = file.fetch_at(now, now - 150, now).unwrap();
+		assert_eq!((series.step, series.values), (60, vec![Some(40.0), Some(3.5)]));
+
+		assert!(rrd.import("load", "AVERAGE", &path).is_err());
+		assert!(rrd.import("value", "HWPREDICT", &path).is_err());
+	}
+}
@@ -0,0 +1,9 @@
+// Reading rrdtool's RRD files and backfilling whisper files from them, for
+// moving collectd, munin and the like over to graphite
+
+mod error;
+mod file;
+mod import;
+
+pub use self::error::RrdError;
+pub use self::file::{ RrdFile, DataSource, RoundRobinArchive };
@@ -0,0 +1,210 @@
+use std::collections::{ HashMap, HashSet };
+use std::sync::Mutex;
+use std::time::Duration;
+
+use whisper::NamedPoint;
+use super::{ StatsdSample, StatsdValue };
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct StatsdConfig {
+	// First node of every metric, `stats` in statsd
+	pub prefix: String,
+	pub flush_interval: Duration,
+	// Timer percentiles, each adding `upper_90`, `mean_90`, `sum_90` and `count_90`
+	pub percentiles: Vec<f64>,
+	// statsd keeps sending a gauge's last value until told otherwise
+	pub delete_gauges: bool
+}
+
+impl Default for StatsdConfig {
+	fn default() -> StatsdConfig {
+		StatsdConfig {
+			prefix: "stats".to_string(),
+			flush_interval: Duration::from_secs(10),
+			percentiles: vec![90.0],
+			delete_gauges: false
+		}
+	}
+}
+
+#[derive(Default)]
+struct Buckets {
+	counters: HashMap<String, f64>,
+	// The values, and how many they stand for with sample rates counted in
+	timers: HashMap<String, (Vec<f64>, f64)>,
+	gauges: HashMap<String, f64>,
+	sets: HashMap<String, HashSet<String>>
+}
+
+// What has been received since the last flush
+pub struct StatsdAggregator {
+	config: StatsdConfig,
+	buckets: Mutex<Buckets>
+}
+
+impl StatsdAggregator {
+	pub fn new(config: StatsdConfig) -> StatsdAggregator {
+		StatsdAggregator { config: config, buckets: Mutex::new(Buckets::default()) }
+	}
+
+	pub fn config(&self) -> &StatsdConfig {
+		&self.config
+	}
+
+	pub fn add(&self, sample: StatsdSample) {
+		let mut buckets = self.buckets.lock().unwrap();
+		match sample.value {
+			StatsdValue::Counter(value) => {
+				let counter = buckets.counters.entry(sample.name).or_insert(0.0);
+				*counter = *counter + value / sample.sample_rate;
+			},
+			StatsdValue::Timer(value) => {
+				let timer = buckets.timers.entry(sample.name).or_insert((vec![], 0.0));
+				timer.0.push(value);
+				timer.1 = timer.1 + 1.0 / sample.sample_rate;
+			},
+			StatsdValue::Gauge(value) => {
+				buckets.gauges.insert(sample.name, value);
+			},
+			StatsdValue::GaugeDelta(delta) => {
+				let gauge = buckets.gauges.entry(sample.name).or_insert(0.0);
+				*gauge = *gauge + delta;
+			},
+			StatsdValue::Set(member) => {
+				buckets.sets.entry(sample.name).or_insert_with(HashSet::new).insert(member);
+			}
+		}
+	}
+
+	// Rolls the interval up into points at `timestamp` and starts the next one
+	pub fn flush(&self, timestamp: u32) -> Vec<NamedPoint> {
+		let buckets = {
+			let mut buckets = self.buckets.lock().unwrap();
+			let gauges = if self.config.delete_gauges { HashMap::new() } else { buckets.gauges.clone() };
+			let flushed = ::std::mem::replace(&mut *buckets, Buckets::default());
+			buckets.gauges = gauges;
+			flushed
+		};
+
+		let interval = self.config.flush_interval.as_secs() as f64 + self.config.flush_interval.subsec_nanos() as f64 / 1e9;
+		let prefix = &self.config.prefix;
+		let mut points = vec![];
+		{
+			let mut point = |name: String, value: f64| points.push(NamedPoint::new(name, timestamp, value));
+
+			for (name, count) in buckets.counters {
+				point(format!("{}.counters.{}.count", prefix, name), count);
+				point(format!("{}.counters.{}.rate", prefix, name), count / interval);
+			}
+
+			for (name, (mut values, count)) in buckets.timers {
+				values.sort_by(|a, b| a.partial_cmp(b).unwrap());
+				let metric = format!("{}.timers.{}", prefix, name);
+				let sum : f64 = values.iter().sum();
+				let mean = sum / values.len() as f64;
+				let variance = values.iter().map(|value| (value - mean) * (value - mean)).sum::<f64>() / values.len() as f64;
+				let middle = values.len() / 2;
+				let median = if values.len() % 2 == 0 { (values[middle - 1] + values[middle]) / 2.0 } else { values[middle] };
+
+				point(format!("{}.count", metric), count);
+				point(format!("{}.count_ps", metric), count / interval);
+				point(format!("{}.lower", metric), values[0]);
+				point(format!("{}.upper", metric), values[values.len() - 1]);
+				point(format!("{}.sum", metric), sum);
+				point(format!("{}.mean", metric), mean);
+				point(format!("{}.median", metric), median);
+				point(format!("{}.std", metric), variance.sqrt());
+
+				for &percentile in &self.config.percentiles {
+					let within = (percentile / 100.0 * values.len() as f64).round() as usize;
+					if within == 0 {
+						continue;
+					}
+					let within = ::std::cmp::min(within, values.len());
+					let suffix = percentile.to_string().replace('.', "_");
+					let sum_within : f64 = values[..within].iter().sum();
+					point(format!("{}.count_{}", metric, suffix), within as f64);
+					point(format!("{}.upper_{}", metric, suffix), values[within - 1]);
+					point(format!("{}.sum_{}", metric, suffix), sum_within);
+					point(format!("{}.mean_{}", metric, suffix), sum_within / within as f64);
+				}
+			}
+
+			for (name, value) in buckets.gauges {
+				point(format!("{}.gauges.{}", prefix, name), value);
+			}
+
+			for (name, members) in buckets.sets {
+				point(format!("{}.sets.{}.count", prefix, name), members.len() as f64);
+			}
+		}
+
+		points.sort_by(|a, b| a.metric_name().cmp(b.metric_name()));
+		points
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn flushed(aggregator: &StatsdAggregator, lines: &[&str]) -> Vec<(String, f64)> {
+		for line in lines {
+			aggregator.add(StatsdSample::parse(line).unwrap());
+		}
+		aggregator.flush(1440392100).iter().map(|point| {
+			assert_eq!(point.point().0, 1440392100);
+			(point.metric_name().to_string(), point.point().1)
+		}).collect()
+	}
+
+	fn points(expected: &[(&str, f64)]) -> Vec<(String, f64)> {
+		expected.iter().map(|&(name, value)| (name.to_string(), value)).collect()
+	}
+
+	#[test]
+	fn test_flush() {
+		let aggregator = StatsdAggregator::new(StatsdConfig::default());
+		let lines = ["requests:1|c", "requests:2|c|@0.5", "latency:10|ms", "latency:30|ms", "latency:20|ms", "latency:40|ms|@0.5", "queue:5|g", "queue:+2|g", "users:a|s", "users:b|s", "users:a|s"];
+
+		assert_eq!(flushed(&aggregator, &lines), points(&[
+			("stats.counters.requests.count", 5.0),
+			("stats.counters.requests.rate", 0.5),
+			("stats.gauges.queue", 7.0),
+			("stats.sets.users.count", 2.0),
+			("stats.timers.latency.count", 5.0),
+			("stats.timers.latency.count_90", 4.0),
+			("stats.timers.latency.count_ps", 0.5),
+			("stats.timers.latency.lower", 10.0),
+			("stats.timers.latency.mean", 25.0),
+			("stats.timers.latency.mean_90", 25.0),
+			("stats.timers.latency.median", 25.0),
+			("stats.timers.latency.std", 125f64.sqrt()),
+			("stats.timers.latency.sum", 100.0),
+			("stats.timers.latency.sum_90", 100.0),
+			("stats.timers.latency.upper", 40.0),
+			("stats.timers.latency.upper_90", 40.0)
+		]));
+
+		// Only the gauge carries over
+		assert_eq!(flushed(&aggregator, &[]), points(&[("stats.gauges.queue", 7.0)]));
+	}
+
+	#[test]
+	fn test_percentiles() {
+		let aggregator = StatsdAggregator::new(StatsdConfig { percentiles: vec![50.0, 99.9], delete_gauges: true, .. StatsdConfig::default() });
+		let lines : Vec<String> = (1..11).map(|value| format!("t:{}|ms", value)).collect();
+		let lines : Vec<&str> = lines.iter().map(|line| line.as_str()).collect();
+		let flushed = flushed(&aggregator, &lines);
+
+		let value = |name: &str| flushed.iter().find(|point| point.0 == name).map(|point| point.1);
+		assert_eq!(value("stats.timers.t.upper_50"), Some(5.0));
+		assert_eq!(value("stats.timers.t.mean_50"), Some(3.0));
+		assert_eq!(value("stats.timers.t.upper_99_9"), Some(10.0));
+		assert_eq!(value("stats.timers.t.median"), Some(5.5));
+
+		aggregator.add(StatsdSample::parse("g:1|g").unwrap());
+		aggregator.flush(1440392100);
+		assert_eq!(aggregator.flush(1440392110), vec![]);
+	}
+}
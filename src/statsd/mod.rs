@@ -0,0 +1,11 @@
+// statsd's counters, timers, gauges and sets, rolled up every flush interval
+// into the points statsd's graphite backend would send, for setups that would
+// otherwise run statsd just to feed carbon
+
+mod sample;
+mod aggregator;
+mod server;
+
+pub use self::sample::{ StatsdSample, StatsdValue };
+pub use self::aggregator::{ StatsdAggregator, StatsdConfig };
+pub use self::server::{ StatsdServer, StatsdStats };
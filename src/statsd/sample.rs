@@ -0,0 +1,94 @@
+#[derive(Debug, PartialEq, Clone)]
+pub enum StatsdValue {
+	Counter(f64),
+	// Timers and histograms, in whatever unit the client uses
+	Timer(f64),
+	Gauge(f64),
+	// A gauge sent with a sign, added to the current value
+	GaugeDelta(f64),
+	Set(String)
+}
+
+// One `name:value|type|@sample_rate` line. Tags after a `|#` are ignored.
+#[derive(Debug, PartialEq, Clone)]
+pub struct StatsdSample {
+	pub name: String,
+	pub value: StatsdValue,
+	// Counters and timers sampled at 0.1 count ten times
+	pub sample_rate: f64
+}
+
+impl StatsdSample {
+	pub fn parse(line: &str) -> Result<StatsdSample, String> {
+		let line = line.trim();
+		let colon = try!( line.find(':').ok_or_else(|| format!("line `{}` has no value", line)) );
+		let name = sanitize(&line[..colon]);
+		if name.is_empty() {
+			return Err(format!("line `{}` has no name", line));
+		}
+
+		let mut parts = line[colon+1..].split('|');
+		let value = parts.next().unwrap_or("");
+		let kind = try!( parts.next().ok_or_else(|| format!("line `{}` has no type", line)) );
+		let mut sample_rate = 1.0;
+		for part in parts {
+			if part.starts_with('@') {
+				sample_rate = try!( part[1..].parse::<f64>().map_err(|_| format!("invalid sample rate `{}`", part)) );
+				if !(sample_rate > 0.0 && sample_rate <= 1.0) {
+					return Err(format!("invalid sample rate `{}`", part));
+				}
+			} else if !part.starts_with('#') {
+				return Err(format!("invalid line `{}`", line));
+			}
+		}
+
+		let number = || value.parse::<f64>().ok().filter(|number| number.is_finite()).ok_or_else(|| format!("invalid value `{}`", value));
+		let value = match kind {
+			"c" => StatsdValue::Counter(try!( number() )),
+			"ms" | "h" => StatsdValue::Timer(try!( number() )),
+			"g" if value.starts_with('+') || value.starts_with('-') => StatsdValue::GaugeDelta(try!( number() )),
+			"g" => StatsdValue::Gauge(try!( number() )),
+			"s" => StatsdValue::Set(value.to_string()),
+			_ => return Err(format!("unknown type `{}`", kind))
+		};
+
+		Ok(StatsdSample { name: name, value: value, sample_rate: sample_rate })
+	}
+}
+
+// The way statsd cleans up names: whitespace to `_`, `/` to `-`, and anything
+// else that isn't a letter, digit, `_`, `-` or `.` dropped
+fn sanitize(name: &str) -> String {
+	let name : String = name.split_whitespace().collect::<Vec<_>>().join("_");
+	name.chars().map(|c| if c == '/' { '-' } else { c }).filter(|&c| c.is_ascii_alphanumeric() || c == '_' || c == '-' || c == '.').collect()
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn sample(name: &str, value: StatsdValue, sample_rate: f64) -> StatsdSample {
+		StatsdSample { name: name.to_string(), value: value, sample_rate: sample_rate }
+	}
+
+	#[test]
+	fn test_parse() {
+		assert_eq!(StatsdSample::parse("api.requests:1|c"), Ok(sample("api.requests", StatsdValue::Counter(1.0), 1.0)));
+		assert_eq!(StatsdSample::parse("api.requests:3|c|@0.5|#env:prod"), Ok(sample("api.requests", StatsdValue::Counter(3.0), 0.5)));
+		assert_eq!(StatsdSample::parse("api.latency:12.5|ms"), Ok(sample("api.latency", StatsdValue::Timer(12.5), 1.0)));
+		assert_eq!(StatsdSample::parse("queue size:-2|g"), Ok(sample("queue_size", StatsdValue::GaugeDelta(-2.0), 1.0)));
+		assert_eq!(StatsdSample::parse("disk/sda:70|g"), Ok(sample("disk-sda", StatsdValue::Gauge(70.0), 1.0)));
+		assert_eq!(StatsdSample::parse("users:alice|s"), Ok(sample("users", StatsdValue::Set("alice".to_string()), 1.0)));
+	}
+
+	#[test]
+	fn test_invalid() {
+		assert!(StatsdSample::parse("api.requests").is_err());
+		assert!(StatsdSample::parse("api.requests:1").is_err());
+		assert!(StatsdSample::parse("api.requests:one|c").is_err());
+		assert!(StatsdSample::parse("api.requests:1|x").is_err());
+		assert!(StatsdSample::parse("api.requests:1|c|@2").is_err());
+		assert!(StatsdSample::parse("api.requests:nan|ms").is_err());
+		assert!(StatsdSample::parse(":1|c").is_err());
+	}
+}
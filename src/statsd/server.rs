@@ -0,0 +1,159 @@
+use std::io;
+use std::net::{ UdpSocket, ToSocketAddrs, SocketAddr };
+use std::str;
+use std::sync::{ Arc, Mutex };
+use std::time::Instant;
+
+use whisper::{ WhisperCache, current_time };
+use super::{ StatsdAggregator, StatsdConfig, StatsdSample };
+
+const MAX_DATAGRAM_SIZE : usize = 65535;
+
+#[derive(Debug, Default, PartialEq, Clone)]
+pub struct StatsdStats {
+	pub datagrams_received: u64,
+	pub samples_received: u64,
+	pub invalid_lines: u64,
+	pub flushes: u64,
+	pub points_written: u64,
+	pub write_errors: u64
+}
+
+// statsd over UDP, any number of lines per datagram. The receiving thread
+// also flushes, between datagrams, so a quiet socket still gets its points
+// written on time.
+pub struct StatsdServer {
+	socket: UdpSocket,
+	aggregator: StatsdAggregator,
+	cache: Arc<WhisperCache>,
+	stats: Mutex<StatsdStats>
+}
+
+impl StatsdServer {
+	pub fn bind<A>(address: A, cache: Arc<WhisperCache>, config: StatsdConfig) -> io::Result<StatsdServer>
+		where A: ToSocketAddrs {
+		Ok(StatsdServer {
+			socket: try!( UdpSocket::bind(address) ),
+			aggregator: StatsdAggregator::new(config),
+			cache: cache,
+			stats: Mutex::new(StatsdStats::default())
+		})
+	}
+
+	pub fn local_addr(&self) -> io::Result<SocketAddr> {
+		self.socket.local_addr()
+	}
+
+	pub fn stats(&self) -> StatsdStats {
+		self.stats.lock().unwrap().clone()
+	}
+
+	// Receives datagrams and flushes every flush interval until the socket fails
+	pub fn run(&self) -> io::Result<()> {
+		let flush_interval = self.aggregator.config().flush_interval;
+		let mut datagram = vec![0u8; MAX_DATAGRAM_SIZE];
+		let mut next_flush = Instant::now() + flush_interval;
+		loop {
+			let now = Instant::now();
+			if now >= next_flush {
+				self.flush();
+				next_flush = next_flush + flush_interval;
+				continue;
+			}
+
+			try!( self.socket.set_read_timeout(Some(next_flush - now)) );
+			match self.socket.recv_from(&mut datagram) {
+				Ok((size, _)) => self.receive_datagram(&datagram[..size]),
+				Err(ref err) if err.kind() == io::ErrorKind::WouldBlock || err.kind() == io::ErrorKind::TimedOut || err.kind() == io::ErrorKind::Interrupted => continue,
+				Err(err) => return Err(err)
+			}
+		}
+	}
+
+	// Writes what was aggregated since the last flush to the cache
+	pub fn flush(&self) {
+		let timestamp = current_time();
+		let (mut written, mut failed) = (0, 0);
+		for named_point in self.aggregator.flush(timestamp) {
+			match self.cache.write(named_point) {
+				Ok(()) => written = written + 1,
+				Err(err) => {
+					warn!("could not write an aggregated point: {}", err);
+					failed = failed + 1;
+				}
+			}
+		}
+
+		let mut stats = self.stats.lock().unwrap();
+		stats.flushes = stats.flushes + 1;
+		stats.points_written = stats.points_written + written;
+		stats.write_errors = stats.write_errors + failed;
+	}
+
+	// Unlike carbon's, invalid lines don't spoil the rest of the datagram
+	fn receive_datagram(&self, datagram: &[u8]) {
+		let (mut received, mut invalid) = (0, 0);
+		for line in datagram.split(|&byte| byte == b'\n').filter(|line| !line.iter().all(|byte| byte.is_ascii_whitespace())) {
+			match str::from_utf8(line).map_err(|_| "invalid utf8 character".to_string()).and_then(StatsdSample::parse) {
+				Ok(sample) => {
+					self.aggregator.add(sample);
+					received = received + 1;
+				},
+				Err(reason) => {
+					debug!("dropping invalid line: {}", reason);
+					invalid = invalid + 1;
+				}
+			}
+		}
+
+		let mut stats = self.stats.lock().unwrap();
+		stats.datagrams_received = stats.datagrams_received + 1;
+		stats.samples_received = stats.samples_received + received;
+		stats.invalid_lines = stats.invalid_lines + invalid;
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use whisper::{ Schema, temp_path };
+	use std::fs;
+	use std::thread;
+	use std::time::Duration;
+
+	#[test]
+	fn test_statsd_server() {
+		let base_path = temp_path("test_statsd_server");
+		fs::remove_dir_all(&base_path).ok();
+		let cache = Arc::new(WhisperCache::new(&base_path, 10, Schema::parse("1s:60s").unwrap()));
+
+		let server = Arc::new(StatsdServer::bind("127.0.0.1:0", cache.clone(), StatsdConfig { flush_interval: Duration::from_millis(200), .. StatsdConfig::default() }).unwrap());
+		let address = server.local_addr().unwrap();
+		{
+			let server = server.clone();
+			thread::spawn(move || server.run());
+		}
+
+		let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+		client.send_to(b"api.requests:1|c\napi.requests:1|c\nnot a sample\n", address).unwrap();
+		client.send_to(b"api.latency:20|ms", address).unwrap();
+
+		for _ in 0..200 {
+			let stats = server.stats();
+			if stats.points_written == 14 {
+				break;
+			}
+			thread::sleep(Duration::from_millis(10));
+		}
+		let stats = server.stats();
+		assert_eq!(stats.datagrams_received, 2);
+		assert_eq!(stats.invalid_lines, 1);
+		assert_eq!(stats.samples_received, 3);
+		// Two points of the counter and twelve of the timer
+		assert_eq!(stats.points_written, 14);
+
+		let now = current_time();
+		let values = cache.fetch("stats.counters.api.requests.count", now - 5, now).unwrap().values;
+		assert_eq!(values.iter().filter_map(|value| *value).collect::<Vec<_>>(), vec![2.0]);
+	}
+}
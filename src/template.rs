@@ -0,0 +1,63 @@
+// The metric name templates of the protocols that don't speak carbon's
+// plaintext, e.g. `telegraf.{host}.{measurement}.{field}`: literal text and
+// `{placeholder}`s, which each protocol fills in its own way.
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Piece<'a> {
+	Literal(&'a str),
+	Placeholder(&'a str)
+}
+
+// `kind` names what is parsed in errors, "naming scheme" or "template".
+// Literals can't hold what would make paths or globs of the names.
+pub fn parse<'a>(template: &'a str, kind: &str) -> Result<Vec<Piece<'a>>, String> {
+	let mut pieces = vec![];
+	let mut rest = template;
+	while !rest.is_empty() {
+		match rest.find('{') {
+			Some(0) => {
+				let end = try!( rest.find('}').ok_or_else(|| format!("unclosed `{{` in {} `{}`", kind, template)) );
+				let placeholder = &rest[1..end];
+				if placeholder.is_empty() || placeholder.contains('{') {
+					return Err(format!("invalid placeholder in {} `{}`", kind, template));
+				}
+				pieces.push(Piece::Placeholder(placeholder));
+				rest = &rest[end+1..];
+			},
+			found => {
+				let end = found.unwrap_or(rest.len());
+				let literal = &rest[..end];
+				if literal.contains(|c| c == '}' || c == '/' || c == '\0' || c == '*' || c == '?' || c == '[' || c == ']') {
+					return Err(format!("invalid character in {} `{}`", kind, template));
+				}
+				pieces.push(Piece::Literal(literal));
+				rest = &rest[end..];
+			}
+		}
+	}
+	Ok(pieces)
+}
+
+// What a tag, label or attribute value looks like as a metric name node:
+// anything but letters, digits, `_` and `-` replaced by `_`
+pub fn sanitize(value: &str) -> String {
+	value.chars().map(|c| if c.is_ascii_alphanumeric() || c == '_' || c == '-' { c } else { '_' }).collect()
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_parse() {
+		assert_eq!(parse("otel.{service.name}.{name}", "naming scheme").unwrap(),
+			vec![Piece::Literal("otel."), Piece::Placeholder("service.name"), Piece::Literal("."), Piece::Placeholder("name")]);
+		assert_eq!(parse("", "template").unwrap(), vec![]);
+		assert_eq!(parse("{job}.{instance", "template").unwrap_err(), "unclosed `{` in template `{job}.{instance`");
+		assert!(parse("{}.{name}", "naming scheme").is_err());
+		assert!(parse("{a{b}", "naming scheme").is_err());
+		assert!(parse("/etc/{name}", "naming scheme").is_err());
+		assert!(parse("*.{job}", "template").is_err());
+		assert_eq!(sanitize("web01.example.com:9100"), "web01_example_com_9100");
+	}
+}
@@ -0,0 +1,106 @@
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{ Context, Poll };
+
+use tokio::task::{ self, JoinHandle };
+
+use whisper::{ WhisperCache, TimeSeries };
+use whisper::error::{ Result, WhisperError };
+use super::NamedPoint;
+
+// A WhisperCache for async servers. Every call touches the disk sooner or
+// later, so each runs on tokio's blocking pool and hands back a future of its
+// result. Needs the `tokio` feature, and to be called within a tokio runtime.
+#[derive(Clone)]
+pub struct AsyncWhisperCache {
+	cache: Arc<WhisperCache>
+}
+
+// The result of a call running on the blocking pool
+pub struct Blocking<T> {
+	handle: JoinHandle<Result<T>>
+}
+
+impl<T> Future for Blocking<T> {
+	type Output = Result<T>;
+
+	fn poll(mut self: Pin<&mut Self>, context: &mut Context) -> Poll<Result<T>> {
+		match Pin::new(&mut self.handle).poll(context) {
+			Poll::Ready(Ok(result)) => Poll::Ready(result),
+			// The call panicked, or the runtime is shutting down
+			Poll::Ready(Err(err)) => Poll::Ready(Err(WhisperError::Io(io::Error::new(io::ErrorKind::Other, err)))),
+			Poll::Pending => Poll::Pending
+		}
+	}
+}
+
+impl AsyncWhisperCache {
+	pub fn new(cache: Arc<WhisperCache>) -> AsyncWhisperCache {
+		AsyncWhisperCache { cache: cache }
+	}
+
+	// For the settings, stats and anything else that doesn't wait on the disk
+	pub fn cache(&self) -> &Arc<WhisperCache> {
+		&self.cache
+	}
+
+	pub fn write(&self, named_point: NamedPoint) -> Blocking<()> {
+		self.run(move |cache| cache.write(named_point))
+	}
+
+	// All in one trip to the blocking pool, a datagram's worth for instance.
+	// Stops at the first point that fails.
+	pub fn write_many(&self, named_points: Vec<NamedPoint>) -> Blocking<()> {
+		self.run(move |cache| {
+			for named_point in named_points {
+				try!( cache.write(named_point) );
+			}
+			Ok(())
+		})
+	}
+
+	pub fn fetch(&self, metric_name: &str, from: u32, until: u32) -> Blocking<TimeSeries> {
+		let metric_name = metric_name.to_string();
+		self.run(move |cache| cache.fetch(&metric_name, from, until))
+	}
+
+	pub fn flush_pending(&self) -> Blocking<()> {
+		self.run(|cache| cache.flush_pending())
+	}
+
+	pub fn flush_all(&self) -> Blocking<()> {
+		self.run(|cache| cache.flush_all())
+	}
+
+	fn run<T, F>(&self, f: F) -> Blocking<T>
+		where T: Send + 'static, F: FnOnce(&WhisperCache) -> Result<T> + Send + 'static {
+		let cache = self.cache.clone();
+		Blocking { handle: task::spawn_blocking(move || f(&cache)) }
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use whisper::{ Schema, current_time, temp_path };
+	use std::fs;
+	use tokio::runtime::Builder;
+
+	#[test]
+	fn test_async_cache() {
+		let base_path = temp_path("test_async_cache");
+		fs::remove_dir_all(&base_path).ok();
+		let now = current_time();
+		let now = now - now % 10;
+
+		let cache = AsyncWhisperCache::new(Arc::new(WhisperCache::new(&base_path, 10, Schema::parse("10s:60s").unwrap())));
+		let runtime = Builder::new_current_thread().build().unwrap();
+
+		runtime.block_on(cache.write_many(vec![NamedPoint::new("async.a".to_string(), now - 10, 1.0), NamedPoint::new("async.a".to_string(), now, 2.0)])).unwrap();
+		runtime.block_on(cache.flush_all()).unwrap();
+		let series = runtime.block_on(cache.fetch("async.a", now - 20, now)).unwrap();
+		assert_eq!(series.values, vec![Some(1.0), Some(2.0)]);
+	}
+}
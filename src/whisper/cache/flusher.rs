@@ -0,0 +1,117 @@
+use std::sync::Arc;
+use std::sync::mpsc::{ self, Sender, RecvTimeoutError };
+use std::thread::{ self, JoinHandle };
+use std::time::Duration;
+use std::cmp;
+
+use whisper::WhisperCache;
+use whisper::error::Result;
+
+// Threads writing out what a write back cache holds every `interval`, so
+// writers don't have to. Each thread takes care of its own shards.
+pub struct Flusher {
+	cache: Arc<WhisperCache>,
+	// Dropped to wake the threads up and have them stop
+	stop: Vec< Sender<()> >,
+	threads: Vec< JoinHandle<()> >
+}
+
+impl Flusher {
+	// At most one thread per shard is started
+	pub fn start(cache: Arc<WhisperCache>, thread_count: usize, interval: Duration) -> Flusher {
+		let thread_count = cmp::min(cmp::max(thread_count, 1), cache.shard_count());
+		let mut stop = vec![];
+		let mut threads = vec![];
+
+		for thread_index in 0..thread_count {
+			let (stop_sender, stop_receiver) = mpsc::channel::<()>();
+			let cache = cache.clone();
+			let shards : Vec<usize> = (thread_index..cache.shard_count()).filter(|shard| shard % thread_count == thread_index).collect();
+
+			threads.push(thread::spawn(move || {
+				while let Err(RecvTimeoutError::Timeout) = stop_receiver.recv_timeout(interval) {
+					for &shard in &shards {
+						if let Err(err) = cache.flush_shard(shard) {
+							warn!("could not flush shard {}: {}", shard, err);
+						}
+					}
+				}
+			}));
+			stop.push(stop_sender);
+		}
+
+		Flusher { cache: cache, stop: stop, threads: threads }
+	}
+
+	// Waits for the threads to finish and writes what was left behind
+	pub fn stop(mut self) -> Result<()> {
+		self.join();
+		self.cache.flush_pending()
+	}
+
+	fn join(&mut self) {
+		self.stop.clear();
+		for thread in self.threads.drain(..) {
+			thread.join().ok();
+		}
+	}
+}
+
+impl Drop for Flusher {
+	fn drop(&mut self) {
+		self.join();
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use whisper::{ NamedPoint, Schema, WriteBack, current_time, temp_path };
+	use std::time::Duration;
+	use std::fs;
+
+	#[test]
+	fn test_flusher() {
+		let base_path = temp_path("test_cache_flusher");
+		fs::remove_dir_all(&base_path).ok();
+		let now = current_time();
+
+		let cache = Arc::new(WhisperCache::with_shards(&base_path, Schema::parse("10s:60s").unwrap(), 100, None, 4));
+		cache.set_write_back(Some(WriteBack { max_points: 100, flush_interval: Duration::from_secs(3600) }));
+		let flusher = Flusher::start(cache.clone(), 2, Duration::from_millis(10));
+
+		for i in 0..10 {
+			cache.write(NamedPoint::new(format!("flushed.metric{}", i), now, 1.0)).unwrap();
+		}
+		for _ in 0..100 {
+			if cache.stats().pending_points == 0 {
+				break;
+			}
+			thread::sleep(Duration::from_millis(10));
+		}
+		assert_eq!(cache.stats().pending_points, 0);
+
+		cache.write(NamedPoint::new("flushed.last".to_string(), now, 1.0)).unwrap();
+		flusher.stop().unwrap();
+		assert_eq!(cache.stats().pending_points, 0);
+	}
+
+	#[test]
+	fn test_max_pending_points() {
+		let base_path = temp_path("test_cache_max_pending_points");
+		fs::remove_dir_all(&base_path).ok();
+		let now = current_time();
+
+		let cache = WhisperCache::new(&base_path, 100, Schema::parse("10s:60s").unwrap());
+		cache.set_write_back(Some(WriteBack { max_points: 100, flush_interval: Duration::from_secs(3600) }));
+		cache.set_max_pending_points(Some(2));
+
+		for i in 0..2 {
+			cache.write(NamedPoint::new(format!("backpressure.metric{}", i), now, 1.0)).unwrap();
+		}
+		assert_eq!(cache.stats().pending_points, 2);
+		// The third point is one too many
+		cache.write(NamedPoint::new("backpressure.metric2".to_string(), now, 1.0)).unwrap();
+		assert_eq!(cache.stats().pending_points, 0);
+	}
+}
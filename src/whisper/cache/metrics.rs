@@ -0,0 +1,138 @@
+use std::collections::VecDeque;
+use std::time::Duration;
+
+use super::{ CacheStats, NamedPoint };
+
+// How many of the latest flush times the percentiles are taken from, per shard
+pub const FLUSH_TIME_SAMPLES : usize = 1024;
+
+// A snapshot of what a WhisperCache has done, for daemons to report about
+// themselves. Flush times are those of update_many on one file, the
+// percentiles covering the latest FLUSH_TIME_SAMPLES flushes of each shard.
+#[derive(Debug, PartialEq, Clone, Default)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct CacheMetrics {
+	pub points_received: u64,
+	// Written to their file, as they came or flushed
+	pub points_persisted: u64,
+	pub pending_points: usize,
+	pub creates: u64,
+	pub evictions: u64,
+	pub open_files: usize,
+	pub flushes: u64,
+	pub flush_time_p50: Duration,
+	pub flush_time_p90: Duration,
+	pub flush_time_p99: Duration,
+	pub flush_time_max: Duration
+}
+
+impl CacheMetrics {
+	pub fn new(stats: &CacheStats, points_received: u64, points_persisted: u64, flushes: u64, mut flush_times: Vec<Duration>) -> CacheMetrics {
+		flush_times.sort();
+		CacheMetrics {
+			points_received: points_received,
+			points_persisted: points_persisted,
+			pending_points: stats.pending_points,
+			creates: stats.creates,
+			evictions: stats.evictions,
+			open_files: stats.open_files,
+			flushes: flushes,
+			flush_time_p50: CacheMetrics::percentile(&flush_times, 50),
+			flush_time_p90: CacheMetrics::percentile(&flush_times, 90),
+			flush_time_p99: CacheMetrics::percentile(&flush_times, 99),
+			flush_time_max: flush_times.last().cloned().unwrap_or_default()
+		}
+	}
+
+	// One point per metric, named `prefix.<field>`. Times are in seconds, same
+	// as carbon's own avgUpdateTime.
+	pub fn named_points(&self, prefix: &str, timestamp: u32) -> Vec<NamedPoint> {
+		let values = [
+			("points_received", self.points_received as f64),
+			("points_persisted", self.points_persisted as f64),
+			("pending_points", self.pending_points as f64),
+			("creates", self.creates as f64),
+			("evictions", self.evictions as f64),
+			("open_files", self.open_files as f64),
+			("flushes", self.flushes as f64),
+			("flush_time_p50", CacheMetrics::seconds(self.flush_time_p50)),
+			("flush_time_p90", CacheMetrics::seconds(self.flush_time_p90)),
+			("flush_time_p99", CacheMetrics::seconds(self.flush_time_p99)),
+			("flush_time_max", CacheMetrics::seconds(self.flush_time_max))
+		];
+		values.iter().map(|&(name, value)| NamedPoint::new(format!("{}.{}", prefix, name), timestamp, value)).collect()
+	}
+
+	// Nearest rank of sorted samples
+	fn percentile(sorted: &[Duration], percent: usize) -> Duration {
+		if sorted.is_empty() {
+			return Duration::default();
+		}
+		let rank = (sorted.len() * percent + 99) / 100;
+		sorted[rank.saturating_sub(1)]
+	}
+
+	fn seconds(duration: Duration) -> f64 {
+		duration.as_secs() as f64 + duration.subsec_nanos() as f64 / 1e9
+	}
+}
+
+// The latest FLUSH_TIME_SAMPLES flush times
+pub struct FlushTimes {
+	samples: VecDeque<Duration>
+}
+
+impl FlushTimes {
+	pub fn new() -> FlushTimes {
+		FlushTimes { samples: VecDeque::with_capacity(FLUSH_TIME_SAMPLES) }
+	}
+
+	pub fn record(&mut self, duration: Duration) {
+		if self.samples.len() == FLUSH_TIME_SAMPLES {
+			self.samples.pop_front();
+		}
+		self.samples.push_back(duration);
+	}
+
+	pub fn samples(&self) -> Vec<Duration> {
+		self.samples.iter().cloned().collect()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_percentiles() {
+		let flush_times = (1..101).rev().map(|millis| Duration::from_millis(millis)).collect();
+		let metrics = CacheMetrics::new(&CacheStats::default(), 0, 0, 100, flush_times);
+
+		assert_eq!(metrics.flush_time_p50, Duration::from_millis(50));
+		assert_eq!(metrics.flush_time_p90, Duration::from_millis(90));
+		assert_eq!(metrics.flush_time_p99, Duration::from_millis(99));
+		assert_eq!(metrics.flush_time_max, Duration::from_millis(100));
+		assert_eq!(CacheMetrics::new(&CacheStats::default(), 0, 0, 0, vec![]).flush_time_p99, Duration::default());
+	}
+
+	#[test]
+	fn test_flush_times() {
+		let mut flush_times = FlushTimes::new();
+		for millis in 0..FLUSH_TIME_SAMPLES as u64 + 10 {
+			flush_times.record(Duration::from_millis(millis));
+		}
+		let samples = flush_times.samples();
+		assert_eq!(samples.len(), FLUSH_TIME_SAMPLES);
+		assert_eq!(samples[0], Duration::from_millis(10));
+	}
+
+	#[test]
+	fn test_named_points() {
+		let metrics = CacheMetrics { creates: 3, flush_time_max: Duration::from_millis(1500), .. CacheMetrics::default() };
+		let points = metrics.named_points("carbon.agents.host.cache", 1440392100);
+
+		assert_eq!(points.len(), 11);
+		assert!(points.contains(&NamedPoint::new("carbon.agents.host.cache.creates".to_string(), 1440392100, 3.0)));
+		assert!(points.contains(&NamedPoint::new("carbon.agents.host.cache.flush_time_max".to_string(), 1440392100, 1.5)));
+	}
+}
@@ -1,85 +1,479 @@
 // use carbon::CarbonMsg;
 // use whisper::{ WhisperFile, MutexWhisperFile };
-use whisper::{ WhisperFile, Schema };
+use whisper::{ WhisperFile, Schema, SchemaResolver, AggregationType, TimeSeries, TaggedSeries, TagIndex, RewriteRules, MetricFilter, PathMapping, DataRoot, MetricIndex };
+use whisper::error::Result;
 use std::path::{ Path, PathBuf };
-use std::fs::DirBuilder;
-use std::io;
-use std::sync::{ Arc, Mutex };
-use lru_cache::LruCache;
+use std::sync::{ Arc, Mutex, RwLock, TryLockError };
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{ Hash, Hasher };
+use std::cmp;
+use std::fmt;
+use std::time::{ Duration, Instant };
 
 mod named_point;
+mod stats;
+mod write_back;
+mod shard;
+mod flusher;
+mod metrics;
+mod writer;
+mod query_cache;
+mod replication;
+#[cfg(feature = "tokio")]
+mod async_cache;
 pub use self::named_point::NamedPoint;
+pub use self::metrics::CacheMetrics;
+pub use self::writer::{ IngestQueue, CacheWriter, QueueError };
+pub use self::query_cache::QueryCaching;
+pub use self::replication::{ Replication, Replica, ReplicaProtocol, ReplicaConfig, ReplicaStats, read_framed };
+#[cfg(feature = "tokio")]
+pub use self::async_cache::{ AsyncWhisperCache, Blocking };
+pub use self::flusher::Flusher;
+pub use self::stats::CacheStats;
+pub use self::write_back::WriteBack;
+use self::shard::{ Shard, Flush, FlushDue };
 
-type WhisperMutex = Arc<Mutex<WhisperFile>>;
-
+// Metrics are split between shards by the hash of their name, each with its
+// own lock and LRU, so writers of different metrics rarely wait on each other.
+// The open files and resident bytes budgets are divided evenly between them.
+//
+// Every method takes &self, a cache is shared between threads behind an Arc.
+// Locks are always taken shard first, then file. Flushing only holds the shard
+// while taking a metric's points out of it, the points are written with just
+// their file locked. A file busy being written is waited for with the shard
+// let go.
 pub struct WhisperCache {
 	pub base_path: PathBuf,
-	// open_files: HashMap< PathBuf, WhisperMutex >,
-	open_files: LruCache< PathBuf, WhisperMutex >,
-	schema: Schema
+	shards: Vec< Mutex<Shard> >,
+	write_config: RwLock< Arc<WriteConfig> >
+}
+
+impl fmt::Debug for WhisperCache {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		f.debug_struct("WhisperCache").field("base_path", &self.base_path).field("shards", &self.shards.len()).finish()
+	}
+}
+
+// What every write goes through before its shard. Setters swap in a new one,
+// so a write only takes a read lock to get hold of all of it.
+#[derive(Default, Clone)]
+struct WriteConfig {
+	metric_filter: Option< Arc<MetricFilter> >,
+	tag_index: Option< Arc<TagIndex> >,
+	rewrite_rules: Option< Arc<RewriteRules> >,
+	replication: Option< Arc<Replication> >
 }
 
 impl WhisperCache {
 	pub fn new<P>(base_path: P, size: usize, schema: Schema) -> WhisperCache
         where P: AsRef<Path> {
+		WhisperCache::new_with_resolver(base_path, size, schema)
+	}
+
+	// Points of metrics that have no file yet create it, along with its parent
+	// directories, laid out the way `resolver` says for their metric name.
+	// Writes fail with NoMatchingSchema when it has nothing for the metric.
+	pub fn new_with_resolver<P, R>(base_path: P, size: usize, resolver: R) -> WhisperCache
+        where P: AsRef<Path>, R: SchemaResolver + 'static {
+		WhisperCache::with_capacity(base_path, resolver, size, None)
+	}
+
+	// Keeps at most `max_open_files` files open and, when given, closes the
+	// least recently used ones until no more than `max_resident_bytes` are mapped.
+	// The file being written is never closed, even if it alone is over budget.
+	// Each open file holds a descriptor and its mmap, closed files are reopened
+	// the next time they are written.
+	pub fn with_capacity<P, R>(base_path: P, resolver: R, max_open_files: usize, max_resident_bytes: Option<u64>) -> WhisperCache
+        where P: AsRef<Path>, R: SchemaResolver + 'static {
+		WhisperCache::with_shards(base_path, resolver, max_open_files, max_resident_bytes, 1)
+	}
+
+	// Same as with_capacity, split into `shard_count` shards
+	pub fn with_shards<P, R>(base_path: P, resolver: R, max_open_files: usize, max_resident_bytes: Option<u64>, shard_count: usize) -> WhisperCache
+        where P: AsRef<Path>, R: SchemaResolver + 'static {
+		let shard_count = cmp::max(shard_count, 1);
+		let resolver : Arc<dyn SchemaResolver> = Arc::new(resolver);
+		let files_per_shard = WhisperCache::share(max_open_files as u64, shard_count) as usize;
+		let bytes_per_shard = max_resident_bytes.map(|max| WhisperCache::share(max, shard_count));
+
 		WhisperCache {
 			base_path: base_path.as_ref().to_path_buf(),
-			open_files: LruCache::new(size),
-			schema: schema
+			shards: (0..shard_count).map(|_| {
+				Mutex::new(Shard::new(base_path.as_ref().to_path_buf(), resolver.clone(), files_per_shard, bytes_per_shard))
+			}).collect(),
+			write_config: RwLock::new(Arc::new(WriteConfig::default()))
 		}
 	}
 
-	pub fn write(&mut self, named_point: NamedPoint) -> Result<(), io::Error> {
-		let metric_rel_path = named_point.rel_path();
+	pub fn shard_count(&self) -> usize {
+		self.shards.len()
+	}
 
-		let cache_entry = try!( self.get(metric_rel_path) );
-		let mut whisper_file = cache_entry.lock().unwrap();
+	// Totals of every shard
+	pub fn stats(&self) -> CacheStats {
+		self.shard_stats().iter().fold(CacheStats::default(), |total, stats| CacheStats {
+			hits: total.hits + stats.hits,
+			misses: total.misses + stats.misses,
+			creates: total.creates + stats.creates,
+			evictions: total.evictions + stats.evictions,
+			replaced: total.replaced + stats.replaced,
+			open_files: total.open_files + stats.open_files,
+			resident_bytes: total.resident_bytes + stats.resident_bytes,
+			pending_points: total.pending_points + stats.pending_points,
+			query_hits: total.query_hits + stats.query_hits,
+			query_misses: total.query_misses + stats.query_misses
+		})
+	}
 
-		// We assume opened files always succeed in writes
-		whisper_file.write(&named_point.point());
-		Ok(())
+	// Totals of every shard, flush time percentiles over all of their samples
+	pub fn metrics(&self) -> CacheMetrics {
+		let (mut points_received, mut points_persisted, mut flushes, mut flush_times) = (0, 0, 0, vec![]);
+		for shard in &self.shards {
+			let (received, persisted, flushed, times) = shard.lock().unwrap().metrics();
+			points_received = points_received + received;
+			points_persisted = points_persisted + persisted;
+			flushes = flushes + flushed;
+			flush_times.extend(times);
+		}
+		CacheMetrics::new(&self.stats(), points_received, points_persisted, flushes, flush_times)
 	}
 
-	fn get(&mut self, metric_rel_path: PathBuf) -> Result< &WhisperMutex, io::Error> {
+	pub fn shard_stats(&self) -> Vec<CacheStats> {
+		self.shards.iter().map(|shard| shard.lock().unwrap().stats()).collect()
+	}
 
-		if self.open_files.contains_key(&metric_rel_path) {
+	pub fn max_open_files(&self) -> usize {
+		self.shards.iter().map(|shard| shard.lock().unwrap().max_open_files()).sum()
+	}
+
+	// Closes the least recently used files right away when there are more open
+	pub fn set_max_open_files(&self, max_open_files: usize) {
+		let files_per_shard = WhisperCache::share(max_open_files as u64, self.shards.len()) as usize;
+		for shard in &self.shards {
+			shard.lock().unwrap().set_max_open_files(files_per_shard);
+		}
+	}
+
+	// None, the default, writes every point as it comes. Turning write back off
+	// leaves what's pending until the next flush.
+	pub fn set_write_back(&self, write_back: Option<WriteBack>) {
+		for shard in &self.shards {
+			shard.lock().unwrap().set_write_back(write_back);
+		}
+	}
+
+	// With write back, writers flush their shard themselves once the cache as a
+	// whole holds more than `max_pending_points`. None, the default, lets
+	// pending points pile up until their interval or threshold.
+	pub fn set_max_pending_points(&self, max_pending_points: Option<usize>) {
+		let points_per_shard = max_pending_points.map(|max| WhisperCache::share(max as u64, self.shards.len()) as usize);
+		for shard in &self.shards {
+			shard.lock().unwrap().set_max_pending_points(points_per_shard);
+		}
+	}
 
-			debug!("file cache hit. resolved {:?}", metric_rel_path);
-			Ok( self.open_files.get_mut(&metric_rel_path).unwrap() )
+	// How pending points of a metric that fall in the same interval of its
+	// finest archive are merged before being written. Last, the default, keeps
+	// the latest written, same as writing them one by one would.
+	pub fn set_coalesce_policy(&self, coalesce_policy: AggregationType) {
+		for shard in &self.shards {
+			shard.lock().unwrap().set_coalesce_policy(coalesce_policy.clone());
+		}
+	}
+
+	// Every `interval`, a write to an open file first makes sure it's still the
+	// one on disk, and opens it again when it was replaced or removed. None,
+	// the default, never looks.
+	pub fn set_replaced_check_interval(&self, interval: Option<Duration>) {
+		for shard in &self.shards {
+			shard.lock().unwrap().set_replaced_check_interval(interval);
+		}
+	}
+
+	// How metric names become the paths of their files. Set it before writing
+	// anything, files already open stay where they are. Names it refuses fail
+	// with WhisperError::InvalidMetricName.
+	pub fn set_path_mapping(&self, path_mapping: PathMapping) {
+		for shard in &self.shards {
+			shard.lock().unwrap().set_path_mapping(path_mapping.clone());
+		}
+	}
+
+	pub fn path_mapping(&self) -> PathMapping {
+		self.data_root().path_mapping().clone()
+	}
+
+	// Symlinks under the base path may lead into `directory` too, besides the
+	// base path itself. Files behind any other symlink are neither opened nor
+	// created, writing them fails with WhisperError::OutsideDataRoot.
+	pub fn allow_symlinks_into<P>(&self, directory: P)
+		where P: AsRef<Path> {
+		for shard in &self.shards {
+			shard.lock().unwrap().allow_symlinks_into(directory.as_ref());
+		}
+	}
+
+	// The base path with the path mapping and symlink targets files are
+	// resolved by, for a WhisperTree to agree with
+	pub fn data_root(&self) -> DataRoot {
+		self.shards[0].lock().unwrap().data_root().clone()
+	}
+
+	// Tagged series written from then on are added to `tag_index` so they can
+	// be found by their tags. None, the default, keeps no index.
+	pub fn set_tag_index(&self, tag_index: Option< Arc<TagIndex> >) {
+		self.update_write_config(|config| config.tag_index = tag_index);
+	}
+
+	pub fn tag_index(&self) -> Option< Arc<TagIndex> > {
+		self.write_config().tag_index.clone()
+	}
+
+	// Metrics whose files the cache creates from then on are added to
+	// `metric_index`, for a WhisperTree sharing it to find
+	pub fn set_metric_index(&self, metric_index: Option< Arc<MetricIndex> >) {
+		for shard in &self.shards {
+			shard.lock().unwrap().set_metric_index(metric_index.clone());
+		}
+	}
+
+	pub fn metric_index(&self) -> Option< Arc<MetricIndex> > {
+		self.shards[0].lock().unwrap().metric_index()
+	}
+
+	// Names written from then on are rewritten by both stages of
+	// `rewrite_rules` before anything else is done with them, the file they
+	// go to and its schema included. None, the default, keeps names as they are.
+	pub fn set_rewrite_rules(&self, rewrite_rules: Option< Arc<RewriteRules> >) {
+		self.update_write_config(|config| config.rewrite_rules = rewrite_rules);
+	}
 
-		} else {
+	pub fn rewrite_rules(&self) -> Option< Arc<RewriteRules> > {
+		self.write_config().rewrite_rules.clone()
+	}
 
-			// debug!("file cache miss. resolving {:?}", metric_rel_path);
+	// Points of metrics `metric_filter` turns away are dropped as they are
+	// written, before their names are rewritten, and counted in its stats.
+	// None, the default, takes every metric.
+	pub fn set_metric_filter(&self, metric_filter: Option< Arc<MetricFilter> >) {
+		self.update_write_config(|config| config.metric_filter = metric_filter);
+	}
 
-			let path_for_insert = metric_rel_path.clone();
-			let path_for_relookup = metric_rel_path.clone();
+	pub fn metric_filter(&self) -> Option< Arc<MetricFilter> > {
+		self.write_config().metric_filter.clone()
+	}
 
-			let path_on_disk = self.base_path.join(metric_rel_path);
+	// Points written from then on are shipped to the replicas of
+	// `replication` once the cache took them, filtered and renamed. None, the
+	// default, replicates nothing.
+	pub fn set_replication(&self, replication: Option< Arc<Replication> >) {
+		self.update_write_config(|config| config.replication = replication);
+	}
 
-			let whisper_file = if path_on_disk.exists() && path_on_disk.is_file() {
+	pub fn replication(&self) -> Option< Arc<Replication> > {
+		self.write_config().replication.clone()
+	}
 
-				debug!("`{:?}` exists on disk. opening.", path_on_disk);
-				WhisperFile::open(&path_on_disk)
+	fn write_config(&self) -> Arc<WriteConfig> {
+		self.write_config.read().unwrap().clone()
+	}
 
-			} else {
+	fn update_write_config<F>(&self, update: F)
+		where F: FnOnce(&mut WriteConfig) {
+		let mut write_config = self.write_config.write().unwrap();
+		let mut updated = (**write_config).clone();
+		update(&mut updated);
+		*write_config = Arc::new(updated);
+	}
 
-				// Verify the folder structure is present.
-				// TODO: benchmark (for my own curiosity)
-				// TODO: assumption here is that we do not store in root FS
-				if !path_on_disk.parent().unwrap().is_dir() {
-					debug!("parent dir for `{:?}` must be created first", path_on_disk.parent());
-					try!( DirBuilder::new().recursive(true).create( path_on_disk.parent().unwrap() ) );
+	pub fn write(&self, named_point: NamedPoint) -> Result<()> {
+		let config = self.write_config();
+		if let Some(ref metric_filter) = config.metric_filter {
+			if !metric_filter.accept(named_point.metric_name()) {
+				return Ok(());
+			}
+		}
+		let named_point = match config.rewrite_rules {
+			Some(ref rewrite_rules) => {
+				let name = rewrite_rules.rewrite_all(named_point.metric_name());
+				if name == named_point.metric_name() {
+					named_point
+				} else {
+					NamedPoint::new(name, named_point.point().0, named_point.point().1)
 				}
-				debug!("`{:?}` must now be created", path_on_disk);
-				try!( WhisperFile::new(&path_on_disk, &self.schema) )
+			},
+			None => named_point
+		};
+		if let Some(ref tag_index) = config.tag_index {
+			if let Ok(series) = TaggedSeries::parse(named_point.metric_name()) {
+				try!( tag_index.add(&series) );
+			}
+		}
+		let replicated = config.replication.as_ref().map(|_| named_point.clone());
+		let index = self.shard_index(named_point.metric_name());
+		let due = try!( self.shards[index].lock().unwrap().accept(named_point) );
+		// Taken, whether or not flushing the other points works out
+		if let (Some(replication), Some(named_point)) = (config.replication.as_ref(), replicated) {
+			replication.replicate(&named_point);
+		}
+		match due {
+			Some(FlushDue::All) => self.flush_shard(index),
+			Some(FlushDue::Metric(metric_name)) => WhisperCache::flush_metric(&self.shards[index], &metric_name),
+			None => Ok(())
+		}
+	}
 
+	// None, the default, reads the file on every fetch. Each shard keeps
+	// results for its share of `max_metrics`.
+	pub fn set_query_caching(&self, query_caching: Option<QueryCaching>) {
+		let query_caching = query_caching.map(|query_caching| QueryCaching {
+			max_metrics: WhisperCache::share(query_caching.max_metrics as u64, self.shards.len()) as usize,
+			.. query_caching
+		});
+		for shard in &self.shards {
+			shard.lock().unwrap().set_query_caching(query_caching);
+		}
+	}
+
+	// What the file of `metric_name` holds between `from` and `until`, with the
+	// points still pending merged in, so reads see writes right away the way
+	// graphite-web's carbonlink queries do
+	pub fn fetch(&self, metric_name: &str, from: u32, until: u32) -> Result<TimeSeries> {
+		let shard = self.shard_for(metric_name);
+		// Nothing to merge with, the file only exists once they're written
+		let unwritten = try!( shard.lock().unwrap().unwritten(metric_name) );
+		if unwritten {
+			try!( WhisperCache::flush_metric(shard, metric_name) );
+		}
+		shard.lock().unwrap().fetch(metric_name, from, until)
+	}
+
+	// For a file changed on disk by something else: results kept of
+	// `metric_name` are dropped, and its file closed to be opened again when
+	// it's another one now or gone. Returns whether it was closed.
+	pub fn invalidate(&self, metric_name: &str) -> Result<bool> {
+		self.shard_for(metric_name).lock().unwrap().invalidate(metric_name)
+	}
+
+	// Writes every pending point. Points of a metric that fail to be written
+	// for an io error are kept pending for the next flush, the ones no retry
+	// would help are dropped. The first error is returned once the other
+	// metrics are written.
+	pub fn flush_pending(&self) -> Result<()> {
+		let mut result = Ok(());
+		for index in 0..self.shards.len() {
+			let flushed = self.flush_shard(index);
+			if result.is_ok() {
+				result = flushed;
+			}
+		}
+		result
+	}
+
+	// Writes the pending points of one of the shards, 0 to shard_count() - 1
+	pub fn flush_shard(&self, index: usize) -> Result<()> {
+		let shard = &self.shards[index];
+		let metric_names = {
+			let mut locked_shard = shard.lock().unwrap();
+			// Marked first, so writers coming in meanwhile don't all set out to
+			// flush it too
+			locked_shard.mark_flushed();
+			locked_shard.pending_metrics()
+		};
+
+		let mut result = Ok(());
+		for metric_name in metric_names {
+			let flushed = WhisperCache::flush_metric(shard, &metric_name);
+			if result.is_ok() {
+				result = flushed;
+			}
+		}
+		result
+	}
+
+	// Writes every pending point and syncs the mmap of every open file, so
+	// nothing is lost if the process stops right after
+	pub fn flush_all(&self) -> Result<()> {
+		let result = self.flush_pending();
+		let synced = self.each_shard(|shard| shard.sync_open_files());
+		result.and(synced)
+	}
+
+	// Same as dropping the cache, but tells when something couldn't be written
+	pub fn close(self) -> Result<()> {
+		self.flush_all()
+	}
+
+	fn flush_metric(shard: &Mutex<Shard>, metric_name: &str) -> Result<()> {
+		loop {
+			let mut locked_shard = shard.lock().unwrap();
+			let file = match try!( locked_shard.pending_file(metric_name) ) {
+				Some(file) => file,
+				None => return Ok(())
+			};
+			// Points are only taken with their file locked, so batches of a
+			// metric taken one after the other are written in that order too
+			let whisper_file = match file.try_lock() {
+				Ok(whisper_file) => Some(whisper_file),
+				Err(TryLockError::WouldBlock) => None,
+				Err(TryLockError::Poisoned(err)) => panic!("{}", err)
 			};
+			if let Some(mut whisper_file) = whisper_file {
+				let flush = locked_shard.take_pending(metric_name);
+				drop(locked_shard);
+				return WhisperCache::write_flush(shard, metric_name, flush, &mut whisper_file);
+			}
+			// Waited for without the shard, which the writer may be after
+			drop(locked_shard);
+			drop(file.lock().unwrap());
+		}
+	}
+
+	fn write_flush(shard: &Mutex<Shard>, metric_name: &str, flush: Flush, whisper_file: &mut WhisperFile) -> Result<()> {
+		let started = Instant::now();
+		let written = flush.write(whisper_file);
+		let mut locked_shard = shard.lock().unwrap();
+		locked_shard.record_flush(&flush, started.elapsed(), written.is_ok());
+		if let Err(ref err) = written {
+			locked_shard.restore_pending(metric_name, flush, err);
+		}
+		written
+	}
+
+	fn shard_for(&self, metric_name: &str) -> &Mutex<Shard> {
+		&self.shards[self.shard_index(metric_name)]
+	}
 
-			self.open_files.insert(path_for_insert, Arc::new( Mutex::new(whisper_file) ) );
-			Ok( self.open_files.get_mut(&path_for_relookup).unwrap() )
+	fn shard_index(&self, metric_name: &str) -> usize {
+		let mut hasher = DefaultHasher::new();
+		metric_name.hash(&mut hasher);
+		(hasher.finish() % self.shards.len() as u64) as usize
+	}
 
+	// Runs `f` on every shard, the first error is returned once all of them ran
+	fn each_shard<F>(&self, f: F) -> Result<()>
+		where F: Fn(&mut Shard) -> Result<()> {
+		let mut result = Ok(());
+		for shard in &self.shards {
+			let done = f(&mut shard.lock().unwrap());
+			if result.is_ok() {
+				result = done;
+			}
 		}
+		result
+	}
 
+	// Rounded up so the shards together get at least `total`
+	fn share(total: u64, shard_count: usize) -> u64 {
+		(total + shard_count as u64 - 1) / shard_count as u64
+	}
+}
+
+impl Drop for WhisperCache {
+	fn drop(&mut self) {
+		if let Err(err) = self.flush_all() {
+			warn!("could not flush the cache: {}", err);
+		}
 	}
 }
 
@@ -87,19 +481,543 @@ impl WhisperCache {
 mod test {
 	extern crate test;
 	use test::Bencher;
-	use whisper::{ WhisperCache, NamedPoint, Schema };
+	use whisper::{ WhisperCache, WhisperFile, WhisperError, NamedPoint, Schema, SchemaRules, AggregationRules, AggregationType, StorageRules, CacheStats, WriteBack, QueryCaching, TaggedSeries, TagIndex, RewriteRules, MetricFilter, RegexList, FilterStats, PathMapping, temp_path, current_time };
+	use std::time::Duration;
+	use std::env;
+	use std::fs;
+	use std::path::PathBuf;
+	use std::sync::{ Arc, mpsc };
+	use std::thread;
 
 	#[bench]
 	fn test_opening_new_whisper_file(b: &mut Bencher){
 		let default_specs = vec!["1s:60s".to_string(), "1m:1y".to_string()];
 		let schema = Schema::new_from_retention_specs(default_specs).unwrap();
 
-		let mut cache = WhisperCache::new("/tmp", 100, schema);
-		let current_time = 1434598525;
+		let cache = WhisperCache::new(env::temp_dir(), 100, schema);
+		let now = current_time();
 
 		b.iter(move ||{
-			let metric = NamedPoint::new("hey.there.bear".to_string(), current_time, 0.0);
+			let metric = NamedPoint::new("hey.there.bear".to_string(), now, 0.0);
 			cache.write(metric).unwrap();
 		});
 	}
+
+	#[test]
+	fn test_stats() {
+		let base_path = temp_path("test_cache_stats");
+		fs::remove_dir_all(&base_path).ok();
+		let schema = Schema::parse("10s:60s").unwrap();
+		// header + one archive info + 6 points
+		let file_size = 16 + 12 + 6*12;
+
+		let cache = WhisperCache::with_capacity(&base_path, schema, 2, Some(2 * file_size));
+		let now = current_time();
+		// c pushes out b, the least recently used
+		for name in &["a", "b", "a", "c", "a"] {
+			cache.write(NamedPoint::new(format!("stats.{}", name), now, 1.0)).unwrap();
+		}
+
+		assert_eq!(cache.stats(), CacheStats {
+			hits: 2,
+			misses: 3,
+			creates: 3,
+			evictions: 1,
+			replaced: 0,
+			open_files: 2,
+			resident_bytes: 2 * file_size,
+			pending_points: 0,
+			query_hits: 0,
+			query_misses: 0
+		});
+
+		// Over the byte budget before the capacity
+		let cache = WhisperCache::with_capacity(&base_path, Schema::parse("10s:60s").unwrap(), 10, Some(file_size));
+		cache.write(NamedPoint::new("stats.a".to_string(), now, 1.0)).unwrap();
+		cache.write(NamedPoint::new("stats.b".to_string(), now, 1.0)).unwrap();
+		assert_eq!(cache.stats().open_files, 1);
+		assert_eq!(cache.stats().evictions, 1);
+	}
+
+	#[test]
+	fn test_write_back() {
+		let base_path = PathBuf::from(temp_path("test_cache_write_back"));
+		fs::remove_dir_all(&base_path).ok();
+		let cache = WhisperCache::new(&base_path, 10, Schema::parse("10s:60s").unwrap());
+		cache.set_write_back(Some(WriteBack { max_points: 3, flush_interval: Duration::from_secs(3600) }));
+
+		let now = current_time();
+		let now = now - now % 10;
+		for i in 0..2 {
+			cache.write(NamedPoint::new("buffered.a".to_string(), now - 20 + i * 10, i as f64)).unwrap();
+		}
+		cache.write(NamedPoint::new("buffered.b".to_string(), now, 5.0)).unwrap();
+		assert_eq!(cache.stats().pending_points, 3);
+		assert!(!base_path.join("buffered/a.wsp").exists());
+
+		// The third point of a reaches the threshold
+		cache.write(NamedPoint::new("buffered.a".to_string(), now, 2.0)).unwrap();
+		assert_eq!(cache.stats().pending_points, 1);
+		let file = WhisperFile::open(base_path.join("buffered/a.wsp")).unwrap();
+		assert_eq!(file.fetch(now - 30, now).unwrap().values, vec![Some(0.0), Some(1.0), Some(2.0)]);
+
+		cache.flush_pending().unwrap();
+		assert_eq!(cache.stats().pending_points, 0);
+		let file = WhisperFile::open(base_path.join("buffered/b.wsp")).unwrap();
+		assert_eq!(file.fetch(now - 10, now).unwrap().values, vec![Some(5.0)]);
+
+		// An elapsed interval writes everything
+		cache.set_write_back(Some(WriteBack { max_points: 100, flush_interval: Duration::from_secs(0) }));
+		cache.write(NamedPoint::new("buffered.c".to_string(), now, 7.0)).unwrap();
+		assert_eq!(cache.stats().pending_points, 0);
+	}
+
+	#[test]
+	fn test_keep_points_that_fail_to_flush() {
+		let base_path = PathBuf::from(temp_path("test_cache_keep_points_that_fail_to_flush"));
+		fs::remove_dir_all(&base_path).ok();
+		fs::create_dir_all(&base_path).unwrap();
+		let cache = WhisperCache::new(&base_path, 10, Schema::parse("10s:60s").unwrap());
+		cache.set_write_back(Some(WriteBack { max_points: 100, flush_interval: Duration::from_secs(3600) }));
+
+		let now = current_time();
+		let now = now - now % 10;
+		// A file where the directory of the metric goes
+		fs::File::create(base_path.join("blocked")).unwrap();
+		cache.write(NamedPoint::new("blocked.a".to_string(), now - 10, 1.0)).unwrap();
+		cache.write(NamedPoint::new("blocked.a".to_string(), now, 2.0)).unwrap();
+		assert!(cache.flush_pending().is_err());
+		assert_eq!(cache.stats().pending_points, 2);
+
+		fs::remove_file(base_path.join("blocked")).unwrap();
+		cache.flush_pending().unwrap();
+		assert_eq!(cache.stats().pending_points, 0);
+		let file = WhisperFile::open(base_path.join("blocked/a.wsp")).unwrap();
+		assert_eq!(file.fetch(now - 20, now).unwrap().values, vec![Some(1.0), Some(2.0)]);
+	}
+
+	#[test]
+	fn test_flush_around_busy_file() {
+		let base_path = PathBuf::from(temp_path("test_cache_flush_around_busy_file"));
+		fs::remove_dir_all(&base_path).ok();
+		let now = current_time();
+		let now = now - now % 10;
+		let cache = Arc::new(WhisperCache::new(&base_path, 10, Schema::parse("10s:60s").unwrap()));
+		cache.set_write_back(Some(WriteBack { max_points: 100, flush_interval: Duration::from_millis(200) }));
+		cache.write(NamedPoint::new("busy.a".to_string(), now, 1.0)).unwrap();
+
+		// Held the way a long write of it would
+		let file = cache.shards[0].lock().unwrap().pending_file("busy.a").unwrap().unwrap();
+		let whisper_file = file.lock().unwrap();
+		thread::sleep(Duration::from_millis(250));
+		// Due, it flushes the whole shard and waits on the file of a
+		let flushing = {
+			let cache = cache.clone();
+			thread::spawn(move || cache.write(NamedPoint::new("busy.b".to_string(), now, 2.0)))
+		};
+		while cache.stats().pending_points < 2 {
+			thread::sleep(Duration::from_millis(1));
+		}
+		thread::sleep(Duration::from_millis(20));
+
+		// Another metric of the same shard is still taken
+		let (sender, receiver) = mpsc::channel();
+		{
+			let cache = cache.clone();
+			thread::spawn(move || sender.send(cache.write(NamedPoint::new("busy.c".to_string(), now, 3.0)).is_ok()).unwrap());
+		}
+		assert!(receiver.recv_timeout(Duration::from_secs(5)).unwrap());
+		assert_eq!(cache.stats().pending_points, 3);
+
+		drop(whisper_file);
+		flushing.join().unwrap().unwrap();
+		assert_eq!(cache.stats().pending_points, 1);
+		let file = WhisperFile::open(base_path.join("busy/b.wsp")).unwrap();
+		assert_eq!(file.fetch(now - 10, now).unwrap().values, vec![Some(2.0)]);
+	}
+
+	#[test]
+	fn test_flush_on_drop() {
+		let base_path = PathBuf::from(temp_path("test_cache_flush_on_drop"));
+		fs::remove_dir_all(&base_path).ok();
+		let now = current_time();
+		let now = now - now % 10;
+
+		{
+			let cache = WhisperCache::new(&base_path, 10, Schema::parse("10s:60s").unwrap());
+			cache.set_write_back(Some(WriteBack { max_points: 100, flush_interval: Duration::from_secs(3600) }));
+			cache.write(NamedPoint::new("dropped.a".to_string(), now, 1.0)).unwrap();
+		}
+		let file = WhisperFile::open(base_path.join("dropped/a.wsp")).unwrap();
+		assert_eq!(file.fetch(now - 10, now).unwrap().values, vec![Some(1.0)]);
+
+		let cache = WhisperCache::new(&base_path, 10, Schema::parse("10s:60s").unwrap());
+		cache.set_write_back(Some(WriteBack { max_points: 100, flush_interval: Duration::from_secs(3600) }));
+		cache.write(NamedPoint::new("dropped.b".to_string(), now, 2.0)).unwrap();
+		cache.close().unwrap();
+		let file = WhisperFile::open(base_path.join("dropped/b.wsp")).unwrap();
+		assert_eq!(file.fetch(now - 10, now).unwrap().values, vec![Some(2.0)]);
+	}
+
+	#[test]
+	fn test_reopen_closed_files() {
+		let base_path = PathBuf::from(temp_path("test_cache_reopen"));
+		fs::remove_dir_all(&base_path).ok();
+		let now = current_time();
+		let now = now - now % 10;
+
+		let cache = WhisperCache::with_capacity(&base_path, Schema::parse("10s:60s").unwrap(), 3, None);
+		for (i, name) in ["a", "b", "c"].iter().enumerate() {
+			cache.write(NamedPoint::new(format!("reopen.{}", name), now - 10, i as f64)).unwrap();
+		}
+
+		cache.set_max_open_files(1);
+		assert_eq!(cache.max_open_files(), 1);
+		assert_eq!(cache.stats().open_files, 1);
+		assert_eq!(cache.stats().evictions, 2);
+
+		// a was closed, it is opened again with what was written before
+		cache.write(NamedPoint::new("reopen.a".to_string(), now, 10.0)).unwrap();
+		cache.flush_all().unwrap();
+		let file = WhisperFile::open(base_path.join("reopen/a.wsp")).unwrap();
+		assert_eq!(file.fetch(now - 20, now).unwrap().values, vec![Some(0.0), Some(10.0)]);
+
+		cache.set_max_open_files(0);
+		assert_eq!(cache.max_open_files(), 1);
+	}
+
+	#[test]
+	fn test_shards() {
+		let base_path = PathBuf::from(temp_path("test_cache_shards"));
+		fs::remove_dir_all(&base_path).ok();
+		let now = current_time();
+
+		// 10 files don't split evenly, each shard gets 3
+		let cache = WhisperCache::with_shards(&base_path, Schema::parse("10s:60s").unwrap(), 10, None, 4);
+		assert_eq!(cache.max_open_files(), 12);
+
+		let cache = WhisperCache::with_shards(&base_path, Schema::parse("10s:60s").unwrap(), 100, None, 4);
+		assert_eq!(cache.shard_count(), 4);
+
+		for i in 0..20 {
+			cache.write(NamedPoint::new(format!("sharded.metric{}", i), now, 1.0)).unwrap();
+		}
+		cache.write(NamedPoint::new("sharded.metric0".to_string(), now, 2.0)).unwrap();
+
+		let shard_stats = cache.shard_stats();
+		assert_eq!(shard_stats.len(), 4);
+		assert!(shard_stats.iter().all(|stats| stats.open_files < 20));
+		let stats = cache.stats();
+		assert_eq!(stats.misses, 20);
+		assert_eq!(stats.hits, 1);
+		assert_eq!(stats.misses, shard_stats.iter().map(|stats| stats.misses).sum());
+		assert_eq!(stats.open_files, 20);
+	}
+
+	#[test]
+	fn test_concurrent_writes() {
+		let base_path = PathBuf::from(temp_path("test_cache_concurrent_writes"));
+		fs::remove_dir_all(&base_path).ok();
+		let now = current_time();
+
+		let cache = Arc::new(WhisperCache::with_shards(&base_path, Schema::parse("10s:60s").unwrap(), 100, None, 4));
+		let writers : Vec<_> = (0..4).map(|thread| {
+			let cache = cache.clone();
+			thread::spawn(move || {
+				for i in 0..10 {
+					cache.write(NamedPoint::new(format!("concurrent.thread{}.metric{}", thread, i), now, 1.0)).unwrap();
+				}
+			})
+		}).collect();
+		for writer in writers {
+			writer.join().unwrap();
+		}
+
+		assert_eq!(cache.stats().misses, 40);
+	}
+
+	#[test]
+	fn test_create_missing_files() {
+		let base_path = PathBuf::from(temp_path("test_cache_create_missing_files"));
+		fs::remove_dir_all(&base_path).ok();
+		let now = current_time();
+
+		let schema_rules = SchemaRules::parse("[carbon]\npattern = ^carbon\\.\nretentions = 60:90d\n").unwrap();
+		let aggregation_rules = AggregationRules::parse("[count]\npattern = \\.count$\naggregationMethod = sum\n").unwrap();
+		let cache = WhisperCache::new_with_resolver(&base_path, 10, StorageRules::new(schema_rules, aggregation_rules));
+
+		cache.write(NamedPoint::new("carbon.agents.host.count".to_string(), now, 1.0)).unwrap();
+		let file = WhisperFile::open(base_path.join("carbon/agents/host/count.wsp")).unwrap();
+		assert_eq!(file.info().archives[0].seconds_per_point, 60);
+		assert_eq!(file.info().aggregation_type, AggregationType::Sum);
+
+		// Opened, not created, the second time around
+		cache.write(NamedPoint::new("carbon.agents.host.count".to_string(), now, 2.0)).unwrap();
+		assert_eq!(cache.stats().creates, 1);
+
+		match cache.write(NamedPoint::new("servers.load".to_string(), now, 1.0)) {
+			Err(WhisperError::NoMatchingSchema(ref metric)) if metric == "servers.load" => (),
+			other => panic!("expected NoMatchingSchema, got {:?}", other)
+		}
+		assert!(!base_path.join("servers").exists());
+	}
+
+	#[test]
+	fn test_path_mapping() {
+		let base_path = PathBuf::from(temp_path("test_cache_path_mapping"));
+		fs::remove_dir_all(&base_path).ok();
+		let cache = WhisperCache::new(&base_path, 10, Schema::parse("10s:60s").unwrap());
+		let now = current_time();
+
+		for name in &["servers..load", "servers.../../escaped"] {
+			match cache.write(NamedPoint::new(name.to_string(), now, 1.0)) {
+				Err(WhisperError::InvalidMetricName(ref metric, _)) if metric == name => (),
+				other => panic!("expected InvalidMetricName, got {:?}", other)
+			}
+		}
+		assert!(!base_path.exists());
+
+		cache.set_path_mapping(PathMapping::escaping());
+		cache.write(NamedPoint::new("servers.../../escaped".to_string(), now, 1.0)).unwrap();
+		assert!(base_path.join("servers/%2F/%2Fescaped.wsp").is_file());
+		assert_eq!(cache.fetch("servers.../../escaped", now - 10, now).unwrap().values, vec![Some(1.0)]);
+		assert_eq!(cache.path_mapping(), PathMapping::escaping());
+	}
+
+	#[test]
+	#[cfg(unix)]
+	fn test_symlinks_out_of_data_root() {
+		let base = PathBuf::from(temp_path("test_cache_data_root"));
+		fs::remove_dir_all(&base).ok();
+		let (base_path, disk2) = (base.join("whisper"), base.join("disk2"));
+		fs::create_dir_all(&base_path).unwrap();
+		fs::create_dir_all(&disk2).unwrap();
+		::std::os::unix::fs::symlink(&disk2, base_path.join("apps")).unwrap();
+		let cache = WhisperCache::new(&base_path, 10, Schema::parse("10s:60s").unwrap());
+		let now = current_time();
+
+		match cache.write(NamedPoint::new("apps.requests".to_string(), now, 1.0)) {
+			Err(WhisperError::OutsideDataRoot(ref path)) => assert_eq!(*path, base_path.join("apps/requests.wsp")),
+			other => panic!("expected OutsideDataRoot, got {:?}", other)
+		}
+		assert!(!disk2.join("requests.wsp").exists());
+
+		cache.allow_symlinks_into(&disk2);
+		cache.write(NamedPoint::new("apps.requests".to_string(), now, 1.0)).unwrap();
+		assert!(disk2.join("requests.wsp").is_file());
+		assert_eq!(cache.data_root().symlink_targets(), &[disk2]);
+	}
+
+	#[test]
+	fn test_tagged_series() {
+		let base_path = PathBuf::from(temp_path("test_cache_tagged_series"));
+		fs::remove_dir_all(&base_path).ok();
+		let cache = WhisperCache::new(&base_path, 10, Schema::parse("10s:60s").unwrap());
+		let tag_index = Arc::new(TagIndex::new());
+		cache.set_tag_index(Some(tag_index.clone()));
+		let now = current_time();
+
+		cache.write(NamedPoint::new("disk.used;server=web01;datacenter=dc1".to_string(), now, 1.0)).unwrap();
+		cache.write(NamedPoint::new("disk.used;datacenter=dc1;server=web01".to_string(), now, 2.0)).unwrap();
+		cache.write(NamedPoint::new("disk.used".to_string(), now, 3.0)).unwrap();
+
+		assert_eq!(tag_index.find(&["server=web01"]).unwrap(), vec!["disk.used;datacenter=dc1;server=web01"]);
+		assert!(base_path.join(TaggedSeries::parse("disk.used;datacenter=dc1;server=web01").unwrap().rel_path()).is_file());
+		assert!(base_path.join("disk/used.wsp").is_file());
+		assert_eq!(cache.stats().creates, 2);
+	}
+
+	#[test]
+	fn test_rewrite_rules() {
+		let base_path = PathBuf::from(temp_path("test_cache_rewrite_rules"));
+		fs::remove_dir_all(&base_path).ok();
+		let schema_rules = SchemaRules::parse("[system]\npattern = \\.system\\.\nretentions = 10s:60s\n").unwrap();
+		let cache = WhisperCache::new_with_resolver(&base_path, 10, StorageRules::new(schema_rules, AggregationRules::default()));
+		cache.set_rewrite_rules(Some(Arc::new(RewriteRules::parse("[pre]\n^collectd_([a-z0-9]+)\\. = \\1.system.\n").unwrap())));
+		let now = current_time();
+		let now = now - now % 10;
+
+		// Resolved by its new name, which has a schema when the old one doesn't
+		cache.write(NamedPoint::new("collectd_web01.cpu".to_string(), now, 1.0)).unwrap();
+		assert!(base_path.join("web01/system/cpu.wsp").is_file());
+		assert!(!base_path.join("collectd_web01").exists());
+		assert_eq!(cache.fetch("web01.system.cpu", now - 10, now).unwrap().values, vec![Some(1.0)]);
+	}
+
+	#[test]
+	fn test_metric_filter() {
+		let base_path = PathBuf::from(temp_path("test_cache_metric_filter"));
+		fs::remove_dir_all(&base_path).ok();
+		let cache = WhisperCache::new(&base_path, 10, Schema::parse("10s:60s").unwrap());
+		let metric_filter = Arc::new(MetricFilter::new(RegexList::parse("^servers\\.\n").unwrap(), RegexList::parse("\\.tmp$\n").unwrap()));
+		cache.set_metric_filter(Some(metric_filter.clone()));
+		let now = current_time();
+
+		for name in &["servers.load", "servers.tmp", "apps.load"] {
+			cache.write(NamedPoint::new(name.to_string(), now, 1.0)).unwrap();
+		}
+		assert!(base_path.join("servers/load.wsp").is_file());
+		assert!(!base_path.join("servers/tmp.wsp").exists());
+		assert!(!base_path.join("apps").exists());
+		assert_eq!(metric_filter.stats(), FilterStats { accepted: 1, whitelist_rejects: 1, blacklist_matches: 1 });
+		assert_eq!(cache.stats().creates, 1);
+	}
+
+	#[test]
+	fn test_metrics() {
+		let base_path = PathBuf::from(temp_path("test_cache_metrics"));
+		fs::remove_dir_all(&base_path).ok();
+		let now = current_time();
+		let now = now - now % 10;
+
+		let cache = WhisperCache::with_shards(&base_path, Schema::parse("10s:60s").unwrap(), 10, None, 2);
+		cache.write(NamedPoint::new("metrics.direct".to_string(), now, 1.0)).unwrap();
+		cache.set_write_back(Some(WriteBack { max_points: 2, flush_interval: Duration::from_secs(3600) }));
+		for i in 0..3 {
+			cache.write(NamedPoint::new("metrics.buffered".to_string(), now - i * 10, 1.0)).unwrap();
+		}
+
+		let metrics = cache.metrics();
+		assert_eq!(metrics.points_received, 4);
+		assert_eq!(metrics.points_persisted, 3);
+		assert_eq!(metrics.pending_points, 1);
+		assert_eq!(metrics.creates, 2);
+		assert_eq!(metrics.flushes, 1);
+		assert!(metrics.flush_time_max >= metrics.flush_time_p50);
+	}
+
+	#[test]
+	fn test_coalesce_pending_points() {
+		let base_path = PathBuf::from(temp_path("test_cache_coalesce"));
+		fs::remove_dir_all(&base_path).ok();
+		let now = current_time();
+		let now = now - now % 10;
+
+		let cache = WhisperCache::new(&base_path, 10, Schema::parse("10s:60s").unwrap());
+		cache.set_write_back(Some(WriteBack { max_points: 100, flush_interval: Duration::from_secs(3600) }));
+		cache.set_coalesce_policy(AggregationType::Sum);
+		for &(timestamp, value) in &[(now - 20, 1.0), (now - 10, 2.0), (now - 15, 3.0), (now - 5, 4.0)] {
+			cache.write(NamedPoint::new("coalesced.a".to_string(), timestamp, value)).unwrap();
+		}
+		cache.flush_pending().unwrap();
+
+		let file = WhisperFile::open(base_path.join("coalesced/a.wsp")).unwrap();
+		assert_eq!(file.fetch(now - 30, now - 10).unwrap().values, vec![Some(4.0), Some(6.0)]);
+		assert_eq!(cache.metrics().points_persisted, 4);
+	}
+
+	#[test]
+	fn test_reopen_replaced_files() {
+		let base_path = PathBuf::from(temp_path("test_cache_reopen_replaced"));
+		fs::remove_dir_all(&base_path).ok();
+		let now = current_time();
+		let now = now - now % 10;
+		let path = base_path.join("replaced/a.wsp");
+
+		let cache = WhisperCache::new(&base_path, 10, Schema::parse("10s:60s").unwrap());
+		cache.set_replaced_check_interval(Some(Duration::from_secs(0)));
+		cache.write(NamedPoint::new("replaced.a".to_string(), now - 10, 1.0)).unwrap();
+
+		// Swapped for a file with another layout, the way whisper-resize.py would
+		let replacement = path.with_extension("new");
+		WhisperFile::new(&replacement, &Schema::parse("10s:120s").unwrap()).unwrap();
+		fs::rename(&replacement, &path).unwrap();
+
+		cache.write(NamedPoint::new("replaced.a".to_string(), now, 2.0)).unwrap();
+		assert_eq!(cache.stats().replaced, 1);
+		let file = WhisperFile::open(&path).unwrap();
+		assert_eq!(file.info().max_retention, 120);
+		assert_eq!(file.fetch(now - 20, now).unwrap().values, vec![None, Some(2.0)]);
+
+		// Removed altogether, it's created again
+		fs::remove_file(&path).unwrap();
+		cache.write(NamedPoint::new("replaced.a".to_string(), now, 3.0)).unwrap();
+		assert_eq!(cache.stats().replaced, 2);
+		assert_eq!(WhisperFile::open(&path).unwrap().info().max_retention, 60);
+	}
+
+	#[test]
+	fn test_fetch_pending_points() {
+		let base_path = PathBuf::from(temp_path("test_cache_fetch"));
+		fs::remove_dir_all(&base_path).ok();
+		let now = current_time();
+		let now = now - now % 10;
+
+		let cache = WhisperCache::new(&base_path, 10, Schema::parse("10s:60s").unwrap());
+		assert!(cache.fetch("fetched.a", now - 30, now).is_err());
+		assert!(!base_path.join("fetched/a.wsp").exists());
+
+		cache.set_write_back(Some(WriteBack { max_points: 100, flush_interval: Duration::from_secs(3600) }));
+		cache.write(NamedPoint::new("fetched.a".to_string(), now - 20, 1.0)).unwrap();
+		// No file yet, the pending points are written to have one
+		assert_eq!(cache.fetch("fetched.a", now - 30, now).unwrap().values, vec![Some(1.0), None, None]);
+
+		cache.write(NamedPoint::new("fetched.a".to_string(), now - 20, 5.0)).unwrap();
+		cache.write(NamedPoint::new("fetched.a".to_string(), now, 2.0)).unwrap();
+		assert_eq!(cache.fetch("fetched.a", now - 30, now).unwrap().values, vec![Some(5.0), None, Some(2.0)]);
+		assert_eq!(cache.stats().pending_points, 2);
+	}
+
+	#[test]
+	fn test_query_caching() {
+		let base_path = PathBuf::from(temp_path("test_cache_query_caching"));
+		fs::remove_dir_all(&base_path).ok();
+		let now = current_time();
+		let now = now - now % 10;
+
+		let cache = WhisperCache::new(&base_path, 10, Schema::parse("10s:60s").unwrap());
+		cache.set_query_caching(Some(QueryCaching { max_metrics: 10, ttl: Duration::from_secs(3600) }));
+		cache.write(NamedPoint::new("queried.a".to_string(), now, 1.0)).unwrap();
+
+		cache.fetch("queried.a", now - 30, now).unwrap();
+		// Changed behind the cache's back, the cached result is still served
+		WhisperFile::open(base_path.join("queried/a.wsp")).unwrap().write(&::whisper::Point(now, 7.0)).unwrap();
+		assert_eq!(cache.fetch("queried.a", now - 30, now).unwrap().values[2], Some(1.0));
+		assert_eq!((cache.stats().query_hits, cache.stats().query_misses), (1, 1));
+
+		// A write through the cache drops it
+		cache.write(NamedPoint::new("queried.a".to_string(), now - 10, 2.0)).unwrap();
+		assert_eq!(cache.fetch("queried.a", now - 30, now).unwrap().values, vec![None, Some(2.0), Some(7.0)]);
+		assert_eq!(cache.stats().query_misses, 2);
+	}
+
+	#[test]
+	fn test_send_sync() {
+		fn assert_send_sync<T: Send + Sync>() {}
+		assert_send_sync::<WhisperCache>();
+	}
+
+	#[test]
+	fn test_concurrent_write_back() {
+		let base_path = PathBuf::from(temp_path("test_cache_concurrent_write_back"));
+		fs::remove_dir_all(&base_path).ok();
+		let now = current_time();
+		let now = now - now % 10;
+
+		// A single shard, so the writers and the flushing thread all share it
+		let cache = Arc::new(WhisperCache::new(&base_path, 100, Schema::parse("10s:60s").unwrap()));
+		cache.set_write_back(Some(WriteBack { max_points: 3, flush_interval: Duration::from_secs(3600) }));
+		let threads : Vec<_> = (0..4).map(|thread| {
+			let cache = cache.clone();
+			thread::spawn(move || {
+				for i in 0..50 {
+					if thread == 0 {
+						cache.flush_pending().unwrap();
+					} else {
+						cache.write(NamedPoint::new(format!("concurrent.thread{}", thread), now - (i % 5) * 10, thread as f64)).unwrap();
+					}
+				}
+			})
+		}).collect();
+		for thread in threads {
+			thread.join().unwrap();
+		}
+		cache.flush_pending().unwrap();
+
+		let metrics = cache.metrics();
+		assert_eq!(metrics.points_received, 150);
+		assert_eq!(metrics.points_persisted, 150);
+		let file = WhisperFile::open(base_path.join("concurrent/thread2.wsp")).unwrap();
+		assert_eq!(file.fetch(now - 50, now).unwrap().values, vec![Some(2.0); 5]);
+	}
 }
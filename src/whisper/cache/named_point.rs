@@ -2,15 +2,24 @@ use std::path::PathBuf;
 use std::str;
 
 use whisper::Point;
+use whisper::{ TaggedSeries, PathMapping };
+use whisper::error;
 
-#[derive(PartialEq,Debug)]
+#[derive(PartialEq,Debug,Clone)]
 pub struct NamedPoint {
 	metric_name: String,
 	point: Point
 }
 
 impl NamedPoint {
+	// Tagged names are kept normalized, tags sorted, so one series always has
+	// the same name whatever order its tags came in
 	pub fn new(name: String, timestamp: u32, value: f64) -> NamedPoint {
+		let name = if TaggedSeries::is_tagged(&name) {
+			TaggedSeries::parse(&name).map(|series| series.path()).unwrap_or(name)
+		} else {
+			name
+		};
 		NamedPoint {
 			metric_name: name,
 			point: Point(timestamp, value)
@@ -68,12 +77,21 @@ impl NamedPoint {
         Ok(msg)
     }
 
-	pub fn rel_path(&self) -> PathBuf {
+	pub fn rel_path(&self) -> error::Result<PathBuf> {
         // Would love to have the NamedPoint keep the UDP datagram or whatever around.
         // But easier to copy that string to this `metric_name` and carry on!
-        let mut rel_path : String = self.metric_name.replace(".","/");
-        rel_path.push_str(".wsp");
-        PathBuf::from(rel_path)
+        NamedPoint::rel_path_for(&self.metric_name)
+	}
+
+	// Where the file of `metric_name` goes with the default PathMapping,
+	// relative to the cache's base path. Tagged series are hashed under
+	// `_tagged/` the way carbon lays them out.
+	pub fn rel_path_for(metric_name: &str) -> error::Result<PathBuf> {
+        PathMapping::default().rel_path(metric_name)
+	}
+
+	pub fn metric_name(&self) -> &str {
+		&self.metric_name
 	}
 
 	pub fn point(&self) -> &Point {
@@ -132,6 +150,17 @@ mod tests {
     	assert_eq!(msgs_opt.unwrap(), expected);
     }
 
+    #[test]
+    fn test_tagged_datagram_line() {
+        let msg = NamedPoint::parse_line("disk.used;server=web01;datacenter=dc1 5 1434598525").unwrap();
+        assert_eq!(msg.metric_name(), "disk.used;datacenter=dc1;server=web01");
+        assert_eq!(msg.rel_path().unwrap(), TaggedSeries::parse("disk.used;datacenter=dc1;server=web01").unwrap().rel_path());
+        assert!(msg.rel_path().unwrap().starts_with("_tagged"));
+
+        assert_eq!(NamedPoint::rel_path_for("disk.used").unwrap(), PathBuf::from("disk/used.wsp"));
+        assert!(NamedPoint::rel_path_for("disk..used").is_err());
+    }
+
     #[bench]
     fn bench_bad_datagram(b: &mut Bencher){
         let datagram = "home.pets.monkeys.squeeky.squeeks nan";
@@ -0,0 +1,86 @@
+use std::collections::HashMap;
+use std::cmp;
+use std::time::{ Duration, Instant };
+use lru_cache::LruCache;
+
+use whisper::TimeSeries;
+
+// How a WhisperCache keeps the results of fetch around for repeated queries.
+// Results of the `max_metrics` most recently fetched metrics are kept for `ttl`,
+// or until their metric is written to again.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct QueryCaching {
+	pub max_metrics: usize,
+	pub ttl: Duration
+}
+
+// By metric name, then (from, until). The step is whatever archive these
+// picked, so it's not part of the key.
+pub struct QueryCache {
+	ttl: Duration,
+	results: LruCache< String, HashMap<(u32, u32), (TimeSeries, Instant)> >
+}
+
+impl QueryCache {
+	pub fn new(query_caching: QueryCaching) -> QueryCache {
+		QueryCache {
+			ttl: query_caching.ttl,
+			results: LruCache::new(cmp::max(query_caching.max_metrics, 1))
+		}
+	}
+
+	pub fn get(&mut self, metric_name: &str, from: u32, until: u32) -> Option<TimeSeries> {
+		let ttl = self.ttl;
+		self.results.get_mut(metric_name)
+			.and_then(|results| results.get(&(from, until)))
+			.and_then(|&(ref series, cached_at)| if cached_at.elapsed() < ttl { Some(series.clone()) } else { None })
+	}
+
+	pub fn insert(&mut self, metric_name: &str, from: u32, until: u32, series: TimeSeries) {
+		if !self.results.contains_key(metric_name) {
+			self.results.insert(metric_name.to_string(), HashMap::new());
+		}
+		let ttl = self.ttl;
+		let results = self.results.get_mut(metric_name).unwrap();
+		// Whatever else expired goes now, so forgotten ranges don't pile up
+		results.retain(|_, &mut (_, cached_at)| cached_at.elapsed() < ttl);
+		results.insert((from, until), (series, Instant::now()));
+	}
+
+	pub fn invalidate(&mut self, metric_name: &str) {
+		self.results.remove(metric_name);
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::thread;
+
+	#[test]
+	fn test_query_cache() {
+		let series = TimeSeries { from: 100, until: 120, step: 10, values: vec![Some(1.0), None] };
+		let mut query_cache = QueryCache::new(QueryCaching { max_metrics: 1, ttl: Duration::from_secs(3600) });
+
+		query_cache.insert("a", 90, 120, series.clone());
+		assert_eq!(query_cache.get("a", 90, 120), Some(series.clone()));
+		assert_eq!(query_cache.get("a", 90, 130), None);
+
+		// Only one metric is kept
+		query_cache.insert("b", 90, 120, series.clone());
+		assert_eq!(query_cache.get("a", 90, 120), None);
+
+		query_cache.invalidate("b");
+		assert_eq!(query_cache.get("b", 90, 120), None);
+	}
+
+	#[test]
+	fn test_query_cache_ttl() {
+		let series = TimeSeries { from: 100, until: 120, step: 10, values: vec![Some(1.0), None] };
+		let mut query_cache = QueryCache::new(QueryCaching { max_metrics: 10, ttl: Duration::from_millis(10) });
+
+		query_cache.insert("a", 90, 120, series);
+		thread::sleep(Duration::from_millis(20));
+		assert_eq!(query_cache.get("a", 90, 120), None);
+	}
+}
@@ -0,0 +1,375 @@
+use std::cmp;
+use std::collections::VecDeque;
+use std::io::{ self, Read, Write };
+use std::net::TcpStream;
+use std::sync::{ Arc, Mutex, Condvar };
+use std::thread;
+use std::time::Duration;
+
+use byteorder::{ ByteOrder, BigEndian, WriteBytesExt };
+
+use whisper::{ WhisperCache, Point };
+use super::NamedPoint;
+
+// Points written to the socket at once
+const BATCH_SIZE : usize = 500;
+
+// The name length, the longest name it counts and the point
+const MAX_FRAME_LENGTH : usize = 2 + u16::MAX as usize + 4 + 8;
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum ReplicaProtocol {
+	// carbon's plaintext `metric value timestamp` lines, for a TcpReceiver or
+	// any carbon daemon
+	Line,
+	// Frames of a 4 byte length and then the name length in 2 bytes, the name,
+	// the timestamp in 4 bytes and the value as a 64 bit float, all big
+	// endian. Values go through exactly, `read_framed` takes them in. Names
+	// longer than 65535 bytes are refused.
+	Framed
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct ReplicaConfig {
+	// Points held while the follower is away, the oldest dropped beyond that
+	pub max_buffered: usize,
+	// Waited after a failed connection, doubling up to `max_reconnect_interval`
+	pub reconnect_interval: Duration,
+	pub max_reconnect_interval: Duration,
+	// A follower that takes no bytes for that long is given up on and
+	// reconnected to
+	pub write_timeout: Duration
+}
+
+impl Default for ReplicaConfig {
+	fn default() -> ReplicaConfig {
+		ReplicaConfig {
+			max_buffered: 100000,
+			reconnect_interval: Duration::from_millis(500),
+			max_reconnect_interval: Duration::from_secs(30),
+			write_timeout: Duration::from_secs(10)
+		}
+	}
+}
+
+#[derive(Debug, Default, PartialEq, Clone)]
+pub struct ReplicaStats {
+	pub points_sent: u64,
+	// Pushed out of a full buffer
+	pub points_dropped: u64,
+	// Named longer than a frame holds
+	pub points_refused: u64,
+	pub buffered: usize,
+	pub connected: bool,
+	pub connections: u64,
+	pub connection_failures: u64
+}
+
+struct State {
+	buffer: VecDeque<(String, Point)>,
+	stats: ReplicaStats,
+	stopped: bool
+}
+
+// A follower points are shipped to by a thread of its own, buffered while it
+// can't be reached and sent on once it's back
+pub struct Replica {
+	address: String,
+	protocol: ReplicaProtocol,
+	config: ReplicaConfig,
+	state: Arc< (Mutex<State>, Condvar) >
+}
+
+impl Replica {
+	pub fn start(address: &str, protocol: ReplicaProtocol, config: ReplicaConfig) -> Replica {
+		let state = Arc::new((Mutex::new(State { buffer: VecDeque::new(), stats: ReplicaStats::default(), stopped: false }), Condvar::new()));
+		{
+			let (address, state) = (address.to_string(), state.clone());
+			thread::spawn(move || ship(&address, protocol, &config, &state));
+		}
+		Replica { address: address.to_string(), protocol: protocol, config: config, state: state }
+	}
+
+	pub fn address(&self) -> &str {
+		&self.address
+	}
+
+	pub fn protocol(&self) -> ReplicaProtocol {
+		self.protocol
+	}
+
+	pub fn stats(&self) -> ReplicaStats {
+		let state = self.state.0.lock().unwrap();
+		ReplicaStats { buffered: state.buffer.len(), .. state.stats.clone() }
+	}
+
+	pub fn send(&self, metric_name: &str, point: &Point) {
+		let &(ref state, ref wake) = &*self.state;
+		let mut state = state.lock().unwrap();
+		if self.protocol == ReplicaProtocol::Framed && metric_name.len() > u16::MAX as usize {
+			state.stats.points_refused = state.stats.points_refused + 1;
+			return;
+		}
+		if state.buffer.len() >= self.config.max_buffered {
+			state.buffer.pop_front();
+			state.stats.points_dropped = state.stats.points_dropped + 1;
+		}
+		state.buffer.push_back((metric_name.to_string(), *point));
+		wake.notify_one();
+	}
+}
+
+// The thread sends on what's buffered as long as the follower takes it, and
+// stops at the first failure. Dropping doesn't wait for it.
+impl Drop for Replica {
+	fn drop(&mut self) {
+		let &(ref state, ref wake) = &*self.state;
+		state.lock().unwrap().stopped = true;
+		wake.notify_one();
+	}
+}
+
+// Every point a cache writes, shipped to each of the followers
+pub struct Replication {
+	replicas: Vec<Replica>
+}
+
+impl Replication {
+	pub fn new(replicas: Vec<Replica>) -> Replication {
+		Replication { replicas: replicas }
+	}
+
+	pub fn replicas(&self) -> &[Replica] {
+		&self.replicas
+	}
+
+	pub fn replicate(&self, named_point: &NamedPoint) {
+		for replica in &self.replicas {
+			replica.send(named_point.metric_name(), named_point.point());
+		}
+	}
+}
+
+fn ship(address: &str, protocol: ReplicaProtocol, config: &ReplicaConfig, state: &(Mutex<State>, Condvar)) {
+	let &(ref state, ref wake) = state;
+	let mut backoff = config.reconnect_interval;
+	loop {
+		let mut stream = match TcpStream::connect(address).and_then(|stream| stream.set_write_timeout(Some(config.write_timeout)).map(|_| stream)) {
+			Ok(stream) => stream,
+			Err(err) => {
+				let mut locked = state.lock().unwrap();
+				locked.stats.connection_failures = locked.stats.connection_failures + 1;
+				if locked.stopped {
+					return;
+				}
+				debug!("could not connect to replica {}: {}", address, err);
+				drop(wake.wait_timeout(locked, backoff).unwrap());
+				backoff = cmp::min(backoff * 2, config.max_reconnect_interval);
+				continue;
+			}
+		};
+		backoff = config.reconnect_interval;
+		{
+			let mut locked = state.lock().unwrap();
+			locked.stats.connections = locked.stats.connections + 1;
+			locked.stats.connected = true;
+		}
+
+		loop {
+			let batch : Vec<(String, Point)> = {
+				let mut locked = state.lock().unwrap();
+				while locked.buffer.is_empty() && !locked.stopped {
+					locked = wake.wait(locked).unwrap();
+				}
+				if locked.buffer.is_empty() {
+					return;
+				}
+				let size = cmp::min(locked.buffer.len(), BATCH_SIZE);
+				locked.buffer.drain(..size).collect()
+			};
+
+			let mut bytes = vec![];
+			for &(ref metric_name, ref point) in &batch {
+				encode(protocol, metric_name, point, &mut bytes);
+			}
+
+			let mut locked = match stream.write_all(&bytes) {
+				Ok(()) => {
+					let mut locked = state.lock().unwrap();
+					locked.stats.points_sent = locked.stats.points_sent + batch.len() as u64;
+					continue;
+				},
+				Err(err) => {
+					warn!("lost replica {}: {}", address, err);
+					state.lock().unwrap()
+				}
+			};
+			// Back in front of what came since, for the next connection
+			for point in batch.into_iter().rev() {
+				if locked.buffer.len() >= config.max_buffered {
+					locked.stats.points_dropped = locked.stats.points_dropped + 1;
+					continue;
+				}
+				locked.buffer.push_front(point);
+			}
+			locked.stats.connected = false;
+			if locked.stopped {
+				return;
+			}
+			break;
+		}
+	}
+}
+
+fn encode(protocol: ReplicaProtocol, metric_name: &str, point: &Point, bytes: &mut Vec<u8>) {
+	match protocol {
+		ReplicaProtocol::Line => {
+			bytes.extend(format!("{} {} {}\n", metric_name, point.1, point.0).into_bytes());
+		},
+		ReplicaProtocol::Framed => {
+			let name = metric_name.as_bytes();
+			bytes.write_u32::<BigEndian>(2 + name.len() as u32 + 4 + 8).unwrap();
+			bytes.write_u16::<BigEndian>(name.len() as u16).unwrap();
+			bytes.extend(name);
+			bytes.write_u32::<BigEndian>(point.0).unwrap();
+			bytes.write_f64::<BigEndian>(point.1).unwrap();
+		}
+	}
+}
+
+// The follower's side of ReplicaProtocol::Framed: writes every frame read to
+// `cache` until the stream ends, returning how many were
+pub fn read_framed<R>(mut reader: R, cache: &WhisperCache) -> io::Result<u64>
+	where R: Read {
+	let mut received = 0;
+	let mut length = [0u8; 4];
+	let invalid = || io::Error::new(io::ErrorKind::InvalidData, "invalid replication frame");
+	loop {
+		match reader.read_exact(&mut length) {
+			Ok(()) => (),
+			Err(ref err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(received),
+			Err(err) => return Err(err)
+		}
+		// Checked before anything is allocated for it
+		let frame_length = BigEndian::read_u32(&length) as usize;
+		if frame_length > MAX_FRAME_LENGTH {
+			return Err(invalid());
+		}
+		let mut frame = vec![0u8; frame_length];
+		try!( reader.read_exact(&mut frame) );
+
+		if frame.len() < 2 {
+			return Err(invalid());
+		}
+		let name_length = BigEndian::read_u16(&frame[..2]) as usize;
+		if frame.len() != 2 + name_length + 12 {
+			return Err(invalid());
+		}
+		let name = try!( String::from_utf8(frame[2..2+name_length].to_vec()).map_err(|_| invalid()) );
+		let timestamp = BigEndian::read_u32(&frame[2+name_length..]);
+		let value = BigEndian::read_f64(&frame[2+name_length+4..]);
+
+		if let Err(err) = cache.write(NamedPoint::new(name, timestamp, value)) {
+			warn!("could not write a replicated point: {}", err);
+		}
+		received = received + 1;
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use whisper::{ Schema, temp_path, current_time };
+	use std::fs;
+	use std::io::{ BufRead, BufReader };
+	use std::net::TcpListener;
+
+	fn wait_for<F>(condition: F) where F: Fn() -> bool {
+		for _ in 0..500 {
+			if condition() {
+				return;
+			}
+			thread::sleep(Duration::from_millis(10));
+		}
+		panic!("timed out");
+	}
+
+	#[test]
+	fn test_line_replica() {
+		let base_path = temp_path("test_cache_line_replica");
+		fs::remove_dir_all(&base_path).ok();
+		let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+		let address = listener.local_addr().unwrap().to_string();
+
+		let cache = WhisperCache::new(&base_path, 10, Schema::parse("10s:60s").unwrap());
+		let replication = Arc::new(Replication::new(vec![Replica::start(&address, ReplicaProtocol::Line, ReplicaConfig::default())]));
+		cache.set_replication(Some(replication.clone()));
+		let now = current_time();
+		let now = now - now % 10;
+		cache.write(NamedPoint::new("replicated.a".to_string(), now, 1.5)).unwrap();
+		cache.write(NamedPoint::new("replicated.b".to_string(), now, 2.0)).unwrap();
+
+		let (stream, _) = listener.accept().unwrap();
+		let lines : Vec<String> = BufReader::new(stream).lines().take(2).map(|line| line.unwrap()).collect();
+		assert_eq!(lines, vec![format!("replicated.a 1.5 {}", now), format!("replicated.b 2 {}", now)]);
+		wait_for(|| replication.replicas()[0].stats().points_sent == 2);
+	}
+
+	#[test]
+	fn test_framed_replica_reconnects() {
+		let base_path = temp_path("test_cache_framed_replica");
+		fs::remove_dir_all(&base_path).ok();
+		// Nothing listens on it at first
+		let address = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+		let config = ReplicaConfig { max_buffered: 2, reconnect_interval: Duration::from_millis(10), max_reconnect_interval: Duration::from_millis(20), write_timeout: Duration::from_secs(10) };
+		let replica = Replica::start(&address.to_string(), ReplicaProtocol::Framed, config);
+		let now = current_time();
+		let now = now - now % 10;
+		for value in 0..3 {
+			replica.send("replicated.c", &Point(now - 10 * value, value as f64 + 0.25));
+		}
+		wait_for(|| replica.stats().connection_failures > 0);
+		assert_eq!(replica.stats().points_dropped, 1);
+		assert_eq!(replica.stats().buffered, 2);
+
+		let listener = TcpListener::bind(address).unwrap();
+		let follower = WhisperCache::new(&base_path, 10, Schema::parse("10s:60s").unwrap());
+		let (stream, _) = listener.accept().unwrap();
+		wait_for(|| replica.stats().points_sent == 2);
+		drop(replica);
+		assert_eq!(read_framed(stream, &follower).unwrap(), 2);
+		assert_eq!(follower.fetch("replicated.c", now - 30, now).unwrap().values, vec![Some(2.25), Some(1.25), None]);
+	}
+
+	#[test]
+	fn test_frame_limits() {
+		let base_path = temp_path("test_cache_frame_limits");
+		fs::remove_dir_all(&base_path).ok();
+		let address = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+		let replica = Replica::start(&address.to_string(), ReplicaProtocol::Framed, ReplicaConfig::default());
+		replica.send(&"x".repeat(70000), &Point(1440392100, 1.0));
+		assert_eq!((replica.stats().points_refused, replica.stats().buffered), (1, 0));
+
+		let follower = WhisperCache::new(&base_path, 10, Schema::parse("10s:60s").unwrap());
+		let err = read_framed(&[0xff, 0xff, 0xff, 0xff][..], &follower).unwrap_err();
+		assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+	}
+
+	#[test]
+	fn test_stalled_follower() {
+		let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+		let config = ReplicaConfig { write_timeout: Duration::from_millis(100), .. ReplicaConfig::default() };
+		let replica = Replica::start(&listener.local_addr().unwrap().to_string(), ReplicaProtocol::Line, config);
+		// Taken, never read: more than the socket buffers hold
+		let (_stream, _) = listener.accept().unwrap();
+		let metric_name = format!("replicated.{}", "x".repeat(200));
+		for value in 0..50000 {
+			replica.send(&metric_name, &Point(1440392100, value as f64));
+		}
+		wait_for(|| replica.stats().connections >= 2);
+
+		let started = ::std::time::Instant::now();
+		drop(replica);
+		assert!(started.elapsed() < Duration::from_secs(1));
+	}
+}
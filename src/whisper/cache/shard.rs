@@ -0,0 +1,494 @@
+use whisper::{ WhisperFile, SchemaResolver, AggregationType, TimeSeries, Point, PathMapping, DataRoot, MetricIndex };
+use whisper::error::{ Result, WhisperError };
+use std::path::{ Path, PathBuf };
+use std::fs::{ self, DirBuilder };
+use std::sync::{ Arc, Mutex };
+use std::collections::HashMap;
+use std::cmp;
+use std::time::{ Duration, Instant };
+use lru_cache::LruCache;
+
+use super::{ NamedPoint, CacheStats, WriteBack };
+use super::write_back::{ self, PendingPoints };
+use super::metrics::FlushTimes;
+use super::query_cache::{ QueryCache, QueryCaching };
+
+pub type WhisperMutex = Arc<Mutex<WhisperFile>>;
+
+// Points taken out of a shard to be written to their file, which can happen
+// once the shard is unlocked
+pub struct Flush {
+	rel_path: PathBuf,
+	points: Vec<Point>,
+	coalesce_policy: AggregationType
+}
+
+// What a point taken with write-back leaves to be written out
+pub enum FlushDue {
+	Metric(String),
+	All
+}
+
+impl Flush {
+	pub fn write(&self, whisper_file: &mut WhisperFile) -> Result<()> {
+		// Archives go from finest to coarsest
+		let step = whisper_file.archives()[0].seconds_per_point();
+		whisper_file.update_many(&write_back::coalesce(&self.points, step, &self.coalesce_policy))
+	}
+}
+
+#[cfg(unix)]
+use std::os::unix::fs::MetadataExt;
+
+// An open file, how many bytes of it are mapped and what it was on disk
+struct OpenFile {
+	file: WhisperMutex,
+	size: u64,
+	identity: Option<FileIdentity>,
+	checked_at: Instant
+}
+
+// What tells a file apart from another one put in its place, by a resize or
+// a merge run by hand for instance
+#[derive(Debug, PartialEq)]
+struct FileIdentity {
+	device: u64,
+	inode: u64,
+	size: u64
+}
+
+impl FileIdentity {
+	#[cfg(unix)]
+	fn of(path: &Path) -> Option<FileIdentity> {
+		fs::metadata(path).ok().map(|metadata| FileIdentity { device: metadata.dev(), inode: metadata.ino(), size: metadata.len() })
+	}
+
+	#[cfg(not(unix))]
+	fn of(path: &Path) -> Option<FileIdentity> {
+		fs::metadata(path).ok().map(|metadata| FileIdentity { device: 0, inode: 0, size: metadata.len() })
+	}
+}
+
+// The files and pending points of the metrics whose names hash to one shard,
+// with its own share of the cache's budgets
+pub struct Shard {
+	data_root: DataRoot,
+	open_files: LruCache< PathBuf, OpenFile >,
+	resolver: Arc<dyn SchemaResolver>,
+	max_resident_bytes: Option<u64>,
+	stats: CacheStats,
+	write_back: Option<WriteBack>,
+	max_pending_points: Option<usize>,
+	coalesce_policy: AggregationType,
+	replaced_check_interval: Option<Duration>,
+	query_cache: Option<QueryCache>,
+	metric_index: Option< Arc<MetricIndex> >,
+	// By metric name
+	pending: HashMap<String, PendingPoints>,
+	pending_points: usize,
+	last_flush: Instant,
+	points_received: u64,
+	points_persisted: u64,
+	flushes: u64,
+	flush_times: FlushTimes
+}
+
+impl Shard {
+	pub fn new(base_path: PathBuf, resolver: Arc<dyn SchemaResolver>, max_open_files: usize, max_resident_bytes: Option<u64>) -> Shard {
+		Shard {
+			data_root: DataRoot::new(base_path),
+			open_files: LruCache::new(cmp::max(max_open_files, 1)),
+			resolver: resolver,
+			max_resident_bytes: max_resident_bytes,
+			stats: CacheStats::default(),
+			write_back: None,
+			max_pending_points: None,
+			coalesce_policy: AggregationType::Last,
+			replaced_check_interval: None,
+			query_cache: None,
+			metric_index: None,
+			pending: HashMap::new(),
+			pending_points: 0,
+			last_flush: Instant::now(),
+			points_received: 0,
+			points_persisted: 0,
+			flushes: 0,
+			flush_times: FlushTimes::new()
+		}
+	}
+
+	pub fn stats(&self) -> CacheStats {
+		CacheStats {
+			open_files: self.open_files.len(),
+			pending_points: self.pending_points,
+			.. self.stats.clone()
+		}
+	}
+
+	// (points received, points persisted, flushes, latest flush times)
+	pub fn metrics(&self) -> (u64, u64, u64, Vec<Duration>) {
+		(self.points_received, self.points_persisted, self.flushes, self.flush_times.samples())
+	}
+
+	pub fn max_open_files(&self) -> usize {
+		self.open_files.capacity()
+	}
+
+	pub fn set_max_open_files(&mut self, max_open_files: usize) {
+		let max_open_files = cmp::max(max_open_files, 1);
+		while self.open_files.len() > max_open_files {
+			self.close_lru();
+		}
+		self.open_files.set_capacity(max_open_files);
+	}
+
+	pub fn data_root(&self) -> &DataRoot {
+		&self.data_root
+	}
+
+	pub fn set_path_mapping(&mut self, path_mapping: PathMapping) {
+		self.data_root = self.data_root.clone().with_path_mapping(path_mapping);
+	}
+
+	pub fn allow_symlinks_into(&mut self, directory: &Path) {
+		self.data_root = self.data_root.clone().allow_symlinks_into(directory);
+	}
+
+	pub fn metric_index(&self) -> Option< Arc<MetricIndex> > {
+		self.metric_index.clone()
+	}
+
+	pub fn set_metric_index(&mut self, metric_index: Option< Arc<MetricIndex> >) {
+		self.metric_index = metric_index;
+	}
+
+	pub fn set_write_back(&mut self, write_back: Option<WriteBack>) {
+		self.write_back = write_back;
+	}
+
+	pub fn set_max_pending_points(&mut self, max_pending_points: Option<usize>) {
+		self.max_pending_points = max_pending_points;
+	}
+
+	pub fn set_coalesce_policy(&mut self, coalesce_policy: AggregationType) {
+		self.coalesce_policy = coalesce_policy;
+	}
+
+	pub fn set_replaced_check_interval(&mut self, replaced_check_interval: Option<Duration>) {
+		self.replaced_check_interval = replaced_check_interval;
+	}
+
+	pub fn set_query_caching(&mut self, query_caching: Option<QueryCaching>) {
+		self.query_cache = query_caching.map(QueryCache::new);
+	}
+
+	// Writes the point, or holds it with write-back, which is when it's taken.
+	// What's left to write out then is up to the caller, once the shard is
+	// let go.
+	pub fn accept(&mut self, named_point: NamedPoint) -> Result<Option<FlushDue>> {
+		self.points_received = self.points_received + 1;
+		if let Some(ref mut query_cache) = self.query_cache {
+			query_cache.invalidate(named_point.metric_name());
+		}
+		let write_back = match self.write_back {
+			Some(write_back) => write_back,
+			None => {
+				let metric_rel_path = try!( self.data_root.path_mapping().rel_path(named_point.metric_name()) );
+
+				try!( try!( self.get(named_point.metric_name(), metric_rel_path) ).lock().unwrap().write(&named_point.point()) );
+				self.points_persisted = self.points_persisted + 1;
+				return Ok(None);
+			}
+		};
+
+		if !self.pending.contains_key(named_point.metric_name()) {
+			let rel_path = try!( self.data_root.path_mapping().rel_path(named_point.metric_name()) );
+			self.pending.insert(named_point.metric_name().to_string(), PendingPoints { rel_path: rel_path, points: vec![] });
+		}
+		let metric_full = {
+			let pending = self.pending.get_mut(named_point.metric_name()).unwrap();
+			pending.points.push(*named_point.point());
+			pending.points.len() >= write_back.max_points
+		};
+		self.pending_points = self.pending_points + 1;
+
+		// Whoever writes past the limit pays for flushing, which slows them down
+		// until the flushers catch up
+		let over_limit = self.max_pending_points.map_or(false, |max| self.pending_points > max);
+		if over_limit || self.last_flush.elapsed() >= write_back.flush_interval {
+			Ok(Some(FlushDue::All))
+		} else if metric_full {
+			Ok(Some(FlushDue::Metric(named_point.metric_name().to_string())))
+		} else {
+			Ok(None)
+		}
+	}
+
+	// In path order, neighbouring files tend to be neighbours on disk too
+	pub fn pending_metrics(&self) -> Vec<String> {
+		let mut metric_names : Vec<(&PathBuf, &String)> = self.pending.iter()
+			.map(|(metric_name, pending)| (&pending.rel_path, metric_name))
+			.collect();
+		metric_names.sort();
+		metric_names.into_iter().map(|(_, metric_name)| metric_name.clone()).collect()
+	}
+
+	pub fn mark_flushed(&mut self) {
+		self.last_flush = Instant::now();
+	}
+
+	pub fn sync_open_files(&mut self) -> Result<()> {
+		let mut result = Ok(());
+		for (_, open_file) in self.open_files.iter() {
+			let flushed = open_file.file.lock().unwrap().flush();
+			if result.is_ok() {
+				result = flushed;
+			}
+		}
+		result
+	}
+
+	pub fn fetch(&mut self, metric_name: &str, from: u32, until: u32) -> Result<TimeSeries> {
+		if let Some(series) = self.query_cache.as_mut().and_then(|query_cache| query_cache.get(metric_name, from, until)) {
+			self.stats.query_hits = self.stats.query_hits + 1;
+			return Ok(series);
+		}
+		let series = try!( self.fetch_uncached(metric_name, from, until) );
+		if let Some(ref mut query_cache) = self.query_cache {
+			self.stats.query_misses = self.stats.query_misses + 1;
+			query_cache.insert(metric_name, from, until, series.clone());
+		}
+		Ok(series)
+	}
+
+	fn fetch_uncached(&mut self, metric_name: &str, from: u32, until: u32) -> Result<TimeSeries> {
+		let metric_rel_path = try!( self.data_root.path_mapping().rel_path(metric_name) );
+		if !self.pending.contains_key(metric_name) && !self.open_files.contains_key(&metric_rel_path) && !try!( self.data_root.contain(&metric_rel_path) ).is_file() {
+			// Reading never creates the file
+			try!( fs::metadata(self.data_root.path().join(&metric_rel_path)) );
+		}
+
+		let mut series = try!( try!( self.get(metric_name, metric_rel_path) ).lock().unwrap().fetch(from, until) );
+		if let Some(pending) = self.pending.get(metric_name) {
+			// Pending points are newer than whatever the file holds for their interval
+			for point in write_back::coalesce(&pending.points, series.step, &self.coalesce_policy) {
+				if point.0 >= series.from && point.0 < series.until {
+					series.values[((point.0 - series.from) / series.step) as usize] = Some(point.1);
+				}
+			}
+		}
+		Ok(series)
+	}
+
+	// Whether `metric_name` has pending points and no file yet for a fetch to
+	// merge them with
+	pub fn unwritten(&mut self, metric_name: &str) -> Result<bool> {
+		let rel_path = match self.pending.get(metric_name) {
+			Some(pending) => pending.rel_path.clone(),
+			None => return Ok(false)
+		};
+		Ok(!self.open_files.contains_key(&rel_path) && !try!( self.data_root.contain(&rel_path) ).is_file())
+	}
+
+	// The file the pending points of `metric_name` go to, opened or created.
+	// They stay pending when that fails for an io error, a full disk say, to
+	// be tried again by the next flush. The ones no retry would help, matching
+	// no schema, are dropped.
+	pub fn pending_file(&mut self, metric_name: &str) -> Result<Option<WhisperMutex>> {
+		let rel_path = match self.pending.get(metric_name) {
+			Some(pending) => pending.rel_path.clone(),
+			None => return Ok(None)
+		};
+		match self.get(metric_name, rel_path) {
+			Ok(file) => Ok(Some(file.clone())),
+			Err(err) => {
+				if !is_transient(&err) {
+					self.drop_pending(metric_name);
+				}
+				Err(err)
+			}
+		}
+	}
+
+	// The pending points of `metric_name`, to be written to the file from
+	// pending_file
+	pub fn take_pending(&mut self, metric_name: &str) -> Flush {
+		let rel_path = self.pending.get(metric_name).map(|pending| pending.rel_path.clone()).unwrap_or_default();
+		let points = self.drop_pending(metric_name);
+		Flush { rel_path: rel_path, points: points, coalesce_policy: self.coalesce_policy.clone() }
+	}
+
+	// Puts the points of a flush that failed for an io error back, ahead of
+	// the ones written since
+	pub fn restore_pending(&mut self, metric_name: &str, flush: Flush, err: &WhisperError) {
+		if !is_transient(err) {
+			return;
+		}
+		let Flush { rel_path, mut points, .. } = flush;
+		self.pending_points = self.pending_points + points.len();
+		let pending = self.pending.entry(metric_name.to_string()).or_insert_with(|| PendingPoints { rel_path: rel_path, points: vec![] });
+		points.extend(pending.points.drain(..));
+		pending.points = points;
+	}
+
+	fn drop_pending(&mut self, metric_name: &str) -> Vec<Point> {
+		match self.pending.remove(metric_name) {
+			Some(pending) => {
+				self.pending_points = self.pending_points - pending.points.len();
+				pending.points
+			},
+			None => vec![]
+		}
+	}
+
+	// Forgets what was read of `metric_name`, and closes its file when the one
+	// on disk is another one or gone. Returns whether it was closed.
+	pub fn invalidate(&mut self, metric_name: &str) -> Result<bool> {
+		let rel_path = try!( self.data_root.path_mapping().rel_path(metric_name) );
+		if let Some(ref mut query_cache) = self.query_cache {
+			query_cache.invalidate(metric_name);
+		}
+		let replaced = match self.open_files.get_mut(&rel_path) {
+			Some(open_file) => FileIdentity::of(&self.data_root.path().join(&rel_path)) != open_file.identity,
+			None => return Ok(false)
+		};
+		if replaced {
+			if let Some(closed) = self.open_files.remove(&rel_path) {
+				self.stats.resident_bytes = self.stats.resident_bytes - closed.size;
+				self.stats.replaced = self.stats.replaced + 1;
+			}
+		}
+		Ok(replaced)
+	}
+
+	pub fn record_flush(&mut self, flush: &Flush, duration: Duration, written: bool) {
+		self.flush_times.record(duration);
+		if written {
+			self.flushes = self.flushes + 1;
+			self.points_persisted = self.points_persisted + flush.points.len() as u64;
+		}
+	}
+
+	fn get(&mut self, metric_name: &str, metric_rel_path: PathBuf) -> Result< &WhisperMutex > {
+
+		if self.open_files.contains_key(&metric_rel_path) && self.was_replaced(&metric_rel_path) {
+			debug!("{:?} was replaced on disk, reopening", metric_rel_path);
+			if let Some(replaced) = self.open_files.remove(&metric_rel_path) {
+				self.stats.resident_bytes = self.stats.resident_bytes - replaced.size;
+				self.stats.replaced = self.stats.replaced + 1;
+			}
+		}
+
+		if self.open_files.contains_key(&metric_rel_path) {
+
+			debug!("file cache hit. resolved {:?}", metric_rel_path);
+			self.stats.hits = self.stats.hits + 1;
+			Ok( &self.open_files.get_mut(&metric_rel_path).unwrap().file )
+
+		} else {
+
+			// debug!("file cache miss. resolving {:?}", metric_rel_path);
+			self.stats.misses = self.stats.misses + 1;
+
+			let path_for_insert = metric_rel_path.clone();
+			let path_for_relookup = metric_rel_path.clone();
+
+			// Symlinks followed no further than the data root allows
+			let path_on_disk = try!( self.data_root.contain(metric_rel_path) );
+
+			let whisper_file = if path_on_disk.exists() && path_on_disk.is_file() {
+
+				debug!("`{:?}` exists on disk. opening.", path_on_disk);
+				try!( WhisperFile::open(&path_on_disk) )
+
+			} else {
+
+				// Resolved first so a metric no rule matches leaves no directories behind
+				let schema = try!( self.resolver.schema_for(metric_name).ok_or_else(|| WhisperError::NoMatchingSchema(metric_name.to_string())) );
+				let (aggregation_type, x_files_factor) = self.resolver.aggregation_for(metric_name);
+
+				// Verify the folder structure is present.
+				// TODO: benchmark (for my own curiosity)
+				// TODO: assumption here is that we do not store in root FS
+				if !path_on_disk.parent().unwrap().is_dir() {
+					debug!("parent dir for `{:?}` must be created first", path_on_disk.parent());
+					try!( DirBuilder::new().recursive(true).create( path_on_disk.parent().unwrap() ) );
+				}
+				debug!("`{:?}` must now be created", path_on_disk);
+				let whisper_file = try!( WhisperFile::new_with_options(&path_on_disk, &schema, aggregation_type, x_files_factor) );
+				self.stats.creates = self.stats.creates + 1;
+				if let (Some(metric_index), Ok(metric_name)) = (self.metric_index.as_ref(), self.data_root.path_mapping().normalize(metric_name)) {
+					metric_index.add(&metric_name);
+				}
+				whisper_file
+
+			};
+
+			let size = Shard::mapped_size(&whisper_file);
+			self.make_room(size);
+			self.stats.resident_bytes = self.stats.resident_bytes + size;
+			let open_file = OpenFile {
+				file: Arc::new( Mutex::new(whisper_file) ),
+				size: size,
+				identity: FileIdentity::of(&path_on_disk),
+				checked_at: Instant::now()
+			};
+			self.open_files.insert(path_for_insert, open_file);
+			Ok( &self.open_files.get_mut(&path_for_relookup).unwrap().file )
+
+		}
+
+	}
+
+	// Whether the open file at `metric_rel_path` no longer is the one on disk,
+	// looked at once every replaced_check_interval at most
+	fn was_replaced(&mut self, metric_rel_path: &PathBuf) -> bool {
+		let check_interval = match self.replaced_check_interval {
+			Some(check_interval) => check_interval,
+			None => return false
+		};
+		let path_on_disk = self.data_root.path().join(metric_rel_path);
+		let open_file = self.open_files.get_mut(metric_rel_path).unwrap();
+		if open_file.checked_at.elapsed() < check_interval {
+			return false;
+		}
+
+		open_file.checked_at = Instant::now();
+		FileIdentity::of(&path_on_disk) != open_file.identity
+	}
+
+	// Closes least recently used files until one more of `size` bytes fits
+	fn make_room(&mut self, size: u64) {
+		loop {
+			let over_capacity = self.open_files.len() >= self.open_files.capacity();
+			let over_budget = self.max_resident_bytes.map_or(false, |max| self.stats.resident_bytes + size > max);
+			if self.open_files.is_empty() || !(over_capacity || over_budget) {
+				return;
+			}
+			self.close_lru();
+		}
+	}
+
+	fn close_lru(&mut self) {
+		if let Some((path, evicted)) = self.open_files.remove_lru() {
+			debug!("closing {:?} to make room", path);
+			if let Err(err) = evicted.file.lock().unwrap().flush() {
+				warn!("could not sync {:?} before closing it: {}", path, err);
+			}
+			self.stats.evictions = self.stats.evictions + 1;
+			self.stats.resident_bytes = self.stats.resident_bytes - evicted.size;
+		}
+	}
+
+	fn mapped_size(whisper_file: &WhisperFile) -> u64 {
+		whisper_file.info().archives.last().map_or(0, |archive| (archive.offset + archive.size) as u64)
+	}
+}
+
+// Whether the same write could work later on
+fn is_transient(err: &WhisperError) -> bool {
+	match *err {
+		WhisperError::Io(_) => true,
+		_ => false
+	}
+}
@@ -0,0 +1,22 @@
+// What a WhisperCache has been up to since it was created
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct CacheStats {
+	// Writes to a file that was already open
+	pub hits: u64,
+	// Writes that had to open or create their file first
+	pub misses: u64,
+	// Misses that found no file and created one
+	pub creates: u64,
+	// Files closed to stay within the capacity or the resident bytes budget
+	pub evictions: u64,
+	// Open files found replaced on disk, and opened again
+	pub replaced: u64,
+	pub open_files: usize,
+	// Bytes mapped by the open files
+	pub resident_bytes: u64,
+	// Points held back by the WriteBack policy, not written yet
+	pub pending_points: usize,
+	// Fetches answered by the QueryCaching results, and those that had to read
+	pub query_hits: u64,
+	pub query_misses: u64
+}
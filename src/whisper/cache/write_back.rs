@@ -0,0 +1,63 @@
+use std::path::PathBuf;
+use std::time::Duration;
+
+use whisper::{ Point, AggregationType };
+
+// How a WhisperCache holds on to points before writing them, carbon-cache style.
+// Points of a metric are written together with update_many once it has
+// `max_points` of them, and every metric is written out `flush_interval` after
+// the last time everything was.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct WriteBack {
+	pub max_points: usize,
+	pub flush_interval: Duration
+}
+
+// Points of one metric waiting to be written
+pub struct PendingPoints {
+	pub rel_path: PathBuf,
+	pub points: Vec<Point>
+}
+
+// Points falling in the same `step` interval are rolled up into one with
+// `policy`, keeping the order they came in so Last means the latest written.
+// Coalesced points are stamped with the start of their interval, in order.
+pub fn coalesce(points: &[Point], step: u32, policy: &AggregationType) -> Vec<Point> {
+	if step == 0 {
+		return points.to_vec();
+	}
+
+	let mut sorted = points.to_vec();
+	// Stable, so points of one interval stay in arrival order
+	sorted.sort_by_key(|point| point.0 - point.0 % step);
+
+	let mut coalesced = vec![];
+	let mut start = 0;
+	while start < sorted.len() {
+		let interval = sorted[start].0 - sorted[start].0 % step;
+		let count = sorted[start..].iter().take_while(|point| point.0 - point.0 % step == interval).count();
+		let values : Vec<f64> = sorted[start .. start + count].iter().map(|point| point.1).collect();
+		let value = policy.aggregate(&values, values.len()).unwrap_or(values[values.len() - 1]);
+		coalesced.push(Point(interval, value));
+		start = start + count;
+	}
+	coalesced
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_coalesce() {
+		// Interleaved: 100 and 110 each written twice, out of order
+		let points = vec![Point(105, 1.0), Point(110, 2.0), Point(100, 3.0), Point(120, 4.0), Point(119, 5.0)];
+
+		assert_eq!(coalesce(&points, 10, &AggregationType::Last), vec![Point(100, 3.0), Point(110, 5.0), Point(120, 4.0)]);
+		assert_eq!(coalesce(&points, 10, &AggregationType::Sum), vec![Point(100, 4.0), Point(110, 7.0), Point(120, 4.0)]);
+		assert_eq!(coalesce(&points, 10, &AggregationType::Max), vec![Point(100, 3.0), Point(110, 5.0), Point(120, 4.0)]);
+		// Nothing to roll up with, the latest is kept
+		assert_eq!(coalesce(&points, 10, &AggregationType::Unknown), vec![Point(100, 3.0), Point(110, 5.0), Point(120, 4.0)]);
+		assert_eq!(coalesce(&[], 10, &AggregationType::Last), vec![]);
+	}
+}
@@ -0,0 +1,157 @@
+use std::error;
+use std::fmt;
+use std::sync::Arc;
+use std::sync::mpsc::{ self, SyncSender, TrySendError };
+use std::thread::{ self, JoinHandle };
+
+use whisper::WhisperCache;
+use super::NamedPoint;
+
+// Why a point couldn't be queued, handing it back
+#[derive(Debug, PartialEq)]
+pub enum QueueError {
+	// As many points as the queue holds are waiting already
+	Full(NamedPoint),
+	// The queue was closed
+	Closed(NamedPoint)
+}
+
+impl fmt::Display for QueueError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match *self {
+			QueueError::Full(ref point) => write!(f, "ingestion queue is full, dropping a point of '{}'", point.metric_name()),
+			QueueError::Closed(ref point) => write!(f, "ingestion queue is closed, dropping a point of '{}'", point.metric_name())
+		}
+	}
+}
+
+impl error::Error for QueueError {
+	fn description(&self) -> &str {
+		match *self {
+			QueueError::Full(_) => "ingestion queue is full",
+			QueueError::Closed(_) => "ingestion queue is closed"
+		}
+	}
+}
+
+// A bounded queue in front of a cache, written into it by a thread of its own.
+// Listeners send points through CacheWriters and get to choose what to do when
+// storage can't keep up: wait with `send`, or drop and count with `try_send`.
+pub struct IngestQueue {
+	writer: Option<CacheWriter>,
+	thread: Option< JoinHandle<()> >
+}
+
+#[derive(Clone)]
+pub struct CacheWriter {
+	sender: SyncSender<NamedPoint>
+}
+
+impl IngestQueue {
+	// Holds up to `capacity` points not written yet
+	pub fn new(cache: Arc<WhisperCache>, capacity: usize) -> IngestQueue {
+		let (sender, receiver) = mpsc::sync_channel::<NamedPoint>(capacity);
+		let thread = thread::spawn(move || {
+			for named_point in receiver {
+				if let Err(err) = cache.write(named_point) {
+					warn!("could not write a queued point: {}", err);
+				}
+			}
+		});
+
+		IngestQueue { writer: Some(CacheWriter { sender: sender }), thread: Some(thread) }
+	}
+
+	pub fn writer(&self) -> CacheWriter {
+		self.writer.as_ref().unwrap().clone()
+	}
+
+	// Waits for what's queued to be written. Only returns once every
+	// CacheWriter handed out is dropped too.
+	pub fn close(mut self) {
+		self.join();
+	}
+
+	fn join(&mut self) {
+		self.writer = None;
+		if let Some(thread) = self.thread.take() {
+			thread.join().ok();
+		}
+	}
+}
+
+impl Drop for IngestQueue {
+	fn drop(&mut self) {
+		self.join();
+	}
+}
+
+impl CacheWriter {
+	// Blocks while the queue is full
+	pub fn send(&self, named_point: NamedPoint) -> Result<(), QueueError> {
+		self.sender.send(named_point).map_err(|err| QueueError::Closed(err.0))
+	}
+
+	pub fn try_send(&self, named_point: NamedPoint) -> Result<(), QueueError> {
+		self.sender.try_send(named_point).map_err(|err| match err {
+			TrySendError::Full(named_point) => QueueError::Full(named_point),
+			TrySendError::Disconnected(named_point) => QueueError::Closed(named_point)
+		})
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use whisper::{ Schema, temp_path, current_time };
+	use std::fs;
+
+	#[test]
+	fn test_ingest_queue() {
+		let base_path = temp_path("test_cache_ingest_queue");
+		fs::remove_dir_all(&base_path).ok();
+		let cache = Arc::new(WhisperCache::new(&base_path, 10, Schema::parse("10s:60s").unwrap()));
+		let now = current_time();
+
+		let queue = IngestQueue::new(cache.clone(), 4);
+		let writer = queue.writer();
+		for i in 0..20 {
+			writer.send(NamedPoint::new(format!("queued.metric{}", i % 3), now, 1.0)).unwrap();
+		}
+		drop(writer);
+		queue.close();
+
+		assert_eq!(cache.metrics().points_received, 20);
+	}
+
+	#[test]
+	fn test_queue_full() {
+		let base_path = temp_path("test_cache_queue_full");
+		fs::remove_dir_all(&base_path).ok();
+		let cache = Arc::new(WhisperCache::new(&base_path, 10, Schema::parse("10s:60s").unwrap()));
+		let now = current_time();
+
+		let queue = IngestQueue::new(cache.clone(), 1);
+		let writer = queue.writer();
+		let accepted = {
+			// The queue's thread can take one point at most, then waits on the shard
+			let _stalled = cache.shards[0].lock().unwrap();
+			let mut accepted = 0;
+			loop {
+				match writer.try_send(NamedPoint::new("queued.full".to_string(), now, 1.0)) {
+					Ok(()) => accepted = accepted + 1,
+					Err(err) => {
+						assert_eq!(err, QueueError::Full(NamedPoint::new("queued.full".to_string(), now, 1.0)));
+						break;
+					}
+				}
+				assert!(accepted <= 2);
+			}
+			accepted
+		};
+
+		drop(writer);
+		queue.close();
+		assert_eq!(cache.metrics().points_received, accepted);
+	}
+}
@@ -0,0 +1,249 @@
+use std::fs::File;
+use std::io::Read;
+use std::path::{ Path, PathBuf };
+use std::time::Duration;
+
+use toml;
+
+use whisper::{ WhisperCache, SchemaRules, AggregationRules, StorageRules, SchemaError };
+use whisper::error::Result;
+use whisper::schema::conf::Section;
+
+pub const DEFAULT_MAX_OPEN_FILES : usize = 1024;
+pub const DEFAULT_FLUSH_INTERVAL : u64 = 1;
+pub const DEFAULT_SHARDS : usize = 1;
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct CacheConfig {
+	pub base_path: PathBuf,
+	pub max_open_files: usize,
+	pub max_resident_bytes: Option<u64>,
+	pub shards: usize,
+	// How often daemons should flush what the cache holds
+	pub flush_interval: Duration
+}
+
+// Everything a carbon-like daemon needs to set up its storage, read from one file:
+//
+//     [cache]
+//     base_path = "/opt/graphite/storage/whisper"
+//     max_open_files = 1024    # optional
+//     max_resident_bytes = 1_000_000_000  # optional, no limit unless set
+//     shards = 16              # optional, 1 by default
+//     flush_interval = 1       # seconds, optional
+//
+//     [[schemas]]              # tried in order, same as storage-schemas.conf
+//     name = "carbon"          # optional
+//     pattern = "^carbon\\."
+//     retentions = "60:90d"    # or ["10s:1d", "1m:30d"]
+//
+//     [[aggregations]]         # same as storage-aggregation.conf
+//     pattern = "\\.count$"
+//     aggregation_method = "sum"
+//     x_files_factor = 0.0
+//
+// Other tables are left for the daemon to read with the toml crate.
+#[derive(Debug)]
+pub struct Config {
+	pub cache: CacheConfig,
+	pub schema_rules: SchemaRules,
+	pub aggregation_rules: AggregationRules
+}
+
+// The file as it is written, before the rules are checked
+#[derive(Deserialize)]
+struct ConfigFile {
+	cache: Option<CacheTable>,
+	#[serde(default)]
+	schemas: Vec<SchemaTable>,
+	#[serde(default)]
+	aggregations: Vec<AggregationTable>
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct CacheTable {
+	base_path: PathBuf,
+	max_open_files: Option<usize>,
+	max_resident_bytes: Option<u64>,
+	shards: Option<usize>,
+	flush_interval: Option<u64>
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SchemaTable {
+	name: Option<String>,
+	pattern: Option<String>,
+	retentions: Option<Retentions>
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Retentions {
+	One(String),
+	Many(Vec<String>)
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct AggregationTable {
+	name: Option<String>,
+	pattern: Option<String>,
+	aggregation_method: Option<String>,
+	x_files_factor: Option<f64>
+}
+
+impl Config {
+	pub fn open<P>(path: P) -> Result<Config>
+		where P: AsRef<Path> {
+		let mut contents = String::new();
+		try!(try!(File::open(path)).read_to_string(&mut contents));
+		Ok(try!(Config::parse(&contents)))
+	}
+
+	pub fn parse(contents: &str) -> ::std::result::Result<Config, SchemaError> {
+		let file : ConfigFile = try!(toml::from_str(contents).map_err(|e: toml::de::Error| {
+			// Lines count from 1 in errors, 0 standing for the file as a whole
+			let line = e.line_col().map(|(line, _)| line + 1).unwrap_or(0);
+			SchemaError::InvalidConfig(line, e.to_string())
+		}));
+
+		let cache = match file.cache {
+			Some(table) => try!(Config::cache_config(table)),
+			None => return Err(SchemaError::InvalidConfig(0, "no [cache] table".to_string()))
+		};
+
+		let schema_sections = file.schemas.into_iter().enumerate().map(|(index, table)| {
+			let mut entries = vec![];
+			if let Some(pattern) = table.pattern {
+				entries.push(("pattern".to_string(), pattern));
+			}
+			match table.retentions {
+				Some(Retentions::One(spec)) => entries.push(("retentions".to_string(), spec)),
+				Some(Retentions::Many(specs)) => entries.push(("retentions".to_string(), specs.join(","))),
+				None => ()
+			}
+			Section { name: table.name.unwrap_or_else(|| format!("schemas[{}]", index)), entries: entries }
+		}).collect();
+
+		let aggregation_sections = file.aggregations.into_iter().enumerate().map(|(index, table)| {
+			let mut entries = vec![];
+			if let Some(pattern) = table.pattern {
+				entries.push(("pattern".to_string(), pattern));
+			}
+			if let Some(method) = table.aggregation_method {
+				entries.push(("aggregationmethod".to_string(), method));
+			}
+			if let Some(xff) = table.x_files_factor {
+				entries.push(("xfilesfactor".to_string(), xff.to_string()));
+			}
+			Section { name: table.name.unwrap_or_else(|| format!("aggregations[{}]", index)), entries: entries }
+		}).collect();
+
+		Ok(Config {
+			cache: cache,
+			schema_rules: try!(SchemaRules::from_sections(schema_sections)),
+			aggregation_rules: try!(AggregationRules::from_sections(aggregation_sections))
+		})
+	}
+
+	// A cache storing under base_path that creates files following the rules
+	pub fn into_cache(self) -> WhisperCache {
+		WhisperCache::with_shards(self.cache.base_path, StorageRules::new(self.schema_rules, self.aggregation_rules), self.cache.max_open_files, self.cache.max_resident_bytes, self.cache.shards)
+	}
+
+	fn cache_config(table: CacheTable) -> ::std::result::Result<CacheConfig, SchemaError> {
+		let positive = |key: &str, value: Option<usize>, default: usize| match value {
+			Some(0) => Err(SchemaError::InvalidConfig(0, format!("[cache] {} must be a positive integer", key))),
+			Some(value) => Ok(value),
+			None => Ok(default)
+		};
+
+		if table.base_path.as_os_str().is_empty() {
+			return Err(SchemaError::InvalidConfig(0, "[cache] has an empty base_path".to_string()));
+		}
+		if table.max_resident_bytes == Some(0) {
+			return Err(SchemaError::InvalidConfig(0, "[cache] max_resident_bytes must be a positive integer".to_string()));
+		}
+		Ok(CacheConfig {
+			max_open_files: try!(positive("max_open_files", table.max_open_files, DEFAULT_MAX_OPEN_FILES)),
+			shards: try!(positive("shards", table.shards, DEFAULT_SHARDS)),
+			max_resident_bytes: table.max_resident_bytes,
+			flush_interval: Duration::from_secs(table.flush_interval.unwrap_or(DEFAULT_FLUSH_INTERVAL)),
+			base_path: table.base_path
+		})
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use whisper::AggregationType;
+
+	const CONFIG : &'static str = "
+[cache]
+base_path = \"/opt/graphite/storage/whisper\"
+max_open_files = 100
+max_resident_bytes = 1_000_000
+shards = 4
+
+[[schemas]]
+name = \"carbon\"
+pattern = '^carbon\\.'
+retentions = \"60:90d\"
+
+[[schemas]]
+pattern = \".*\"
+retentions = [\"10s:1d\", \"1m:30d\"]
+
+[[aggregations]]
+pattern = '\\.count$'
+aggregation_method = \"sum\"
+x_files_factor = 0
+
+[relay]
+destinations = [\"127.0.0.1:2004\"]
+";
+
+	#[test]
+	fn test_parse() {
+		let config = Config::parse(CONFIG).unwrap();
+
+		assert_eq!(config.cache, CacheConfig {
+			base_path: PathBuf::from("/opt/graphite/storage/whisper"),
+			max_open_files: 100,
+			max_resident_bytes: Some(1000000),
+			shards: 4,
+			flush_interval: Duration::from_secs(DEFAULT_FLUSH_INTERVAL)
+		});
+
+		let rules = config.schema_rules.rules();
+		assert_eq!(rules.len(), 2);
+		assert_eq!(rules[0].name, "carbon");
+		assert_eq!(rules[1].name, "schemas[1]");
+		assert_eq!(config.schema_rules.schema_for("servers.load").unwrap().retention_policies.len(), 2);
+		assert_eq!(config.aggregation_rules.aggregation_for("servers.requests.count"), (AggregationType::Sum, 0.0));
+	}
+
+	#[test]
+	fn test_parse_errors() {
+		assert_eq!(Config::parse("").unwrap_err(), SchemaError::InvalidConfig(0, "no [cache] table".to_string()));
+		assert!(Config::parse("[cache]\nmax_open_files = 10").is_err());
+		assert!(Config::parse("[cache]\nbase_path = 1").is_err());
+		assert!(Config::parse("[cache]\nbase_path = \"/tmp\"\nflush = 1").is_err());
+		assert!(Config::parse("[cache]\nbase_path = \"/tmp\"\nshards = 0").is_err());
+		assert!(Config::parse("[cache]\nbase_path = \"/tmp\"\n[schemas]\npattern = \".*\"").is_err());
+		assert!(Config::parse("[cache]\nbase_path = \"/tmp\"\n[[aggregations]]\npattern = \".*\"\nxff = 0.5").is_err());
+
+		match Config::parse("[cache]\nbase_path = \"/tmp\"\nshards = [") {
+			Err(SchemaError::InvalidConfig(line, _)) => assert!(line > 0),
+			other => panic!("unexpected {:?}", other)
+		}
+
+		match Config::parse("[cache]\nbase_path = \"/tmp\"\n[[schemas]]\npattern = \".*\"\nretentions = \"10x:1d\"") {
+			Err(SchemaError::InvalidRule(name, _)) => assert_eq!(name, "schemas[0]"),
+			other => panic!("unexpected {:?}", other)
+		}
+	}
+}
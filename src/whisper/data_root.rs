@@ -0,0 +1,157 @@
+use std::fs;
+use std::io;
+use std::path::{ Component, Path, PathBuf };
+
+use whisper::{ PathMapping, MetricPattern, MetricNode };
+use whisper::error::{ Result, WhisperError };
+
+// A data directory with the PathMapping its files are laid out by. The paths
+// it resolves stay under it: metric names go through the mapping, relative
+// paths can't go up, and a symlink on the way has to lead back into the root
+// or one of the directories allowed as symlink targets, for roots stitched
+// together from several disks. Checked on every resolve, what is done with
+// the path afterwards is up to the filesystem.
+#[derive(Debug, PartialEq, Clone)]
+pub struct DataRoot {
+	root: PathBuf,
+	mapping: PathMapping,
+	symlink_targets: Vec<PathBuf>
+}
+
+impl DataRoot {
+	// The root doesn't have to exist yet
+	pub fn new<P>(root: P) -> DataRoot
+		where P: AsRef<Path> {
+		DataRoot { root: root.as_ref().to_path_buf(), mapping: PathMapping::default(), symlink_targets: vec![] }
+	}
+
+	pub fn with_path_mapping(mut self, mapping: PathMapping) -> DataRoot {
+		self.mapping = mapping;
+		self
+	}
+
+	pub fn allow_symlinks_into<P>(mut self, directory: P) -> DataRoot
+		where P: AsRef<Path> {
+		self.symlink_targets.push(directory.as_ref().to_path_buf());
+		self
+	}
+
+	pub fn path(&self) -> &Path {
+		&self.root
+	}
+
+	pub fn path_mapping(&self) -> &PathMapping {
+		&self.mapping
+	}
+
+	pub fn symlink_targets(&self) -> &[PathBuf] {
+		&self.symlink_targets
+	}
+
+	// Where the file of `metric_name` is, whether or not there's one
+	pub fn resolve(&self, metric_name: &str) -> Result<PathBuf> {
+		self.contain(try!( self.mapping.rel_path(metric_name) ))
+	}
+
+	// The directory of the branch `prefix`
+	pub fn resolve_dir(&self, prefix: &str) -> Result<PathBuf> {
+		self.contain(try!( self.mapping.rel_dir(prefix) ))
+	}
+
+	// `rel_path` under the root, the root itself for an empty one, and
+	// WhisperError::OutsideDataRoot when it leads anywhere else
+	pub fn contain<P>(&self, rel_path: P) -> Result<PathBuf>
+		where P: AsRef<Path> {
+		let rel_path = rel_path.as_ref();
+		let outside = || WhisperError::OutsideDataRoot(self.root.join(rel_path));
+		let normal = rel_path.components().all(|component| match component {
+			Component::Normal(_) => true,
+			_ => false
+		});
+		if !normal {
+			return Err(outside());
+		}
+
+		// Checked down from the root until the first part not there yet
+		let mut path = self.root.clone();
+		let mut missing = false;
+		for component in rel_path.components() {
+			path.push(component);
+			if missing {
+				continue;
+			}
+			match fs::symlink_metadata(&path) {
+				Ok(ref metadata) if metadata.file_type().is_symlink() => if !self.holds(&path) {
+					return Err(outside());
+				},
+				Ok(_) => (),
+				Err(ref err) if err.kind() == io::ErrorKind::NotFound => missing = true,
+				Err(err) => return Err(WhisperError::Io(err))
+			}
+		}
+		Ok(path)
+	}
+
+	// The branches and leaves `pattern` matches, as the mapping names them,
+	// leaving out the ones symlinked out of the root
+	pub fn find(&self, pattern: &MetricPattern) -> io::Result<Vec<MetricNode>> {
+		let nodes = try!( pattern.find_mapped(&self.root, &self.mapping) );
+		Ok(nodes.into_iter().filter(|node| if node.is_leaf { self.resolve(&node.path).is_ok() } else { self.resolve_dir(&node.path).is_ok() }).collect())
+	}
+
+	// Whether `path`, wherever its symlinks lead, is in the root or one of the
+	// allowed symlink targets. A broken symlink isn't.
+	pub fn holds<P>(&self, path: P) -> bool
+		where P: AsRef<Path> {
+		let real_path = match fs::canonicalize(path) {
+			Ok(real_path) => real_path,
+			Err(_) => return false
+		};
+		Some(&self.root).into_iter().chain(self.symlink_targets.iter())
+			.filter_map(|directory| fs::canonicalize(directory).ok())
+			.any(|directory| real_path.starts_with(directory))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use whisper::temp_path;
+	#[cfg(unix)]
+	use std::os::unix::fs::symlink;
+
+	#[test]
+	#[cfg(unix)]
+	fn test_resolve() {
+		let base = PathBuf::from(temp_path("test_data_root"));
+		fs::remove_dir_all(&base).ok();
+		let (root, disk2, elsewhere) = (base.join("whisper"), base.join("disk2"), base.join("elsewhere"));
+		for dir in &[root.join("servers"), root.join("builds"), disk2.join("apps"), elsewhere.clone()] {
+			fs::create_dir_all(dir).unwrap();
+		}
+		symlink(disk2.join("apps"), root.join("apps")).unwrap();
+		symlink(&elsewhere, root.join("servers/web01")).unwrap();
+		symlink(root.join("builds"), root.join("ci")).unwrap();
+		symlink(base.join("missing"), root.join("broken")).unwrap();
+
+		let data_root = DataRoot::new(&root);
+		assert_eq!(data_root.resolve("servers.web02.load").unwrap(), root.join("servers/web02/load.wsp"));
+		assert_eq!(data_root.resolve("ci.runs").unwrap(), root.join("ci/runs.wsp"));
+		for metric in &["servers.web01.load", "apps.shop.requests", "broken.load"] {
+			match data_root.resolve(metric) {
+				Err(WhisperError::OutsideDataRoot(ref path)) => assert!(path.starts_with(&root)),
+				other => panic!("expected {} to be outside, got {:?}", metric, other)
+			}
+		}
+		assert!(data_root.contain("../elsewhere/load.wsp").is_err());
+		assert!(data_root.contain("/etc/passwd").is_err());
+		assert!(data_root.resolve("servers..load").is_err());
+
+		let data_root = data_root.allow_symlinks_into(&disk2);
+		assert_eq!(data_root.resolve("apps.shop.requests").unwrap(), root.join("apps/shop/requests.wsp"));
+		assert_eq!(data_root.resolve_dir("apps.shop").unwrap(), root.join("apps/shop"));
+		assert!(data_root.resolve("servers.web01.load").is_err());
+
+		assert_eq!(DataRoot::new(base.join("nothing")).resolve("a.b").unwrap(), base.join("nothing/a/b.wsp"));
+	}
+}
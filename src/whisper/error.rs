@@ -0,0 +1,104 @@
+use std::error;
+use std::fmt;
+use std::io;
+use std::path::PathBuf;
+use std::result;
+
+use whisper::schema::SchemaError;
+
+#[derive(Debug)]
+pub enum WhisperError {
+	Io(io::Error),
+	// (from, until) of a window that ends before it starts
+	InvalidTimeInterval(u32, u32),
+	TimestampNotCovered(u32),
+	// Further ahead than the file's FuturePolicy allows
+	FutureTimestamp(u32),
+	// Timestamp of a NaN or infinite value refused by the NonFinitePolicy
+	NonFiniteValue(u32),
+	InvalidXFilesFactor(f32),
+	InvalidSchema(SchemaError),
+	// Metric name no schema rule matched, so its file can't be created
+	NoMatchingSchema(String),
+	// (metric name, why) of a name the PathMapping can't make a path from
+	InvalidMetricName(String, String),
+	// A path that would lead out of its DataRoot
+	OutsideDataRoot(PathBuf),
+	// AggregationType::Unknown can be read from a file but never written to one
+	UnknownAggregationType,
+	InvalidHeader(String),
+	ReadOnly,
+	InvalidArchiveIndex(usize),
+	// (slot, points in the archive)
+	SlotOutOfRange(usize, usize),
+	// The two files don't have the same (seconds per point, points) archives
+	IncompatibleArchives
+}
+
+pub type Result<T> = result::Result<T, WhisperError>;
+
+impl fmt::Display for WhisperError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match *self {
+			WhisperError::Io(ref err) => write!(f, "io error: {}", err),
+			WhisperError::InvalidTimeInterval(from, until) => write!(f, "invalid time interval: from time '{}' is after until time '{}'", from, until),
+			WhisperError::TimestampNotCovered(timestamp) => write!(f, "timestamp {} is not covered by any archives in this database", timestamp),
+			WhisperError::FutureTimestamp(timestamp) => write!(f, "timestamp {} is too far in the future", timestamp),
+			WhisperError::NonFiniteValue(timestamp) => write!(f, "value at {} is not a finite number", timestamp),
+			WhisperError::InvalidXFilesFactor(xff) => write!(f, "invalid xFilesFactor {}, not between 0 and 1", xff),
+			WhisperError::InvalidSchema(ref err) => write!(f, "invalid schema: {}", err),
+			WhisperError::NoMatchingSchema(ref metric) => write!(f, "no schema rule matches metric '{}'", metric),
+			WhisperError::InvalidMetricName(ref metric, ref reason) => write!(f, "invalid metric name '{}': {}", metric, reason),
+			WhisperError::OutsideDataRoot(ref path) => write!(f, "{} leads out of the data root", path.display()),
+			WhisperError::UnknownAggregationType => write!(f, "unknown aggregation method"),
+			WhisperError::InvalidHeader(ref reason) => write!(f, "invalid header: {}", reason),
+			WhisperError::ReadOnly => write!(f, "file was opened read-only"),
+			WhisperError::InvalidArchiveIndex(index) => write!(f, "file has no archive {}", index),
+			WhisperError::SlotOutOfRange(index, points) => write!(f, "slot {} is out of range for an archive of {} points", index, points),
+			WhisperError::IncompatibleArchives => write!(f, "archive configurations are unalike, resize one of the files first")
+		}
+	}
+}
+
+impl error::Error for WhisperError {
+	fn description(&self) -> &str {
+		match *self {
+			WhisperError::Io(ref err) => err.description(),
+			WhisperError::InvalidTimeInterval(_, _) => "invalid time interval",
+			WhisperError::TimestampNotCovered(_) => "timestamp not covered by any archives",
+			WhisperError::FutureTimestamp(_) => "timestamp too far in the future",
+			WhisperError::NonFiniteValue(_) => "value is not a finite number",
+			WhisperError::InvalidXFilesFactor(_) => "invalid xFilesFactor",
+			WhisperError::InvalidSchema(ref err) => err.description(),
+			WhisperError::NoMatchingSchema(_) => "no schema rule matches metric",
+			WhisperError::InvalidMetricName(_, _) => "invalid metric name",
+			WhisperError::OutsideDataRoot(_) => "path leads out of the data root",
+			WhisperError::UnknownAggregationType => "unknown aggregation method",
+			WhisperError::InvalidHeader(_) => "invalid header",
+			WhisperError::ReadOnly => "file was opened read-only",
+			WhisperError::InvalidArchiveIndex(_) => "no such archive",
+			WhisperError::SlotOutOfRange(_, _) => "slot out of range",
+			WhisperError::IncompatibleArchives => "archive configurations are unalike"
+		}
+	}
+
+	fn cause(&self) -> Option<&error::Error> {
+		match *self {
+			WhisperError::Io(ref err) => Some(err),
+			WhisperError::InvalidSchema(ref err) => Some(err),
+			_ => None
+		}
+	}
+}
+
+impl From<io::Error> for WhisperError {
+	fn from(err: io::Error) -> WhisperError {
+		WhisperError::Io(err)
+	}
+}
+
+impl From<SchemaError> for WhisperError {
+	fn from(err: SchemaError) -> WhisperError {
+		WhisperError::InvalidSchema(err)
+	}
+}
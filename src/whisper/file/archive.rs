@@ -1,9 +1,12 @@
 use std::fmt;
+use std::cmp;
+use std::io;
 
 use memmap::MmapViewSync;
-use byteorder::{ByteOrder, BigEndian };
+use byteorder::{ ByteOrder, BigEndian };
 
 use whisper::Point;
+use whisper::error::{ Result, WhisperError };
 use super::super::point::{ self };
 
 // offset + seconds_per_point + points
@@ -24,6 +27,55 @@ pub struct Archive {
 	mmap_view: MmapViewSync
 }
 
+// Every slot of an archive in file order, empty ones included. Yields (slot index, point).
+pub struct Dump<'a> {
+	archive: &'a Archive,
+	index: usize
+}
+
+impl<'a> Iterator for Dump<'a> {
+	type Item = (usize, Point);
+
+	fn next(&mut self) -> Option<(usize, Point)> {
+		if self.index >= self.archive.points() {
+			return None;
+		}
+
+		let index = self.index;
+		self.index = self.index + 1;
+		Some((index, self.archive.read_index(&ArchiveIndex(index as u32))))
+	}
+
+	fn size_hint(&self) -> (usize, Option<usize>) {
+		let remaining = self.archive.points() - self.index;
+		(remaining, Some(remaining))
+	}
+}
+
+// The points of an archive in chronological order, empty and outdated slots skipped
+pub struct Points<'a> {
+	archive: &'a Archive,
+	interval: u32,
+	until: u32
+}
+
+impl<'a> Iterator for Points<'a> {
+	type Item = Point;
+
+	fn next(&mut self) -> Option<Point> {
+		while self.interval < self.until {
+			let interval = self.interval;
+			self.interval = self.interval.saturating_add(self.archive.seconds_per_point);
+
+			let point = self.archive.read_index(&self.archive.archive_index(&BucketName(interval)));
+			if point.0 == interval {
+				return Some(point);
+			}
+		}
+		None
+	}
+}
+
 impl fmt::Debug for Archive {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(f, "Archive(seconds_per_point: {}, points: {})", self.seconds_per_point, self.points)
@@ -45,26 +97,92 @@ impl Archive {
 		let archive_index = self.archive_index(&bucket_name);
 
 		let start = archive_index.0 as usize * point::POINT_SIZE;
-		let end = archive_index.0 as usize * point::POINT_SIZE + point::POINT_SIZE;
+		let end = start + point::POINT_SIZE;
 
-		let mut point_slice = &mut self.mut_slice()[start .. end];
+		let point_slice = &mut self.mut_slice()[start .. end];
 		point.write_to_slice(bucket_name, point_slice);
 	}
 
+	// Expects points sorted by timestamp. Points falling in the same slot collapse
+	// to the last one, and the slots are written in the order they sit in the
+	// file, so the writes walk the mmap front to back.
+	pub fn write_many(&mut self, points: &[Point]) {
+		if points.is_empty() {
+			return;
+		}
+
+		// In an empty archive the first point written anchors the others
+		let anchor = match self.anchor_bucket_name() {
+			BucketName(0) => self.bucket_name(points[0].0),
+			anchor => anchor
+		};
+		let mut slots : Vec<(usize, Point)> = points.iter().map(|point| {
+			let bucket_name = self.bucket_name(point.0);
+			(self.index_from_anchor(&anchor, &bucket_name).0 as usize, Point(bucket_name.0, point.1))
+		}).collect();
+		// Stable, the latest point of a slot stays last
+		slots.sort_by_key(|slot| slot.0);
+
+		let slice = self.mut_slice();
+		for (i, &(index, point)) in slots.iter().enumerate() {
+			if i + 1 < slots.len() && slots[i + 1].0 == index {
+				continue;
+			}
+			let start = index * point::POINT_SIZE;
+			point.write_to_slice(BucketName(point.0), &mut slice[start .. start + point::POINT_SIZE]);
+		}
+	}
+
+	// Empties the slots holding intervals in [from_interval, until_interval). The first
+	// slot anchors every index, so instead of zeroing it there it's moved back a whole
+	// lap: indexes stay the same but it no longer matches any interval being read.
+	// With nothing left to anchor it's emptied too.
+	pub fn clear(&mut self, from_interval: u32, until_interval: u32) {
+		let anchor = self.anchor_bucket_name().0;
+		let retention = self.retention();
+		// Moved back by an earlier clear, or outdated anyway
+		let mut anchor_outdated = match self.last_timestamp() {
+			Some(newest) => anchor as u64 + retention as u64 <= newest as u64,
+			None => false
+		};
+
+		for index in 0..self.points {
+			let point = self.read_index(&ArchiveIndex(index as u32));
+			if point.0 == 0 || point.0 < from_interval || point.0 >= until_interval {
+				continue;
+			}
+
+			let start = index * point::POINT_SIZE;
+			let end = start + point::POINT_SIZE;
+			let cleared = if index == 0 && anchor >= retention {
+				anchor_outdated = true;
+				BucketName(anchor - retention)
+			} else {
+				BucketName(0)
+			};
+			Point(cleared.0, 0.0).write_to_slice(cleared, &mut self.mut_slice()[start .. end]);
+		}
+
+		if anchor_outdated && (1..self.points).all(|index| self.read_index(&ArchiveIndex(index as u32)).0 == 0) {
+			Point(0, 0.0).write_to_slice(BucketName(0), &mut self.mut_slice()[0 .. point::POINT_SIZE]);
+		}
+	}
+
 	pub fn read_points(&self, from: BucketName, points: &mut[Point]) {
 		assert!(self.points() >= points.len(), "did not hold: {} >= {}", self.points(), points.len());
 		let start = self.archive_index(&from);
+		let point_size = point::POINT_SIZE;
 
-		let data_needed = points.len()*point::POINT_SIZE as usize;
+		let data_needed = points.len()*point_size;
 
-		let end_of_read = (start.0 as usize)*point::POINT_SIZE + data_needed;
+		let end_of_read = (start.0 as usize)*point_size + data_needed;
 
 		// Wrap around reads need two different passes
 		if end_of_read > self.size() {
 			let overflow_bytes = end_of_read-self.size();
 
 			let mut index = 0;
-			let first_start = start.0 as usize * point::POINT_SIZE;
+			let first_start = start.0 as usize * point_size;
 			let first_end = self.size();
 			let first_data = &self.slice()[first_start .. first_end];
 
@@ -72,30 +190,143 @@ impl Archive {
 			let second_end = overflow_bytes;
 			let second_data = &self.slice()[second_start .. second_end];
 
-			for pt_data in first_data.chunks(point::POINT_SIZE) {
+			for pt_data in first_data.chunks(point_size) {
 				assert!(pt_data.len() >= 8, "pt_data.len(): {} < 8 (first_start: {}, first_end: {})", pt_data.len(), first_start, first_end);
 				points[index] = Point::new_from_slice(pt_data);
 				index = index + 1;
 			};
-			for pt_data in second_data.chunks(point::POINT_SIZE) {
+			for pt_data in second_data.chunks(point_size) {
 				assert!(pt_data.len() >= 8, "pt_data.len(): {} < 8", pt_data.len());
 				points[index] = Point::new_from_slice(pt_data);
 				index = index + 1;
 			};
 		} else {
-			let start_index = start.0 as usize * point::POINT_SIZE;
+			let start_index = start.0 as usize * point_size;
 			let end_index = end_of_read;
 
 			let points_data = &self.slice()[start_index .. end_index];
-			for (i,pt_data) in points_data.chunks(point::POINT_SIZE).enumerate() {
+			for (i,pt_data) in points_data.chunks(point_size).enumerate() {
 				assert!(pt_data.len() >= 8, "pt_data.len(): {} < 8", pt_data.len());
-				println!("pt_data: 0x{:x}{:x}{:x}{:x}", pt_data[0], pt_data[1], pt_data[2], pt_data[3]);
 				// TODO: should we instead pass the point in to the constructor?
 				points[i] = Point::new_from_slice(pt_data)
 			};
 		};
 	}
 
+	// Values for every interval in [from_interval, until_interval). Slots still
+	// holding a point from a previous lap of the ring (or never written) are None.
+	pub fn fetch(&self, from_interval: u32, until_interval: u32) -> Vec<Option<f64>> {
+		let step = self.seconds_per_point;
+		let count = ((until_interval - from_interval) / step) as usize;
+		let mut values = Vec::with_capacity(count);
+
+		if self.anchor_bucket_name().0 == 0 {
+			values.resize(count, None);
+			return values;
+		}
+
+		let mut interval = from_interval;
+		for _ in 0..count {
+			let archive_index = self.archive_index(&BucketName(interval));
+			let point = self.read_index(&archive_index);
+			if point.0 == interval {
+				values.push(Some(point.1));
+			} else {
+				values.push(None);
+			}
+			interval = interval + step;
+		}
+
+		values
+	}
+
+	// The point in slot `index` as it is on disk, no timestamp math involved
+	pub fn read_slot(&self, index: usize) -> Result<Point> {
+		if index >= self.points {
+			return Err(WhisperError::SlotOutOfRange(index, self.points));
+		}
+		Ok(self.read_index(&ArchiveIndex(index as u32)))
+	}
+
+	// Puts `point` in slot `index` as is. Nothing checks the timestamp belongs there,
+	// a point in the wrong slot reads as empty.
+	pub fn write_slot(&mut self, index: usize, point: &Point) -> Result<()> {
+		if index >= self.points {
+			return Err(WhisperError::SlotOutOfRange(index, self.points));
+		}
+
+		let start = index * point::POINT_SIZE;
+		let end = start + point::POINT_SIZE;
+		point.write_to_slice(BucketName(point.0), &mut self.mut_slice()[start .. end]);
+		Ok(())
+	}
+
+	// Oldest stored interval, only reading the timestamp of each slot
+	pub fn first_timestamp(&self) -> Option<u32> {
+		// Slots a lap or more behind the newest are outdated, a cleared anchor
+		// among them
+		let newest = match self.last_timestamp() {
+			Some(newest) => newest as u64,
+			None => return None
+		};
+		let retention = self.retention() as u64;
+		self.slice().chunks(point::POINT_SIZE)
+			.map(|slot| BigEndian::read_u32(&slot[0..4]))
+			.filter(|&timestamp| timestamp != 0 && timestamp as u64 + retention > newest)
+			.min()
+	}
+
+	// Newest stored interval, only reading the timestamp of each slot
+	pub fn last_timestamp(&self) -> Option<u32> {
+		self.slice().chunks(point::POINT_SIZE)
+			.map(|slot| BigEndian::read_u32(&slot[0..4]))
+			.max()
+			.and_then(|timestamp| if timestamp == 0 { None } else { Some(timestamp) })
+	}
+
+	pub fn flush(&mut self) -> io::Result<()> {
+		self.mmap_view.flush()
+	}
+
+	// Walks back one retention from the newest point, wrapping around the ring as needed
+	pub fn iter(&self) -> Points {
+		self.iter_range(0, u32::max_value())
+	}
+
+	// Only the points with from <= timestamp < until
+	pub fn iter_range(&self, from: u32, until: u32) -> Points {
+		let step = self.seconds_per_point;
+		let (oldest, newest) = match self.last_timestamp() {
+			Some(last) => (last.saturating_sub(self.retention() - step), last.saturating_add(step)),
+			None => (0, 0)
+		};
+
+		// Stored timestamps are aligned, so start at the first interval not before `from`
+		let from_interval = match from % step {
+			0 => from,
+			remainder => from.saturating_add(step - remainder)
+		};
+
+		Points {
+			archive: self,
+			interval: cmp::max(from_interval, oldest),
+			until: cmp::min(until, newest)
+		}
+	}
+
+	pub fn dump(&self) -> Dump {
+		Dump { archive: self, index: 0 }
+	}
+
+	// Slots holding a timestamp off the archive's precision, or one that belongs
+	// in another slot. Either way reads can't see the point.
+	pub fn misplaced_slots(&self) -> usize {
+		let anchor = self.anchor_bucket_name();
+		self.dump().filter(|&(index, point)| {
+			point.0 != 0 && (point.0 % self.seconds_per_point != 0 || self.index_from_anchor(&anchor, &BucketName(point.0)).0 as usize != index)
+		}).count()
+	}
+
 	#[inline]
 	pub fn seconds_per_point(&self) -> u32 {
 		self.seconds_per_point
@@ -106,6 +337,11 @@ impl Archive {
 		self.points
 	}
 
+	#[inline]
+	pub fn retention(&self) -> u32 {
+		self.seconds_per_point * self.points as u32
+	}
+
 	#[inline]
 	pub fn size(&self) -> usize {
 		self.mmap_view.len()
@@ -121,22 +357,34 @@ impl Archive {
     fn archive_index(&self, bucket_name: &BucketName) -> ArchiveIndex {
     	// This line unnecessarily keeps that first data page hot all the time.
     	// TODO: cache
-    	let anchor_bucket_name = self.anchor_bucket_name();
+    	self.index_from_anchor(&self.anchor_bucket_name(), bucket_name)
+    }
+
+    #[inline]
+    fn index_from_anchor(&self, anchor_bucket_name: &BucketName, bucket_name: &BucketName) -> ArchiveIndex {
     	if anchor_bucket_name.0 == 0 {
     		ArchiveIndex(0)
     	} else {
-    		let time_distance = bucket_name.0 + anchor_bucket_name.0;
-    		// let distance_in_points = time_distance / self.seconds_per_point;
-    		// let point_distance = ( anchor_bucket_name.0 - bucket_name.0 ) % (self.points as u32);
-    		let point_distance = time_distance / self.seconds_per_point;
-    		// panic!("({}-{}) % {} = {}", anchor_bucket_name.0, bucket_name.0, self.points, point_distance);
+    		// Buckets before the anchor have a negative distance and wrap around backwards
+    		let time_distance = bucket_name.0 as i64 - anchor_bucket_name.0 as i64;
+    		let point_distance = Archive::py_div(time_distance, self.seconds_per_point as i64);
     		let index = Archive::py_mod(point_distance, self.points as u32);
     		ArchiveIndex(index)
     	}
     }
 
-    fn py_mod(input: u32, base: u32) -> u32 {
-        let remainder = input as i64 % base as i64;
+    // Integer division rounding towards negative infinity, like python's `//`
+    fn py_div(input: i64, base: i64) -> i64 {
+        let quotient = input / base;
+        if (input % base != 0) && ((input < 0) != (base < 0)) {
+            quotient - 1
+        } else {
+            quotient
+        }
+    }
+
+    fn py_mod(input: i64, base: u32) -> u32 {
+        let remainder = input % base as i64;
 
         if remainder < 0 {
             (base as i64 + remainder) as u32
@@ -145,10 +393,16 @@ impl Archive {
         }
     }
 
+    #[inline]
+    fn read_index(&self, archive_index: &ArchiveIndex) -> Point {
+    	let start = archive_index.0 as usize * point::POINT_SIZE;
+    	let end = start + point::POINT_SIZE;
+    	Point::new_from_slice(&self.slice()[start .. end])
+    }
+
     #[inline]
     pub fn anchor_bucket_name(&self) -> BucketName {
-    	let first_four_bytes = BigEndian::read_u32(&self.slice()[0..5]);
-    	BucketName( first_four_bytes )
+    	BucketName( BigEndian::read_u32(&self.slice()[0..4]) )
     }
 
     #[inline]
@@ -265,4 +519,132 @@ mod tests {
 			assert_eq!(points_buf[0].1, 8.0);
 		}
 	}
+
+	#[test]
+	fn test_write_many(){
+		let anon_view = build_mmap().into_view_sync();
+		let mut archive = Archive::new(2, 3, anon_view);
+
+		// Duplicate intervals keep the last value
+		archive.write_many(&[
+			Point(1440392090, 1.0),
+			Point(1440392091, 2.0),
+			Point(1440392098, 3.0)
+		]);
+
+		let mut points_buf = vec![Point(0, 0.0); 3];
+		archive.read_points(archive.anchor_bucket_name(), &mut points_buf[..]);
+		assert_eq!(points_buf, vec![
+			Point(1440392088, 100.0),
+			Point(1440392090, 2.0),
+			Point(1440392098, 3.0)
+		]);
+	}
+
+	#[test]
+	fn test_write_many_wrapping(){
+		let anon_view = build_mmap().into_view_sync();
+		let mut archive = Archive::new(2, 3, anon_view);
+
+		// 1440392094 is a whole lap after the anchor and lands in its slot, the
+		// latest of the two wins whatever order the slots are written in
+		archive.write_many(&[
+			Point(1440392088, 1.0),
+			Point(1440392092, 2.0),
+			Point(1440392094, 3.0)
+		]);
+
+		assert_eq!(archive.read_index(&ArchiveIndex(0)), Point(1440392094, 3.0));
+		assert_eq!(archive.read_index(&ArchiveIndex(2)), Point(1440392092, 2.0));
+	}
+
+	#[test]
+	fn test_clear(){
+		let anon_view = build_mmap().into_view_sync();
+		let mut archive = Archive::new(2, 3, anon_view);
+
+		archive.clear(1440392088, 1440392092);
+		assert_eq!(archive.fetch(1440392088, 1440392094), vec![None, None, Some(100.0)]);
+		// Writing around the cleared anchor still lands in the same slots
+		assert_eq!(archive.archive_index(&BucketName(1440392092)), ArchiveIndex(2));
+		archive.write(&Point(1440392088, 1.0));
+		assert_eq!(archive.fetch(1440392088, 1440392094), vec![Some(1.0), None, Some(100.0)]);
+	}
+
+	#[test]
+	fn test_clear_first_timestamp(){
+		let anon_view = build_mmap().into_view_sync();
+		let mut archive = Archive::new(2, 3, anon_view);
+
+		// The anchor moved back a lap is no point
+		archive.clear(1440392088, 1440392090);
+		assert_eq!(archive.first_timestamp(), Some(1440392090));
+		assert_eq!(archive.last_timestamp(), Some(1440392092));
+
+		archive.clear(1440392088, 1440392094);
+		assert_eq!(archive.first_timestamp(), None);
+		assert_eq!(archive.last_timestamp(), None);
+	}
+
+	#[test]
+	fn test_first_last_timestamp(){
+		let anon_view = build_mmap().into_view_sync();
+		let mut archive = Archive::new(2, 3, anon_view);
+		assert_eq!(archive.first_timestamp(), Some(1440392088));
+		assert_eq!(archive.last_timestamp(), Some(1440392092));
+
+		// Wrapped around: the newest point sits before the oldest
+		archive.write(&Point(1440392094, 1.0));
+		assert_eq!(archive.first_timestamp(), Some(1440392090));
+		assert_eq!(archive.last_timestamp(), Some(1440392094));
+
+		let empty = Archive::new(2, 3, Mmap::anonymous(36, Protection::ReadWrite).unwrap().into_view_sync());
+		assert_eq!(empty.first_timestamp(), None);
+		assert_eq!(empty.last_timestamp(), None);
+	}
+
+	#[test]
+	fn test_iter(){
+		let anon_view = build_mmap().into_view_sync();
+		let mut archive = Archive::new(2, 3, anon_view);
+		archive.write(&Point(1440392094, 1.0));
+
+		// The newest point wrapped around to the first slot
+		let points : Vec<Point> = archive.iter().collect();
+		assert_eq!(points, vec![Point(1440392090, 100.0), Point(1440392092, 100.0), Point(1440392094, 1.0)]);
+
+		let points : Vec<Point> = archive.iter_range(1440392091, 1440392094).collect();
+		assert_eq!(points, vec![Point(1440392092, 100.0)]);
+
+		let empty = Archive::new(2, 3, Mmap::anonymous(36, Protection::ReadWrite).unwrap().into_view_sync());
+		assert_eq!(empty.iter().count(), 0);
+	}
+
+	#[test]
+	fn test_read_write_slot(){
+		let anon_view = build_mmap().into_view_sync();
+		let mut archive = Archive::new(2, 3, anon_view);
+
+		assert_eq!(archive.read_slot(1).unwrap(), Point(1440392090, 100.0));
+		archive.write_slot(2, &Point(1440392086, 7.0)).unwrap();
+		assert_eq!(archive.read_slot(2).unwrap(), Point(1440392086, 7.0));
+		assert_eq!(archive.fetch(1440392086, 1440392088), vec![Some(7.0)]);
+
+		assert!(archive.read_slot(3).is_err());
+		assert!(archive.write_slot(3, &Point(1440392086, 7.0)).is_err());
+	}
+
+	#[test]
+	fn test_dump(){
+		let anon_view = build_mmap().into_view_sync();
+		let mut archive = Archive::new(2, 3, anon_view);
+		archive.write(&Point(1440392086, 5.0));
+
+		let dumped : Vec<(usize, Point)> = archive.dump().collect();
+		assert_eq!(dumped, vec![
+			(0, Point(1440392088, 100.0)),
+			(1, Point(1440392090, 100.0)),
+			(2, Point(1440392086, 5.0))
+		]);
+	}
 }
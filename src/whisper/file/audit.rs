@@ -0,0 +1,140 @@
+use std::fmt;
+use std::fs;
+use std::path::Path;
+
+use whisper::error::WhisperError;
+use super::{ WhisperFile, AggregationType, SchemaDiff };
+use super::header::Header;
+
+// Something wrong with a whisper file
+#[derive(Debug, PartialEq, Clone)]
+pub enum FileDefect {
+	// Couldn't be read at all
+	Unreadable(String),
+	Empty,
+	// Shorter than its archive infos make it, as files are left by a full disk
+	Truncated { size: u64, expected: u64 },
+	// Longer than its archive infos make it
+	TrailingBytes { size: u64, expected: u64 },
+	InvalidHeader(String),
+	UnknownAggregationMethod,
+	// Slots reads can't see, see Archive::misplaced_slots
+	MisplacedPoints { archive: usize, slots: usize },
+	// Not laid out the way it would be created today
+	SchemaMismatch(SchemaDiff)
+}
+
+impl fmt::Display for FileDefect {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match *self {
+			FileDefect::Unreadable(ref reason) => write!(f, "unreadable: {}", reason),
+			FileDefect::Empty => write!(f, "empty"),
+			FileDefect::Truncated { size, expected } => write!(f, "truncated: {} bytes, archives need {}", size, expected),
+			FileDefect::TrailingBytes { size, expected } => write!(f, "{} bytes past the archives' {}", size - expected, expected),
+			FileDefect::InvalidHeader(ref reason) => write!(f, "invalid header: {}", reason),
+			FileDefect::UnknownAggregationMethod => write!(f, "unknown aggregation method"),
+			FileDefect::MisplacedPoints { archive, slots } => write!(f, "{} misplaced points in archive {}", slots, archive),
+			FileDefect::SchemaMismatch(ref diff) => write!(f, "schema mismatch: {:?}", diff)
+		}
+	}
+}
+
+impl WhisperFile {
+	// Everything wrong with the file at `path`, nothing when it is sound. The
+	// bytes are looked at before the file is opened, so files too broken to
+	// open still get their defect named.
+	pub fn audit<P>(path: P) -> Vec<FileDefect>
+		where P: AsRef<Path> {
+		let data = match fs::read(path.as_ref()) {
+			Ok(data) => data,
+			Err(err) => return vec![FileDefect::Unreadable(err.to_string())]
+		};
+		if data.is_empty() {
+			return vec![FileDefect::Empty];
+		}
+
+		let size = data.len() as u64;
+		match (Header::validate(&data), Header::expected_size(&data)) {
+			(Ok(()), Some(expected)) if size > expected => return vec![FileDefect::TrailingBytes { size: size, expected: expected }],
+			(Ok(()), _) => (),
+			(Err(_), Some(expected)) if size < expected => return vec![FileDefect::Truncated { size: size, expected: expected }],
+			(Err(WhisperError::InvalidHeader(reason)), _) => return vec![FileDefect::InvalidHeader(reason)],
+			(Err(err), _) => return vec![FileDefect::Unreadable(err.to_string())]
+		}
+
+		let file = match WhisperFile::open_readonly(path.as_ref()) {
+			Ok(file) => file,
+			Err(err) => return vec![FileDefect::Unreadable(err.to_string())]
+		};
+		let mut defects = vec![];
+		if file.header.aggregation_type == AggregationType::Unknown {
+			defects.push(FileDefect::UnknownAggregationMethod);
+		}
+		for (index, archive) in file.archives.iter().enumerate() {
+			let slots = archive.misplaced_slots();
+			if slots > 0 {
+				defects.push(FileDefect::MisplacedPoints { archive: index, slots: slots });
+			}
+		}
+		defects
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use whisper::{ WhisperFile, FileDefect, Schema, Point, temp_path };
+	use std::fs::{ self, OpenOptions };
+	use std::io::{ Seek, SeekFrom, Write };
+
+	#[test]
+	fn test_audit() {
+		let path = &temp_path("test_audit.wsp");
+		fs::remove_file(path).ok();
+		let schema = Schema::parse("10s:60s,60s:600s").unwrap();
+		let size = schema.size_on_disk() as u64;
+		{
+			let mut file = WhisperFile::new(path, &schema).unwrap();
+			file.write_at(1440392100, &Point(1440392100, 1.0)).unwrap();
+			file.write_at(1440392100, &Point(1440392090, 2.0)).unwrap();
+		}
+		assert_eq!(WhisperFile::audit(path), vec![]);
+
+		// A copy of the second point in a slot it doesn't belong in, and a
+		// timestamp off the minute
+		{
+			let mut file = WhisperFile::open(path).unwrap();
+			file.archives[0].write_slot(1, &Point(0, 0.0)).unwrap();
+			file.archives[0].write_slot(2, &Point(1440392090, 2.0)).unwrap();
+			file.archives[1].write_slot(3, &Point(1440392101, 2.0)).unwrap();
+		}
+		assert_eq!(WhisperFile::audit(path), vec![
+			FileDefect::MisplacedPoints { archive: 0, slots: 1 },
+			FileDefect::MisplacedPoints { archive: 1, slots: 1 }
+		]);
+
+		OpenOptions::new().append(true).open(path).unwrap().write_all(b"junk").unwrap();
+		assert_eq!(WhisperFile::audit(path), vec![FileDefect::TrailingBytes { size: size + 4, expected: size }]);
+
+		OpenOptions::new().write(true).open(path).unwrap().set_len(size - 12).unwrap();
+		assert_eq!(WhisperFile::audit(path), vec![FileDefect::Truncated { size: size - 12, expected: size }]);
+
+		{
+			let mut file = OpenOptions::new().write(true).open(path).unwrap();
+			file.set_len(size).unwrap();
+			file.seek(SeekFrom::Start(8)).unwrap();
+			file.write_all(&[0x40, 0, 0, 0]).unwrap();
+		}
+		match WhisperFile::audit(path).as_slice() {
+			[FileDefect::InvalidHeader(ref reason)] => assert!(reason.contains("xFilesFactor"), "{}", reason),
+			other => panic!("expected an invalid header, got {:?}", other)
+		}
+
+		OpenOptions::new().write(true).open(path).unwrap().set_len(0).unwrap();
+		assert_eq!(WhisperFile::audit(path), vec![FileDefect::Empty]);
+		fs::remove_file(path).unwrap();
+		match WhisperFile::audit(path).as_slice() {
+			[FileDefect::Unreadable(_)] => (),
+			other => panic!("expected an unreadable file, got {:?}", other)
+		}
+	}
+}
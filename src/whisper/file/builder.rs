@@ -0,0 +1,170 @@
+use std::fs::{ self, OpenOptions };
+use std::io;
+use std::path::Path;
+#[cfg(unix)]
+use std::os::unix::fs::OpenOptionsExt;
+
+use memmap::{ Mmap, Protection };
+
+use whisper::{ Schema, AggregationType };
+use whisper::error::{ Result, WhisperError };
+use super::header::Header;
+use super::{ WhisperFile, Allocation, DEFAULT_X_FILES_FACTOR };
+
+// Everything a new file can be created with. Defaults to the same file as
+// WhisperFile::new: averaging, an xFilesFactor of 0.5 and fully allocated.
+//
+//     let file = try!( WhisperBuilder::new(schema).aggregation(AggregationType::Sum).sparse(true).create(path) );
+#[derive(Debug, Clone)]
+pub struct WhisperBuilder {
+	schema: Schema,
+	aggregation_type: AggregationType,
+	x_files_factor: f32,
+	allocation: Allocation,
+	mode: Option<u32>,
+	create_new: bool
+}
+
+impl WhisperBuilder {
+	pub fn new(schema: Schema) -> WhisperBuilder {
+		WhisperBuilder {
+			schema: schema,
+			aggregation_type: AggregationType::Average,
+			x_files_factor: DEFAULT_X_FILES_FACTOR,
+			allocation: Allocation::Full,
+			mode: None,
+			create_new: false
+		}
+	}
+
+	pub fn aggregation(mut self, aggregation_type: AggregationType) -> WhisperBuilder {
+		self.aggregation_type = aggregation_type;
+		self
+	}
+
+	pub fn x_files_factor(mut self, x_files_factor: f32) -> WhisperBuilder {
+		self.x_files_factor = x_files_factor;
+		self
+	}
+
+	pub fn allocation(mut self, allocation: Allocation) -> WhisperBuilder {
+		self.allocation = allocation;
+		self
+	}
+
+	// Same as carbon's WHISPER_SPARSE_CREATE
+	pub fn sparse(self, sparse: bool) -> WhisperBuilder {
+		self.allocation(if sparse { Allocation::Sparse } else { Allocation::Full })
+	}
+
+	// Permission bits of the new file, masked by the umask like open(2).
+	// 0o666 unless set.
+	#[cfg(unix)]
+	pub fn mode(mut self, mode: u32) -> WhisperBuilder {
+		self.mode = Some(mode);
+		self
+	}
+
+	// Fail with an AlreadyExists io error rather than replace a file at the
+	// path, however recently it showed up, like OpenOptions::create_new
+	pub fn create_new(mut self, create_new: bool) -> WhisperBuilder {
+		self.create_new = create_new;
+		self
+	}
+
+	pub fn create<P>(&self, path: P) -> Result<WhisperFile>
+		where P: AsRef<Path> {
+		if !(self.x_files_factor >= 0.0 && self.x_files_factor <= 1.0) {
+			return Err(WhisperError::InvalidXFilesFactor(self.x_files_factor));
+		}
+		if self.aggregation_type == AggregationType::Unknown {
+			return Err(WhisperError::UnknownAggregationType);
+		}
+		try!( self.schema.validate() );
+
+		// Built next to the destination and renamed over it once complete, so
+		// nobody ever opens a half written file. A link can't replace what's
+		// there, so create_new links it in instead.
+		let tmp_path = WhisperFile::temporary_path(path.as_ref());
+		let mut options = OpenOptions::new();
+		options.read(true).write(true).create(true).truncate(true);
+		self.set_mode(&mut options);
+		let mut opened_file = try!( options.open(&tmp_path) );
+
+		let header = Header::new(self.aggregation_type.clone(), self.schema.max_retention(), self.x_files_factor);
+		let created = WhisperFile::write_new_file(&mut opened_file, &self.schema, &header, self.allocation)
+			.and_then(|_| self.publish(&tmp_path, path.as_ref()).map_err(WhisperError::from));
+		if let Err(err) = created {
+			fs::remove_file(&tmp_path).ok();
+			return Err(err);
+		}
+
+		let mmap = try!( Mmap::open(&opened_file, Protection::ReadWrite ) );
+
+		WhisperFile::open_mmap(path.as_ref(), opened_file, mmap, false)
+	}
+
+	fn publish(&self, tmp_path: &Path, path: &Path) -> io::Result<()> {
+		if self.create_new {
+			try!( fs::hard_link(tmp_path, path) );
+			fs::remove_file(tmp_path)
+		} else {
+			fs::rename(tmp_path, path)
+		}
+	}
+
+	#[cfg(unix)]
+	fn set_mode(&self, options: &mut OpenOptions) {
+		if let Some(mode) = self.mode {
+			options.mode(mode);
+		}
+	}
+
+	#[cfg(not(unix))]
+	fn set_mode(&self, _options: &mut OpenOptions) {}
+}
+
+#[cfg(test)]
+mod tests {
+	use whisper::{ Schema, WhisperBuilder, WhisperFile, AggregationType, Allocation, WhisperError, temp_path };
+	use std::fs;
+
+	#[test]
+	fn test_builder() {
+		let path = &temp_path("test_builder.wsp");
+		fs::remove_file(path).ok();
+		let schema = Schema::parse("10s:60s,60s:600s").unwrap();
+
+		let file = WhisperBuilder::new(schema.clone()).aggregation(AggregationType::Sum).x_files_factor(0.0).sparse(true).create(path).unwrap();
+		assert_eq!(file.info().aggregation_type, AggregationType::Sum);
+		assert_eq!(file.info().x_files_factor, 0.0);
+
+		match WhisperBuilder::new(schema.clone()).create_new(true).create(path) {
+			Err(WhisperError::Io(ref err)) if err.kind() == ::std::io::ErrorKind::AlreadyExists => (),
+			other => panic!("unexpected {:?}", other.map(|_| ()))
+		}
+		assert_eq!(WhisperFile::open(path).unwrap().info().aggregation_type, AggregationType::Sum);
+
+		let builder = WhisperBuilder::new(schema).sparse(false);
+		assert_eq!(builder.allocation, Allocation::Full);
+		match builder.clone().x_files_factor(2.0).create(path) {
+			Err(WhisperError::InvalidXFilesFactor(_)) => (),
+			other => panic!("unexpected {:?}", other.map(|_| ()))
+		}
+		match builder.aggregation(AggregationType::Unknown).create(path) {
+			Err(WhisperError::UnknownAggregationType) => (),
+			other => panic!("unexpected {:?}", other.map(|_| ()))
+		}
+	}
+
+	#[cfg(unix)]
+	#[test]
+	fn test_builder_mode() {
+		use std::os::unix::fs::PermissionsExt;
+
+		let path = &temp_path("test_builder_mode.wsp");
+		fs::remove_file(path).ok();
+		WhisperBuilder::new(Schema::parse("10s:60s").unwrap()).mode(0o600).create(path).unwrap();
+		assert_eq!(fs::metadata(path).unwrap().permissions().mode() & 0o777, 0o600);
+	}
+}
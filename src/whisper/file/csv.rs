@@ -0,0 +1,55 @@
+use std::io::Write;
+
+use whisper::error::Result;
+use super::{ WhisperFile, current_time };
+
+impl WhisperFile {
+	pub fn dump_csv<W>(&self, writer: &mut W, null: &str) -> Result<()>
+		where W: Write {
+		self.dump_csv_at(current_time(), writer, null)
+	}
+
+	// Every archive as `archive,timestamp,value` rows, finest first, each over
+	// its whole retention up to `now`. Intervals nothing was written to get
+	// `null`, an empty string leaves them blank.
+	pub fn dump_csv_at<W>(&self, now: u32, writer: &mut W, null: &str) -> Result<()>
+		where W: Write {
+		try!( writeln!(writer, "archive,timestamp,value") );
+		for archive_index in 0..self.archives.len() {
+			let archive = &self.archives[archive_index];
+			let series = self.fetch_archive(archive_index, now.saturating_sub(archive.retention()), now);
+			for (timestamp, value) in series.iter() {
+				match value {
+					Some(value) => try!( writeln!(writer, "{},{},{}", archive_index, timestamp, value) ),
+					None => try!( writeln!(writer, "{},{},{}", archive_index, timestamp, null) )
+				}
+			}
+		}
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use whisper::{ Schema, WhisperFile, Point, temp_path };
+	use std::fs;
+
+	#[test]
+	fn test_dump_csv() {
+		let path = temp_path("test_dump_csv.wsp");
+		fs::remove_file(&path).ok();
+		let now = 1440392100;
+		let schema = Schema::new_from_retention_specs(vec!["10s:30s".to_string(), "30s:60s".to_string()]).unwrap();
+		let mut file = WhisperFile::new(&path, &schema).unwrap();
+		file.update_many_at(now, &[Point(now - 20, 1.0), Point(now - 10, 2.0), Point(now, 3.0)]).unwrap();
+
+		let mut csv = vec![];
+		file.dump_csv_at(now, &mut csv, "nan").unwrap();
+		assert_eq!(String::from_utf8(csv).unwrap(), "archive,timestamp,value\n\
+			0,1440392080,1\n\
+			0,1440392090,2\n\
+			0,1440392100,3\n\
+			1,1440392070,1.5\n\
+			1,1440392100,nan\n");
+	}
+}
@@ -0,0 +1,97 @@
+use std::cmp;
+
+use whisper::error::{ Result, WhisperError };
+use super::{ WhisperFile, current_time };
+
+// The points of one archive that differ between two files
+#[derive(Debug, PartialEq)]
+pub struct ArchiveDiff {
+	pub archive_index: usize,
+	// (timestamp, value in this file, value in the other file)
+	pub diffs: Vec<(u32, Option<f64>, Option<f64>)>,
+	// How many points were compared
+	pub total_points: usize
+}
+
+impl WhisperFile {
+	pub fn diff(&self, other: &WhisperFile, from: u32, until: u32, ignore_empty: bool) -> Result<Vec<ArchiveDiff>> {
+		self.diff_at(current_time(), other, from, until, ignore_empty)
+	}
+
+	// Same as whisper-diff.py: compares the files archive by archive, each archive only
+	// over the time before the previous (finer) one. Points empty in both files are
+	// never compared, with `ignore_empty` points empty in either file are skipped.
+	pub fn diff_at(&self, now: u32, other: &WhisperFile, from: u32, until: u32, ignore_empty: bool) -> Result<Vec<ArchiveDiff>> {
+		if from > until {
+			return Err(WhisperError::InvalidTimeInterval(from, until));
+		}
+		if !self.same_archives(other) {
+			return Err(WhisperError::IncompatibleArchives);
+		}
+		let _lock = try!( self.lock_shared() );
+
+		let mut archive_indexes : Vec<usize> = (0..self.archives.len()).collect();
+		archive_indexes.sort_by_key(|&index| self.archives[index].retention());
+
+		let mut until = cmp::min(until, now);
+		let mut archive_diffs = Vec::with_capacity(archive_indexes.len());
+		for archive_index in archive_indexes {
+			let start_time = now.saturating_sub(self.archives[archive_index].retention());
+			let ours = self.fetch_archive(archive_index, cmp::max(start_time, from), until);
+			let theirs = other.fetch_archive(archive_index, cmp::max(start_time, from), until);
+
+			let points : Vec<(u32, Option<f64>, Option<f64>)> = ours.iter().zip(theirs.values.iter())
+				.map(|((timestamp, a), b)| (timestamp, a, *b))
+				.filter(|&(_, a, b)| if ignore_empty { a.is_some() && b.is_some() } else { a.is_some() || b.is_some() })
+				.collect();
+
+			archive_diffs.push(ArchiveDiff {
+				archive_index: archive_index,
+				total_points: points.len(),
+				diffs: points.into_iter().filter(|&(_, a, b)| a != b).collect()
+			});
+
+			until = cmp::min(start_time, until);
+		}
+
+		Ok(archive_diffs)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use whisper::{ Schema, WhisperFile, Point, temp_path };
+	use super::ArchiveDiff;
+	use std::fs;
+
+	fn build_file(path: &str) -> WhisperFile {
+		fs::remove_file(path).ok();
+		let schema = Schema::new_from_retention_specs(vec!["10s:60s".to_string(), "60s:600s".to_string()]).unwrap();
+		WhisperFile::new(path, &schema).unwrap()
+	}
+
+	#[test]
+	fn test_diff() {
+		let now = 1440392100;
+		let mut a = build_file(&temp_path("test_diff_a.wsp"));
+		let mut b = build_file(&temp_path("test_diff_b.wsp"));
+
+		a.update_many_at(now, &[Point(now - 50, 1.0), Point(now - 40, 2.0), Point(now - 30, 3.0), Point(now - 300, 4.0)]).unwrap();
+		b.update_many_at(now, &[Point(now - 50, 1.0), Point(now - 40, 7.0), Point(now - 300, 4.0)]).unwrap();
+
+		let diffs = a.diff_at(now, &b, 0, now, false).unwrap();
+		assert_eq!(diffs[0], ArchiveDiff {
+			archive_index: 0,
+			diffs: vec![(now - 40, Some(2.0), Some(7.0)), (now - 30, Some(3.0), None)],
+			total_points: 3
+		});
+		// Only up to where the first archive starts, which b's two points don't fill enough to propagate
+		assert_eq!(diffs[1], ArchiveDiff { archive_index: 1, diffs: vec![(now - 60, Some(2.0), None)], total_points: 2 });
+
+		let diffs = a.diff_at(now, &b, 0, now, true).unwrap();
+		assert_eq!(diffs[0].diffs, vec![(now - 40, Some(2.0), Some(7.0))]);
+
+		let diffs = a.diff_at(now, &b, now - 35, now, false).unwrap();
+		assert_eq!(diffs[0].diffs, vec![(now - 30, Some(3.0), None)]);
+	}
+}
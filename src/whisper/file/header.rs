@@ -1,14 +1,25 @@
 use std::fmt;
+use std::cmp;
+use std::str::FromStr;
 
 use memmap::MmapViewSync;
 use byteorder::{ ByteOrder, BigEndian };
 
 use super::archive::{ self, Archive };
 use super::super::point;
+use whisper::error::{ self, WhisperError };
 
 #[derive(Debug, PartialEq, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub enum AggregationType {
 	Average,
+	Sum,
+	Last,
+	Max,
+	Min,
+	AvgZero,
+	AbsMax,
+	AbsMin,
 	Unknown
 }
 
@@ -16,31 +27,111 @@ impl fmt::Display for AggregationType {
 	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
 		match *self {
 			AggregationType::Average => write!(f, "average"),
+			AggregationType::Sum => write!(f, "sum"),
+			AggregationType::Last => write!(f, "last"),
+			AggregationType::Max => write!(f, "max"),
+			AggregationType::Min => write!(f, "min"),
+			AggregationType::AvgZero => write!(f, "avg_zero"),
+			AggregationType::AbsMax => write!(f, "absmax"),
+			AggregationType::AbsMin => write!(f, "absmin"),
 			AggregationType::Unknown => write!(f, "unknown")
 		}
 	}
 }
 
+// Accepts the method names used by whisper.py and storage-aggregation.conf
+impl FromStr for AggregationType {
+	type Err = String;
+
+	fn from_str(s: &str) -> Result<AggregationType, String> {
+		match s {
+			"average" | "avg" => Ok(AggregationType::Average),
+			"sum" => Ok(AggregationType::Sum),
+			"last" => Ok(AggregationType::Last),
+			"max" => Ok(AggregationType::Max),
+			"min" => Ok(AggregationType::Min),
+			"avg_zero" => Ok(AggregationType::AvgZero),
+			"absmax" => Ok(AggregationType::AbsMax),
+			"absmin" => Ok(AggregationType::AbsMin),
+			_ => Err(format!("Unrecognized aggregation method: {}", s))
+		}
+	}
+}
+
 impl AggregationType {
 	pub fn from_u32(val: u32) -> AggregationType {
 		match val {
+			1 => AggregationType::Average,
+			2 => AggregationType::Sum,
+			3 => AggregationType::Last,
+			4 => AggregationType::Max,
+			5 => AggregationType::Min,
+			6 => AggregationType::AvgZero,
+			7 => AggregationType::AbsMax,
+			8 => AggregationType::AbsMin,
 			_ => AggregationType::Unknown
 		}
 	}
 
 	pub fn to_u32(&self) -> u32 {
 		match *self {
-			AggregationType::Average => 0,
+			AggregationType::Average => 1,
+			AggregationType::Sum => 2,
+			AggregationType::Last => 3,
+			AggregationType::Max => 4,
+			AggregationType::Min => 5,
+			AggregationType::AvgZero => 6,
+			AggregationType::AbsMax => 7,
+			AggregationType::AbsMin => 8,
 			AggregationType::Unknown => 10
 		}
 	}
+
+	// Rolls up the known values out of `neighbor_count` higher precision slots.
+	// None when the method can't be applied.
+	pub fn aggregate(&self, known_values: &[f64], neighbor_count: usize) -> Option<f64> {
+		assert!(known_values.len() <= neighbor_count, "{} known values out of {} neighbors", known_values.len(), neighbor_count);
+		if known_values.is_empty() {
+			return None;
+		}
+
+		// Folding left to right keeps float results identical to python's sum()
+		let sum = || known_values.iter().fold(0.0, |sum, value| sum + value);
+
+		match *self {
+			AggregationType::Average => Some(sum() / known_values.len() as f64),
+			AggregationType::Sum => Some(sum()),
+			AggregationType::Last => known_values.last().cloned(),
+			AggregationType::Max => known_values.iter().cloned().fold(None, |max, value| match max {
+				Some(max) if max >= value => Some(max),
+				_ => Some(value)
+			}),
+			AggregationType::Min => known_values.iter().cloned().fold(None, |min, value| match min {
+				Some(min) if min <= value => Some(min),
+				_ => Some(value)
+			}),
+			// Unknown slots count as zero
+			AggregationType::AvgZero => Some(sum() / neighbor_count as f64),
+			// Like python's max(values, key=abs), the first of equally large magnitudes wins
+			AggregationType::AbsMax => known_values.iter().cloned().fold(None, |max, value| match max {
+				Some(max) if max.abs() >= value.abs() => Some(max),
+				_ => Some(value)
+			}),
+			AggregationType::AbsMin => known_values.iter().cloned().fold(None, |min, value| match min {
+				Some(min) if min.abs() <= value.abs() => Some(min),
+				_ => Some(value)
+			}),
+			AggregationType::Unknown => None
+		}
+	}
 }
 
 #[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Header {
 	pub aggregation_type: AggregationType,
 	pub max_retention: u32,
-	pub x_files_factor: f32,
+	pub x_files_factor: f32
 }
 
 pub const STATIC_HEADER_SIZE : usize = 16;
@@ -53,8 +144,8 @@ struct ArchiveInfo(u32,usize);
 impl Header {
 	pub fn new_from_slice(mmap_data: &[u8]) -> Header {
 		let aggregation_type_u32 = BigEndian::read_u32(&mmap_data[0..4]);
-		let max_retention = BigEndian::read_u32(&mmap_data[4..9]);
-		let x_files_factor = BigEndian::read_f32(&mmap_data[8..13]);
+		let max_retention = BigEndian::read_u32(&mmap_data[4..8]);
+		let x_files_factor = BigEndian::read_f32(&mmap_data[8..12]);
 
 		let agg_type = AggregationType::from_u32(aggregation_type_u32);
 
@@ -69,9 +160,83 @@ impl Header {
 		}
 	}
 
+	// Checks everything mmap_to_archives relies on, so a truncated or foreign
+	// file is an error instead of a panic (or worse, archives made of garbage).
+	pub fn validate(mmap_data: &[u8]) -> error::Result<()> {
+		if mmap_data.len() < STATIC_HEADER_SIZE {
+			return Err(WhisperError::InvalidHeader(format!("file is {} bytes, too small for a header", mmap_data.len())));
+		}
+
+		let max_retention = BigEndian::read_u32(&mmap_data[4..8]);
+		let x_files_factor = BigEndian::read_f32(&mmap_data[8..12]);
+		let archive_count = Header::archive_count(mmap_data);
+
+		if !(x_files_factor >= 0.0 && x_files_factor <= 1.0) {
+			return Err(WhisperError::InvalidHeader(format!("xFilesFactor {} is not between 0 and 1", x_files_factor)));
+		}
+		if archive_count == 0 {
+			return Err(WhisperError::InvalidHeader("file has no archives".to_string()));
+		}
+		// Cheap upper bound that also keeps the multiplication below from overflowing
+		if archive_count > mmap_data.len() / archive::ARCHIVE_INFO_SIZE {
+			return Err(WhisperError::InvalidHeader(format!("archive count {} does not fit in a {} byte file", archive_count, mmap_data.len())));
+		}
+
+		let archives_start = Header::archives_start(archive_count);
+		if mmap_data.len() < archives_start {
+			return Err(WhisperError::InvalidHeader(format!("file is {} bytes, too small for {} archive infos", mmap_data.len(), archive_count)));
+		}
+
+		let mut expected_offset = archives_start as u64;
+		let mut largest_retention = 0u64;
+		for (index, info_slice) in mmap_data[STATIC_HEADER_SIZE .. archives_start].chunks(archive::ARCHIVE_INFO_SIZE).enumerate() {
+			let offset = BigEndian::read_u32(&info_slice[0..4]) as u64;
+			let seconds_per_point = BigEndian::read_u32(&info_slice[4..8]) as u64;
+			let points = BigEndian::read_u32(&info_slice[8..12]) as u64;
+
+			if seconds_per_point == 0 || points == 0 {
+				return Err(WhisperError::InvalidHeader(format!("archive {} has {} seconds per point and {} points", index, seconds_per_point, points)));
+			}
+			// Archives are split out of the mmap back to back, so there can be no gaps
+			if offset != expected_offset {
+				return Err(WhisperError::InvalidHeader(format!("archive {} starts at offset {}, expected {}", index, offset, expected_offset)));
+			}
+
+			expected_offset = offset + points * point::POINT_SIZE as u64;
+			largest_retention = cmp::max(largest_retention, seconds_per_point * points);
+		}
+
+		if (mmap_data.len() as u64) < expected_offset {
+			return Err(WhisperError::InvalidHeader(format!("file is truncated: {} bytes, archives need {}", mmap_data.len(), expected_offset)));
+		}
+		if max_retention as u64 != largest_retention {
+			return Err(WhisperError::InvalidHeader(format!("max retention {} does not match the largest archive retention {}", max_retention, largest_retention)));
+		}
+
+		Ok(())
+	}
+
+	// The size the archive infos make the file, None when the header or the
+	// infos themselves aren't all there
+	pub fn expected_size(mmap_data: &[u8]) -> Option<u64> {
+		if mmap_data.len() < STATIC_HEADER_SIZE {
+			return None;
+		}
+		let archives_start = Header::archives_start(Header::archive_count(mmap_data));
+		if mmap_data.len() < archives_start {
+			return None;
+		}
+		let archive_ends = mmap_data[STATIC_HEADER_SIZE .. archives_start].chunks(archive::ARCHIVE_INFO_SIZE).map(|info_slice| {
+			let offset = BigEndian::read_u32(&info_slice[0..4]) as u64;
+			let points = BigEndian::read_u32(&info_slice[8..12]) as u64;
+			offset + points * point::POINT_SIZE as u64
+		});
+		Some(archive_ends.fold(archives_start as u64, cmp::max))
+	}
+
 	#[inline]
 	fn archive_count(mmap_data: &[u8]) -> usize {
-		BigEndian::read_u32(&mmap_data[12..17]) as usize
+		BigEndian::read_u32(&mmap_data[12..16]) as usize
 	}
 
 	#[inline]
@@ -94,8 +259,9 @@ impl Header {
 		self.x_files_factor
 	}
 
-	// Consumes MmapViewSync to create Archives with smaller MmapViewSync
-	pub fn mmap_to_archives(&self, mmap_data: MmapViewSync) -> Vec<Archive> {
+	// Consumes MmapViewSync to create Archives with smaller MmapViewSync.
+	// The header's own view is handed back so it can be rewritten in place.
+	pub fn mmap_to_archives(&self, mmap_data: MmapViewSync) -> (MmapViewSync, Vec<Archive>) {
 		let (archive_infos, archive_count) = {
 			let raw_data = &unsafe{ mmap_data.as_slice() }; // localize not safe stuff
 			let count = Header::archive_count(raw_data);
@@ -103,9 +269,9 @@ impl Header {
 			(infos, count)
 		};
 
-		// chop off the header and throw it away, we're done with it
+		// chop off the header, the rest is archive data
 		let start = Header::archives_start(archive_count);
-		let (_,mut archive_data) = mmap_data.split_at(start).unwrap();
+		let (header_data,mut archive_data) = mmap_data.split_at(start).unwrap();
 
 		let mut archives : Vec<Archive> = Vec::with_capacity(archive_count);
 		// use infos to progressively cut down archive_data into each individual archive
@@ -132,14 +298,28 @@ impl Header {
 			archives.push( archive );
 		}
 
-		archives
+		(header_data, archives)
+	}
+
+	// Rewrites the aggregation method stored in the header data
+	pub fn write_aggregation_type(&mut self, header_data: &mut MmapViewSync, aggregation_type: AggregationType) {
+		let raw_data = unsafe{ header_data.as_mut_slice() };
+		BigEndian::write_u32(&mut raw_data[0..4], aggregation_type.to_u32());
+		self.aggregation_type = aggregation_type;
+	}
+
+	// Rewrites the xFilesFactor stored in the header data
+	pub fn write_x_files_factor(&mut self, header_data: &mut MmapViewSync, x_files_factor: f32) {
+		let raw_data = unsafe{ header_data.as_mut_slice() };
+		BigEndian::write_f32(&mut raw_data[8..12], x_files_factor);
+		self.x_files_factor = x_files_factor;
 	}
 
 	fn archive_infos(archive_count: usize, all_header_data: &[u8]) -> Vec<ArchiveInfo> {
 		let mut archive_infos : Vec<ArchiveInfo> = Vec::with_capacity(archive_count);
 
 		let ai_start = STATIC_HEADER_SIZE;
-		let ai_end = STATIC_HEADER_SIZE + archive::ARCHIVE_INFO_SIZE*archive_count;
+		let ai_end = Header::archives_start(archive_count);
 
 		let chunks = {
 			let archive_info_slice = &all_header_data[ ai_start .. ai_end ];
@@ -148,12 +328,55 @@ impl Header {
 
 		for archive_info_slice in chunks {
 			// we don't use offset because of how the MmapViewSync is consumed in to smaller MmapViewSyncs
-			// let _offset = BigEndian::read_u32(&archive_info_slice[0..4]);
-			let seconds_per_point = BigEndian::read_u32(&archive_info_slice[4..9]);
-			let points = BigEndian::read_u32(&archive_info_slice[8..]) as usize;
+			let seconds_per_point = BigEndian::read_u32(&archive_info_slice[4..8]);
+			let points = BigEndian::read_u32(&archive_info_slice[8..12]) as usize;
 			archive_infos.push(ArchiveInfo(seconds_per_point,points));
 		}
 
 		archive_infos
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	// Expected values are from whisper.py's aggregate() on the same inputs
+	#[test]
+	fn test_aggregate() {
+		let known_values = [1.0, 6.0, 2.0];
+
+		assert_eq!(AggregationType::Average.aggregate(&known_values, 4), Some(3.0));
+		assert_eq!(AggregationType::Sum.aggregate(&known_values, 4), Some(9.0));
+		assert_eq!(AggregationType::Last.aggregate(&known_values, 4), Some(2.0));
+		assert_eq!(AggregationType::Max.aggregate(&known_values, 4), Some(6.0));
+		assert_eq!(AggregationType::Min.aggregate(&known_values, 4), Some(1.0));
+		assert_eq!(AggregationType::AvgZero.aggregate(&known_values, 4), Some(2.25));
+		assert_eq!(AggregationType::Unknown.aggregate(&known_values, 4), None);
+
+		assert_eq!(AggregationType::Sum.aggregate(&[0.1, 0.2, 0.3], 3), Some(0.6000000000000001));
+		assert_eq!(AggregationType::Average.aggregate(&[0.1, 0.2, 0.3], 3), Some(0.20000000000000004));
+		assert_eq!(AggregationType::Max.aggregate(&[-3.0, -1.5], 2), Some(-1.5));
+		assert_eq!(AggregationType::Average.aggregate(&[], 2), None);
+	}
+
+	#[test]
+	fn test_aggregate_abs() {
+		let known_values = [-7.0, 3.0, 7.0, -0.5, 0.5];
+
+		assert_eq!(AggregationType::AbsMax.aggregate(&known_values, 5), Some(-7.0));
+		assert_eq!(AggregationType::AbsMin.aggregate(&known_values, 5), Some(-0.5));
+		assert_eq!(AggregationType::from_u32(7), AggregationType::AbsMax);
+		assert_eq!(AggregationType::from_u32(8), AggregationType::AbsMin);
+	}
+
+	#[test]
+	fn test_aggregation_type_u32() {
+		for aggregation_type in vec![AggregationType::Average, AggregationType::Sum, AggregationType::Last, AggregationType::Max, AggregationType::Min,
+				AggregationType::AvgZero, AggregationType::AbsMax, AggregationType::AbsMin] {
+			assert_eq!(AggregationType::from_u32(aggregation_type.to_u32()), aggregation_type);
+			assert_eq!(aggregation_type.to_string().parse::<AggregationType>(), Ok(aggregation_type.clone()));
+		}
+		assert_eq!(AggregationType::from_u32(0), AggregationType::Unknown);
+	}
+}
@@ -0,0 +1,219 @@
+use std::io::{ self, BufRead, Read };
+
+use whisper::{ Point, JsonValue };
+use whisper::error::{ Result, WhisperError };
+use super::{ WhisperFile, current_time };
+
+// Rows sent to update_many at a time
+const BATCH_SIZE : usize = 1000;
+
+// What an import did. Rows count from 1, a CSV header included; a row that
+// can't be read or written is reported and the import goes on.
+#[derive(Debug, Default, PartialEq, Clone)]
+pub struct ImportReport {
+	pub rows: usize,
+	pub points_written: usize,
+	// Rows without a value, left alone
+	pub empty_rows: usize,
+	// (row, reason)
+	pub errors: Vec<(usize, String)>
+}
+
+impl WhisperFile {
+	pub fn import_csv<R>(&mut self, reader: R) -> Result<ImportReport>
+		where R: BufRead {
+		self.import_csv_at(current_time(), reader)
+	}
+
+	// Backfills `timestamp,value` rows, what TimeSeries::to_csv writes. A first
+	// row that isn't a timestamp is taken for a header, and empty values,
+	// `null`, `None` and `NaN` for missing ones.
+	pub fn import_csv_at<R>(&mut self, now: u32, reader: R) -> Result<ImportReport>
+		where R: BufRead {
+		let mut report = ImportReport::default();
+		let mut batch = Vec::with_capacity(BATCH_SIZE);
+		for (index, line) in reader.lines().enumerate() {
+			let line = try!( line );
+			let row = index + 1;
+			report.rows = row;
+			if line.trim().is_empty() {
+				report.empty_rows = report.empty_rows + 1;
+				continue;
+			}
+
+			let mut fields = line.split(',').map(|field| field.trim());
+			let (timestamp, value) = match (fields.next(), fields.next(), fields.next()) {
+				(Some(timestamp), Some(value), None) => (timestamp, value),
+				_ => {
+					report.errors.push((row, "expected timestamp,value".to_string()));
+					continue;
+				}
+			};
+			let timestamp = match timestamp.parse::<u32>() {
+				Ok(timestamp) => timestamp,
+				Err(_) if row == 1 => continue,
+				Err(_) => {
+					report.errors.push((row, format!("invalid timestamp `{}`", timestamp)));
+					continue;
+				}
+			};
+			match value {
+				"" | "null" | "None" | "NaN" | "nan" => report.empty_rows = report.empty_rows + 1,
+				value => match value.parse::<f64>() {
+					Ok(value) => batch.push((row, Point(timestamp, value))),
+					Err(_) => report.errors.push((row, format!("invalid value `{}`", value)))
+				}
+			}
+
+			if batch.len() == BATCH_SIZE {
+				self.import_batch(now, &batch, &mut report);
+				batch.clear();
+			}
+		}
+		self.import_batch(now, &batch, &mut report);
+		Ok(report)
+	}
+
+	pub fn import_json<R>(&mut self, reader: R) -> Result<ImportReport>
+		where R: Read {
+		self.import_json_at(current_time(), reader)
+	}
+
+	// Backfills graphite's `[[value, timestamp], ...]` datapoints, either on
+	// their own or as the one target of a render_json document. Rows are the
+	// datapoints, nulls counting as empty.
+	pub fn import_json_at<R>(&mut self, now: u32, mut reader: R) -> Result<ImportReport>
+		where R: Read {
+		let mut text = String::new();
+		try!( reader.read_to_string(&mut text) );
+		let document = try!( JsonValue::parse(&text).map_err(invalid_data) );
+
+		let datapoints = match document {
+			JsonValue::Array(ref elements) if !elements.is_empty() && elements.iter().all(|element| element.get("datapoints").is_some()) => {
+				if elements.len() != 1 {
+					return Err(invalid_data(format!("render json of {} targets, expected one", elements.len())));
+				}
+				elements[0].get("datapoints").unwrap()
+			},
+			ref document => document
+		};
+		let datapoints = match *datapoints {
+			JsonValue::Array(ref datapoints) => datapoints,
+			_ => return Err(invalid_data("expected an array of datapoints".to_string()))
+		};
+
+		let mut report = ImportReport::default();
+		let mut batch = Vec::with_capacity(BATCH_SIZE);
+		for (index, datapoint) in datapoints.iter().enumerate() {
+			let row = index + 1;
+			report.rows = row;
+			match *datapoint {
+				JsonValue::Array(ref pair) if pair.len() == 2 => match (&pair[0], &pair[1]) {
+					(&JsonValue::Null, &JsonValue::Number(_)) => report.empty_rows = report.empty_rows + 1,
+					(&JsonValue::Number(value), &JsonValue::Number(timestamp)) if timestamp >= 0.0 && timestamp <= u32::max_value() as f64 => {
+						batch.push((row, Point(timestamp as u32, value)));
+					},
+					_ => report.errors.push((row, "expected a number or null and a timestamp".to_string()))
+				},
+				_ => report.errors.push((row, "expected [value, timestamp]".to_string()))
+			}
+
+			if batch.len() == BATCH_SIZE {
+				self.import_batch(now, &batch, &mut report);
+				batch.clear();
+			}
+		}
+		self.import_batch(now, &batch, &mut report);
+		Ok(report)
+	}
+
+	// Writes the batch with update_many. When that fails, a point refused by
+	// the future policy and such, each row is written by itself to tell which.
+	fn import_batch(&mut self, now: u32, batch: &[(usize, Point)], report: &mut ImportReport) {
+		let oldest = now.saturating_sub(self.header.max_retention);
+		let mut points = Vec::with_capacity(batch.len());
+		for &(row, point) in batch {
+			if point.0 <= oldest {
+				report.errors.push((row, WhisperError::TimestampNotCovered(point.0).to_string()));
+			} else {
+				points.push(point);
+			}
+		}
+		if points.is_empty() {
+			return;
+		}
+
+		if self.update_many_at(now, &points).is_ok() {
+			report.points_written = report.points_written + points.len();
+			return;
+		}
+		for &(row, point) in batch.iter().filter(|&&(_, point)| point.0 > oldest) {
+			match self.write_at(now, &point) {
+				Ok(()) => report.points_written = report.points_written + 1,
+				Err(err) => report.errors.push((row, err.to_string()))
+			}
+		}
+	}
+}
+
+fn invalid_data(reason: String) -> WhisperError {
+	WhisperError::Io(io::Error::new(io::ErrorKind::InvalidData, reason))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use whisper::{ Schema, WhisperFile, temp_path };
+	use std::fs;
+
+	fn build_file(name: &str) -> WhisperFile {
+		let path = temp_path(name);
+		fs::remove_file(&path).ok();
+		WhisperFile::new(&path, &Schema::parse("10s:60s").unwrap()).unwrap()
+	}
+
+	#[test]
+	fn test_import_csv() {
+		let mut file = build_file("test_import_csv.wsp");
+		let now = 1440392100;
+		let csv = "timestamp,value\n\
+			1440392080,1.5\n\
+			1440392090,\n\
+			\n\
+			1440392100,NaN\n\
+			1440392100,2,3\n\
+			yesterday,4\n\
+			1440392070,five\n\
+			1440392000,6\n\
+			1440392200,7\n\
+			1440392070,8\n";
+		let report = file.import_csv_at(now, csv.as_bytes()).unwrap();
+
+		assert_eq!(report.rows, 11);
+		assert_eq!(report.points_written, 2);
+		assert_eq!(report.empty_rows, 3);
+		let rows : Vec<usize> = report.errors.iter().map(|error| error.0).collect();
+		assert_eq!(rows, vec![6, 7, 8, 9, 10]);
+		assert_eq!(report.errors[3].1, "timestamp 1440392000 is not covered by any archives in this database");
+		assert_eq!(file.fetch_at(now, now - 40, now).unwrap().values, vec![Some(8.0), Some(1.5), None, None]);
+	}
+
+	#[test]
+	fn test_import_json() {
+		let mut file = build_file("test_import_json.wsp");
+		let now = 1440392100;
+		let json = "[{\"target\": \"servers.load\", \"datapoints\": [[1.5, 1440392080], [null, 1440392090], [2, 1440392100], [\"3\", 1440392100], 4]}]";
+		let report = file.import_json_at(now, json.as_bytes()).unwrap();
+
+		assert_eq!((report.rows, report.points_written, report.empty_rows), (5, 2, 1));
+		let rows : Vec<usize> = report.errors.iter().map(|error| error.0).collect();
+		assert_eq!(rows, vec![4, 5]);
+		assert_eq!(file.fetch_at(now, now - 30, now).unwrap().values, vec![Some(1.5), None, Some(2.0)]);
+
+		let report = file.import_json_at(now, "[[3, 1440392090]]".as_bytes()).unwrap();
+		assert_eq!(report.points_written, 1);
+		assert_eq!(file.import_json_at(now, "[]".as_bytes()).unwrap(), ImportReport::default());
+		assert!(file.import_json_at(now, "[{\"datapoints\": []}, {\"datapoints\": []}]".as_bytes()).is_err());
+		assert!(file.import_json_at(now, "{\"datapoints\": ".as_bytes()).is_err());
+	}
+}
@@ -0,0 +1,65 @@
+use std::fmt;
+
+use super::header::AggregationType;
+
+// Everything whisper-info.py prints about a file
+#[derive(Debug, PartialEq, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct FileInfo {
+	pub aggregation_type: AggregationType,
+	pub max_retention: u32,
+	pub x_files_factor: f32,
+	pub archive_count: usize,
+	pub archives: Vec<ArchiveInfo>
+}
+
+#[derive(Debug, PartialEq, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct ArchiveInfo {
+	// Byte offset of the archive's first point in the file
+	pub offset: usize,
+	pub seconds_per_point: u32,
+	pub points: usize,
+	pub retention: u32,
+	// Bytes taken by the archive's points
+	pub size: usize
+}
+
+// How full and how fresh a file is, for spotting stale or underfilled metrics
+#[derive(Debug, PartialEq, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct FileStats {
+	// Newest timestamp stored in any archive
+	pub last_update: Option<u32>,
+	pub size_on_disk: u64,
+	pub archives: Vec<ArchiveStats>
+}
+
+#[derive(Debug, PartialEq, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct ArchiveStats {
+	pub points: usize,
+	// Slots holding a point within the archive's retention
+	pub known_points: usize,
+	// known_points as a percentage of points
+	pub fill_percent: f64
+}
+
+// Same layout as whisper-info.py
+impl fmt::Display for FileInfo {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		try!(write!(f, "aggregationMethod: {}\nmaxRetention: {}\nxFilesFactor: {}\n\n", self.aggregation_type, self.max_retention, self.x_files_factor));
+
+		for (index, archive) in self.archives.iter().enumerate() {
+			try!(write!(f, "Archive {}\n{}\n", index, archive));
+		}
+
+		Ok(())
+	}
+}
+
+impl fmt::Display for ArchiveInfo {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "offset: {}\nsecondsPerPoint: {}\npoints: {}\nretention: {}\nsize: {}\n", self.offset, self.seconds_per_point, self.points, self.retention, self.size)
+	}
+}
@@ -0,0 +1,84 @@
+use std::fs::File;
+use std::io;
+
+#[cfg(unix)]
+use libc::{ flock, LOCK_EX, LOCK_SH, LOCK_UN };
+#[cfg(unix)]
+use std::os::unix::prelude::{ AsRawFd, RawFd };
+
+// An advisory flock on a whisper file, released when dropped. Only holds on to
+// the descriptor so the file can keep being written while it's locked.
+// flock is a unix thing, elsewhere locking does nothing.
+pub struct FileLock {
+	#[cfg(unix)]
+	raw_fd: RawFd
+}
+
+impl FileLock {
+	// Blocks until no other writer or reader holds a lock
+	pub fn exclusive(file: &File) -> io::Result<FileLock> {
+		FileLock::lock(file, true)
+	}
+
+	// Blocks until no writer holds a lock
+	pub fn shared(file: &File) -> io::Result<FileLock> {
+		FileLock::lock(file, false)
+	}
+
+	#[cfg(unix)]
+	fn lock(file: &File, exclusive: bool) -> io::Result<FileLock> {
+		let raw_fd = file.as_raw_fd();
+		let retval = unsafe { flock(raw_fd, if exclusive { LOCK_EX } else { LOCK_SH }) };
+		if retval != 0 {
+			return Err(io::Error::last_os_error());
+		}
+		Ok(FileLock { raw_fd: raw_fd })
+	}
+
+	#[cfg(not(unix))]
+	fn lock(_file: &File, _exclusive: bool) -> io::Result<FileLock> {
+		Ok(FileLock {})
+	}
+}
+
+impl Drop for FileLock {
+	#[cfg(unix)]
+	fn drop(&mut self) {
+		unsafe { flock(self.raw_fd, LOCK_UN) };
+	}
+
+	#[cfg(not(unix))]
+	fn drop(&mut self) {}
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+	use super::*;
+	use libc::LOCK_NB;
+	use whisper::temp_path;
+	use std::fs::{ self, File };
+	use std::os::unix::prelude::AsRawFd;
+
+	fn try_exclusive(file: &File) -> bool {
+		unsafe { flock(file.as_raw_fd(), LOCK_EX | LOCK_NB) == 0 }
+	}
+
+	#[test]
+	fn test_file_lock() {
+		let path = &temp_path("test_file_lock");
+		fs::File::create(path).unwrap();
+		let holder = File::open(path).unwrap();
+		let other = File::open(path).unwrap();
+
+		{
+			let _lock = FileLock::shared(&holder).unwrap();
+			assert!(!try_exclusive(&other));
+		}
+		{
+			let _lock = FileLock::exclusive(&holder).unwrap();
+			assert!(!try_exclusive(&other));
+		}
+		// Released on drop
+		assert!(try_exclusive(&other));
+	}
+}
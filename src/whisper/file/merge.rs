@@ -0,0 +1,145 @@
+use std::cmp;
+
+use whisper::Point;
+use whisper::error::{ Result, WhisperError };
+use super::{ WhisperFile, current_time };
+
+impl WhisperFile {
+	pub fn merge_from(&mut self, other: &WhisperFile, from: u32, until: u32) -> Result<()> {
+		self.merge_from_at(current_time(), other, from, until)
+	}
+
+	// Same as whisper-merge.py: copies the known points of `other` between `from` and
+	// `until` over, archive by archive, then propagates them like update_many does.
+	// Both files must have the same archives.
+	pub fn merge_from_at(&mut self, now: u32, other: &WhisperFile, from: u32, until: u32) -> Result<()> {
+		try!( self.check_writable() );
+		let _lock = try!( self.lock_exclusive() );
+		if from > until {
+			return Err(WhisperError::InvalidTimeInterval(from, until));
+		}
+		if !self.same_archives(other) {
+			return Err(WhisperError::IncompatibleArchives);
+		}
+
+		let mut archive_indexes : Vec<usize> = (0..other.archives.len()).collect();
+		archive_indexes.sort_by_key(|&index| other.archives[index].retention());
+
+		for archive_index in archive_indexes {
+			let oldest_time = now.saturating_sub(other.archives[archive_index].retention());
+			// Too old for this archive
+			if until < oldest_time {
+				continue;
+			}
+
+			let series = other.fetch_archive(archive_index, if from < oldest_time { oldest_time } else { from }, until);
+			let points = series.points();
+
+			if !points.is_empty() {
+				self.archive_update_many(archive_index, &points);
+			}
+		}
+
+		self.maybe_flush()
+	}
+
+	pub fn fill_from(&mut self, other: &WhisperFile, from: u32, until: u32) -> Result<()> {
+		self.fill_from_at(current_time(), other, from, until)
+	}
+
+	// Like whisper-fill.py, backfills gaps between `from` and `until` from `other`,
+	// but only slots that are empty here are ever written: each archive is filled
+	// on its own and nothing is propagated, so existing local data is never replaced.
+	// The files may have different archives.
+	pub fn fill_from_at(&mut self, now: u32, other: &WhisperFile, from: u32, until: u32) -> Result<()> {
+		try!( self.check_writable() );
+		let _lock = try!( self.lock_exclusive() );
+		if from > until {
+			return Err(WhisperError::InvalidTimeInterval(from, until));
+		}
+
+		for archive_index in 0..self.archives.len() {
+			let oldest_time = now.saturating_sub(self.archives[archive_index].retention());
+			let archive_from = cmp::max(from, oldest_time);
+			let archive_until = cmp::min(until, now);
+			if archive_from > archive_until {
+				continue;
+			}
+
+			let mut local = self.fetch_archive(archive_index, archive_from, archive_until);
+			let source = try!( other.fetch_at(now, archive_from, archive_until) );
+
+			let mut points = vec![];
+			for Point(timestamp, value) in source.points() {
+				let interval = timestamp - (timestamp % local.step);
+				if interval < local.from || interval >= local.until {
+					continue;
+				}
+
+				let local_index = ((interval - local.from) / local.step) as usize;
+				if local.values[local_index].is_none() {
+					// Also keeps a coarser source from filling the same slot twice
+					local.values[local_index] = Some(value);
+					points.push(Point(interval, value));
+				}
+			}
+
+			if !points.is_empty() {
+				self.archives[archive_index].write_many(&points);
+			}
+		}
+
+		self.maybe_flush()
+	}
+
+	pub(super) fn same_archives(&self, other: &WhisperFile) -> bool {
+		self.archives.len() == other.archives.len() &&
+			self.archives.iter().zip(other.archives.iter()).all(|(a, b)| {
+				a.seconds_per_point() == b.seconds_per_point() && a.points() == b.points()
+			})
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use whisper::{ Schema, WhisperFile, Point, temp_path };
+	use std::fs;
+
+	fn build_file(path: &str, specs: Vec<&str>) -> WhisperFile {
+		fs::remove_file(path).ok();
+		let schema = Schema::new_from_retention_specs(specs.iter().map(|spec| spec.to_string()).collect()).unwrap();
+		WhisperFile::new(path, &schema).unwrap()
+	}
+
+	#[test]
+	fn test_merge_from() {
+		let now = 1440392100;
+		let mut source = build_file(&temp_path("test_merge_from_source.wsp"), vec!["10s:60s", "60s:600s"]);
+		let mut destination = build_file(&temp_path("test_merge_from_destination.wsp"), vec!["10s:60s", "60s:600s"]);
+
+		source.update_many_at(now, &[Point(now - 50, 1.0), Point(now - 20, 2.0), Point(now - 300, 3.0)]).unwrap();
+		destination.write_at(now, &Point(now - 40, 5.0)).unwrap();
+
+		destination.merge_from_at(now, &source, now - 400, now - 30).unwrap();
+		assert_eq!(destination.fetch_at(now, now - 60, now).unwrap().values, vec![Some(1.0), Some(5.0), None, None, None, None]);
+		assert_eq!(destination.fetch_at(now, now - 360, now - 250).unwrap().values, vec![Some(3.0)]);
+
+		let other = build_file(&temp_path("test_merge_from_other.wsp"), vec!["10s:60s"]);
+		assert!(destination.merge_from_at(now, &other, 0, now).is_err());
+	}
+
+	#[test]
+	fn test_fill_from() {
+		let now = 1440392100;
+		let mut source = build_file(&temp_path("test_fill_from_source.wsp"), vec!["10s:60s", "60s:600s"]);
+		let mut destination = build_file(&temp_path("test_fill_from_destination.wsp"), vec!["10s:60s", "60s:600s"]);
+
+		source.update_many_at(now, &[Point(now - 50, 1.0), Point(now - 40, 1.0), Point(now - 20, 1.0), Point(now - 300, 1.0)]).unwrap();
+		destination.update_many_at(now, &[Point(now - 40, 5.0), Point(now - 30, 5.0), Point(now - 10, 5.0)]).unwrap();
+
+		destination.fill_from_at(now, &source, 0, now).unwrap();
+		assert_eq!(destination.fetch_at(now, now - 60, now).unwrap().values, vec![Some(1.0), Some(5.0), Some(5.0), Some(1.0), Some(5.0), None]);
+		// The minute already rolled up locally is kept, the empty one is filled
+		assert_eq!(destination.fetch_at(now, now - 360, now - 1).unwrap().values, vec![Some(1.0), None, None, None, Some(5.0)]);
+	}
+}
@@ -0,0 +1,97 @@
+use std::fs;
+use std::path::Path;
+
+use whisper::{ Schema, AggregationType };
+use whisper::error::{ Result, WhisperError };
+use super::{ WhisperFile, SchemaDiff, current_time };
+
+impl WhisperFile {
+	pub fn migrate<P>(path: P, schema: &Schema, aggregation_type: AggregationType, x_files_factor: f32) -> Result<SchemaDiff>
+		where P: AsRef<Path> {
+		WhisperFile::migrate_at(current_time(), path, schema, aggregation_type, x_files_factor)
+	}
+
+	// Brings the file at `path` in line with a schema, aggregation method and
+	// xFilesFactor, returning what had to change. The aggregation method and
+	// xFilesFactor are set in place. Different archives mean resizing in to a
+	// temporary file next to it (rolling points up when precisions change),
+	// that is renamed over the original, so readers see either
+	// the old or the new file. The original stays locked meanwhile: writers that
+	// lock theirs wait, and then write to the replaced file until they open the
+	// path again. A WhisperCache does with a replaced check interval set.
+	pub fn migrate_at<P>(now: u32, path: P, schema: &Schema, aggregation_type: AggregationType, x_files_factor: f32) -> Result<SchemaDiff>
+		where P: AsRef<Path> {
+		let mut file = try!( WhisperFile::open(path.as_ref()) );
+		file.set_lock_writes(true);
+
+		let diff = file.matches_schema(schema, aggregation_type.clone(), x_files_factor);
+		if diff.matches() {
+			return Ok(diff);
+		}
+
+		if !diff.needs_resize() {
+			if diff.aggregation_type.is_some() {
+				try!( file.set_aggregation_type(aggregation_type) );
+			}
+			if diff.x_files_factor.is_some() {
+				try!( file.set_x_files_factor(x_files_factor) );
+			}
+			try!( file.flush() );
+			return Ok(diff);
+		}
+
+		let _lock = try!( file.lock_exclusive() );
+		let tmp_path = WhisperFile::temporary_path(path.as_ref());
+		let migrated = file.resize_at(now, schema, &tmp_path, diff.precisions.is_some())
+			.and_then(|mut new_file| {
+				try!( new_file.set_aggregation_type(aggregation_type) );
+				try!( new_file.set_x_files_factor(x_files_factor) );
+				new_file.flush()
+			})
+			.and_then(|_| fs::rename(&tmp_path, path.as_ref()).map_err(WhisperError::from));
+		if let Err(err) = migrated {
+			fs::remove_file(&tmp_path).ok();
+			return Err(err);
+		}
+
+		Ok(diff)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use whisper::{ Schema, WhisperFile, AggregationType, Point, temp_path };
+	use std::fs;
+
+	#[test]
+	fn test_migrate() {
+		let now = 1440392100;
+		let path = &temp_path("test_migrate.wsp");
+		fs::remove_file(path).ok();
+		let schema = Schema::parse("10s:60s,60s:600s").unwrap();
+		let mut file = WhisperFile::new(path, &schema).unwrap();
+		file.update_many_at(now, &[Point(now - 20, 1.0), Point(now - 10, 2.0)]).unwrap();
+
+		// Only the header changes
+		let diff = WhisperFile::migrate_at(now, path, &schema, AggregationType::Sum, 0.0).unwrap();
+		assert!(!diff.needs_resize());
+		assert_eq!(WhisperFile::open(path).unwrap().info().aggregation_type, AggregationType::Sum);
+
+		// Longer retention for the fine archive, the data comes along
+		let new_schema = Schema::parse("10s:120s,60s:600s").unwrap();
+		let diff = WhisperFile::migrate_at(now, path, &new_schema, AggregationType::Sum, 0.0).unwrap();
+		assert!(diff.needs_resize());
+
+		let migrated = WhisperFile::open(path).unwrap();
+		assert_eq!(migrated.info().archives[0].retention, 120);
+		assert_eq!(migrated.info().aggregation_type, AggregationType::Sum);
+		assert_eq!(migrated.info().x_files_factor, 0.0);
+		assert_eq!(&migrated.fetch_at(now, now - 60, now).unwrap().values[3..5], &[Some(1.0), Some(2.0)][..]);
+
+		assert!(WhisperFile::migrate_at(now, path, &new_schema, AggregationType::Sum, 0.0).unwrap().matches());
+		let entries = fs::read_dir(::std::env::temp_dir()).unwrap()
+			.filter(|entry| entry.as_ref().unwrap().file_name().to_string_lossy().starts_with(".test_migrate.wsp."))
+			.count();
+		assert_eq!(entries, 0);
+	}
+}
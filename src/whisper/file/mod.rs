@@ -1,31 +1,135 @@
-use memmap::{ Mmap, Protection };
+use memmap::{ Mmap, MmapViewSync, Protection };
 use byteorder::{ BigEndian, WriteBytesExt };
 
 mod header;
+mod info;
+mod resize;
+mod merge;
+mod diff;
+mod lock;
+mod schema_diff;
+mod migrate;
+mod builder;
+mod csv;
+mod import;
+mod audit;
 pub mod archive;
 
-use self::header::{ Header, AggregationType };
+use self::header::Header;
 use self::archive::Archive;
+use self::lock::FileLock;
 
-pub use self::header::STATIC_HEADER_SIZE;
+pub use self::header::{ STATIC_HEADER_SIZE, AggregationType };
 pub use self::archive::ARCHIVE_INFO_SIZE;
+pub use self::info::{ FileInfo, ArchiveInfo, FileStats, ArchiveStats };
+pub use self::diff::ArchiveDiff;
+pub use self::schema_diff::SchemaDiff;
+pub use self::builder::WhisperBuilder;
+pub use self::import::ImportReport;
+pub use self::audit::FileDefect;
 
-use whisper::Point;
+// Keeps temporary file names unique between threads creating the same file
+static TEMPORARY_FILE_COUNT : AtomicUsize = AtomicUsize::new(0);
+
+// How the data region of a new file is allocated on disk. Same distinction as
+// carbon's WHISPER_SPARSE_CREATE.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Allocation {
+	// Only the length is set, the filesystem allocates blocks as points land
+	Sparse,
+	// Every block is reserved up front with posix_fallocate, keeping the file from
+	// fragmenting. Zeroes are written out where that is not supported.
+	Full
+}
+
+// Which archive a fetch reads from
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum ArchiveSelection {
+	// The highest precision archive that still covers `from`, same as whisper.py
+	Best,
+	// Always this archive, whatever its retention
+	Index(usize),
+	// Like Best, but moving on to coarser archives until the fetch returns at
+	// most this many points
+	MaxPoints(usize)
+}
+
+// What writes do with points from the future. Within `tolerance` seconds of now
+// they are written as they are, going to the highest precision archive.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum FuturePolicy {
+	// Anything further ahead is a WhisperError::FutureTimestamp
+	Reject { tolerance: u32 },
+	// Anything further ahead is written at now + tolerance instead
+	Clamp { tolerance: u32 }
+}
+
+// What writes do with NaN and infinite values, which poison every aggregate
+// they get propagated into
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum NonFinitePolicy {
+	// Written like any other value, same as whisper.py
+	Allow,
+	// Fails the write with WhisperError::NonFiniteValue
+	Reject,
+	// The point is dropped
+	Skip,
+	// The point's slot is emptied, as if nothing was ever written there
+	Null
+}
+
+// What a write does to a slot already holding a value for its interval
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum OverwritePolicy {
+	// The new value replaces the old one, same as whisper.py
+	Overwrite,
+	// The old value stays, late duplicates are dropped
+	FirstWriteWins,
+	// Both values are rolled up with the file's aggregation method
+	Aggregate
+}
+
+// whisper.py's default when none is configured
+pub const DEFAULT_X_FILES_FACTOR : f32 = 0.5;
+
+use whisper::{ Point, TimeSeries, current_time };
 use whisper::Schema;
+use whisper::point::POINT_SIZE;
+use whisper::error::{ Result, WhisperError };
 
 // Modules needed to create file on disk
-use std::fs::OpenOptions;
+use std::fs::{ File, OpenOptions };
+use std::process;
+use std::sync::atomic::{ AtomicUsize, Ordering };
 extern crate libc;
-use self::libc::ftruncate;
+#[cfg(target_os = "linux")]
+use self::libc::{ off_t, posix_fallocate, EOPNOTSUPP, EINVAL };
+#[cfg(target_os = "linux")]
 use std::os::unix::prelude::AsRawFd;
-use std::io::{ self, Error};
+#[cfg(target_os = "linux")]
+use std::io::Error;
+use std::io::{ Write, Seek, SeekFrom };
 use std::path::{ Path, PathBuf };
 use std::fmt;
+use std::cmp;
+use std::collections::HashMap;
 
 pub struct WhisperFile {
 	pub path: PathBuf,
 	pub header: Header,
-	pub archives: Vec< Archive >,
+	// Private, Archive writes don't check the file was opened read-write
+	archives: Vec< Archive >,
+	// The static header and archive infos
+	header_mmap: MmapViewSync,
+	// The mmap is not writable, touching it would segfault
+	read_only: bool,
+	// Kept open for flock
+	file: File,
+	lock_writes: bool,
+	autoflush: bool,
+	future_policy: FuturePolicy,
+	non_finite_policy: NonFinitePolicy,
+	overwrite_policy: OverwritePolicy
 }
 
 impl fmt::Debug for WhisperFile {
@@ -40,9 +144,6 @@ impl fmt::Debug for WhisperFile {
 		let mut index = 0;
 		let mut offset = Header::archives_start(self.archives.len());
 
-		let max_points = self.archives.iter().map(|x| x.points()).max().unwrap();
-		let mut points_buf = Vec::with_capacity(max_points);
-
 		for archive in &self.archives {
 			try!(write!(f, "Archive {} info:
   offset: {}
@@ -54,14 +155,8 @@ impl fmt::Debug for WhisperFile {
 Archive {} data:
 ", index, offset, archive.seconds_per_point(), archive.points(), archive.seconds_per_point() * archive.points() as u32, archive.size(), index ));
 
-			unsafe{ points_buf.set_len(archive.points()) };
-			archive.read_points(archive.anchor_bucket_name(), &mut points_buf[..]);
-
-			let mut points_index = 0;
-			for point in &points_buf {
-				try!(write!(f, "{}:	{},          {}\n", points_index, point.0, point.1));
-
-				points_index = points_index + 1;
+			for (slot, point) in archive.dump() {
+				try!(write!(f, "{}:	{},          {}\n", slot, point.0, point.1));
 			}
 
 			offset = offset + archive.size();
@@ -74,84 +169,712 @@ Archive {} data:
 
 
 impl WhisperFile {
-	pub fn new<P>(path: P, schema: &Schema) -> io::Result<WhisperFile>
+	pub fn new<P>(path: P, schema: &Schema) -> Result<WhisperFile>
         where P: AsRef<Path> {
-		let mut opened_file = try!(OpenOptions::new().read(true).write(true).create(true).open(path.as_ref()));
+		WhisperBuilder::new(schema.clone()).create(path)
+	}
 
-		// Allocate space on disk (could be costly!)
-		{
-			let size_needed = schema.size_on_disk();
-			let raw_fd = opened_file.as_raw_fd();
-			let retval = unsafe {
-				// TODO skip to fallocate-like behavior. Will need wrapper for OSX.
-				ftruncate(raw_fd, size_needed as i64)
-			};
-			if retval != 0 {
-				return Err(Error::last_os_error());
-			}
+	pub fn new_with_options<P>(path: P, schema: &Schema, aggregation_type: AggregationType, x_files_factor: f32) -> Result<WhisperFile>
+        where P: AsRef<Path> {
+		WhisperBuilder::new(schema.clone()).aggregation(aggregation_type).x_files_factor(x_files_factor).create(path)
+	}
+
+	pub fn new_with_allocation<P>(path: P, schema: &Schema, aggregation_type: AggregationType, x_files_factor: f32, allocation: Allocation) -> Result<WhisperFile>
+        where P: AsRef<Path> {
+		WhisperBuilder::new(schema.clone()).aggregation(aggregation_type).x_files_factor(x_files_factor).allocation(allocation).create(path)
+	}
+
+	// Preallocates the file and writes the header and archive infos
+	fn write_new_file(opened_file: &mut File, schema: &Schema, header: &Header, allocation: Allocation) -> Result<()> {
+		let retention_policies = schema.canonical_policies();
+		let archives_start = Header::archives_start(retention_policies.len());
+		let size_needed = retention_policies.iter()
+			.fold(archives_start as u64, |size, policy| size + policy.points() as u64 * POINT_SIZE as u64);
+		// Offsets are u32, so is the size of a file
+		if size_needed > u32::max_value() as u64 {
+			return Err(WhisperError::InvalidHeader(format!("a file of {} bytes is past what u32 archive offsets reach", size_needed)));
 		}
+		try!( WhisperFile::allocate(opened_file, size_needed as usize, allocation) );
 
-		let xff = 0.5;
-		let header = Header::new(AggregationType::Unknown, schema.max_retention(), xff);
 		{
 			try!( opened_file.write_u32::<BigEndian>( header.aggregation_type.to_u32() ));
 			try!( opened_file.write_u32::<BigEndian>( header.max_retention ) );
 			try!( opened_file.write_f32::<BigEndian>( header.x_files_factor ) );
-			try!( opened_file.write_u32::<BigEndian>( schema.retention_policies.len() as u32 ) );
+			try!( opened_file.write_u32::<BigEndian>( retention_policies.len() as u32 ) );
 		}
 
-		let mut archive_offset = Header::archives_start( schema.retention_policies.len() ) as u32;
-		for retention_policy in &schema.retention_policies {
-			try!( opened_file.write_u32::<BigEndian>( archive_offset as u32 ) );
+		let mut archive_offset = archives_start as u32;
+		for retention_policy in &retention_policies {
+			try!( opened_file.write_u32::<BigEndian>( archive_offset ) );
 			try!( opened_file.write_u32::<BigEndian>( retention_policy.precision ) );
-			try!( opened_file.write_u32::<BigEndian>( retention_policy.points()  ) );
+			try!( opened_file.write_u32::<BigEndian>( retention_policy.points() ) );
 
 			archive_offset = archive_offset + retention_policy.size_on_disk();
 		}
 
-		let mmap = Mmap::open(&opened_file, Protection::ReadWrite ).unwrap();
+		// The rename must never be visible before the contents are
+		try!( opened_file.sync_all() );
+		Ok(())
+	}
+
+	// Allocate space on disk (could be costly!)
+	fn allocate(opened_file: &mut File, size_needed: usize, allocation: Allocation) -> Result<()> {
+		match allocation {
+			Allocation::Sparse => try!( opened_file.set_len(size_needed as u64) ),
+			Allocation::Full => try!( WhisperFile::fallocate(opened_file, size_needed) )
+		}
+		Ok(())
+	}
+
+	// Reserving the blocks means running out of disk space is an error here
+	// instead of a SIGBUS on some later write through the mmap
+	#[cfg(target_os = "linux")]
+	fn fallocate(opened_file: &mut File, size_needed: usize) -> Result<()> {
+		// Returns the error instead of setting errno
+		let retval = unsafe { posix_fallocate(opened_file.as_raw_fd(), 0, size_needed as off_t) };
+		match retval {
+			0 => Ok(()),
+			// The filesystem can't do it (and the libc didn't emulate it)
+			EOPNOTSUPP | EINVAL => WhisperFile::zero_fill(opened_file, size_needed),
+			errno => Err(WhisperError::from(Error::from_raw_os_error(errno)))
+		}
+	}
+
+	#[cfg(not(target_os = "linux"))]
+	fn fallocate(opened_file: &mut File, size_needed: usize) -> Result<()> {
+		WhisperFile::zero_fill(opened_file, size_needed)
+	}
+
+	// Writing every byte forces the filesystem to allocate them
+	fn zero_fill(opened_file: &mut File, size_needed: usize) -> Result<()> {
+		let zeroes = [0u8; 4096];
+		let mut remaining = size_needed;
+		while remaining > 0 {
+			let chunk = cmp::min(remaining, zeroes.len());
+			try!( opened_file.write_all(&zeroes[.. chunk]) );
+			remaining = remaining - chunk;
+		}
 
-		Ok( WhisperFile::open_mmap(path.as_ref(), mmap) )
+		try!( opened_file.seek(SeekFrom::Start(0)) );
+		Ok(())
 	}
 
-	// TODO: open should validate contents of whisper file
-	// and return Result<WhisperFile, io::Error>
-	pub fn open<P>(path: P) -> WhisperFile
+	// A hidden, unique name in the same directory, so the rename stays on one filesystem
+	fn temporary_path(path: &Path) -> PathBuf {
+		let file_name = path.file_name().map(|name| name.to_string_lossy().into_owned()).unwrap_or_default();
+		let count = TEMPORARY_FILE_COUNT.fetch_add(1, Ordering::Relaxed);
+		path.with_file_name(format!(".{}.{}.{}.tmp", file_name, process::id(), count))
+	}
+
+	pub fn open<P>(path: P) -> Result<WhisperFile>
+        where P: AsRef<Path> {
+		let file = try!( OpenOptions::new().read(true).write(true).open(path.as_ref()) );
+		let mmap = try!( Mmap::open(&file, Protection::ReadWrite) );
+		WhisperFile::open_mmap(path.as_ref(), file, mmap, false)
+	}
+
+	// For looking at live files without any risk of changing them. Every method
+	// that would write returns WhisperError::ReadOnly.
+	pub fn open_readonly<P>(path: P) -> Result<WhisperFile>
         where P: AsRef<Path> {
-		let mmap = Mmap::open_path(path.as_ref(), Protection::ReadWrite).unwrap();
-		WhisperFile::open_mmap(path.as_ref(), mmap)
+		let file = try!( File::open(path.as_ref()) );
+		let mmap = try!( Mmap::open(&file, Protection::Read) );
+		WhisperFile::open_mmap(path.as_ref(), file, mmap, true)
 	}
 
-	fn open_mmap<P>(path: P, mmap: Mmap) -> WhisperFile
+	fn open_mmap<P>(path: P, file: File, mmap: Mmap, read_only: bool) -> Result<WhisperFile>
 	where P: AsRef<Path> {
 		let mmap_view = mmap.into_view_sync();
 
 		let header = {
 			let slice = unsafe{ mmap_view.as_slice() };
+			try!( Header::validate(slice) );
 			Header::new_from_slice(slice)
 		};
-		let archives = header.mmap_to_archives(mmap_view);
+		let (header_mmap, archives) = header.mmap_to_archives(mmap_view);
 
 		let whisper_file = WhisperFile {
 			path: path.as_ref().to_path_buf(),
 			header: header,
-			archives: archives
+			archives: archives,
+			header_mmap: header_mmap,
+			read_only: read_only,
+			file: file,
+			lock_writes: false,
+			autoflush: false,
+			future_policy: FuturePolicy::Reject { tolerance: 0 },
+			non_finite_policy: NonFinitePolicy::Allow,
+			overwrite_policy: OverwritePolicy::Overwrite
+		};
+		Ok(whisper_file)
+	}
+
+	pub fn archives(&self) -> &[Archive] {
+		&self.archives
+	}
+
+	// Oldest point stored in any archive
+	pub fn first_timestamp(&self) -> Option<u32> {
+		self.archives.iter().filter_map(|archive| archive.first_timestamp()).min()
+	}
+
+	// Newest point stored in any archive
+	pub fn last_timestamp(&self) -> Option<u32> {
+		self.archives.iter().filter_map(|archive| archive.last_timestamp()).max()
+	}
+
+	pub fn stats(&self) -> Result<FileStats> {
+		self.stats_at(current_time())
+	}
+
+	// Walks every slot once. Slots left over from before the archive's retention
+	// window don't count as known.
+	pub fn stats_at(&self, now: u32) -> Result<FileStats> {
+		let mut archive_stats = Vec::with_capacity(self.archives.len());
+
+		for archive in &self.archives {
+			let oldest_time = now.saturating_sub(archive.retention());
+			let mut known_points = 0;
+			for (_, point) in archive.dump() {
+				if point.0 > oldest_time && point.0 <= now {
+					known_points = known_points + 1;
+				}
+			}
+
+			archive_stats.push(ArchiveStats {
+				points: archive.points(),
+				known_points: known_points,
+				fill_percent: known_points as f64 * 100.0 / archive.points() as f64
+			});
+		}
+
+		Ok(FileStats {
+			last_update: self.last_timestamp(),
+			size_on_disk: try!( self.file.metadata() ).len(),
+			archives: archive_stats
+		})
+	}
+
+	pub fn info(&self) -> FileInfo {
+		let mut offset = Header::archives_start(self.archives.len());
+		let mut archive_infos = Vec::with_capacity(self.archives.len());
+
+		for archive in &self.archives {
+			archive_infos.push(ArchiveInfo {
+				offset: offset,
+				seconds_per_point: archive.seconds_per_point(),
+				points: archive.points(),
+				retention: archive.retention(),
+				size: archive.size()
+			});
+			offset = offset + archive.size();
+		}
+
+		FileInfo {
+			aggregation_type: self.header.aggregation_type(),
+			max_retention: self.header.max_retention(),
+			x_files_factor: self.header.x_files_factor(),
+			archive_count: self.archives.len(),
+			archives: archive_infos
+		}
+	}
+
+	// Same as whisper-set-aggregation-method.py: only changes how future writes
+	// propagate, what's already in the lower archives is left alone. Returns the
+	// previous method.
+	pub fn set_aggregation_type(&mut self, aggregation_type: AggregationType) -> Result<AggregationType> {
+		try!( self.check_writable() );
+		let _lock = try!( self.lock_exclusive() );
+		if aggregation_type == AggregationType::Unknown {
+			return Err(WhisperError::UnknownAggregationType);
+		}
+
+		let old_aggregation_type = self.header.aggregation_type();
+		self.header.write_aggregation_type(&mut self.header_mmap, aggregation_type);
+		try!( self.maybe_flush() );
+		Ok(old_aggregation_type)
+	}
+
+	// Same as whisper-set-xfilesfactor.py, returns the previous xFilesFactor
+	pub fn set_x_files_factor(&mut self, x_files_factor: f32) -> Result<f32> {
+		try!( self.check_writable() );
+		let _lock = try!( self.lock_exclusive() );
+		if !(x_files_factor >= 0.0 && x_files_factor <= 1.0) {
+			return Err(WhisperError::InvalidXFilesFactor(x_files_factor));
+		}
+
+		let old_x_files_factor = self.header.x_files_factor();
+		self.header.write_x_files_factor(&mut self.header_mmap, x_files_factor);
+		try!( self.maybe_flush() );
+		Ok(old_x_files_factor)
+	}
+
+	#[inline]
+	pub fn is_read_only(&self) -> bool {
+		self.read_only
+	}
+
+	// Same as carbon's WHISPER_LOCK_WRITES: every write then holds an exclusive
+	// flock on the file and every fetch a shared one, so several processes can
+	// share a file safely. Off by default.
+	pub fn set_lock_writes(&mut self, lock_writes: bool) {
+		self.lock_writes = lock_writes;
+	}
+
+	// Same as carbon's WHISPER_AUTOFLUSH: every write then waits for the changes to
+	// reach the disk instead of leaving it to the kernel. Off by default.
+	pub fn set_autoflush(&mut self, autoflush: bool) {
+		self.autoflush = autoflush;
+	}
+
+	// Guards against clients with skewed clocks shadowing the whole ring with
+	// points from the future. By default anything past now is rejected.
+	pub fn set_future_policy(&mut self, future_policy: FuturePolicy) {
+		self.future_policy = future_policy;
+	}
+
+	fn apply_future_policy(&self, now: u32, point: &Point) -> Result<Point> {
+		match self.future_policy {
+			FuturePolicy::Reject { tolerance } if point.0 > now.saturating_add(tolerance) => Err(WhisperError::FutureTimestamp(point.0)),
+			FuturePolicy::Clamp { tolerance } if point.0 > now.saturating_add(tolerance) => Ok(Point(now.saturating_add(tolerance), point.1)),
+			_ => Ok(*point)
+		}
+	}
+
+	pub fn set_non_finite_policy(&mut self, non_finite_policy: NonFinitePolicy) {
+		self.non_finite_policy = non_finite_policy;
+	}
+
+	// None when the point is to be skipped
+	fn apply_non_finite_policy(&self, point: Point) -> Result<Option<Point>> {
+		if point.1.is_finite() {
+			return Ok(Some(point));
+		}
+
+		match self.non_finite_policy {
+			NonFinitePolicy::Reject => Err(WhisperError::NonFiniteValue(point.0)),
+			NonFinitePolicy::Skip => Ok(None),
+			NonFinitePolicy::Allow | NonFinitePolicy::Null => Ok(Some(point))
+		}
+	}
+
+	#[inline]
+	fn is_null(&self, point: &Point) -> bool {
+		self.non_finite_policy == NonFinitePolicy::Null && !point.1.is_finite()
+	}
+
+	// Only applies to the archive a point is written to, propagated values always
+	// replace what's in the lower archives
+	pub fn set_overwrite_policy(&mut self, overwrite_policy: OverwritePolicy) {
+		self.overwrite_policy = overwrite_policy;
+	}
+
+	// Applies the overwrite policy to sorted points headed for one archive, in order,
+	// so a point also sees what the points before it in the batch left behind
+	fn apply_overwrite_policy(&self, archive_index: usize, points: &[Point]) -> Vec<Point> {
+		if self.overwrite_policy == OverwritePolicy::Overwrite {
+			return points.to_vec();
+		}
+
+		let archive = &self.archives[archive_index];
+		let step = archive.seconds_per_point();
+		let mut current : HashMap<u32, Option<f64>> = HashMap::new();
+		let mut resolved = Vec::with_capacity(points.len());
+
+		for point in points {
+			let interval = point.0 - (point.0 % step);
+			if self.is_null(point) {
+				current.insert(interval, None);
+				resolved.push(*point);
+				continue;
+			}
+
+			let existing = *current.entry(interval).or_insert_with(|| archive.fetch(interval, interval + step)[0]);
+			let value = match (existing, self.overwrite_policy) {
+				(Some(_), OverwritePolicy::FirstWriteWins) => continue,
+				(Some(existing), OverwritePolicy::Aggregate) => self.header.aggregation_type.aggregate(&[existing, point.1], 2).unwrap_or(point.1),
+				_ => point.1
+			};
+
+			current.insert(interval, Some(value));
+			resolved.push(Point(point.0, value));
+		}
+
+		resolved
+	}
+
+	// Writes the changed pages of the mmap back to disk (msync)
+	pub fn flush(&mut self) -> Result<()> {
+		if self.read_only {
+			return Ok(());
+		}
+
+		try!( self.header_mmap.flush() );
+		for archive in self.archives.iter_mut() {
+			try!( archive.flush() );
+		}
+		Ok(())
+	}
+
+	#[inline]
+	fn maybe_flush(&mut self) -> Result<()> {
+		if self.autoflush {
+			self.flush()
+		} else {
+			Ok(())
+		}
+	}
+
+	fn lock_exclusive(&self) -> Result<Option<FileLock>> {
+		if self.lock_writes {
+			Ok(Some(try!( FileLock::exclusive(&self.file) )))
+		} else {
+			Ok(None)
+		}
+	}
+
+	fn lock_shared(&self) -> Result<Option<FileLock>> {
+		if self.lock_writes {
+			Ok(Some(try!( FileLock::shared(&self.file) )))
+		} else {
+			Ok(None)
+		}
+	}
+
+	#[inline]
+	fn check_writable(&self) -> Result<()> {
+		if self.read_only {
+			Err(WhisperError::ReadOnly)
+		} else {
+			Ok(())
+		}
+	}
+
+	pub fn write(&mut self, point: &Point) -> Result<()> {
+		self.write_at(current_time(), point)
+	}
+
+	// The point goes to the highest precision archive still covering its age and is
+	// then propagated to the coarser archives below it. An archive covers ages
+	// short of its retention: a point as old as that would take the slot of now.
+	pub fn write_at(&mut self, now: u32, point: &Point) -> Result<()> {
+		try!( self.check_writable() );
+		let _lock = try!( self.lock_exclusive() );
+		let point = match try!( self.apply_non_finite_policy(try!( self.apply_future_policy(now, point) )) ) {
+			Some(point) => point,
+			None => return Ok(())
 		};
-		whisper_file
+		let age = now.saturating_sub(point.0);
+		if age >= self.header.max_retention {
+			return Err(WhisperError::TimestampNotCovered(point.0));
+		}
+
+		let archive_index = self.archives.iter().position(|archive| archive.retention() > age).unwrap();
+		let point = match self.apply_overwrite_policy(archive_index, &[point]).pop() {
+			Some(point) => point,
+			None => return Ok(())
+		};
+		let interval = point.0 - (point.0 % self.archives[archive_index].seconds_per_point());
+		if self.is_null(&point) {
+			let step = self.archives[archive_index].seconds_per_point();
+			self.archives[archive_index].clear(interval, interval + step);
+		} else {
+			self.archives[archive_index].write(&point);
+		}
+
+		for lower in archive_index + 1 .. self.archives.len() {
+			if !self.propagate(interval, lower - 1, lower) {
+				break;
+			}
+		}
+
+		self.maybe_flush()
+	}
+
+	pub fn update_many(&mut self, points: &[Point]) -> Result<()> {
+		self.update_many_at(current_time(), points)
+	}
+
+	// Same as whisper.py's update_many: walking from the newest point, each point goes
+	// to the first archive whose retention covers its age. Points older than the whole
+	// file are dropped. A single point rejected by the future policy fails the whole batch.
+	pub fn update_many_at(&mut self, now: u32, points: &[Point]) -> Result<()> {
+		try!( self.check_writable() );
+		let _lock = try!( self.lock_exclusive() );
+
+		let mut sorted_points = Vec::with_capacity(points.len());
+		for point in points {
+			let point = try!( self.apply_future_policy(now, point) );
+			if let Some(point) = try!( self.apply_non_finite_policy(point) ) {
+				sorted_points.push(point);
+			}
+		}
+		// Stable, so the last of several points with the same timestamp wins
+		sorted_points.sort_by_key(|point| point.0);
+
+		let mut archive_index = 0;
+		let mut archive_points : Vec<Point> = Vec::with_capacity(sorted_points.len());
+
+		for point in sorted_points.iter().rev() {
+			let age = now.saturating_sub(point.0);
+			while archive_index < self.archives.len() && self.archives[archive_index].retention() <= age {
+				if !archive_points.is_empty() {
+					archive_points.reverse();
+					self.archive_update_many(archive_index, &archive_points);
+					archive_points.clear();
+				}
+				archive_index = archive_index + 1;
+			}
+
+			if archive_index == self.archives.len() {
+				break;
+			}
+			archive_points.push(*point);
+		}
+
+		if archive_index < self.archives.len() && !archive_points.is_empty() {
+			archive_points.reverse();
+			self.archive_update_many(archive_index, &archive_points);
+		}
+
+		self.maybe_flush()
+	}
+
+	fn archive_update_many(&mut self, archive_index: usize, points: &[Point]) {
+		let resolved = self.apply_overwrite_policy(archive_index, points);
+		let points = &resolved[..];
+		if points.is_empty() {
+			return;
+		}
+
+		if points.iter().any(|point| self.is_null(point)) {
+			self.archive_write_with_nulls(archive_index, points);
+		} else {
+			self.archives[archive_index].write_many(points);
+		}
+
+		let mut higher = archive_index;
+		for lower in archive_index + 1 .. self.archives.len() {
+			let lower_spp = self.archives[lower].seconds_per_point();
+			let mut lower_intervals : Vec<u32> = points.iter().map(|point| point.0 - (point.0 % lower_spp)).collect();
+			lower_intervals.dedup();
+
+			let mut propagate_further = false;
+			for interval in lower_intervals {
+				if self.propagate(interval, higher, lower) {
+					propagate_further = true;
+				}
+			}
+
+			if !propagate_further {
+				break;
+			}
+			higher = lower;
+		}
+	}
+
+	// Empties every slot whose interval overlaps [from, until], in all archives.
+	// Nothing is propagated, the coarser archives are expected to be cleared too.
+	pub fn clear_range(&mut self, from: u32, until: u32) -> Result<()> {
+		try!( self.check_writable() );
+		let _lock = try!( self.lock_exclusive() );
+		if from > until {
+			return Err(WhisperError::InvalidTimeInterval(from, until));
+		}
+
+		for archive in self.archives.iter_mut() {
+			let step = archive.seconds_per_point();
+			let from_interval = from - (from % step);
+			let until_interval = (until - (until % step)).saturating_add(step);
+			archive.clear(from_interval, until_interval);
+		}
+
+		self.maybe_flush()
+	}
+
+	// Like Archive::write_many, with the null points emptying their slots. The last
+	// point of an interval still wins.
+	fn archive_write_with_nulls(&mut self, archive_index: usize, points: &[Point]) {
+		let step = self.archives[archive_index].seconds_per_point();
+		let mut values = Vec::with_capacity(points.len());
+
+		for (i, point) in points.iter().enumerate() {
+			let interval = point.0 - (point.0 % step);
+			if i + 1 < points.len() && points[i + 1].0 - (points[i + 1].0 % step) == interval {
+				continue;
+			}
+
+			if self.is_null(point) {
+				self.archives[archive_index].clear(interval, interval + step);
+			} else {
+				values.push(*point);
+			}
+		}
+
+		self.archives[archive_index].write_many(&values);
+	}
+
+	// Same as whisper.py's __propagate: aggregates the `higher` archive's points
+	// covering the `lower` archive interval around `timestamp` into one lower point,
+	// as long as enough of them are known to satisfy the xFilesFactor.
+	fn propagate(&mut self, timestamp: u32, higher: usize, lower: usize) -> bool {
+		let lower_spp = self.archives[lower].seconds_per_point();
+		let lower_interval_start = timestamp - (timestamp % lower_spp);
+		let lower_interval_end = lower_interval_start + lower_spp;
+
+		let neighbor_values = self.archives[higher].fetch(lower_interval_start, lower_interval_end);
+		let known_values : Vec<f64> = neighbor_values.iter().filter_map(|value| *value).collect();
+		if known_values.is_empty() {
+			return false;
+		}
+
+		let known_percent = known_values.len() as f64 / neighbor_values.len() as f64;
+		if known_percent < self.header.x_files_factor as f64 {
+			return false;
+		}
+
+		match self.header.aggregation_type.aggregate(&known_values, neighbor_values.len()) {
+			Some(aggregate_value) => {
+				self.archives[lower].write(&Point(lower_interval_start, aggregate_value));
+				true
+			},
+			None => false
+		}
+	}
+
+	pub fn fetch(&self, from: u32, until: u32) -> Result<TimeSeries> {
+		self.fetch_at(current_time(), from, until)
 	}
 
-	pub fn write(&mut self, point: &Point) {
-		self.archives[0].write(&point);
+	// Same as whisper.py's fetch: picks the highest precision archive that still covers
+	// `from`. A window completely outside the retention yields a series without values.
+	pub fn fetch_at(&self, now: u32, from: u32, until: u32) -> Result<TimeSeries> {
+		self.fetch_with_at(now, from, until, ArchiveSelection::Best)
 	}
+
+	pub fn fetch_with(&self, from: u32, until: u32, selection: ArchiveSelection) -> Result<TimeSeries> {
+		self.fetch_with_at(current_time(), from, until, selection)
+	}
+
+	// A fetch reading from the archive picked by `selection`. The window is clamped
+	// to that archive's retention.
+	pub fn fetch_with_at(&self, now: u32, from: u32, until: u32, selection: ArchiveSelection) -> Result<TimeSeries> {
+		let _lock = try!( self.lock_shared() );
+		if from > until {
+			return Err(WhisperError::InvalidTimeInterval(from, until));
+		}
+		if let ArchiveSelection::Index(archive_index) = selection {
+			if archive_index >= self.archives.len() {
+				return Err(WhisperError::InvalidArchiveIndex(archive_index));
+			}
+		}
+
+		let best_index = {
+			let diff = now.saturating_sub(from);
+			match self.archives.iter().position(|archive| archive.retention() >= diff) {
+				Some(archive_index) => archive_index,
+				None => self.archives.len() - 1
+			}
+		};
+		let retention = match selection {
+			ArchiveSelection::Index(archive_index) => self.archives[archive_index].retention(),
+			_ => self.header.max_retention
+		};
+
+		let oldest_time = now.saturating_sub(retention);
+		if from > now || until < oldest_time {
+			return Ok(TimeSeries { from: from, until: until, step: 0, values: vec![] });
+		}
+
+		let from = cmp::max(from, oldest_time);
+		let until = cmp::min(until, now);
+
+		let archive_index = match selection {
+			ArchiveSelection::Best => best_index,
+			ArchiveSelection::Index(archive_index) => archive_index,
+			ArchiveSelection::MaxPoints(max_points) => (best_index .. self.archives.len())
+				.find(|&archive_index| {
+					let (from_interval, until_interval) = fetch_intervals(self.archives[archive_index].seconds_per_point(), from, until);
+					((until_interval - from_interval) / self.archives[archive_index].seconds_per_point()) as usize <= max_points
+				})
+				.unwrap_or(self.archives.len() - 1)
+		};
+
+		Ok(self.fetch_archive(archive_index, from, until))
+	}
+
+	pub fn fetch_consolidated(&self, from: u32, until: u32, max_points: usize, function: &AggregationType) -> Result<TimeSeries> {
+		self.fetch_consolidated_at(current_time(), from, until, max_points, function)
+	}
+
+	// A fetch downsampled to at most `max_points` values, see TimeSeries::consolidate
+	pub fn fetch_consolidated_at(&self, now: u32, from: u32, until: u32, max_points: usize, function: &AggregationType) -> Result<TimeSeries> {
+		let series = try!( self.fetch_at(now, from, until) );
+		Ok(series.consolidate(max_points, function))
+	}
+
+	pub fn history(&self) -> Result<Vec<Point>> {
+		self.history_at(current_time())
+	}
+
+	// Every point the file holds at the best precision it has it in, oldest
+	// first: the first archive's, then each coarser archive's for the time
+	// before the finer ones reach back to
+	pub fn history_at(&self, now: u32) -> Result<Vec<Point>> {
+		let _lock = try!( self.lock_shared() );
+		let mut history = vec![];
+		let mut covered_from = u32::max_value();
+		for archive in &self.archives {
+			let from = now.saturating_sub(archive.retention());
+			if from >= covered_from {
+				continue;
+			}
+			let mut points : Vec<Point> = archive.iter_range(from, covered_from).collect();
+			points.extend(history);
+			history = points;
+			covered_from = from;
+		}
+		Ok(history)
+	}
+
+	// Reads [from, until] from one archive, aligned to its intervals the way whisper.py does
+	fn fetch_archive(&self, archive_index: usize, from: u32, until: u32) -> TimeSeries {
+		let archive = &self.archives[archive_index];
+
+		let step = archive.seconds_per_point();
+		let (from_interval, until_interval) = fetch_intervals(step, from, until);
+
+		TimeSeries {
+			from: from_interval,
+			until: until_interval,
+			step: step,
+			values: archive.fetch(from_interval, until_interval)
+		}
+	}
+}
+
+// The [from_interval, until_interval) a fetch of [from, until] reads for archives of `step`
+fn fetch_intervals(step: u32, from: u32, until: u32) -> (u32, u32) {
+	let from_interval = from - (from % step) + step;
+	let mut until_interval = until - (until % step) + step;
+	if from_interval == until_interval {
+		// Zero-length time range: always include the next point
+		until_interval = until_interval + step;
+	}
+	(from_interval, until_interval)
 }
 
 #[cfg(test)]
 mod tests {
-	use whisper::{ Schema, WhisperFile, Point };
+	use whisper::{ Schema, SchemaError, WhisperFile, WhisperError, Point, temp_path };
+	use whisper::schema::RetentionPolicy;
 	use super::header;
+	use super::{ TimeSeries, ArchiveSelection, FuturePolicy, NonFinitePolicy, OverwritePolicy, Allocation, AggregationType, ArchiveInfo, ArchiveStats, DEFAULT_X_FILES_FACTOR };
 
 	use std::io::Cursor;
 	use std::io::Write;
+	use std::fs;
+	use std::f64;
 	use memmap::{ Mmap, Protection };
 
 	// whisper-create.py blah.wsp 60:5
@@ -193,36 +916,525 @@ mod tests {
 
 		let hdr = header::Header::new_from_slice(unsafe{ anon_mmap.as_mut_slice() });
 
-		assert_eq!(hdr.aggregation_type(), header::AggregationType::Unknown);
+		assert_eq!(hdr.aggregation_type(), header::AggregationType::Average);
 		assert_eq!(hdr.max_retention(), 300);
 		assert_eq!(hdr.x_files_factor(), 0.5);
 
 		let mmap_view = anon_mmap.into_view_sync();
-		let archives = hdr.mmap_to_archives(mmap_view);
+		let (_, archives) = hdr.mmap_to_archives(mmap_view);
 		assert_eq!(archives.len(), 1);
 		assert_eq!(archives[0].seconds_per_point(), 60);
 		assert_eq!(archives[0].points(), 5);
 		assert_eq!(archives[0].size(), 60); // 5 points * (8 bytes float + 4 bytes ts) = 60 bytes
 	}
 
+	#[test]
+	fn test_new_with_options() {
+		let path = &temp_path("test_new_with_options.wsp");
+		fs::remove_file(path).ok();
+		let schema = Schema::new_from_retention_specs(vec!["60s:300s".to_string()]).unwrap();
+		WhisperFile::new_with_options(path, &schema, AggregationType::Max, 0.1).unwrap();
+
+		let file = WhisperFile::open(path).unwrap();
+		assert_eq!(file.header.aggregation_type(), AggregationType::Max);
+		assert_eq!(file.header.x_files_factor(), 0.1);
+		assert!(WhisperFile::new_with_options(path, &schema, AggregationType::Max, 1.5).is_err());
+		assert_eq!("max".parse::<AggregationType>(), Ok(AggregationType::Max));
+	}
+
+	#[test]
+	fn test_write_sum_aggregation() {
+		let path = &temp_path("test_write_sum_aggregation.wsp");
+		fs::remove_file(path).ok();
+		let schema = Schema::new_from_retention_specs(vec!["10s:60s".to_string(), "30s:120s".to_string()]).unwrap();
+		let mut file = WhisperFile::new_with_options(path, &schema, AggregationType::Sum, 0.0).unwrap();
+
+		let now = 1440392100;
+		file.update_many_at(now, &[Point(1440392070, 1.0), Point(1440392090, 6.0)]).unwrap();
+		assert_eq!(file.fetch_at(now, now - 100, now - 5).unwrap().values, vec![None, None, Some(7.0)]);
+	}
+
+	#[test]
+	fn test_open() {
+		let file = WhisperFile::open("test/whisper/60s_1y.wsp").unwrap();
+		assert_eq!(file.header.max_retention(), 31536000);
+		assert_eq!(file.archives.len(), 1);
+		assert_eq!(file.archives[0].points(), 525600);
+
+		assert!(WhisperFile::open("test/fixtures/abcs.txt").is_err());
+		assert!(WhisperFile::open("test/fixtures/missing.wsp").is_err());
+	}
+
+	#[test]
+	fn test_stats() {
+		let path = &temp_path("test_stats.wsp");
+		fs::remove_file(path).ok();
+		let schema = Schema::new_from_retention_specs(vec!["10s:60s".to_string(), "60s:600s".to_string()]).unwrap();
+		let mut file = WhisperFile::new(path, &schema).unwrap();
+
+		let now = 1440392100;
+		assert_eq!(file.stats_at(now).unwrap().last_update, None);
+
+		file.update_many_at(now, &[Point(now - 50, 1.0), Point(now - 40, 2.0), Point(now - 30, 3.0)]).unwrap();
+		let stats = file.stats_at(now).unwrap();
+		assert_eq!(stats.last_update, Some(now - 30));
+		assert_eq!(stats.size_on_disk, schema.size_on_disk() as u64);
+		assert_eq!(stats.archives, vec![
+			ArchiveStats { points: 6, known_points: 3, fill_percent: 50.0 },
+			ArchiveStats { points: 10, known_points: 1, fill_percent: 10.0 }
+		]);
+
+		assert_eq!(file.first_timestamp(), Some(now - 60));
+		assert_eq!(file.last_timestamp(), Some(now - 30));
+
+		// Long after the writes, nothing is inside the first archive's window anymore
+		assert_eq!(file.stats_at(now + 120).unwrap().archives[0].known_points, 0);
+	}
+
+	#[test]
+	fn test_fetch_consolidated() {
+		let path = &temp_path("test_fetch_consolidated.wsp");
+		fs::remove_file(path).ok();
+		let schema = Schema::new_from_retention_specs(vec!["10s:60s".to_string()]).unwrap();
+		let mut file = WhisperFile::new(path, &schema).unwrap();
+
+		let now = 1440392100;
+		let points : Vec<Point> = (1..6).map(|i| Point(now - i * 10, i as f64)).collect();
+		file.update_many_at(now, &points).unwrap();
+
+		let series = file.fetch_consolidated_at(now, now - 60, now, 3, &AggregationType::Max).unwrap();
+		assert_eq!(series.step, 20);
+		assert_eq!(series.values, vec![Some(5.0), Some(3.0), Some(1.0)]);
+	}
+
+	#[test]
+	fn test_fetch_with() {
+		let path = &temp_path("test_fetch_with.wsp");
+		fs::remove_file(path).ok();
+		let schema = Schema::new_from_retention_specs(vec!["10s:60s".to_string(), "60s:600s".to_string()]).unwrap();
+		let mut file = WhisperFile::new(path, &schema).unwrap();
+
+		let now = 1440392100;
+		let points : Vec<Point> = (1..6).map(|i| Point(now - i * 10, i as f64)).collect();
+		file.update_many_at(now, &points).unwrap();
+
+		assert_eq!(file.fetch_with_at(now, now - 60, now, ArchiveSelection::Best).unwrap(), file.fetch_at(now, now - 60, now).unwrap());
+		assert_eq!(file.fetch_with_at(now, now - 60, now, ArchiveSelection::MaxPoints(6)).unwrap().step, 10);
+		assert_eq!(file.fetch_with_at(now, now - 60, now, ArchiveSelection::MaxPoints(2)).unwrap().step, 60);
+		// Nothing fits, the coarsest archive it is
+		assert_eq!(file.fetch_with_at(now, now - 60, now, ArchiveSelection::MaxPoints(0)).unwrap().step, 60);
+
+		let series = file.fetch_with_at(now, now - 61, now, ArchiveSelection::Index(1)).unwrap();
+		assert_eq!((series.step, series.values), (60, vec![Some(3.0), None]));
+		// Clamped to the forced archive's retention
+		assert_eq!(file.fetch_with_at(now, now - 300, now, ArchiveSelection::Index(0)).unwrap().from, now - 50);
+		assert!(file.fetch_with_at(now, now - 60, now, ArchiveSelection::Index(2)).is_err());
+	}
+
+	#[test]
+	fn test_history() {
+		let path = &temp_path("test_history.wsp");
+		fs::remove_file(path).ok();
+		let schema = Schema::new_from_retention_specs(vec!["10s:30s".to_string(), "30s:90s".to_string()]).unwrap();
+		let mut file = WhisperFile::new(path, &schema).unwrap();
+
+		let now = 1440392100;
+		file.update_many_at(now, &[Point(now - 80, 5.0), Point(now - 20, 1.0), Point(now - 10, 2.0), Point(now, 3.0)]).unwrap();
+
+		// The rollup of now-20 and now-10 into now-30 is left to the finer archive
+		assert_eq!(file.history_at(now).unwrap(), vec![Point(now - 90, 5.0), Point(now - 20, 1.0), Point(now - 10, 2.0), Point(now, 3.0)]);
+	}
+
+	#[test]
+	fn test_info() {
+		let path = &temp_path("test_info.wsp");
+		fs::remove_file(path).ok();
+		let schema = Schema::new_from_retention_specs(vec!["10s:60s".to_string(), "60s:600s".to_string()]).unwrap();
+		let file = WhisperFile::new_with_options(path, &schema, AggregationType::Sum, 0.25).unwrap();
+
+		let info = file.info();
+		assert_eq!(info.aggregation_type, AggregationType::Sum);
+		assert_eq!(info.max_retention, 600);
+		assert_eq!(info.x_files_factor, 0.25);
+		assert_eq!(info.archive_count, 2);
+		assert_eq!(info.archives, vec![
+			ArchiveInfo { offset: 40, seconds_per_point: 10, points: 6, retention: 60, size: 72 },
+			ArchiveInfo { offset: 112, seconds_per_point: 60, points: 10, retention: 600, size: 120 }
+		]);
+		assert!(info.to_string().starts_with("aggregationMethod: sum\nmaxRetention: 600\nxFilesFactor: 0.25\n\nArchive 0\noffset: 40\n"));
+	}
+
+	#[test]
+	fn test_set_aggregation_type() {
+		let path = &temp_path("test_set_aggregation_type.wsp");
+		fs::remove_file(path).ok();
+		let schema = Schema::new_from_retention_specs(vec!["10s:60s".to_string(), "60s:600s".to_string()]).unwrap();
+		let mut file = WhisperFile::new(path, &schema).unwrap();
+
+		assert_eq!(file.set_aggregation_type(AggregationType::Max).unwrap(), AggregationType::Average);
+		assert!(file.set_aggregation_type(AggregationType::Unknown).is_err());
+		assert_eq!(file.header.aggregation_type(), AggregationType::Max);
+		assert_eq!(WhisperFile::open(path).unwrap().header.aggregation_type(), AggregationType::Max);
+
+		let mut read_only = WhisperFile::open_readonly(path).unwrap();
+		assert!(read_only.set_aggregation_type(AggregationType::Sum).is_err());
+	}
+
+	#[test]
+	fn test_set_x_files_factor() {
+		let path = &temp_path("test_set_x_files_factor.wsp");
+		fs::remove_file(path).ok();
+		let schema = Schema::new_from_retention_specs(vec!["10s:60s".to_string(), "60s:600s".to_string()]).unwrap();
+		let mut file = WhisperFile::new(path, &schema).unwrap();
+
+		assert_eq!(file.set_x_files_factor(0.1).unwrap(), DEFAULT_X_FILES_FACTOR);
+		assert!(file.set_x_files_factor(1.5).is_err());
+		assert!(file.set_x_files_factor(-0.5).is_err());
+		assert_eq!(file.header.x_files_factor(), 0.1);
+		assert_eq!(WhisperFile::open(path).unwrap().header.x_files_factor(), 0.1);
+	}
+
+	#[test]
+	fn test_clear_range() {
+		let path = &temp_path("test_clear_range.wsp");
+		fs::remove_file(path).ok();
+		let schema = Schema::new_from_retention_specs(vec!["10s:60s".to_string(), "60s:600s".to_string()]).unwrap();
+		let mut file = WhisperFile::new(path, &schema).unwrap();
+
+		let now = 1440392100;
+		let points : Vec<Point> = (1..6).map(|i| Point(now - i * 10, i as f64)).collect();
+		file.update_many_at(now, &points).unwrap();
+
+		file.clear_range(now - 35, now - 25).unwrap();
+		let series = file.fetch_at(now, now - 60, now).unwrap();
+		// Both intervals overlapping the range are emptied
+		assert_eq!(series.values, vec![Some(5.0), None, None, Some(2.0), Some(1.0), None]);
+		// The minute the garbage was propagated to is gone too
+		assert_eq!(file.fetch_at(now, now - 600, now - 61).unwrap().values.iter().filter(|value| value.is_some()).count(), 0);
+		assert!(file.clear_range(now, now - 10).is_err());
+	}
+
+	#[test]
+	fn test_new_with_allocation() {
+		let schema = Schema::new_from_retention_specs(vec!["10s:60s".to_string(), "60s:600s".to_string()]).unwrap();
+		for &(path, allocation) in &[(&temp_path("test_new_sparse.wsp"), Allocation::Sparse), (&temp_path("test_new_full.wsp"), Allocation::Full)] {
+			fs::remove_file(path).ok();
+			WhisperFile::new_with_allocation(path, &schema, AggregationType::Average, 0.5, allocation).unwrap();
+
+			assert_eq!(fs::metadata(path).unwrap().len(), schema.size_on_disk() as u64);
+			let file = WhisperFile::open(path).unwrap();
+			assert_eq!(file.fetch_at(1440392100, 1440391500, 1440392100).unwrap().values, vec![None; 10]);
+		}
+	}
+
+	#[test]
+	fn test_new_invalid_schema() {
+		let path = &temp_path("test_new_invalid_schema.wsp");
+		fs::remove_file(path).ok();
+		let schema = Schema { retention_policies: vec![RetentionPolicy { precision: 10, retention: 60 }, RetentionPolicy { precision: 15, retention: 600 }] };
+
+		match WhisperFile::new(path, &schema) {
+			Err(WhisperError::InvalidSchema(SchemaError::UnevenPrecision(10, 15))) => (),
+			other => panic!("expected an invalid schema, got {:?}", other.map(|_| ()))
+		}
+		assert!(fs::metadata(path).is_err());
+	}
+
+	#[test]
+	fn test_new_sorts_archives() {
+		let path = &temp_path("test_new_sorts_archives.wsp");
+		fs::remove_file(path).ok();
+		let schema = Schema { retention_policies: vec![RetentionPolicy { precision: 60, retention: 600 }, RetentionPolicy { precision: 10, retention: 60 }] };
+
+		let file = WhisperFile::new(path, &schema).unwrap();
+		let precisions : Vec<u32> = file.info().archives.iter().map(|archive| archive.seconds_per_point).collect();
+		assert_eq!(precisions, vec![10, 60]);
+	}
+
+	#[test]
+	fn test_zero_fill() {
+		let path = &temp_path("test_zero_fill");
+		let mut file = fs::OpenOptions::new().read(true).write(true).create(true).truncate(true).open(path).unwrap();
+		WhisperFile::zero_fill(&mut file, 10000).unwrap();
+
+		// Ready for the header to be written at the start
+		file.write_all(&[1]).unwrap();
+		let contents = fs::read(path).unwrap();
+		assert_eq!(contents.len(), 10000);
+		assert_eq!(contents[0], 1);
+		assert!(contents[1..].iter().all(|&byte| byte == 0));
+	}
+
+	#[test]
+	fn test_lock_writes() {
+		let path = &temp_path("test_lock_writes.wsp");
+		fs::remove_file(path).ok();
+		let schema = Schema::new_from_retention_specs(vec!["10s:60s".to_string()]).unwrap();
+		let mut file = WhisperFile::new(path, &schema).unwrap();
+		file.set_lock_writes(true);
+
+		let now = 1440392100;
+		file.write_at(now, &Point(now - 10, 1.0)).unwrap();
+		assert_eq!(file.fetch_at(now, now - 20, now).unwrap().values, vec![Some(1.0), None]);
+
+		// The lock is gone once the write is done, another process can take it
+		let mut other = WhisperFile::open(path).unwrap();
+		other.set_lock_writes(true);
+		other.write_at(now, &Point(now - 20, 2.0)).unwrap();
+		assert_eq!(file.fetch_at(now, now - 30, now).unwrap().values, vec![Some(2.0), Some(1.0), None]);
+	}
+
+	#[test]
+	fn test_flush() {
+		let path = &temp_path("test_flush.wsp");
+		fs::remove_file(path).ok();
+		let schema = Schema::new_from_retention_specs(vec!["10s:60s".to_string(), "60s:600s".to_string()]).unwrap();
+		let mut file = WhisperFile::new(path, &schema).unwrap();
+		file.set_autoflush(true);
+
+		let now = 1440392100;
+		file.update_many_at(now, &[Point(now - 20, 1.0), Point(now - 10, 2.0)]).unwrap();
+		file.flush().unwrap();
+		assert_eq!(WhisperFile::open_readonly(path).unwrap().fetch_at(now, now - 30, now).unwrap().values, vec![Some(1.0), Some(2.0), None]);
+		assert!(WhisperFile::open_readonly(path).unwrap().flush().is_ok());
+	}
+
+	#[test]
+	fn test_new_is_atomic() {
+		let dir = &temp_path("test_new_is_atomic");
+		fs::remove_dir_all(dir).ok();
+		fs::create_dir(dir).unwrap();
+		let path = &temp_path("test_new_is_atomic/metric.wsp");
+		let schema = Schema::new_from_retention_specs(vec!["10s:60s".to_string()]).unwrap();
+
+		// Replaces whatever was at the path, leaving no temporary file behind
+		fs::File::create(path).unwrap().write_all(b"garbage").unwrap();
+		WhisperFile::new(path, &schema).unwrap();
+		assert!(WhisperFile::open(path).is_ok());
+		assert_eq!(fs::read_dir(dir).unwrap().count(), 1);
+
+		assert!(WhisperFile::new(&temp_path("test_new_is_atomic/missing/metric.wsp"), &schema).is_err());
+		assert_eq!(fs::read_dir(dir).unwrap().count(), 1);
+	}
+
+	#[test]
+	fn test_open_truncated() {
+		let path = &temp_path("test_open_truncated.wsp");
+		fs::File::create(path).unwrap().write_all(&SAMPLE_FILE[..60]).unwrap();
+		assert!(WhisperFile::open(path).is_err());
+
+		fs::File::create(path).unwrap().write_all(&SAMPLE_FILE[..]).unwrap();
+		assert!(WhisperFile::open(path).is_ok());
+	}
+
+	#[test]
+	fn test_open_readonly() {
+		let path = &temp_path("test_open_readonly.wsp");
+		fs::remove_file(path).ok();
+		let schema = Schema::new_from_retention_specs(vec!["10s:60s".to_string()]).unwrap();
+		let now = 1440392100;
+		WhisperFile::new(path, &schema).unwrap().write_at(now, &Point(now - 10, 1.0)).unwrap();
+
+		let mut file = WhisperFile::open_readonly(path).unwrap();
+		assert!(file.is_read_only());
+		assert_eq!(file.fetch_at(now, now - 20, now).unwrap().values, vec![Some(1.0), None]);
+		assert!(file.write_at(now, &Point(now - 10, 2.0)).is_err());
+		assert!(file.update_many_at(now, &[Point(now - 10, 2.0)]).is_err());
+	}
+
 	#[test]
 	fn test_write() {
-		let path = "/tmp/blah.wsp";
+		let path = &temp_path("blah.wsp");
 		let default_specs = vec!["1s:60s".to_string(), "1m:1y".to_string()];
 		let schema = Schema::new_from_retention_specs(default_specs).unwrap();
 
 		let mut file = WhisperFile::new(path, &schema).unwrap();
 
-		file.write(&Point(10, 0.0))
+		let now = 1440392100;
+		file.write_at(now, &Point(now - 10, 0.0)).unwrap();
+	}
+
+	#[test]
+	fn test_fetch() {
+		let path = &temp_path("test_fetch.wsp");
+		fs::remove_file(path).ok();
+		let schema = Schema::new_from_retention_specs(vec!["10s:60s".to_string()]).unwrap();
+		let mut file = WhisperFile::new(path, &schema).unwrap();
+
+		let now = 1440392100;
+		file.write_at(now, &Point(1440392070, 1.0)).unwrap();
+		file.write_at(now, &Point(1440392090, 3.0)).unwrap();
+
+		let series = file.fetch_at(now, 1440392055, 1440392095).unwrap();
+		assert_eq!(series, TimeSeries {
+			from: 1440392060,
+			until: 1440392100,
+			step: 10,
+			values: vec![None, Some(1.0), None, Some(3.0)]
+		});
+
+		// Older than the retention is clamped, entirely outside is empty
+		assert_eq!(file.fetch_at(now, 0, 1440392075).unwrap().from, 1440392050);
+		assert!(file.fetch_at(now, 0, 10).unwrap().values.is_empty());
+		assert!(file.fetch_at(now, 20, 10).is_err());
+	}
+
+	#[test]
+	fn test_update_many() {
+		let path = &temp_path("test_update_many.wsp");
+		fs::remove_file(path).ok();
+		let schema = Schema::new_from_retention_specs(vec!["10s:60s".to_string(), "60s:600s".to_string()]).unwrap();
+		let mut file = WhisperFile::new(path, &schema).unwrap();
+
+		let now = 1440392100;
+		file.update_many_at(now, &[
+			Point(1440392090, 2.0),
+			Point(1440392070, 1.0),
+			Point(1440392090, 3.0),
+			Point(1440391980, 4.0),
+			Point(1000, 5.0)
+		]).unwrap();
+
+		let recent = file.fetch_at(now, 1440392055, 1440392095).unwrap();
+		assert_eq!(recent.values, vec![None, Some(1.0), None, Some(3.0)]);
+
+		// Too old for the first archive, lands in the second one
+		let older = file.fetch_at(now, 1440391900, 1440392000).unwrap();
+		assert_eq!(older.step, 60);
+		assert_eq!(older.values, vec![None, Some(4.0)]);
 	}
 
 	#[test]
 	fn test_write_aggregation() {
+		let path = &temp_path("test_write_aggregation.wsp");
+		fs::remove_file(path).ok();
+		let schema = Schema::new_from_retention_specs(vec!["10s:60s".to_string(), "30s:120s".to_string()]).unwrap();
+		let mut file = WhisperFile::new(path, &schema).unwrap();
 
+		let now = 1440392100;
+		file.write_at(now, &Point(1440392070, 1.0)).unwrap();
+		// 1 of 3 points known is below the xFilesFactor
+		assert_eq!(file.fetch_at(now, now - 100, now - 5).unwrap().values, vec![None, None, None]);
+
+		file.write_at(now, &Point(1440392080, 2.0)).unwrap();
+		file.write_at(now, &Point(1440392090, 6.0)).unwrap();
+		assert_eq!(file.fetch_at(now, now - 100, now - 5).unwrap().values, vec![None, None, Some(3.0)]);
+
+		file.update_many_at(now, &[Point(1440392040, 4.0), Point(1440392050, 4.0)]).unwrap();
+		assert_eq!(file.fetch_at(now, now - 100, now - 5).unwrap().values, vec![None, Some(4.0), Some(3.0)]);
 	}
 
 	#[test]
 	fn test_write_outside_retention(){
+		let path = &temp_path("test_write_outside_retention.wsp");
+		fs::remove_file(path).ok();
+		let schema = Schema::new_from_retention_specs(vec!["10s:60s".to_string(), "60s:600s".to_string()]).unwrap();
+		let mut file = WhisperFile::new(path, &schema).unwrap();
+
+		let now = 1440392100;
+		assert!(file.write_at(now, &Point(now + 10, 1.0)).is_err());
+		assert!(file.write_at(now, &Point(now - 600, 1.0)).is_err());
+
+		// Older than the first archive goes straight to the second one
+		file.write_at(now, &Point(now - 300, 2.0)).unwrap();
+		assert_eq!(file.fetch_at(now, now - 60, now).unwrap().values, vec![None; 6]);
+		assert_eq!(file.fetch_at(now, now - 360, now - 250).unwrap().values, vec![Some(2.0)]);
+	}
+
+	#[test]
+	fn test_retention_boundary(){
+		let schema = Schema::new_from_retention_specs(vec!["10s:60s".to_string(), "60s:600s".to_string()]).unwrap();
+		let now = 1440392100;
+		let point = Point(now - 60, 5.0);
+
+		let mut files = vec![];
+		for name in &["test_retention_boundary_write.wsp", "test_retention_boundary_update_many.wsp"] {
+			let path = temp_path(name);
+			fs::remove_file(&path).ok();
+			files.push(WhisperFile::new(&path, &schema).unwrap());
+		}
+		files[0].write_at(now, &point).unwrap();
+		files[1].update_many_at(now, &[point]).unwrap();
+
+		// As old as the first archive's retention, so only the second one has it
+		for file in &files {
+			assert_eq!(file.fetch_with_at(now, now - 60, now, ArchiveSelection::Index(0)).unwrap().values, vec![None; 6]);
+			assert_eq!(file.fetch_with_at(now, now - 120, now, ArchiveSelection::Index(1)).unwrap().values, vec![Some(5.0), None]);
+		}
+	}
+
+	#[test]
+	fn test_future_policy(){
+		let path = &temp_path("test_future_policy.wsp");
+		fs::remove_file(path).ok();
+		let schema = Schema::new_from_retention_specs(vec!["10s:60s".to_string()]).unwrap();
+		let mut file = WhisperFile::new(path, &schema).unwrap();
+
+		let now = 1440392100;
+		assert!(file.update_many_at(now, &[Point(now - 10, 1.0), Point(now + 10, 2.0)]).is_err());
+		assert_eq!(file.last_timestamp(), None);
+
+		file.set_future_policy(FuturePolicy::Reject { tolerance: 10 });
+		file.write_at(now, &Point(now + 10, 2.0)).unwrap();
+		assert!(file.write_at(now, &Point(now + 20, 3.0)).is_err());
+
+		file.set_future_policy(FuturePolicy::Clamp { tolerance: 10 });
+		file.update_many_at(now, &[Point(now + 3600, 4.0)]).unwrap();
+		assert_eq!(file.last_timestamp(), Some(now + 10));
+		assert_eq!(file.fetch_at(now + 10, now, now + 10).unwrap().values, vec![Some(4.0)]);
+	}
+
+	#[test]
+	fn test_non_finite_policy(){
+		let path = &temp_path("test_non_finite_policy.wsp");
+		fs::remove_file(path).ok();
+		let schema = Schema::new_from_retention_specs(vec!["10s:60s".to_string(), "60s:600s".to_string()]).unwrap();
+		let mut file = WhisperFile::new(path, &schema).unwrap();
+
+		let now = 1440392100;
+		file.update_many_at(now, &[Point(now - 30, 1.0), Point(now - 20, 2.0), Point(now - 10, 3.0)]).unwrap();
+
+		file.set_non_finite_policy(NonFinitePolicy::Reject);
+		assert!(file.write_at(now, &Point(now - 10, f64::NAN)).is_err());
+		assert!(file.update_many_at(now, &[Point(now - 10, f64::INFINITY)]).is_err());
+
+		file.set_non_finite_policy(NonFinitePolicy::Skip);
+		file.write_at(now, &Point(now - 10, f64::NAN)).unwrap();
+		assert_eq!(file.fetch_at(now, now - 40, now).unwrap().values, vec![Some(1.0), Some(2.0), Some(3.0), None]);
+
+		file.set_non_finite_policy(NonFinitePolicy::Null);
+		file.write_at(now, &Point(now - 10, f64::NAN)).unwrap();
+		file.update_many_at(now, &[Point(now - 30, 5.0), Point(now - 30, f64::NEG_INFINITY), Point(now - 20, f64::NAN), Point(now - 20, 6.0)]).unwrap();
+		assert_eq!(file.fetch_at(now, now - 40, now).unwrap().values, vec![None, Some(6.0), None, None]);
+
+		file.set_non_finite_policy(NonFinitePolicy::Allow);
+		file.write_at(now, &Point(now - 10, f64::INFINITY)).unwrap();
+		assert_eq!(file.fetch_at(now, now - 20, now).unwrap().values, vec![Some(f64::INFINITY), None]);
+	}
+
+	#[test]
+	fn test_overwrite_policy(){
+		let path = &temp_path("test_overwrite_policy.wsp");
+		fs::remove_file(path).ok();
+		let schema = Schema::new_from_retention_specs(vec!["10s:60s".to_string(), "60s:600s".to_string()]).unwrap();
+		let mut file = WhisperFile::new_with_options(path, &schema, AggregationType::Sum, 0.0).unwrap();
+
+		let now = 1440392100;
+		file.write_at(now, &Point(now - 20, 1.0)).unwrap();
+
+		file.set_overwrite_policy(OverwritePolicy::FirstWriteWins);
+		file.write_at(now, &Point(now - 20, 2.0)).unwrap();
+		file.update_many_at(now, &[Point(now - 20, 3.0), Point(now - 10, 4.0), Point(now - 10, 5.0)]).unwrap();
+		assert_eq!(file.fetch_at(now, now - 30, now).unwrap().values, vec![Some(1.0), Some(4.0), None]);
+
+		file.set_overwrite_policy(OverwritePolicy::Aggregate);
+		file.update_many_at(now, &[Point(now - 20, 2.0), Point(now - 20, 3.0)]).unwrap();
+		assert_eq!(file.fetch_at(now, now - 30, now).unwrap().values, vec![Some(6.0), Some(4.0), None]);
+		// The lower archive is recomputed from the new values, not aggregated again
+		assert_eq!(file.fetch_at(now, now - 61, now - 60).unwrap().values, vec![Some(10.0)]);
 
+		file.set_overwrite_policy(OverwritePolicy::Overwrite);
+		file.write_at(now, &Point(now - 20, 7.0)).unwrap();
+		assert_eq!(file.fetch_at(now, now - 30, now).unwrap().values, vec![Some(7.0), Some(4.0), None]);
 	}
 }
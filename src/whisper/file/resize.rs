@@ -0,0 +1,133 @@
+use std::path::Path;
+
+use whisper::{ Point, Schema };
+use whisper::error::Result;
+use super::{ WhisperFile, WhisperBuilder, current_time };
+
+impl WhisperFile {
+	pub fn resize<P>(&self, new_schema: &Schema, new_path: P, aggregate: bool) -> Result<WhisperFile>
+		where P: AsRef<Path> {
+		self.resize_at(current_time(), new_schema, new_path, aggregate)
+	}
+
+	// Same as whisper-resize.py: creates a file at `new_path` with the new retention
+	// policies, but this file's aggregation method and xFilesFactor, and copies the data
+	// over. Without `aggregate` the old archives are replayed through update_many,
+	// with it the old points are merged (coarsest archive first) and rolled up per
+	// interval of each new archive.
+	pub fn resize_at<P>(&self, now: u32, new_schema: &Schema, new_path: P, aggregate: bool) -> Result<WhisperFile>
+		where P: AsRef<Path> {
+		let mut new_file = try!( WhisperBuilder::new(new_schema.clone())
+			.aggregation(self.header.aggregation_type())
+			.x_files_factor(self.header.x_files_factor())
+			.create(new_path) );
+
+		// Coarsest archive first so the finer data written after it wins
+		let mut archive_indexes : Vec<usize> = (0..self.archives.len()).collect();
+		archive_indexes.sort_by_key(|&index| self.archives[index].seconds_per_point());
+		archive_indexes.reverse();
+
+		let mut old_points : Vec<(u32, Option<f64>)> = vec![];
+		for archive_index in archive_indexes {
+			let archive = &self.archives[archive_index];
+			let from = now.saturating_sub(archive.retention()) + archive.seconds_per_point();
+			let series = self.fetch_archive(archive_index, from, now);
+
+			if aggregate {
+				// Only keep what the coarser archives haven't covered yet
+				let covered_until = old_points.last().map(|point| point.0);
+				old_points.extend(series.iter().filter(|point| covered_until.map_or(true, |until| point.0 > until)));
+			} else {
+				try!( new_file.update_many_at(now, &series.points()) );
+			}
+		}
+
+		if aggregate {
+			for archive_index in 0..new_file.archives.len() {
+				let new_points = self.aggregate_for_archive(&old_points, &new_file, archive_index, now);
+				try!( new_file.update_many_at(now, &new_points) );
+			}
+		}
+
+		Ok(new_file)
+	}
+
+	// Rolls the old (timestamp, value) pairs up into the intervals of one of the new archives
+	fn aggregate_for_archive(&self, old_points: &[(u32, Option<f64>)], new_file: &WhisperFile, archive_index: usize, now: u32) -> Vec<Point> {
+		let archive = &new_file.archives[archive_index];
+		let step = archive.seconds_per_point();
+		let until = now - (now % step) + step;
+		let mut interval = until.saturating_sub(archive.retention());
+
+		// The old points are in time order, so one pass over them does
+		let mut left = 0;
+		let mut new_points = vec![];
+		while interval < until {
+			while left < old_points.len() && old_points[left].0 < interval {
+				left = left + 1;
+			}
+			let right = left + old_points[left..].iter().take_while(|point| point.0 < interval + step).count();
+
+			let values = &old_points[left .. right];
+			let known_values : Vec<f64> = values.iter().filter_map(|point| point.1).collect();
+			if !values.is_empty() && known_values.len() as f64 / values.len() as f64 >= self.header.x_files_factor() as f64 {
+				if let Some(value) = self.header.aggregation_type.aggregate(&known_values, values.len()) {
+					new_points.push(Point(interval, value));
+				}
+			}
+
+			interval = interval + step;
+		}
+
+		new_points
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use whisper::{ Schema, WhisperFile, Point, temp_path };
+	use std::fs;
+
+	fn build_file(path: &str, now: u32) -> WhisperFile {
+		fs::remove_file(path).ok();
+		let schema = Schema::new_from_retention_specs(vec!["10s:60s".to_string(), "60s:600s".to_string()]).unwrap();
+		let mut file = WhisperFile::new(path, &schema).unwrap();
+
+		let points : Vec<Point> = (1..60).map(|i| Point(now - i * 10, i as f64)).collect();
+		file.update_many_at(now, &points).unwrap();
+		file
+	}
+
+	#[test]
+	fn test_resize() {
+		let now = 1440392100;
+		let file = build_file(&temp_path("test_resize.wsp"), now);
+
+		fs::remove_file(&temp_path("test_resize_new.wsp")).ok();
+		let new_schema = Schema::new_from_retention_specs(vec!["10s:120s".to_string()]).unwrap();
+		let resized = file.resize_at(now, &new_schema, &temp_path("test_resize_new.wsp"), false).unwrap();
+
+		assert_eq!(resized.info().max_retention, 120);
+		assert_eq!(resized.info().x_files_factor, file.info().x_files_factor);
+		// The fine archive fills in the 10s points after the last minute, which
+		// has the point a minute old: too old for the fine archive. Like
+		// whisper-resize.py its oldest slot is not carried over.
+		let series = resized.fetch_at(now, now - 120, now).unwrap();
+		assert_eq!(&series.values[5..], &[Some(6.0), None, Some(4.0), Some(3.0), Some(2.0), Some(1.0), None][..]);
+	}
+
+	#[test]
+	fn test_resize_aggregate() {
+		let now = 1440392100;
+		let file = build_file(&temp_path("test_resize_aggregate.wsp"), now);
+
+		fs::remove_file(&temp_path("test_resize_aggregate_new.wsp")).ok();
+		let new_schema = Schema::new_from_retention_specs(vec!["30s:600s".to_string()]).unwrap();
+		let resized = file.resize_at(now, &new_schema, &temp_path("test_resize_aggregate_new.wsp"), true).unwrap();
+
+		// Each 30s interval aggregates the old points it contains
+		let series = resized.fetch_at(now, now - 130, now - 31).unwrap();
+		assert_eq!(series.step, 30);
+		assert_eq!(series.values, vec![Some(7.0), None, Some(6.0)]);
+	}
+}
@@ -0,0 +1,76 @@
+use whisper::{ Schema, AggregationType };
+use super::WhisperFile;
+
+// How a file differs from the layout it should have. Each field is None when
+// the file matches, (the file's, the desired) otherwise.
+#[derive(Debug, PartialEq, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct SchemaDiff {
+	// Seconds per point of each archive, finest first
+	pub precisions: Option<(Vec<u32>, Vec<u32>)>,
+	// Retention of each archive, finest first
+	pub retentions: Option<(Vec<u32>, Vec<u32>)>,
+	pub aggregation_type: Option<(AggregationType, AggregationType)>,
+	pub x_files_factor: Option<(f32, f32)>
+}
+
+impl SchemaDiff {
+	pub fn matches(&self) -> bool {
+		!self.needs_resize() && self.aggregation_type.is_none() && self.x_files_factor.is_none()
+	}
+
+	// Archives can only change by rewriting the file, the aggregation method and
+	// xFilesFactor are set in place
+	pub fn needs_resize(&self) -> bool {
+		self.precisions.is_some() || self.retentions.is_some()
+	}
+}
+
+impl WhisperFile {
+	// Compares the file against the schema, aggregation method and xFilesFactor
+	// it would be created with today
+	pub fn matches_schema(&self, schema: &Schema, aggregation_type: AggregationType, x_files_factor: f32) -> SchemaDiff {
+		let policies = schema.canonical_policies();
+		let mut archives : Vec<(u32, u32)> = self.archives.iter().map(|archive| (archive.seconds_per_point(), archive.retention())).collect();
+		archives.sort();
+
+		let differs = |ours: Vec<u32>, theirs: Vec<u32>| if ours == theirs { None } else { Some((ours, theirs)) };
+		SchemaDiff {
+			precisions: differs(archives.iter().map(|archive| archive.0).collect(), policies.iter().map(|policy| policy.precision).collect()),
+			retentions: differs(archives.iter().map(|archive| archive.1).collect(), policies.iter().map(|policy| policy.retention).collect()),
+			aggregation_type: if self.header.aggregation_type == aggregation_type { None } else { Some((self.header.aggregation_type(), aggregation_type)) },
+			x_files_factor: if self.header.x_files_factor == x_files_factor { None } else { Some((self.header.x_files_factor, x_files_factor)) }
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use whisper::{ Schema, WhisperFile, AggregationType, temp_path };
+	use std::fs;
+
+	#[test]
+	fn test_matches_schema() {
+		let path = &temp_path("test_matches_schema.wsp");
+		fs::remove_file(path).ok();
+		let schema = Schema::parse("10s:60s,60s:600s").unwrap();
+		let file = WhisperFile::new_with_options(path, &schema, AggregationType::Sum, 0.5).unwrap();
+
+		let diff = file.matches_schema(&schema, AggregationType::Sum, 0.5);
+		assert!(diff.matches());
+
+		let diff = file.matches_schema(&Schema::parse("60s:600s,10s:60s").unwrap(), AggregationType::Max, 0.0);
+		assert!(!diff.matches());
+		assert!(!diff.needs_resize());
+		assert_eq!(diff.aggregation_type, Some((AggregationType::Sum, AggregationType::Max)));
+		assert_eq!(diff.x_files_factor, Some((0.5, 0.0)));
+
+		let diff = file.matches_schema(&Schema::parse("10s:120s,60s:600s").unwrap(), AggregationType::Sum, 0.5);
+		assert!(diff.needs_resize());
+		assert_eq!(diff.precisions, None);
+		assert_eq!(diff.retentions, Some((vec![60, 600], vec![120, 600])));
+
+		let diff = file.matches_schema(&Schema::parse("10s:60s").unwrap(), AggregationType::Sum, 0.5);
+		assert_eq!(diff.precisions, Some((vec![10, 60], vec![10])));
+	}
+}
@@ -0,0 +1,336 @@
+use std::fs;
+use std::io;
+use std::path::{ Path, PathBuf };
+
+use regex::{ self, Regex };
+
+use whisper::{ PathMapping, PathLayout };
+use whisper::path_mapping::TAGGED_DIRECTORY;
+
+// Something under a data root a metric pattern matched: a directory (branch)
+// or a whisper file (leaf). A directory next to a file of the same name gives
+// one of each.
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct MetricNode {
+	// The dotted path, e.g. `servers.web01.cpu`
+	pub path: String,
+	pub is_leaf: bool
+}
+
+impl MetricNode {
+	// The last node of the path
+	pub fn name(&self) -> &str {
+		self.path.rsplit('.').next().unwrap_or(&self.path)
+	}
+}
+
+// graphite's metric globs, matched node by node so wildcards never cross a
+// dot: `*`, `?`, character classes like `[a-z]` or `[!0-9]`, and alternatives
+// like `{user,system}`. As in graphite-web, wildcards skip hidden entries.
+#[derive(Debug, Clone)]
+pub struct MetricPattern {
+	nodes: Vec<NodePattern>
+}
+
+#[derive(Debug, Clone)]
+enum NodePattern {
+	Exact(String),
+	Glob(Regex)
+}
+
+impl NodePattern {
+	fn parse(node: &str) -> Result<NodePattern, String> {
+		if !node.contains(|c| c == '*' || c == '?' || c == '[' || c == '{') {
+			return Ok(NodePattern::Exact(node.to_string()));
+		}
+
+		let mut expression = String::from("^");
+		let mut alternatives = 0;
+		let mut chars = node.chars().peekable();
+		while let Some(c) = chars.next() {
+			match c {
+				'*' => expression.push_str("[^.]*"),
+				'?' => expression.push_str("[^.]"),
+				'{' => {
+					alternatives = alternatives + 1;
+					expression.push_str("(?:");
+				},
+				',' if alternatives > 0 => expression.push('|'),
+				'}' if alternatives > 0 => {
+					alternatives = alternatives - 1;
+					expression.push(')');
+				},
+				'[' => {
+					let class : String = chars.clone().take_while(|&c| c != ']').collect();
+					let class_length = class.chars().count();
+					if class_length == 0 || class_length == chars.clone().count() {
+						// No closing bracket, so a plain one
+						expression.push_str(&regex::escape("["));
+						continue;
+					}
+					for _ in 0..class_length + 1 {
+						chars.next();
+					}
+					let (negated, class) = if class.starts_with('!') { (true, &class[1..]) } else { (false, &class[..]) };
+					expression.push('[');
+					if negated {
+						expression.push('^');
+					}
+					expression.push_str(&class.replace('\\', "\\\\").replace('[', "\\[").replace('^', "\\^").replace('&', "\\&").replace('~', "\\~"));
+					expression.push(']');
+				},
+				c => expression.push_str(&regex::escape(&c.to_string()))
+			}
+		}
+		if alternatives > 0 {
+			return Err(format!("unclosed `{{` in `{}`", node));
+		}
+		expression.push('$');
+
+		Regex::new(&expression).map(NodePattern::Glob).map_err(|err| format!("invalid pattern `{}`: {}", node, err))
+	}
+
+	fn matches(&self, name: &str) -> bool {
+		match *self {
+			NodePattern::Exact(ref exact) => exact == name,
+			NodePattern::Glob(ref glob) => !name.starts_with('.') && glob.is_match(name)
+		}
+	}
+}
+
+impl MetricPattern {
+	pub fn parse(pattern: &str) -> Result<MetricPattern, String> {
+		if pattern.is_empty() {
+			return Err("empty metric pattern".to_string());
+		}
+		let mut nodes = vec![];
+		for node in pattern.split('.') {
+			// A slash would let the lookup leave the data root
+			if node.is_empty() || node.contains('/') || node.contains('\0') {
+				return Err(format!("invalid metric pattern `{}`", pattern));
+			}
+			nodes.push(try!( NodePattern::parse(node) ));
+		}
+		Ok(MetricPattern { nodes: nodes })
+	}
+
+	pub fn matches(&self, metric_name: &str) -> bool {
+		let names : Vec<&str> = metric_name.split('.').collect();
+		names.len() == self.nodes.len() && self.nodes.iter().zip(names).all(|(node, name)| node.matches(name))
+	}
+
+	// How many nodes a metric needs to match
+	pub fn depth(&self) -> usize {
+		self.nodes.len()
+	}
+
+	// Whether node `index` of a metric can be `name`
+	pub fn matches_node(&self, index: usize, name: &str) -> bool {
+		self.nodes[index].matches(name)
+	}
+
+	// The only name node `index` can have, None for a glob
+	pub fn exact_node(&self, index: usize) -> Option<&str> {
+		match self.nodes[index] {
+			NodePattern::Exact(ref name) => Some(name),
+			NodePattern::Glob(_) => None
+		}
+	}
+
+	// Everything under `data_root`, laid out the way WhisperCache writes files,
+	// that matches. Sorted by path, leaves after branches of the same name.
+	pub fn find<P>(&self, data_root: P) -> io::Result<Vec<MetricNode>>
+		where P: AsRef<Path> {
+		self.find_mapped(data_root, &PathMapping::default())
+	}
+
+	// Same as find, under a data root written with `mapping`. Nodes are
+	// matched as the names the files stand for.
+	pub fn find_mapped<P>(&self, data_root: P, mapping: &PathMapping) -> io::Result<Vec<MetricNode>>
+		where P: AsRef<Path> {
+		if let PathLayout::Hashed(_) = mapping.layout {
+			return self.find_hashed(data_root.as_ref(), mapping);
+		}
+
+		// (directory, dotted path) pairs matching the nodes so far
+		let mut branches = vec![(data_root.as_ref().to_path_buf(), String::new())];
+		let mut found = vec![];
+
+		for (index, node) in self.nodes.iter().enumerate() {
+			let last = index == self.nodes.len() - 1;
+			let mut next_branches = vec![];
+			for &(ref dir, ref path) in &branches {
+				for (child_path, name, is_leaf) in try!( children(dir, node, mapping, index == 0) ) {
+					let metric_path = if path.is_empty() { name } else { format!("{}.{}", path, name) };
+					if is_leaf {
+						if last {
+							found.push(MetricNode { path: metric_path, is_leaf: true });
+						}
+					} else if last {
+						found.push(MetricNode { path: metric_path, is_leaf: false });
+					} else {
+						next_branches.push((child_path, metric_path));
+					}
+				}
+			}
+			branches = next_branches;
+		}
+
+		found.sort();
+		found.dedup();
+		Ok(found)
+	}
+
+	// Every metric there is matched, its first nodes as a branch when it has more
+	fn find_hashed(&self, data_root: &Path, mapping: &PathMapping) -> io::Result<Vec<MetricNode>> {
+		let mut found = vec![];
+		for (_, metric_name) in try!( hashed_metrics(data_root, mapping) ) {
+			let names : Vec<&str> = metric_name.split('.').collect();
+			if names.len() < self.nodes.len() || !self.nodes.iter().zip(&names).all(|(node, name)| node.matches(name)) {
+				continue;
+			}
+			found.push(MetricNode { path: names[..self.nodes.len()].join("."), is_leaf: names.len() == self.nodes.len() });
+		}
+		found.sort();
+		found.dedup();
+		Ok(found)
+	}
+}
+
+// The files under a data root in PathLayout::Hashed with the metrics they hold,
+// in no particular order. Tagged series are left out.
+pub fn hashed_metrics(data_root: &Path, mapping: &PathMapping) -> io::Result<Vec<(PathBuf, String)>> {
+	let levels = match mapping.layout {
+		PathLayout::Hashed(levels) => levels,
+		PathLayout::Hierarchy => 0
+	};
+	let mut directories = vec![(data_root.to_path_buf(), 0)];
+	let mut metrics = vec![];
+	while let Some((directory, level)) = directories.pop() {
+		let entries = match fs::read_dir(&directory) {
+			Ok(entries) => entries,
+			Err(ref err) if err.kind() == io::ErrorKind::NotFound && level == 0 => return Ok(metrics),
+			Err(err) => return Err(err)
+		};
+		for entry in entries {
+			let path = try!( entry ).path();
+			if level == 0 && path.file_name().map_or(false, |file_name| file_name == TAGGED_DIRECTORY) {
+				continue;
+			}
+			if level < levels {
+				if path.is_dir() {
+					directories.push((path, level + 1));
+				}
+			} else if let Some(metric_name) = mapping.metric_for(path.strip_prefix(data_root).unwrap()) {
+				metrics.push((path, metric_name));
+			}
+		}
+	}
+	Ok(metrics)
+}
+
+// (path, node name, is a leaf) of what's in `dir` matching `node`, `top` for
+// the data root. A missing directory has no children.
+fn children(dir: &Path, node: &NodePattern, mapping: &PathMapping, top: bool) -> io::Result<Vec<(PathBuf, String, bool)>> {
+	if let NodePattern::Exact(ref name) = *node {
+		let mut children = vec![];
+		let file_name = match mapping.file_name_for(name, top) {
+			Some(file_name) => file_name,
+			None => return Ok(children)
+		};
+		let branch = dir.join(&file_name);
+		if branch.is_dir() {
+			children.push((branch, name.clone(), false));
+		}
+		let leaf = dir.join(format!("{}.wsp", file_name));
+		if leaf.is_file() {
+			children.push((leaf, name.clone(), true));
+		}
+		return Ok(children);
+	}
+
+	let entries = match fs::read_dir(dir) {
+		Ok(entries) => entries,
+		Err(ref err) if err.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+		Err(err) => return Err(err)
+	};
+	let mut children = vec![];
+	for entry in entries {
+		let entry = try!( entry );
+		let file_name = entry.file_name().to_string_lossy().into_owned();
+		let file_type = try!( entry.file_type() );
+		// Symlinks are followed, graphite's data roots are often stitched together with them
+		let is_dir = file_type.is_dir() || file_type.is_symlink() && entry.path().is_dir();
+		let (file_name, is_leaf) = if is_dir {
+			(&file_name[..], false)
+		} else if file_name.ends_with(".wsp") {
+			(&file_name[..file_name.len() - 4], true)
+		} else {
+			continue;
+		};
+		let name = match mapping.node_for(file_name) {
+			Some(name) => name,
+			None => continue
+		};
+		if node.matches(&name) {
+			children.push((entry.path(), name, is_leaf));
+		}
+	}
+	Ok(children)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use whisper::temp_path;
+	use std::fs::File;
+
+	fn node(path: &str, is_leaf: bool) -> MetricNode {
+		MetricNode { path: path.to_string(), is_leaf: is_leaf }
+	}
+
+	#[test]
+	fn test_matches() {
+		let pattern = MetricPattern::parse("servers.*.cpu.{user,sys*}").unwrap();
+		assert!(pattern.matches("servers.web01.cpu.user"));
+		assert!(pattern.matches("servers.db.cpu.system"));
+		assert!(!pattern.matches("servers.web01.cpu.idle"));
+		assert!(!pattern.matches("servers.web01.cpu"));
+		assert!(!pattern.matches("servers.web.01.cpu.user"));
+
+		let pattern = MetricPattern::parse("web0[1-3].disk[!a].a+b?").unwrap();
+		assert!(pattern.matches("web02.diskb.a+bc"));
+		assert!(!pattern.matches("web04.diskb.a+bc"));
+		assert!(!pattern.matches("web02.diska.a+bc"));
+		assert!(!pattern.matches("web02.diskb.aabc"));
+		assert!(MetricPattern::parse("a[.b").unwrap().matches("a[.b"));
+		assert!(!MetricPattern::parse("*").unwrap().matches(".hidden"));
+
+		assert!(MetricPattern::parse("").is_err());
+		assert!(MetricPattern::parse("a..b").is_err());
+		assert!(MetricPattern::parse("a.{b,c").is_err());
+		assert!(MetricPattern::parse("../etc").is_err());
+	}
+
+	#[test]
+	fn test_find() {
+		let data_root = PathBuf::from(temp_path("test_find_metrics"));
+		fs::remove_dir_all(&data_root).ok();
+		for dir in &["servers/web01/cpu", "servers/web02/cpu", "servers/db/cpu", "servers/web01/.git"] {
+			fs::create_dir_all(data_root.join(dir)).unwrap();
+		}
+		for file in &["servers/web01/cpu/user.wsp", "servers/web01/cpu/system.wsp", "servers/web01/cpu/idle.wsp", "servers/web02/cpu/user.wsp", "servers/web01.wsp", "servers/notes.txt"] {
+			File::create(data_root.join(file)).unwrap();
+		}
+
+		assert_eq!(MetricPattern::parse("*").unwrap().find(&data_root).unwrap(), vec![node("servers", false)]);
+		assert_eq!(MetricPattern::parse("servers.*").unwrap().find(&data_root).unwrap(),
+			vec![node("servers.db", false), node("servers.web01", false), node("servers.web01", true), node("servers.web02", false)]);
+		assert_eq!(MetricPattern::parse("servers.web*.cpu.{user,system}").unwrap().find(&data_root).unwrap(),
+			vec![node("servers.web01.cpu.system", true), node("servers.web01.cpu.user", true), node("servers.web02.cpu.user", true)]);
+		assert_eq!(MetricPattern::parse("servers.web01.cpu.idle").unwrap().find(&data_root).unwrap(), vec![node("servers.web01.cpu.idle", true)]);
+		assert_eq!(MetricPattern::parse("servers.nothing.*").unwrap().find(&data_root).unwrap(), vec![]);
+		assert_eq!(node("servers.web01.cpu", false).name(), "cpu");
+	}
+}
@@ -0,0 +1,317 @@
+use std::str;
+
+use whisper::TimeSeries;
+
+// Arrays and objects in arrays and objects, as deep as serde_json goes. Each
+// level is a frame of the parser's stack, so a body of nothing but `[` would
+// otherwise overflow it.
+const MAX_DEPTH : usize = 128;
+
+// A parsed JSON document. Numbers are f64, the way JavaScript reads them.
+#[derive(Debug, PartialEq, Clone)]
+pub enum JsonValue {
+	Null,
+	Bool(bool),
+	Number(f64),
+	String(String),
+	Array(Vec<JsonValue>),
+	Object(Vec<(String, JsonValue)>)
+}
+
+impl JsonValue {
+	pub fn parse(text: &str) -> Result<JsonValue, String> {
+		let mut parser = Parser { text: text.as_bytes(), index: 0, depth: 0 };
+		let value = try!( parser.value() );
+		parser.skip_whitespace();
+		if parser.index != parser.text.len() {
+			return Err(parser.error("trailing characters"));
+		}
+		Ok(value)
+	}
+
+	pub fn get(&self, key: &str) -> Option<&JsonValue> {
+		match *self {
+			JsonValue::Object(ref members) => members.iter().find(|member| member.0 == key).map(|member| &member.1),
+			_ => None
+		}
+	}
+}
+
+struct Parser<'a> {
+	text: &'a [u8],
+	index: usize,
+	depth: usize
+}
+
+impl<'a> Parser<'a> {
+	fn value(&mut self) -> Result<JsonValue, String> {
+		self.skip_whitespace();
+		match self.text.get(self.index) {
+			Some(&b'{') | Some(&b'[') => {
+				if self.depth == MAX_DEPTH {
+					return Err(self.error("nested too deep"));
+				}
+				self.depth = self.depth + 1;
+				let value = if self.text[self.index] == b'{' { self.object() } else { self.array() };
+				self.depth = self.depth - 1;
+				value
+			},
+			Some(&b'"') => self.string().map(JsonValue::String),
+			Some(&b't') => self.literal("true", JsonValue::Bool(true)),
+			Some(&b'f') => self.literal("false", JsonValue::Bool(false)),
+			Some(&b'n') => self.literal("null", JsonValue::Null),
+			Some(_) => self.number(),
+			None => Err(self.error("unexpected end"))
+		}
+	}
+
+	fn object(&mut self) -> Result<JsonValue, String> {
+		self.index = self.index + 1;
+		let mut members = vec![];
+		self.skip_whitespace();
+		if self.text.get(self.index) == Some(&b'}') {
+			self.index = self.index + 1;
+			return Ok(JsonValue::Object(members));
+		}
+		loop {
+			self.skip_whitespace();
+			if self.text.get(self.index) != Some(&b'"') {
+				return Err(self.error("expected a key"));
+			}
+			let key = try!( self.string() );
+			try!( self.expect(b':') );
+			members.push((key, try!( self.value() )));
+			self.skip_whitespace();
+			match self.text.get(self.index) {
+				Some(&b',') => self.index = self.index + 1,
+				Some(&b'}') => {
+					self.index = self.index + 1;
+					return Ok(JsonValue::Object(members));
+				},
+				_ => return Err(self.error("expected `,` or `}`"))
+			}
+		}
+	}
+
+	fn array(&mut self) -> Result<JsonValue, String> {
+		self.index = self.index + 1;
+		let mut elements = vec![];
+		self.skip_whitespace();
+		if self.text.get(self.index) == Some(&b']') {
+			self.index = self.index + 1;
+			return Ok(JsonValue::Array(elements));
+		}
+		loop {
+			elements.push(try!( self.value() ));
+			self.skip_whitespace();
+			match self.text.get(self.index) {
+				Some(&b',') => self.index = self.index + 1,
+				Some(&b']') => {
+					self.index = self.index + 1;
+					return Ok(JsonValue::Array(elements));
+				},
+				_ => return Err(self.error("expected `,` or `]`"))
+			}
+		}
+	}
+
+	fn string(&mut self) -> Result<String, String> {
+		self.index = self.index + 1;
+		let mut string = String::new();
+		loop {
+			let start = self.index;
+			while self.index < self.text.len() && self.text[self.index] != b'"' && self.text[self.index] != b'\\' {
+				self.index = self.index + 1;
+			}
+			string.push_str(try!( str::from_utf8(&self.text[start..self.index]).map_err(|_| self.error("invalid utf8")) ));
+			match self.text.get(self.index) {
+				Some(&b'"') => {
+					self.index = self.index + 1;
+					return Ok(string);
+				},
+				Some(_) => {
+					let escaped = try!( self.text.get(self.index + 1).cloned().ok_or_else(|| self.error("unexpected end")) );
+					self.index = self.index + 2;
+					match escaped {
+						b'"' => string.push('"'),
+						b'\\' => string.push('\\'),
+						b'/' => string.push('/'),
+						b'b' => string.push('\u{8}'),
+						b'f' => string.push('\u{c}'),
+						b'n' => string.push('\n'),
+						b'r' => string.push('\r'),
+						b't' => string.push('\t'),
+						b'u' => {
+							let code = try!( self.text.get(self.index..self.index + 4)
+								.and_then(|hex| str::from_utf8(hex).ok())
+								.and_then(|hex| u32::from_str_radix(hex, 16).ok())
+								.ok_or_else(|| self.error("invalid unicode escape")) );
+							self.index = self.index + 4;
+							// Surrogate pairs aren't put back together
+							string.push(::std::char::from_u32(code).unwrap_or('\u{fffd}'));
+						},
+						_ => return Err(self.error("invalid escape"))
+					}
+				},
+				None => return Err(self.error("unterminated string"))
+			}
+		}
+	}
+
+	fn number(&mut self) -> Result<JsonValue, String> {
+		let start = self.index;
+		while self.text.get(self.index).map_or(false, |&byte| (byte as char).is_digit(10) || b"+-.eE".contains(&byte)) {
+			self.index = self.index + 1;
+		}
+		str::from_utf8(&self.text[start..self.index]).ok()
+			.and_then(|number| number.parse().ok())
+			.map(JsonValue::Number)
+			.ok_or_else(|| self.error("invalid value"))
+	}
+
+	fn literal(&mut self, literal: &str, value: JsonValue) -> Result<JsonValue, String> {
+		if self.text[self.index..].starts_with(literal.as_bytes()) {
+			self.index = self.index + literal.len();
+			Ok(value)
+		} else {
+			Err(self.error("invalid value"))
+		}
+	}
+
+	fn expect(&mut self, byte: u8) -> Result<(), String> {
+		self.skip_whitespace();
+		if self.text.get(self.index) == Some(&byte) {
+			self.index = self.index + 1;
+			Ok(())
+		} else {
+			Err(self.error(&format!("expected `{}`", byte as char)))
+		}
+	}
+
+	fn skip_whitespace(&mut self) {
+		while self.text.get(self.index).map_or(false, |&byte| byte == b' ' || byte == b'\t' || byte == b'\n' || byte == b'\r') {
+			self.index = self.index + 1;
+		}
+	}
+
+	fn error(&self, reason: &str) -> String {
+		format!("invalid json at byte {}: {}", self.index, reason)
+	}
+}
+
+// Quotes and escapes `value` as a JSON string
+pub fn json_string(value: &str) -> String {
+	let mut json = String::with_capacity(value.len() + 2);
+	json.push('"');
+	for c in value.chars() {
+		match c {
+			'"' => json.push_str("\\\""),
+			'\\' => json.push_str("\\\\"),
+			'\n' => json.push_str("\\n"),
+			'\r' => json.push_str("\\r"),
+			'\t' => json.push_str("\\t"),
+			c if (c as u32) < 0x20 => json.push_str(&format!("\\u{:04x}", c as u32)),
+			c => json.push(c)
+		}
+	}
+	json.push('"');
+	json
+}
+
+// The JSON graphite-web's /render answers format=json with: per target a list
+// of [value, timestamp] pairs, null where nothing was written or the value
+// isn't finite. Byte for byte what it emits, so its consumers can read it.
+pub fn render_json(results: &[(String, TimeSeries)]) -> String {
+	let mut json = String::from("[");
+	for (index, &(ref target, ref series)) in results.iter().enumerate() {
+		if index > 0 {
+			json.push_str(", ");
+		}
+		let target = json_string(target);
+		json.push_str(&format!("{{\"target\": {}, \"tags\": {{\"name\": {}}}, \"datapoints\": [", target, target));
+		for (point_index, (timestamp, value)) in series.iter().enumerate() {
+			if point_index > 0 {
+				json.push_str(", ");
+			}
+			match value {
+				Some(value) if value.is_finite() => json.push_str(&format!("[{}, {}]", python_float(value), timestamp)),
+				_ => json.push_str(&format!("[null, {}]", timestamp))
+			}
+		}
+		json.push_str("]}");
+	}
+	json.push(']');
+	json
+}
+
+// A float the way Python's repr and json.dumps write it. Rust picks the same
+// digits and switches to an exponent at the same magnitudes, but writes it
+// `1e16` and `1e-5` where Python writes `1e+16` and `1e-05`.
+fn python_float(value: f64) -> String {
+	let formatted = format!("{:?}", value);
+	match formatted.find('e') {
+		Some(index) => {
+			let exponent : i32 = formatted[index+1..].parse().unwrap();
+			format!("{}e{}{:02}", &formatted[..index], if exponent < 0 { '-' } else { '+' }, exponent.abs())
+		},
+		None => formatted
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_render_json() {
+		let series = TimeSeries { from: 100, until: 130, step: 10, values: vec![Some(1.0), None, Some(2.5)] };
+		assert_eq!(render_json(&[("a.\"b\"".to_string(), series)]),
+			"[{\"target\": \"a.\\\"b\\\"\", \"tags\": {\"name\": \"a.\\\"b\\\"\"}, \"datapoints\": [[1.0, 100], [null, 110], [2.5, 120]]}]");
+		assert_eq!(render_json(&[]), "[]");
+	}
+
+	#[test]
+	fn test_python_float() {
+		assert_eq!(python_float(3.0), "3.0");
+		assert_eq!(python_float(-0.25), "-0.25");
+		assert_eq!(python_float(0.0001), "0.0001");
+		assert_eq!(python_float(0.00001), "1e-05");
+		assert_eq!(python_float(1.5e300), "1.5e+300");
+		assert_eq!(python_float(1e16), "1e+16");
+		assert_eq!(python_float(1234567890123456.0), "1234567890123456.0");
+	}
+
+	#[test]
+	fn test_parse() {
+		let value = JsonValue::parse(" [{\"target\": \"a\\\"b\\u00e9\", \"datapoints\": [[1.5, 100], [null, 110], [-2e3, 120]], \"ok\": true}, []] ").unwrap();
+		assert_eq!(value, JsonValue::Array(vec![
+			JsonValue::Object(vec![
+				("target".to_string(), JsonValue::String("a\"b\u{e9}".to_string())),
+				("datapoints".to_string(), JsonValue::Array(vec![
+					JsonValue::Array(vec![JsonValue::Number(1.5), JsonValue::Number(100.0)]),
+					JsonValue::Array(vec![JsonValue::Null, JsonValue::Number(110.0)]),
+					JsonValue::Array(vec![JsonValue::Number(-2000.0), JsonValue::Number(120.0)])
+				])),
+				("ok".to_string(), JsonValue::Bool(true))
+			]),
+			JsonValue::Array(vec![])
+		]));
+		if let JsonValue::Array(ref elements) = value {
+			assert_eq!(elements[0].get("ok"), Some(&JsonValue::Bool(true)));
+		}
+
+		for invalid in &["", "[1,", "[1 2]", "{\"a\" 1}", "nul", "\"abc", "[1] 2", "{1: 2}"] {
+			assert!(JsonValue::parse(invalid).is_err(), "{}", invalid);
+		}
+
+		let nested = |depth: usize| format!("{}{}", "[".repeat(depth), "]".repeat(depth));
+		assert!(JsonValue::parse(&nested(MAX_DEPTH)).is_ok());
+		assert!(JsonValue::parse(&nested(MAX_DEPTH + 1)).is_err());
+		assert!(JsonValue::parse(&"[{\"a\":".repeat(100000)).is_err());
+	}
+
+	#[test]
+	fn test_json_string() {
+		assert_eq!(json_string("a\tb\u{1}"), "\"a\\tb\\u0001\"");
+	}
+}
@@ -1,9 +1,55 @@
+mod error;
 mod file;
 mod point;
+mod time_series;
+mod find;
+mod path_mapping;
+mod data_root;
+mod json;
 mod schema;
 mod cache;
+mod tagged;
+mod tree;
+#[cfg(feature = "serde")]
+pub mod config;
 
-pub use self::file::WhisperFile;
+pub use self::error::WhisperError;
+pub use self::file::{ WhisperFile, WhisperBuilder, ArchiveSelection, FuturePolicy, NonFinitePolicy, OverwritePolicy, Allocation, AggregationType, FileInfo, ArchiveInfo, FileStats, ArchiveStats, ArchiveDiff, SchemaDiff, ImportReport, FileDefect };
 pub use self::point::Point;
-pub use self::schema::Schema;
-pub use self::cache::{ WhisperCache, NamedPoint };
+pub use self::time_series::TimeSeries;
+pub use self::find::{ MetricPattern, MetricNode };
+pub use self::path_mapping::{ PathMapping, UnsafeNames, PathLayout };
+pub use self::data_root::DataRoot;
+pub use self::json::{ render_json, JsonValue };
+#[cfg(feature = "http")]
+pub use self::json::json_string;
+pub use self::schema::presets;
+pub use self::schema::{ Schema, SchemaError, SchemaRules, SchemaRule, AggregationRules, AggregationRule, DiskUsagePlan, PrefixUsage, SchemaResolver, StorageRules, RewriteRules, RewriteRule, RewriteStage, RegexList, MetricFilter, FilterStats };
+pub use self::tagged::{ TaggedSeries, TagIndex, TagExpression };
+pub use self::tree::{ WhisperTree, TreeEntry, Walk, DeleteReport, DiskUsage, SubtreeUsage, StalePolicy, StaleAction, StaleMetric, StaleReport, AuditReport, FileAudit, ProvisionProgress, ProvisionReport, MetricIndex };
+#[cfg(all(feature = "watch", target_os = "linux"))]
+pub use self::tree::{ TreeWatcher, WatchStats };
+pub use self::cache::{ WhisperCache, NamedPoint, CacheStats, CacheMetrics, WriteBack, Flusher, IngestQueue, CacheWriter, QueueError, QueryCaching, Replication, Replica, ReplicaProtocol, ReplicaConfig, ReplicaStats, read_framed };
+#[cfg(feature = "tokio")]
+pub use self::cache::{ AsyncWhisperCache, Blocking };
+
+// Seconds since the epoch, what ages and intervals count from
+pub fn current_time() -> u32 {
+	::std::time::SystemTime::now().duration_since(::std::time::UNIX_EPOCH).map(|elapsed| elapsed.as_secs() as u32).unwrap_or(0)
+}
+
+// Tests write their files to the platform's temporary directory
+#[cfg(test)]
+pub fn temp_path(name: &str) -> String {
+	::std::env::temp_dir().join(name).to_string_lossy().into_owned()
+}
+
+// A cache of 10s:60s files under an emptied temp_path, and the start of the
+// interval now falls in
+#[cfg(test)]
+pub fn temp_cache(name: &str) -> (WhisperCache, u32) {
+	let base_path = temp_path(name);
+	::std::fs::remove_dir_all(&base_path).ok();
+	let now = current_time();
+	(WhisperCache::new(&base_path, 10, Schema::parse("10s:60s").unwrap()), now - now % 10)
+}
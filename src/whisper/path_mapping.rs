@@ -0,0 +1,337 @@
+use std::cmp;
+use std::path::{ Component, Path, PathBuf };
+
+use whisper::TaggedSeries;
+use whisper::tagged::sha256;
+use whisper::error::{ Result, WhisperError };
+
+// Where tagged series are kept, by digest rather than name
+pub const TAGGED_DIRECTORY : &'static str = "_tagged";
+
+// What a PathMapping does with names no path can be made from as they are
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum UnsafeNames {
+	// Refused with WhisperError::InvalidMetricName
+	Reject,
+	// Empty nodes are dropped, the way carbon-c-relay cleans up `a..b`. `%`, `/`
+	// and NUL are percent-escaped, and so is the `_` of a first node of `_tagged`.
+	Escape
+}
+
+// How the files of a data root are arranged
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum PathLayout {
+	// A directory per node and a `.wsp` file for the last one, carbon's
+	Hierarchy,
+	// A file per metric named by all of its nodes, `servers.web01.load.wsp`,
+	// under as many directories as given, up to 32, each named by the next two
+	// hex digits of the SHA-256 of the name: `ab/cd/`. No directory fills up
+	// however flat the names are, but branches have no directory of their own
+	// and finding anything reads them all.
+	Hashed(usize)
+}
+
+// How metric names map to files under a data root, by its PathLayout, tagged
+// series by digest under `_tagged` in both. Names too long for the filesystem
+// are refused either way. WhisperCache, WhisperTree and
+// MetricPattern::find_mapped all take one, and a tree has to be read with the
+// mapping it was written with: DataRoot::find, which HttpServer and
+// RemoteRead go through, uses the one of the cache it's taken from.
+#[derive(Debug, PartialEq, Clone)]
+pub struct PathMapping {
+	pub layout: PathLayout,
+	pub unsafe_names: UnsafeNames,
+	// In bytes, every node held to it along with `.wsp`
+	pub max_file_name_length: usize,
+	// In bytes, of the path relative to the data root
+	pub max_path_length: usize
+}
+
+impl Default for PathMapping {
+	fn default() -> PathMapping {
+		PathMapping {
+			layout: PathLayout::Hierarchy,
+			unsafe_names: UnsafeNames::Reject,
+			max_file_name_length: 255,
+			max_path_length: 4095
+		}
+	}
+}
+
+impl PathMapping {
+	pub fn escaping() -> PathMapping {
+		PathMapping { unsafe_names: UnsafeNames::Escape, .. PathMapping::default() }
+	}
+
+	// PathLayout::Hashed two directories deep, 65536 of them
+	pub fn hashed() -> PathMapping {
+		PathMapping { layout: PathLayout::Hashed(2), .. PathMapping::default() }
+	}
+
+	// Where the file of `metric_name` is, relative to the data root
+	pub fn rel_path(&self, metric_name: &str) -> Result<PathBuf> {
+		if TaggedSeries::is_tagged(metric_name) {
+			return TaggedSeries::parse(metric_name)
+				.map(|series| series.rel_path())
+				.map_err(|reason| WhisperError::InvalidMetricName(metric_name.to_string(), reason));
+		}
+		let mut file_names = try!( self.file_names(metric_name) );
+		match self.layout {
+			PathLayout::Hierarchy => {
+				let leaf = format!("{}.wsp", file_names.pop().unwrap());
+				let mut rel_path : PathBuf = file_names.iter().collect();
+				rel_path.push(leaf);
+				Ok(rel_path)
+			},
+			PathLayout::Hashed(levels) => {
+				let leaf = format!("{}.wsp", file_names.join("."));
+				if leaf.len() > self.max_file_name_length {
+					let reason = format!("file name of {} bytes is longer than {}", leaf.len(), self.max_file_name_length);
+					return Err(WhisperError::InvalidMetricName(metric_name.to_string(), reason));
+				}
+				let mut rel_path = hashed_directory(&try!( self.normalize(metric_name) ), levels);
+				rel_path.push(leaf);
+				Ok(rel_path)
+			}
+		}
+	}
+
+	// The directory of the branch `prefix`, relative to the data root. Empty
+	// for PathLayout::Hashed, where every branch is the whole root.
+	pub fn rel_dir(&self, prefix: &str) -> Result<PathBuf> {
+		let file_names = try!( self.file_names(prefix) );
+		match self.layout {
+			PathLayout::Hierarchy => Ok(file_names.iter().collect()),
+			PathLayout::Hashed(_) => Ok(PathBuf::new())
+		}
+	}
+
+	// `metric_name` the way it reads back from its path, `a.b` for `a..b` when
+	// empty nodes are dropped
+	pub fn normalize(&self, metric_name: &str) -> Result<String> {
+		try!( self.file_names(metric_name) );
+		Ok(metric_name.split('.').filter(|node| !node.is_empty()).collect::<Vec<&str>>().join("."))
+	}
+
+	// The file name of each node, extension aside, checked against the limits
+	pub fn file_names(&self, metric_name: &str) -> Result<Vec<String>> {
+		let invalid = |reason: String| WhisperError::InvalidMetricName(metric_name.to_string(), reason);
+		let mut file_names = vec![];
+		for node in metric_name.split('.') {
+			match try!( self.check_node(node, file_names.is_empty()).map_err(&invalid) ) {
+				Some(file_name) => file_names.push(file_name),
+				None => continue
+			}
+		}
+		if file_names.is_empty() {
+			return Err(invalid("no nodes".to_string()));
+		}
+		// The separators and extension included
+		let length = file_names.iter().map(|file_name| file_name.len() + 1).sum::<usize>() + 3;
+		if length > self.max_path_length {
+			return Err(invalid(format!("path of {} bytes is longer than {}", length, self.max_path_length)));
+		}
+		Ok(file_names)
+	}
+
+	// The file name of a single node, `top` for the first. None when it can't
+	// have one. The nodes of PathLayout::Hashed are joined by dots.
+	pub fn file_name_for(&self, node: &str, top: bool) -> Option<String> {
+		self.check_node(node, top).ok().and_then(|file_name| file_name)
+	}
+
+	// The node a file name, extension aside, stands for. None when no name maps
+	// to it, hidden and dotted names included.
+	pub fn node_for(&self, file_name: &str) -> Option<String> {
+		if file_name.is_empty() || file_name.contains('.') {
+			return None;
+		}
+		match self.unsafe_names {
+			UnsafeNames::Reject => Some(file_name.to_string()),
+			UnsafeNames::Escape => unescape(file_name)
+		}
+	}
+
+	// The metric whose file is at `rel_path`, None for anything else. Tagged
+	// series are left out, their names can't be told from their paths.
+	pub fn metric_for<P>(&self, rel_path: P) -> Option<String>
+		where P: AsRef<Path> {
+		let mut file_names = vec![];
+		for component in rel_path.as_ref().components() {
+			match component {
+				Component::Normal(file_name) => match file_name.to_str() {
+					Some(file_name) => file_names.push(file_name),
+					None => return None
+				},
+				_ => return None
+			}
+		}
+
+		match file_names.pop() {
+			Some(leaf) if leaf.ends_with(".wsp") => file_names.push(&leaf[..leaf.len() - 4]),
+			_ => return None
+		}
+		if file_names[0] == TAGGED_DIRECTORY {
+			return None;
+		}
+		match self.layout {
+			PathLayout::Hierarchy => {
+				let nodes : Option<Vec<String>> = file_names.iter().map(|file_name| self.node_for(file_name)).collect();
+				nodes.map(|nodes| nodes.join("."))
+			},
+			PathLayout::Hashed(levels) => {
+				if file_names.len() != levels + 1 {
+					return None;
+				}
+				let nodes : Option<Vec<String>> = file_names[levels].split('.').map(|file_name| self.node_for(file_name)).collect();
+				// Not where a file of that name would be
+				nodes.map(|nodes| nodes.join(".")).filter(|metric_name| hashed_directory(metric_name, levels) == file_names[..levels].iter().collect::<PathBuf>())
+			}
+		}
+	}
+
+	// Ok(None) for a node that's dropped
+	fn check_node(&self, node: &str, top: bool) -> ::std::result::Result<Option<String>, String> {
+		let file_name = match self.unsafe_names {
+			UnsafeNames::Reject => {
+				if node.is_empty() {
+					return Err("empty node".to_string());
+				}
+				if node.contains('/') || node.contains('\0') {
+					return Err(format!("node `{}` holds a path separator or NUL", node.escape_default()));
+				}
+				if top && node == TAGGED_DIRECTORY {
+					return Err(format!("`{}` is where tagged series go", TAGGED_DIRECTORY));
+				}
+				node.to_string()
+			},
+			UnsafeNames::Escape if node.is_empty() => return Ok(None),
+			UnsafeNames::Escape => escape(node, top)
+		};
+		if file_name.len() + 4 > self.max_file_name_length {
+			return Err(format!("node of {} bytes is longer than {}", file_name.len(), self.max_file_name_length - 4));
+		}
+		Ok(Some(file_name))
+	}
+}
+
+fn hashed_directory(metric_name: &str, levels: usize) -> PathBuf {
+	let digest = sha256::hex_digest(metric_name.as_bytes());
+	(0..cmp::min(levels, 32)).map(|level| &digest[2 * level..2 * level + 2]).collect()
+}
+
+fn escape(node: &str, top: bool) -> String {
+	if top && node == TAGGED_DIRECTORY {
+		return format!("%5F{}", &TAGGED_DIRECTORY[1..]);
+	}
+	let mut file_name = String::with_capacity(node.len());
+	for c in node.chars() {
+		match c {
+			'%' => file_name.push_str("%25"),
+			'/' => file_name.push_str("%2F"),
+			'\0' => file_name.push_str("%00"),
+			_ => file_name.push(c)
+		}
+	}
+	file_name
+}
+
+fn unescape(file_name: &str) -> Option<String> {
+	let mut bytes = Vec::with_capacity(file_name.len());
+	let mut rest = file_name.as_bytes();
+	while let Some((&byte, tail)) = rest.split_first() {
+		if byte == b'%' {
+			let hex = match tail.get(..2) {
+				Some(hex) if hex.iter().all(|digit| digit.is_ascii_hexdigit()) => ::std::str::from_utf8(hex).unwrap(),
+				_ => return None
+			};
+			bytes.push(u8::from_str_radix(hex, 16).unwrap());
+			rest = &tail[2..];
+		} else {
+			bytes.push(byte);
+			rest = tail;
+		}
+	}
+	String::from_utf8(bytes).ok()
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_reject() {
+		let mapping = PathMapping::default();
+		assert_eq!(mapping.rel_path("servers.web01.load").unwrap(), PathBuf::from("servers/web01/load.wsp"));
+		assert_eq!(mapping.rel_path("requests.50%").unwrap(), PathBuf::from("requests/50%.wsp"));
+		assert!(mapping.rel_path("disk.used;server=web01").unwrap().starts_with(TAGGED_DIRECTORY));
+		assert!(mapping.rel_path("disk.used;server").is_err());
+
+		for name in &["", "servers..load", ".servers.load", "servers.load.", "servers.../etc/passwd", "a/b.load", "a\0b", "_tagged.x"] {
+			match mapping.rel_path(name) {
+				Err(WhisperError::InvalidMetricName(ref invalid, _)) => assert_eq!(invalid, name),
+				other => panic!("expected `{}` to be invalid, got {:?}", name, other)
+			}
+		}
+		assert!(mapping.rel_path("x._tagged").is_ok());
+
+		let long = "a".repeat(252);
+		assert!(mapping.rel_path(&long).is_err());
+		assert!(mapping.rel_path(&long[1..]).is_ok());
+		let deep = vec!["abcdefgh"; 500].join(".");
+		assert!(mapping.rel_path(&deep).is_err());
+
+		assert_eq!(mapping.metric_for("servers/web01/load.wsp"), Some("servers.web01.load".to_string()));
+		assert_eq!(mapping.metric_for("servers/.git/HEAD.wsp"), None);
+		assert_eq!(mapping.metric_for("servers/web01.old/load.wsp"), None);
+		assert_eq!(mapping.metric_for("servers/notes.txt"), None);
+		assert_eq!(mapping.metric_for("_tagged/e9a/90f/e9a90f.wsp"), None);
+		assert_eq!(mapping.metric_for("../load.wsp"), None);
+	}
+
+	#[test]
+	fn test_escape() {
+		let mapping = PathMapping::escaping();
+		assert_eq!(mapping.rel_path("servers..web01.load.").unwrap(), PathBuf::from("servers/web01/load.wsp"));
+		assert_eq!(mapping.rel_path("servers.../etc/passwd").unwrap(), PathBuf::from("servers/%2Fetc%2Fpasswd.wsp"));
+		assert_eq!(mapping.rel_path("requests.50%").unwrap(), PathBuf::from("requests/50%25.wsp"));
+		assert_eq!(mapping.rel_path("_tagged.x").unwrap(), PathBuf::from("%5Ftagged/x.wsp"));
+		assert_eq!(mapping.rel_dir("a/b.c").unwrap(), PathBuf::from("a%2Fb/c"));
+		assert_eq!(mapping.normalize(".servers..load").unwrap(), "servers.load");
+		assert!(mapping.rel_path("..").is_err());
+		assert!(mapping.rel_path(&"/".repeat(100)).is_err());
+
+		for name in &["servers./etc/passwd", "requests.50%", "_tagged.x", "a\0b.c"] {
+			assert_eq!(mapping.metric_for(mapping.rel_path(name).unwrap()).as_ref().map(|name| name.as_str()), Some(*name));
+		}
+		assert_eq!(mapping.node_for("50%2"), None);
+		assert_eq!(mapping.node_for("%C3%A9t%C3%A9"), Some("été".to_string()));
+		assert_eq!(mapping.node_for("%FF"), None);
+	}
+
+	#[test]
+	fn test_hashed() {
+		let mapping = PathMapping::hashed();
+		let rel_path = mapping.rel_path("servers.web01.load").unwrap();
+		// SHA-256 of `servers.web01.load`
+		let digest = sha256::hex_digest(b"servers.web01.load");
+		assert_eq!(rel_path, PathBuf::from(format!("{}/{}/servers.web01.load.wsp", &digest[0..2], &digest[2..4])));
+		assert_eq!(mapping.metric_for(&rel_path), Some("servers.web01.load".to_string()));
+		assert_eq!(mapping.rel_dir("servers.web01").unwrap(), PathBuf::new());
+		assert!(mapping.rel_path("servers..load").is_err());
+		assert!(mapping.rel_path(&vec!["a"; 127].join(".")).is_err());
+		assert!(mapping.rel_path("disk.used;server=web01").unwrap().starts_with(TAGGED_DIRECTORY));
+		assert!(mapping.rel_path("disk.used;server").is_err());
+
+		// Anywhere but where it hashes to
+		assert_eq!(mapping.metric_for("00/00/servers.web01.load.wsp"), None);
+		assert_eq!(mapping.metric_for(rel_path.parent().unwrap().join(".load.wsp")), None);
+		assert_eq!(mapping.metric_for("servers/web01/load.wsp"), None);
+
+		let mapping = PathMapping { layout: PathLayout::Hashed(1), .. PathMapping::escaping() };
+		let rel_path = mapping.rel_path("requests..a/b").unwrap();
+		assert_eq!(rel_path.file_name().unwrap(), "requests.a%2Fb.wsp");
+		assert_eq!(rel_path.components().count(), 2);
+		assert_eq!(mapping.metric_for(&rel_path), Some("requests.a/b".to_string()));
+	}
+}
@@ -6,7 +6,8 @@ use byteorder::{ ByteOrder, BigEndian, WriteBytesExt };
 
 pub const POINT_SIZE : usize = 12;
 
-#[derive(Debug,PartialEq)]
+#[derive(Debug,PartialEq,Clone,Copy)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Point(pub u32, pub f64);
 
 impl Point {
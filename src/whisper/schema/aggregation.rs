@@ -0,0 +1,150 @@
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+use regex::Regex;
+
+use whisper::AggregationType;
+use whisper::error::Result;
+use whisper::file::DEFAULT_X_FILES_FACTOR;
+use super::SchemaError;
+use super::conf::{ self, Section };
+
+// A [section] of storage-aggregation.conf. Whatever it leaves out is whisper's default.
+#[derive(Debug)]
+pub struct AggregationRule {
+    pub name: String,
+    pub pattern: Regex,
+    pub aggregation_type: Option<AggregationType>,
+    pub x_files_factor: Option<f32>
+}
+
+// Same as carbon's storage-aggregation.conf: the first rule whose pattern matches
+// anywhere in the metric name wins, and metrics no rule matches are averaged with
+// an xFilesFactor of 0.5.
+#[derive(Debug)]
+pub struct AggregationRules {
+    rules: Vec<AggregationRule>
+}
+
+impl AggregationRules {
+    pub fn new(rules: Vec<AggregationRule>) -> AggregationRules {
+        AggregationRules { rules: rules }
+    }
+
+    pub fn open<P>(path: P) -> Result<AggregationRules>
+        where P: AsRef<Path> {
+        let mut contents = String::new();
+        try!(try!(File::open(path)).read_to_string(&mut contents));
+        Ok(try!(AggregationRules::parse(&contents)))
+    }
+
+    pub fn parse(contents: &str) -> ::std::result::Result<AggregationRules, SchemaError> {
+        AggregationRules::from_sections(try!(conf::sections(contents)))
+    }
+
+    pub(crate) fn from_sections(sections: Vec<Section>) -> ::std::result::Result<AggregationRules, SchemaError> {
+        let mut rules = vec![];
+
+        for section in sections {
+            let invalid = |reason: String| SchemaError::InvalidRule(section.name.clone(), reason);
+
+            let pattern = try!(section.get("pattern").ok_or_else(|| invalid("no pattern".to_string())));
+            let pattern = try!(Regex::new(pattern).map_err(|e| invalid(format!("invalid pattern: {}", e))));
+
+            let aggregation_type = match section.get("aggregationmethod") {
+                Some(method) => Some(try!(method.parse::<AggregationType>().map_err(&invalid))),
+                None => None
+            };
+            let x_files_factor = match section.get("xfilesfactor") {
+                Some(xff) => {
+                    let xff = try!(xff.parse::<f32>().map_err(|_| invalid(format!("invalid xFilesFactor {}", xff))));
+                    if !(xff >= 0.0 && xff <= 1.0) {
+                        return Err(invalid(format!("invalid xFilesFactor {}, not between 0 and 1", xff)));
+                    }
+                    Some(xff)
+                },
+                None => None
+            };
+
+            rules.push(AggregationRule {
+                name: section.name.clone(),
+                pattern: pattern,
+                aggregation_type: aggregation_type,
+                x_files_factor: x_files_factor
+            });
+        }
+
+        Ok(AggregationRules::new(rules))
+    }
+
+    pub fn rules(&self) -> &[AggregationRule] {
+        &self.rules
+    }
+
+    pub fn rule_for(&self, metric: &str) -> Option<&AggregationRule> {
+        self.rules.iter().find(|rule| rule.pattern.is_match(metric))
+    }
+
+    pub fn aggregation_for(&self, metric: &str) -> (AggregationType, f32) {
+        match self.rule_for(metric) {
+            Some(rule) => (
+                rule.aggregation_type.clone().unwrap_or(AggregationType::Average),
+                rule.x_files_factor.unwrap_or(DEFAULT_X_FILES_FACTOR)
+            ),
+            None => (AggregationType::Average, DEFAULT_X_FILES_FACTOR)
+        }
+    }
+}
+
+impl Default for AggregationRules {
+    fn default() -> AggregationRules {
+        AggregationRules::new(vec![])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const STORAGE_AGGREGATION : &'static str = "
+[min]
+pattern = \\.min$
+xFilesFactor = 0.1
+aggregationMethod = min
+
+[max]
+pattern = \\.max$
+aggregationMethod = max
+
+[sum]
+pattern = \\.count$
+xFilesFactor = 0
+aggregationMethod = sum
+";
+
+    #[test]
+    fn test_aggregation_for() {
+        let rules = AggregationRules::parse(STORAGE_AGGREGATION).unwrap();
+        assert_eq!(rules.rules().len(), 3);
+
+        assert_eq!(rules.aggregation_for("servers.latency.min"), (AggregationType::Min, 0.1));
+        assert_eq!(rules.aggregation_for("servers.latency.max"), (AggregationType::Max, 0.5));
+        assert_eq!(rules.aggregation_for("servers.requests.count"), (AggregationType::Sum, 0.0));
+        assert_eq!(rules.aggregation_for("servers.load"), (AggregationType::Average, 0.5));
+        assert_eq!(AggregationRules::default().aggregation_for("servers.load"), (AggregationType::Average, 0.5));
+    }
+
+    #[test]
+    fn test_invalid_rules() {
+        let invalid = |contents: &str| match AggregationRules::parse(contents) {
+            Err(SchemaError::InvalidRule(name, _)) => name,
+            other => panic!("unexpected {:?}", other)
+        };
+
+        assert_eq!(invalid("[a]\naggregationMethod = min"), "a");
+        assert_eq!(invalid("[b]\npattern = .*\naggregationMethod = median"), "b");
+        assert_eq!(invalid("[c]\npattern = .*\nxFilesFactor = 1.5"), "c");
+        assert_eq!(invalid("[d]\npattern = .*\nxFilesFactor = half"), "d");
+    }
+}
@@ -0,0 +1,75 @@
+use super::SchemaError;
+
+// One [section] of a carbon config file
+pub struct Section {
+    pub name: String,
+    pub entries: Vec<(String, String)>
+}
+
+impl Section {
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.entries.iter().find(|&&(ref name, _)| name == key).map(|&(_, ref value)| value.as_str())
+    }
+}
+
+// The subset of ConfigParser syntax carbon's storage-*.conf files use:
+// [section] headers, `key = value` or `key: value` entries, and whole line
+// comments starting with # or ;
+pub fn sections(contents: &str) -> Result<Vec<Section>, SchemaError> {
+    let mut sections : Vec<Section> = vec![];
+
+    for (index, line) in contents.lines().enumerate() {
+        let line_number = index + 1;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') || line.starts_with(';') {
+            continue;
+        }
+
+        if line.starts_with('[') {
+            if !line.ends_with(']') || line.len() < 3 {
+                return Err(SchemaError::InvalidConfig(line_number, format!("invalid section header '{}'", line)));
+            }
+            sections.push(Section {
+                name: line[1 .. line.len() - 1].trim().to_string(),
+                entries: vec![]
+            });
+            continue;
+        }
+
+        let separator = match line.find(|c| c == '=' || c == ':') {
+            Some(separator) => separator,
+            None => return Err(SchemaError::InvalidConfig(line_number, format!("expected key = value, got '{}'", line)))
+        };
+        let key = line[.. separator].trim().to_lowercase();
+        let value = line[separator + 1 ..].trim().to_string();
+
+        match sections.last_mut() {
+            Some(section) => section.entries.push((key, value)),
+            None => return Err(SchemaError::InvalidConfig(line_number, format!("'{}' is not in a section", key)))
+        }
+    }
+
+    Ok(sections)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sections() {
+        let contents = "# comment\n[carbon]\npattern = ^carbon\\.\n; another\nretentions: 60:90d\n\n[default]\npattern = .*\n";
+        let sections = sections(contents).unwrap();
+
+        assert_eq!(sections.len(), 2);
+        assert_eq!(sections[0].name, "carbon");
+        assert_eq!(sections[0].get("pattern"), Some("^carbon\\."));
+        assert_eq!(sections[0].get("retentions"), Some("60:90d"));
+        assert_eq!(sections[1].name, "default");
+        assert_eq!(sections[1].get("retentions"), None);
+
+        assert_eq!(super::sections("pattern = .*").err(), Some(SchemaError::InvalidConfig(1, "'pattern' is not in a section".to_string())));
+        assert!(super::sections("[carbon]\nnonsense").is_err());
+        assert!(super::sections("[carbon").is_err());
+    }
+}
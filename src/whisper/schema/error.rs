@@ -0,0 +1,65 @@
+use std::error;
+use std::fmt;
+
+// Why a retention spec or a whole schema was refused. Each variant carries the
+// spec it was found in.
+#[derive(Debug, PartialEq, Clone)]
+pub enum SchemaError {
+    // Not of the form precision:retention, e.g. "10s:8d" or "60:1440"
+    InvalidSpec(String),
+    // (spec, unit)
+    UnknownUnit(String, String),
+    // A precision or retention of zero, or fewer points than one
+    ZeroValue(String),
+    // Doesn't fit in 32 bits of seconds
+    Overflow(String),
+    NoRetentionPolicies,
+    // Two archives with this precision
+    DuplicatePrecision(u32),
+    // (finer precision, coarser precision), the coarser isn't a multiple of the finer
+    UnevenPrecision(u32, u32),
+    // (finer precision, coarser precision), the coarser doesn't retain longer
+    ShorterRetention(u32, u32),
+    // (finer precision, coarser precision), the finer can't hold one point of the coarser
+    TooFewPoints(u32, u32),
+    // (line, reason) a storage-*.conf file can't be read
+    InvalidConfig(usize, String),
+    // (section, reason) a section of a storage-*.conf file is incomplete or malformed
+    InvalidRule(String, String)
+}
+
+impl fmt::Display for SchemaError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            SchemaError::InvalidSpec(ref spec) => write!(f, "invalid retention policy '{}', expected precision:retention", spec),
+            SchemaError::UnknownUnit(ref spec, ref unit) => write!(f, "unknown time unit '{}' in retention policy '{}'", unit, spec),
+            SchemaError::ZeroValue(ref spec) => write!(f, "retention policy '{}' has a zero precision or retention", spec),
+            SchemaError::Overflow(ref spec) => write!(f, "retention policy '{}' is too large", spec),
+            SchemaError::NoRetentionPolicies => write!(f, "no retention policies given"),
+            SchemaError::DuplicatePrecision(precision) => write!(f, "two archives have the same precision of {}s", precision),
+            SchemaError::UnevenPrecision(finer, coarser) => write!(f, "precision {}s does not evenly divide lower precision {}s", finer, coarser),
+            SchemaError::ShorterRetention(finer, coarser) => write!(f, "archive of precision {}s must cover a larger time interval than the one of precision {}s", coarser, finer),
+            SchemaError::TooFewPoints(finer, coarser) => write!(f, "archive of precision {}s does not have enough points to consolidate to precision {}s", finer, coarser),
+            SchemaError::InvalidConfig(line, ref reason) => write!(f, "line {}: {}", line, reason),
+            SchemaError::InvalidRule(ref section, ref reason) => write!(f, "[{}]: {}", section, reason)
+        }
+    }
+}
+
+impl error::Error for SchemaError {
+    fn description(&self) -> &str {
+        match *self {
+            SchemaError::InvalidSpec(_) => "invalid retention policy",
+            SchemaError::UnknownUnit(_, _) => "unknown time unit",
+            SchemaError::ZeroValue(_) => "zero precision or retention",
+            SchemaError::Overflow(_) => "retention policy too large",
+            SchemaError::NoRetentionPolicies => "no retention policies",
+            SchemaError::DuplicatePrecision(_) => "duplicate archive precision",
+            SchemaError::UnevenPrecision(_, _) => "precisions do not divide evenly",
+            SchemaError::ShorterRetention(_, _) => "lower precision archive covers less time",
+            SchemaError::TooFewPoints(_, _) => "not enough points to consolidate",
+            SchemaError::InvalidConfig(_, _) => "invalid config file",
+            SchemaError::InvalidRule(_, _) => "invalid rule"
+        }
+    }
+}
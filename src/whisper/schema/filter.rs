@@ -0,0 +1,135 @@
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+use std::sync::Mutex;
+
+use regex::Regex;
+
+use whisper::error::Result;
+use super::SchemaError;
+
+// A whitelist.conf or blacklist.conf: a regex per line, matching anywhere in
+// the metric name, blank lines and `#` comments aside
+#[derive(Debug, Clone)]
+pub struct RegexList {
+    patterns: Vec<Regex>
+}
+
+impl RegexList {
+    pub fn new(patterns: Vec<Regex>) -> RegexList {
+        RegexList { patterns: patterns }
+    }
+
+    pub fn open<P>(path: P) -> Result<RegexList>
+        where P: AsRef<Path> {
+        let mut contents = String::new();
+        try!(try!(File::open(path)).read_to_string(&mut contents));
+        Ok(try!(RegexList::parse(&contents)))
+    }
+
+    pub fn parse(contents: &str) -> ::std::result::Result<RegexList, SchemaError> {
+        let mut patterns = vec![];
+        for (index, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            patterns.push(try!(Regex::new(line).map_err(|e| SchemaError::InvalidConfig(index + 1, format!("invalid pattern: {}", e)))));
+        }
+        Ok(RegexList::new(patterns))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.patterns.is_empty()
+    }
+
+    pub fn is_match(&self, metric: &str) -> bool {
+        self.patterns.iter().any(|pattern| pattern.is_match(metric))
+    }
+}
+
+#[derive(Debug, Default, PartialEq, Clone)]
+pub struct FilterStats {
+    pub accepted: u64,
+    // Matching nothing in a whitelist that isn't empty
+    pub whitelist_rejects: u64,
+    pub blacklist_matches: u64
+}
+
+// Same as carbon's USE_WHITELIST: with patterns in the whitelist, only the
+// metrics matching one of them are taken, and then none matching the blacklist.
+// An empty whitelist lets everything through.
+#[derive(Debug)]
+pub struct MetricFilter {
+    whitelist: RegexList,
+    blacklist: RegexList,
+    stats: Mutex<FilterStats>
+}
+
+impl MetricFilter {
+    pub fn new(whitelist: RegexList, blacklist: RegexList) -> MetricFilter {
+        MetricFilter { whitelist: whitelist, blacklist: blacklist, stats: Mutex::new(FilterStats::default()) }
+    }
+
+    // The lists carbon reads from its config directory, either one can be missing
+    pub fn open<P>(config_dir: P) -> Result<MetricFilter>
+        where P: AsRef<Path> {
+        let list = |name: &str| {
+            let path = config_dir.as_ref().join(name);
+            if path.is_file() { RegexList::open(path) } else { Ok(RegexList::new(vec![])) }
+        };
+        Ok(MetricFilter::new(try!(list("whitelist.conf")), try!(list("blacklist.conf"))))
+    }
+
+    pub fn stats(&self) -> FilterStats {
+        self.stats.lock().unwrap().clone()
+    }
+
+    // Counted in the stats, unlike `allows`
+    pub fn accept(&self, metric: &str) -> bool {
+        let whitelisted = self.whitelist.is_empty() || self.whitelist.is_match(metric);
+        let blacklisted = whitelisted && self.blacklist.is_match(metric);
+
+        let mut stats = self.stats.lock().unwrap();
+        if !whitelisted {
+            stats.whitelist_rejects = stats.whitelist_rejects + 1;
+        } else if blacklisted {
+            stats.blacklist_matches = stats.blacklist_matches + 1;
+        } else {
+            stats.accepted = stats.accepted + 1;
+        }
+        whitelisted && !blacklisted
+    }
+
+    pub fn allows(&self, metric: &str) -> bool {
+        (self.whitelist.is_empty() || self.whitelist.is_match(metric)) && !self.blacklist.is_match(metric)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_accept() {
+        let whitelist = RegexList::parse("# only servers and carbon\n^servers\\.\n\n^carbon\\.\n").unwrap();
+        let blacklist = RegexList::parse("\\.tmp\\.\n").unwrap();
+        let filter = MetricFilter::new(whitelist, blacklist.clone());
+
+        assert!(filter.accept("servers.web01.load"));
+        assert!(!filter.accept("servers.web01.tmp.load"));
+        assert!(!filter.accept("apps.shop.requests"));
+        assert!(!filter.accept("apps.tmp.requests"));
+        assert!(filter.allows("carbon.agents.a.cpu"));
+        assert_eq!(filter.stats(), FilterStats { accepted: 1, whitelist_rejects: 2, blacklist_matches: 1 });
+
+        let filter = MetricFilter::new(RegexList::new(vec![]), blacklist);
+        assert!(filter.accept("apps.shop.requests"));
+        assert!(!filter.accept("apps.tmp.requests"));
+
+        match RegexList::parse("a\n(b") {
+            Err(SchemaError::InvalidConfig(2, _)) => (),
+            other => panic!("expected an invalid line 2, got {:?}", other)
+        }
+    }
+}
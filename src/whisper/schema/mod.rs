@@ -1,17 +1,34 @@
 mod retention_policy;
+mod error;
+pub(crate) mod conf;
+mod rules;
+mod aggregation;
+mod plan;
+mod resolver;
+mod rewrite;
+mod filter;
+pub mod presets;
 
 use whisper::file::STATIC_HEADER_SIZE;
 use whisper::file::ARCHIVE_INFO_SIZE;
 pub use self::retention_policy::RetentionPolicy;
-
-#[derive(Debug)]
+pub use self::error::SchemaError;
+pub use self::rules::{ SchemaRules, SchemaRule };
+pub use self::aggregation::{ AggregationRules, AggregationRule };
+pub use self::plan::{ DiskUsagePlan, PrefixUsage };
+pub use self::resolver::{ SchemaResolver, StorageRules };
+pub use self::rewrite::{ RewriteRules, RewriteRule, RewriteStage };
+pub use self::filter::{ RegexList, MetricFilter, FilterStats };
+
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Schema {
     pub retention_policies: Vec<RetentionPolicy>
 }
 
 impl Schema {
-    pub fn new_from_retention_specs(specs: Vec<String>) -> Result<Schema, String> {
-        let retention_policies: Result<Vec<RetentionPolicy>, String> =
+    pub fn new_from_retention_specs(specs: Vec<String>) -> Result<Schema, SchemaError> {
+        let retention_policies: Result<Vec<RetentionPolicy>, SchemaError> =
             specs.iter().fold(Ok(vec![]), |policies_result, next| {
                 policies_result
                     .and_then(|mut policies| RetentionPolicy::spec_to_retention_policy(next)
@@ -19,7 +36,71 @@ impl Schema {
                     )
             });
 
-        retention_policies.map(|policies| Schema { retention_policies: policies })
+        let mut schema = try!(retention_policies.map(|policies| Schema { retention_policies: policies }));
+        try!(schema.validate());
+        schema.normalize();
+        Ok(schema)
+    }
+
+    // Same syntax as the retentions line of storage-schemas.conf: "10s:8d,1m:31d,10m:5y"
+    pub fn parse(retentions: &str) -> Result<Schema, SchemaError> {
+        if retentions.trim().is_empty() {
+            return Err(SchemaError::NoRetentionPolicies);
+        }
+
+        let mut policies = vec![];
+        for spec in retentions.split(',') {
+            policies.push(try!(RetentionPolicy::spec_to_retention_policy(spec.trim())));
+        }
+
+        let mut schema = Schema { retention_policies: policies };
+        try!(schema.validate());
+        schema.normalize();
+        Ok(schema)
+    }
+
+    // The checks of whisper.py's validateArchiveList: going from fine to coarse
+    // each precision must be a multiple of the previous one, retain longer, and
+    // the finer archive must hold enough points to roll up into the coarser one.
+    pub fn validate(&self) -> Result<(), SchemaError> {
+        if self.retention_policies.is_empty() {
+            return Err(SchemaError::NoRetentionPolicies);
+        }
+
+        let policies = self.canonical_policies();
+        for pair in policies.windows(2) {
+            let (finer, coarser) = (pair[0], pair[1]);
+            if finer.precision == coarser.precision {
+                return Err(SchemaError::DuplicatePrecision(finer.precision));
+            }
+            if coarser.precision % finer.precision != 0 {
+                return Err(SchemaError::UnevenPrecision(finer.precision, coarser.precision));
+            }
+            if coarser.precision * coarser.points() <= finer.precision * finer.points() {
+                return Err(SchemaError::ShorterRetention(finer.precision, coarser.precision));
+            }
+            if finer.points() < coarser.precision / finer.precision {
+                return Err(SchemaError::TooFewPoints(finer.precision, coarser.precision));
+            }
+        }
+
+        Ok(())
+    }
+
+    // Finest precision first, each retention rounded down to a whole number of
+    // points. The order archives are laid out on disk and propagated in, whatever
+    // order the policies were given in.
+    pub fn canonical_policies(&self) -> Vec<RetentionPolicy> {
+        let mut policies : Vec<RetentionPolicy> = self.retention_policies.iter().map(|policy| RetentionPolicy {
+            precision: policy.precision,
+            retention: policy.points() * policy.precision
+        }).collect();
+        policies.sort_by_key(|policy| policy.precision);
+        policies
+    }
+
+    pub fn normalize(&mut self) {
+        self.retention_policies = self.canonical_policies();
     }
 
     pub fn header_size_on_disk(&self) -> u32 {
@@ -27,6 +108,7 @@ impl Schema {
         (ARCHIVE_INFO_SIZE*self.retention_policies.len()) as u32
     }
 
+    // Bytes a classic file with this schema takes up, header included
     pub fn size_on_disk(&self) -> u32 {
         let retentions_disk_size = self.retention_policies.iter().fold(0, |tally, policy| {
             // debug!("policy: {:?} size on disk: {}", policy, policy.size_on_disk());
@@ -40,7 +122,7 @@ impl Schema {
         if self.retention_policies.len() == 0 {
             0
         } else {
-            self.retention_policies.iter().map(|&rp| rp.retention).max().unwrap()
+            self.retention_policies.iter().map(|&rp| rp.points() * rp.precision).max().unwrap()
         }
     }
 }
@@ -78,4 +160,46 @@ mod tests {
         assert_eq!(little_schema.size_on_disk(), expected);
     }
 
+    #[test]
+    fn test_parse() {
+        let schema = Schema::parse("10s:8d,1m:31d, 10m:5y").unwrap();
+        let pairs: Vec<(u32, u32)> = schema.retention_policies.iter().map(|rp| (rp.precision, rp.retention)).collect();
+        assert_eq!(pairs, vec![(10, 8*24*60*60), (60, 31*24*60*60), (600, 5*365*24*60*60)]);
+
+        assert_eq!(Schema::parse("60:1440").unwrap().retention_policies[0].retention, 60*1440);
+
+        assert_eq!(Schema::parse(" ").unwrap_err(), SchemaError::NoRetentionPolicies);
+        assert_eq!(Schema::parse("10s:8d,").unwrap_err(), SchemaError::InvalidSpec("".to_string()));
+        assert_eq!(Schema::parse("10s:8d,1x:31d").unwrap_err(), SchemaError::UnknownUnit("1x:31d".to_string(), "x".to_string()));
+    }
+
+    #[test]
+    fn test_canonical_policies() {
+        let schema = Schema { retention_policies: vec![
+            RetentionPolicy { precision: 60, retention: 600 },
+            RetentionPolicy { precision: 10, retention: 65 }
+        ] };
+
+        let pairs: Vec<(u32, u32)> = schema.canonical_policies().iter().map(|rp| (rp.precision, rp.retention)).collect();
+        assert_eq!(pairs, vec![(10, 60), (60, 600)]);
+        assert_eq!(schema.max_retention(), 600);
+    }
+
+    #[test]
+    fn test_validate() {
+        assert!(Schema::parse("1m:1d,10s:6h").is_ok());
+
+        assert_eq!(Schema::parse("10s:1d,10s:2d").unwrap_err(), SchemaError::DuplicatePrecision(10));
+        assert_eq!(Schema::parse("10s:1d,15s:2d").unwrap_err(), SchemaError::UnevenPrecision(10, 15));
+        assert_eq!(Schema::parse("10s:1d,1m:1d").unwrap_err(), SchemaError::ShorterRetention(10, 60));
+        assert_eq!(Schema::parse("10s:30s,1m:1d").unwrap_err(), SchemaError::TooFewPoints(10, 60));
+
+        let schema = Schema::parse("1m:1d,10s:6h").unwrap();
+        let precisions: Vec<u32> = schema.retention_policies.iter().map(|rp| rp.precision).collect();
+        assert_eq!(precisions, vec![10, 60]);
+
+        let empty = Schema { retention_policies: vec![] };
+        assert_eq!(empty.validate(), Err(SchemaError::NoRetentionPolicies));
+    }
+
 }
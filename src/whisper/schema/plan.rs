@@ -0,0 +1,80 @@
+use std::collections::BTreeMap;
+
+use super::SchemaRules;
+
+// Bytes the whisper files of every metric under one prefix would take up
+#[derive(Debug, PartialEq, Clone)]
+pub struct PrefixUsage {
+    pub prefix: String,
+    pub metrics: usize,
+    pub bytes: u64
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct DiskUsagePlan {
+    // Sorted by prefix
+    pub prefixes: Vec<PrefixUsage>,
+    pub total_bytes: u64,
+    // Metrics no schema rule matches, which carbon would refuse to create
+    pub unmatched: Vec<String>
+}
+
+impl SchemaRules {
+    // How much disk creating a file for each of `metrics` takes, grouped by their
+    // first `depth` nodes: "servers.web01.cpu" is under "servers.web01" at depth 2.
+    // A depth of 0 puts everything under "".
+    pub fn plan_disk_usage<I, S>(&self, metrics: I, depth: usize) -> DiskUsagePlan
+        where I: IntoIterator<Item=S>, S: AsRef<str> {
+        let mut prefixes : BTreeMap<String, PrefixUsage> = BTreeMap::new();
+        let mut unmatched = vec![];
+        let mut total_bytes = 0;
+
+        for metric in metrics {
+            let metric = metric.as_ref();
+            let bytes = match self.schema_for(metric) {
+                Some(schema) => schema.size_on_disk() as u64,
+                None => {
+                    unmatched.push(metric.to_string());
+                    continue;
+                }
+            };
+
+            let prefix = metric.split('.').take(depth).collect::<Vec<&str>>().join(".");
+            let usage = prefixes.entry(prefix.clone()).or_insert(PrefixUsage { prefix: prefix, metrics: 0, bytes: 0 });
+            usage.metrics = usage.metrics + 1;
+            usage.bytes = usage.bytes + bytes;
+            total_bytes = total_bytes + bytes;
+        }
+
+        DiskUsagePlan {
+            prefixes: prefixes.into_iter().map(|(_, usage)| usage).collect(),
+            total_bytes: total_bytes,
+            unmatched: unmatched
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_plan_disk_usage() {
+        let rules = SchemaRules::parse("[carbon]\npattern = ^carbon\\.\nretentions = 60:10\n\n[servers]\npattern = ^servers\\.\nretentions = 10s:1m,1m:10m").unwrap();
+        // header + archive infos + points
+        let carbon_size = 16 + 12 + 10*12;
+        let servers_size = 16 + 2*12 + (6 + 10)*12;
+
+        let plan = rules.plan_disk_usage(vec!["servers.web01.cpu", "carbon.agents.a", "servers.web01.load", "servers.web02.cpu", "other.metric"], 2);
+        assert_eq!(plan.prefixes, vec![
+            PrefixUsage { prefix: "carbon.agents".to_string(), metrics: 1, bytes: carbon_size },
+            PrefixUsage { prefix: "servers.web01".to_string(), metrics: 2, bytes: 2*servers_size },
+            PrefixUsage { prefix: "servers.web02".to_string(), metrics: 1, bytes: servers_size }
+        ]);
+        assert_eq!(plan.total_bytes, carbon_size + 3*servers_size);
+        assert_eq!(plan.unmatched, vec!["other.metric".to_string()]);
+
+        let plan = rules.plan_disk_usage(vec!["servers.web01.cpu".to_string()], 0);
+        assert_eq!(plan.prefixes[0].prefix, "");
+    }
+}
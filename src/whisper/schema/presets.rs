@@ -0,0 +1,77 @@
+// Retentions applications commonly offer, built without parsing spec strings
+use super::{ Schema, RetentionPolicy };
+
+const MINUTE : u32 = 60;
+const HOUR : u32 = 60 * MINUTE;
+const DAY : u32 = 24 * HOUR;
+const YEAR : u32 = 365 * DAY;
+
+fn schema(policies: &[(u32, u32)]) -> Schema {
+    Schema {
+        retention_policies: policies.iter().map(|&(precision, retention)| RetentionPolicy {
+            precision: precision,
+            retention: retention
+        }).collect()
+    }
+}
+
+// 1s:6h,1m:7d,10m:5y
+pub fn high_resolution() -> Schema {
+    schema(&[(1, 6 * HOUR), (MINUTE, 7 * DAY), (10 * MINUTE, 5 * YEAR)])
+}
+
+// 10s:6h,1m:7d,10m:5y, what statsd's docs recommend for its flush interval
+pub fn statsd() -> Schema {
+    schema(&[(10, 6 * HOUR), (MINUTE, 7 * DAY), (10 * MINUTE, 5 * YEAR)])
+}
+
+// 60s:1d, the catch-all of carbon's example storage-schemas.conf
+pub fn carbon_default() -> Schema {
+    schema(&[(MINUTE, DAY)])
+}
+
+// 60:90d, what carbon's example storage-schemas.conf keeps its own metrics for
+pub fn carbon_self_metrics() -> Schema {
+    schema(&[(MINUTE, 90 * DAY)])
+}
+
+// 1m:30d,15m:1y,1h:5y, for capacity planning and other slow moving data
+pub fn long_term() -> Schema {
+    schema(&[(MINUTE, 30 * DAY), (15 * MINUTE, YEAR), (HOUR, 5 * YEAR)])
+}
+
+impl Schema {
+    // Every preset by name
+    pub fn presets() -> Vec<(&'static str, Schema)> {
+        vec![
+            ("high_resolution", high_resolution()),
+            ("statsd", statsd()),
+            ("carbon_default", carbon_default()),
+            ("carbon_self_metrics", carbon_self_metrics()),
+            ("long_term", long_term())
+        ]
+    }
+
+    pub fn preset(name: &str) -> Option<Schema> {
+        Schema::presets().into_iter().find(|&(preset_name, _)| preset_name == name).map(|(_, schema)| schema)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_presets() {
+        let specs = |schema: &Schema| schema.retention_policies.iter().map(|rp| (rp.precision, rp.retention)).collect::<Vec<(u32, u32)>>();
+
+        for (name, preset) in Schema::presets() {
+            assert!(preset.validate().is_ok(), "{} is invalid", name);
+        }
+        assert_eq!(specs(&high_resolution()), specs(&Schema::parse("1s:6h,1m:7d,10m:5y").unwrap()));
+        assert_eq!(specs(&statsd()), specs(&Schema::parse("10s:6h,1m:7d,10m:5y").unwrap()));
+        assert_eq!(specs(&long_term()), specs(&Schema::parse("1m:30d,15m:1y,1h:5y").unwrap()));
+        assert_eq!(specs(&Schema::preset("carbon_default").unwrap()), vec![(60, 24*60*60)]);
+        assert!(Schema::preset("nonexistent").is_none());
+    }
+}
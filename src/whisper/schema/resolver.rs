@@ -0,0 +1,80 @@
+use whisper::AggregationType;
+use whisper::file::DEFAULT_X_FILES_FACTOR;
+use super::{ Schema, SchemaRules, AggregationRules };
+
+// Decides how the file of a metric is laid out when it has to be created.
+// WhisperCache asks its resolver each time it creates a file.
+pub trait SchemaResolver: Send + Sync {
+    // None when the metric has no schema, its file is not created
+    fn schema_for(&self, metric: &str) -> Option<Schema>;
+
+    fn aggregation_for(&self, metric: &str) -> (AggregationType, f32);
+}
+
+// Every metric gets this schema, averaged with whisper's default xFilesFactor
+impl SchemaResolver for Schema {
+    fn schema_for(&self, _metric: &str) -> Option<Schema> {
+        Some(self.clone())
+    }
+
+    fn aggregation_for(&self, _metric: &str) -> (AggregationType, f32) {
+        (AggregationType::Average, DEFAULT_X_FILES_FACTOR)
+    }
+}
+
+// storage-schemas.conf alone, averaged with whisper's default xFilesFactor
+impl SchemaResolver for SchemaRules {
+    fn schema_for(&self, metric: &str) -> Option<Schema> {
+        SchemaRules::schema_for(self, metric).cloned()
+    }
+
+    fn aggregation_for(&self, _metric: &str) -> (AggregationType, f32) {
+        (AggregationType::Average, DEFAULT_X_FILES_FACTOR)
+    }
+}
+
+// What carbon-cache does with storage-schemas.conf and storage-aggregation.conf
+#[derive(Debug)]
+pub struct StorageRules {
+    pub schema_rules: SchemaRules,
+    pub aggregation_rules: AggregationRules
+}
+
+impl StorageRules {
+    pub fn new(schema_rules: SchemaRules, aggregation_rules: AggregationRules) -> StorageRules {
+        StorageRules {
+            schema_rules: schema_rules,
+            aggregation_rules: aggregation_rules
+        }
+    }
+}
+
+impl SchemaResolver for StorageRules {
+    fn schema_for(&self, metric: &str) -> Option<Schema> {
+        self.schema_rules.schema_for(metric).cloned()
+    }
+
+    fn aggregation_for(&self, metric: &str) -> (AggregationType, f32) {
+        self.aggregation_rules.aggregation_for(metric)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_storage_rules() {
+        let schema_rules = SchemaRules::parse("[carbon]\npattern = ^carbon\\.\nretentions = 60:90d").unwrap();
+        let aggregation_rules = AggregationRules::parse("[sum]\npattern = \\.count$\naggregationMethod = sum").unwrap();
+        let resolver : Box<dyn SchemaResolver> = Box::new(StorageRules::new(schema_rules, aggregation_rules));
+
+        assert_eq!(resolver.schema_for("carbon.agents.a.count").unwrap().max_retention(), 90*24*60*60);
+        assert!(resolver.schema_for("servers.load").is_none());
+        assert_eq!(resolver.aggregation_for("carbon.agents.a.count"), (AggregationType::Sum, DEFAULT_X_FILES_FACTOR));
+        assert_eq!(resolver.aggregation_for("carbon.agents.a.load"), (AggregationType::Average, DEFAULT_X_FILES_FACTOR));
+
+        let schema = Schema::parse("60:1440").unwrap();
+        assert_eq!(SchemaResolver::schema_for(&schema, "anything").unwrap().max_retention(), 60*1440);
+    }
+}
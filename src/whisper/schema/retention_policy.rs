@@ -4,6 +4,8 @@ use whisper::file::archive::ARCHIVE_INFO_SIZE;
 use byteorder::{ BigEndian, WriteBytesExt };
 use regex;
 
+use super::SchemaError;
+
 use std::io::{ BufWriter, Write };
 use std::fs::File;
 
@@ -11,20 +13,41 @@ use std::fs::File;
 // It does not know it's position in the file. Should it just
 // be collapsed in to ArchiveInfo? Possibly.
 #[derive(Debug, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct RetentionPolicy {
     pub precision: u32,
     pub retention: u32
 }
 
 impl RetentionPolicy {
-    pub fn spec_to_retention_policy(spec: &str) -> Result<RetentionPolicy, String> {
+    // Same as whisper.py's parseRetentionDef. Both sides are a number with an optional
+    // unit, a retention without one is a number of points rather than seconds.
+    pub fn spec_to_retention_policy(spec: &str) -> Result<RetentionPolicy, SchemaError> {
         // TODO: regex should be built as const using macro regex!
         // but that's only available in nightlies.
-        let retention_matcher = regex::Regex::new({r"^(\d+)([smhdwy])?:(\d+)([smhdwy])?$"}).unwrap();
-        match retention_matcher.captures(spec) {
-            Some(regex_match) => retention_capture_to_pair(regex_match),
-            None => Err(format!("error: {} is not a valid retention policy", spec))
+        let retention_matcher = regex::Regex::new(r"^(\d+)([a-z]*):(\d+)([a-z]*)$").unwrap();
+        let regex_match = match retention_matcher.captures(spec.trim()) {
+            Some(regex_match) => regex_match,
+            None => return Err(SchemaError::InvalidSpec(spec.to_string()))
+        };
+        let part = |index: usize| regex_match.get(index).map(|m| m.as_str()).unwrap_or("");
+
+        let precision = try!(to_seconds(spec, part(1), part(2)));
+        let retention = if part(4).is_empty() {
+            let points = try!(part(3).parse::<u32>().map_err(|_| SchemaError::Overflow(spec.to_string())));
+            try!(points.checked_mul(precision).ok_or(SchemaError::Overflow(spec.to_string())))
+        } else {
+            try!(to_seconds(spec, part(3), part(4)))
+        };
+
+        if precision == 0 || retention / precision == 0 {
+            return Err(SchemaError::ZeroValue(spec.to_string()));
         }
+
+        Ok(RetentionPolicy {
+            precision: precision,
+            retention: retention
+        })
     }
 
     // TODO how do we guarantee even divisibility?
@@ -55,51 +78,26 @@ impl RetentionPolicy {
     }
 }
 
-fn retention_capture_to_pair(regex_match: regex::Captures) -> Result<RetentionPolicy, String> {
-    let precision_opt = regex_match.get(1).map(|m| m.as_str());
-    let precision_mult = regex_match.get(2).map(|m| m.as_str()).unwrap_or("s");
-    let retention_opt = regex_match.get(3).map(|m| m.as_str());
-    let retention_mult = regex_match.get(4).map(|m| m.as_str());
-
-    match (precision_opt, retention_opt) {
-        (Some(precision), Some(retention)) => {
-            precision.parse::<u32>()
-                .map_err(|e| format!("Unable to parse precision {} in schema as u32: {}", precision, e))
-                .and_then(|base_precision| mult_str_to_num(precision_mult).map(|mult| base_precision * mult))
-                .and_then(|precision| {
-                    retention.parse::<u32>()
-                        .map_err(|e| format!("Unable to parse retention {} in schema as u32: {}", retention, e))
-                        .and_then(|base_retention| match retention_mult {
-                            Some(mult_str) => {
-                                mult_str_to_num(mult_str).map(|mult| base_retention * mult)
-                            },
-                            None => {
-                                // user has not provided a multipler so this is interpreted
-                                // as the number of points so we have to
-                                // calculate retention from the number of points
-                                Ok(base_retention * precision)
-                            }
-                        }).map(|retention| RetentionPolicy {
-                            precision: precision,
-                            retention: retention
-                        })
-            })
-        },
-        _ => Err("Invalid precision or retention period provided in retention policy".to_string())
-    }
+fn to_seconds(spec: &str, number: &str, unit: &str) -> Result<u32, SchemaError> {
+    let number = try!(number.parse::<u32>().map_err(|_| SchemaError::Overflow(spec.to_string())));
+    let multiplier = try!(unit_multiplier(if unit.is_empty() { "s" } else { unit })
+        .ok_or(SchemaError::UnknownUnit(spec.to_string(), unit.to_string())));
+    number.checked_mul(multiplier).ok_or(SchemaError::Overflow(spec.to_string()))
 }
 
-fn mult_str_to_num(mult_str: &str) -> Result<u32, String> {
-    // TODO: is this exactly how whisper does it?
-    match mult_str {
-        "s" => Ok(1),
-        "m" => Ok(60),
-        "h" => Ok(60*60),
-        "d" => Ok(60*60*24),
-        "w" => Ok(60*60*24*7),
-        "y" => Ok(60*60*24*365),
-        _ => Err(format!("Unrecognized time multiplier specified: {}", mult_str)) //Regex should ensure this is impossible
-    }
+// Like whisper.py any prefix of a unit name will do, checked in this order so
+// "m" is minutes. Months are 30 days, years 365.
+fn unit_multiplier(unit: &str) -> Option<u32> {
+    let units : [(&str, u32); 7] = [
+        ("seconds", 1),
+        ("minutes", 60),
+        ("hours", 60*60),
+        ("days", 60*60*24),
+        ("weeks", 60*60*24*7),
+        ("months", 60*60*24*30),
+        ("years", 60*60*24*365)
+    ];
+    units.iter().find(|&&(name, _)| name.starts_with(unit)).map(|&(_, multiplier)| multiplier)
 }
 
 #[cfg(test)]
@@ -148,4 +146,29 @@ mod tests {
         assert_eq!(retention_policy.retention, expected.retention);
     }
 
+    #[test]
+    fn test_spec_units() {
+        let seconds = |spec: &str| RetentionPolicy::spec_to_retention_policy(spec).map(|rp| (rp.precision, rp.retention));
+
+        assert_eq!(seconds("10sec:1min"), Ok((10, 60)));
+        assert_eq!(seconds("1m:2minutes"), Ok((60, 120)));
+        assert_eq!(seconds("1h:1d"), Ok((60*60, 60*60*24)));
+        assert_eq!(seconds("1d:1w"), Ok((60*60*24, 60*60*24*7)));
+        assert_eq!(seconds("1d:1mon"), Ok((60*60*24, 60*60*24*30)));
+        assert_eq!(seconds(" 1h:5years "), Ok((60*60, 5*60*60*24*365)));
+    }
+
+    #[test]
+    fn test_spec_errors() {
+        let error = |spec: &str| RetentionPolicy::spec_to_retention_policy(spec).unwrap_err();
+
+        assert_eq!(error("10s"), SchemaError::InvalidSpec("10s".to_string()));
+        assert_eq!(error("-10s:1d"), SchemaError::InvalidSpec("-10s:1d".to_string()));
+        assert_eq!(error("10x:1d"), SchemaError::UnknownUnit("10x:1d".to_string(), "x".to_string()));
+        assert_eq!(error("0s:1d"), SchemaError::ZeroValue("0s:1d".to_string()));
+        assert_eq!(error("10s:0"), SchemaError::ZeroValue("10s:0".to_string()));
+        assert_eq!(error("1m:30s"), SchemaError::ZeroValue("1m:30s".to_string()));
+        assert_eq!(error("1d:1000y"), SchemaError::Overflow("1d:1000y".to_string()));
+        assert_eq!(error("10s:99999999999"), SchemaError::Overflow("10s:99999999999".to_string()));
+    }
 }
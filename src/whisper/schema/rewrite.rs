@@ -0,0 +1,182 @@
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+use regex::Regex;
+
+use whisper::error::Result;
+use super::SchemaError;
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum RewriteStage {
+    // Before aggregation
+    Pre,
+    // After aggregation, on aggregated metrics too
+    Post
+}
+
+// A `pattern = replacement` line of rewrite-rules.conf
+#[derive(Debug, Clone)]
+pub struct RewriteRule {
+    pub stage: RewriteStage,
+    pub pattern: Regex,
+    // In the regex crate's syntax, `\1` and `\g<name>` turned into `${1}` and `${name}`
+    pub replacement: String
+}
+
+impl RewriteRule {
+    pub fn new(stage: RewriteStage, pattern: &str, replacement: &str) -> ::std::result::Result<RewriteRule, String> {
+        Ok(RewriteRule {
+            stage: stage,
+            pattern: try!(Regex::new(pattern).map_err(|e| format!("invalid pattern: {}", e))),
+            replacement: RewriteRule::replacement(replacement)
+        })
+    }
+
+    // Every match is replaced, same as re.sub
+    pub fn rewrite(&self, metric: &str) -> String {
+        self.pattern.replace_all(metric, self.replacement.as_str()).into_owned()
+    }
+
+    // Python's backreferences to the regex crate's, and its `$` escaped
+    fn replacement(python: &str) -> String {
+        let mut replacement = String::new();
+        let mut chars = python.chars().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                '$' => replacement.push_str("$$"),
+                '\\' => match chars.peek().cloned() {
+                    Some(digit) if digit.is_ascii_digit() => {
+                        let mut group = String::new();
+                        while let Some(digit) = chars.peek().cloned().filter(|c| c.is_ascii_digit()) {
+                            group.push(digit);
+                            chars.next();
+                        }
+                        replacement.push_str(&format!("${{{}}}", group));
+                    },
+                    Some('g') => {
+                        chars.next();
+                        let name : String = chars.by_ref().skip(1).take_while(|&c| c != '>').collect();
+                        replacement.push_str(&format!("${{{}}}", name));
+                    },
+                    Some('\\') => {
+                        chars.next();
+                        replacement.push('\\');
+                    },
+                    _ => replacement.push('\\')
+                },
+                _ => replacement.push(c)
+            }
+        }
+        replacement
+    }
+}
+
+// Same as carbon's rewrite-rules.conf: `[pre]` and `[post]` sections of
+// `regex = replacement` lines, each rule applied in file order to what the
+// ones before it made of the name. Here the first `=` ends the pattern, and
+// `#` starts a comment line.
+#[derive(Debug, Clone)]
+pub struct RewriteRules {
+    rules: Vec<RewriteRule>
+}
+
+impl RewriteRules {
+    pub fn new(rules: Vec<RewriteRule>) -> RewriteRules {
+        RewriteRules { rules: rules }
+    }
+
+    pub fn open<P>(path: P) -> Result<RewriteRules>
+        where P: AsRef<Path> {
+        let mut contents = String::new();
+        try!(try!(File::open(path)).read_to_string(&mut contents));
+        Ok(try!(RewriteRules::parse(&contents)))
+    }
+
+    pub fn parse(contents: &str) -> ::std::result::Result<RewriteRules, SchemaError> {
+        let mut rules = vec![];
+        let mut stage = None;
+
+        for (index, line) in contents.lines().enumerate() {
+            let line_number = index + 1;
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            if line.starts_with('[') && line.ends_with(']') {
+                stage = match line[1 .. line.len() - 1].trim() {
+                    "pre" => Some(RewriteStage::Pre),
+                    "post" => Some(RewriteStage::Post),
+                    section => return Err(SchemaError::InvalidConfig(line_number, format!("unknown section [{}], expected [pre] or [post]", section)))
+                };
+                continue;
+            }
+
+            let stage = match stage {
+                Some(stage) => stage,
+                None => return Err(SchemaError::InvalidConfig(line_number, format!("'{}' is not in a section", line)))
+            };
+            let separator = match line.find('=') {
+                Some(separator) => separator,
+                None => return Err(SchemaError::InvalidConfig(line_number, format!("expected pattern = replacement, got '{}'", line)))
+            };
+            let rule = try!(RewriteRule::new(stage, line[.. separator].trim(), line[separator + 1 ..].trim())
+                .map_err(|reason| SchemaError::InvalidConfig(line_number, reason)));
+            rules.push(rule);
+        }
+
+        Ok(RewriteRules::new(rules))
+    }
+
+    pub fn rules(&self) -> &[RewriteRule] {
+        &self.rules
+    }
+
+    pub fn rewrite(&self, stage: RewriteStage, metric: &str) -> String {
+        self.rules.iter()
+            .filter(|rule| rule.stage == stage)
+            .fold(metric.to_string(), |metric, rule| rule.rewrite(&metric))
+    }
+
+    // Both stages, for when nothing is aggregated in between
+    pub fn rewrite_all(&self, metric: &str) -> String {
+        self.rewrite(RewriteStage::Post, &self.rewrite(RewriteStage::Pre, metric))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const REWRITE_RULES : &'static str = "
+[pre]
+^collectd_([a-z0-9]+)\\. = \\1.system.
+# strip the hostname suffix
+\\.example\\.com(\\.|$) = \\1
+
+[post]
+_sum$ =
+(?P<app>app[0-9]+)\\.price = \\g<app>.$price
+";
+
+    #[test]
+    fn test_rewrite() {
+        let rules = RewriteRules::parse(REWRITE_RULES).unwrap();
+        assert_eq!(rules.rules().len(), 4);
+
+        assert_eq!(rules.rewrite(RewriteStage::Pre, "collectd_web01.cpu"), "web01.system.cpu");
+        assert_eq!(rules.rewrite(RewriteStage::Pre, "servers.db01.example.com.load"), "servers.db01.load");
+        assert_eq!(rules.rewrite(RewriteStage::Pre, "requests_sum"), "requests_sum");
+        assert_eq!(rules.rewrite(RewriteStage::Post, "requests_sum"), "requests");
+        assert_eq!(rules.rewrite_all("collectd_web01.app1.price"), "web01.system.app1.$price");
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert_eq!(RewriteRules::parse("a = b").unwrap_err(), SchemaError::InvalidConfig(1, "'a = b' is not in a section".to_string()));
+        assert_eq!(RewriteRules::parse("[pre]\na b").unwrap_err(), SchemaError::InvalidConfig(2, "expected pattern = replacement, got 'a b'".to_string()));
+        assert!(RewriteRules::parse("[middle]\n").is_err());
+        assert!(RewriteRules::parse("[pre]\n( = b").is_err());
+    }
+}
@@ -0,0 +1,134 @@
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+use regex::Regex;
+
+use whisper::error::Result;
+use super::{ Schema, SchemaError };
+use super::conf::{ self, Section };
+
+// A [section] of storage-schemas.conf
+#[derive(Debug)]
+pub struct SchemaRule {
+    pub name: String,
+    pub pattern: Regex,
+    pub schema: Schema
+}
+
+// Same as carbon's storage-schemas.conf: rules are tried in file order and the
+// first one whose pattern matches anywhere in the metric name wins.
+#[derive(Debug)]
+pub struct SchemaRules {
+    rules: Vec<SchemaRule>
+}
+
+impl SchemaRules {
+    pub fn new(rules: Vec<SchemaRule>) -> SchemaRules {
+        SchemaRules { rules: rules }
+    }
+
+    // Every metric gets `schema`
+    pub fn from_schema(schema: Schema) -> SchemaRules {
+        SchemaRules::new(vec![SchemaRule {
+            name: "default".to_string(),
+            pattern: Regex::new("").unwrap(),
+            schema: schema
+        }])
+    }
+
+    pub fn open<P>(path: P) -> Result<SchemaRules>
+        where P: AsRef<Path> {
+        let mut contents = String::new();
+        try!(try!(File::open(path)).read_to_string(&mut contents));
+        Ok(try!(SchemaRules::parse(&contents)))
+    }
+
+    pub fn parse(contents: &str) -> ::std::result::Result<SchemaRules, SchemaError> {
+        SchemaRules::from_sections(try!(conf::sections(contents)))
+    }
+
+    pub(crate) fn from_sections(sections: Vec<Section>) -> ::std::result::Result<SchemaRules, SchemaError> {
+        let mut rules = vec![];
+
+        for section in sections {
+            let invalid = |reason: String| SchemaError::InvalidRule(section.name.clone(), reason);
+
+            let pattern = try!(section.get("pattern").ok_or_else(|| invalid("no pattern".to_string())));
+            let pattern = try!(Regex::new(pattern).map_err(|e| invalid(format!("invalid pattern: {}", e))));
+            let retentions = try!(section.get("retentions").ok_or_else(|| invalid("no retentions".to_string())));
+            let schema = try!(Schema::parse(retentions).map_err(|e| invalid(e.to_string())));
+
+            rules.push(SchemaRule {
+                name: section.name.clone(),
+                pattern: pattern,
+                schema: schema
+            });
+        }
+
+        Ok(SchemaRules::new(rules))
+    }
+
+    pub fn rules(&self) -> &[SchemaRule] {
+        &self.rules
+    }
+
+    pub fn rule_for(&self, metric: &str) -> Option<&SchemaRule> {
+        self.rules.iter().find(|rule| rule.pattern.is_match(metric))
+    }
+
+    pub fn schema_for(&self, metric: &str) -> Option<&Schema> {
+        self.rule_for(metric).map(|rule| &rule.schema)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const STORAGE_SCHEMAS : &'static str = "
+# Schema definitions for Whisper files. Entries are scanned in order,
+# and first match wins.
+[carbon]
+pattern = ^carbon\\.
+retentions = 60:90d
+
+[collectd]
+pattern = ^collectd\\.
+retentions = 10s:1d,1m:30d
+
+[default_1min_for_1day]
+pattern = .*
+retentions = 60s:1d
+";
+
+    #[test]
+    fn test_schema_for() {
+        let rules = SchemaRules::parse(STORAGE_SCHEMAS).unwrap();
+        assert_eq!(rules.rules().len(), 3);
+
+        let name_and_retention = |metric: &str| rules.rule_for(metric).map(|rule| (rule.name.as_str(), rule.schema.max_retention()));
+        assert_eq!(name_and_retention("carbon.agents.a.cpuUsage"), Some(("carbon", 90*24*60*60)));
+        assert_eq!(name_and_retention("collectd.host.load"), Some(("collectd", 30*24*60*60)));
+        assert_eq!(name_and_retention("servers.carbon.load"), Some(("default_1min_for_1day", 24*60*60)));
+
+        let strict = SchemaRules::parse("[carbon]\npattern = ^carbon\\.\nretentions = 60:90d").unwrap();
+        assert!(strict.schema_for("servers.load").is_none());
+    }
+
+    #[test]
+    fn test_invalid_rules() {
+        assert_eq!(SchemaRules::parse("[nothing]\nretentions = 60:90d").unwrap_err(), SchemaError::InvalidRule("nothing".to_string(), "no pattern".to_string()));
+        assert!(match SchemaRules::parse("[bad]\npattern = (\nretentions = 60:90d").unwrap_err() {
+            SchemaError::InvalidRule(ref name, _) => name == "bad",
+            _ => false
+        });
+        match SchemaRules::parse("[bad]\npattern = .*\nretentions = 60:90x").unwrap_err() {
+            SchemaError::InvalidRule(name, reason) => {
+                assert_eq!(name, "bad");
+                assert!(reason.contains("60:90x"));
+            },
+            err => panic!("unexpected {:?}", err)
+        }
+    }
+}
@@ -0,0 +1,218 @@
+use std::collections::BTreeSet;
+use std::fs::{ File, OpenOptions };
+use std::io::{ self, BufRead, BufReader, Write };
+use std::path::Path;
+use std::sync::Mutex;
+
+use regex::Regex;
+
+use super::TaggedSeries;
+
+// One of graphite's seriesByTag expressions: `tag=value`, `tag!=value`,
+// `tag=~regex` or `tag!=~regex`. A series without the tag has it empty, and
+// regexes match from the start of the value, the way graphite's do.
+#[derive(Debug, Clone)]
+pub struct TagExpression {
+	pub tag: String,
+	operator: Operator,
+	value: String
+}
+
+#[derive(Debug, Clone)]
+enum Operator {
+	Equal,
+	NotEqual,
+	Match(Regex),
+	NotMatch(Regex)
+}
+
+impl TagExpression {
+	pub fn parse(expression: &str) -> Result<TagExpression, String> {
+		let index = try!( expression.find('=').ok_or_else(|| format!("expression `{}` has no operator", expression)) );
+		let (tag, negated) = if expression[..index].ends_with('!') {
+			(&expression[..index - 1], true)
+		} else {
+			(&expression[..index], false)
+		};
+		if tag.is_empty() {
+			return Err(format!("expression `{}` has no tag", expression));
+		}
+
+		let rest = &expression[index+1..];
+		let (operator, value) = if rest.starts_with('~') {
+			let value = &rest[1..];
+			let regex = try!( Regex::new(&format!("^(?:{})", value)).map_err(|err| format!("invalid regex in `{}`: {}", expression, err)) );
+			(if negated { Operator::NotMatch(regex) } else { Operator::Match(regex) }, value)
+		} else {
+			(if negated { Operator::NotEqual } else { Operator::Equal }, rest)
+		};
+		Ok(TagExpression { tag: tag.to_string(), operator: operator, value: value.to_string() })
+	}
+
+	pub fn matches(&self, series: &TaggedSeries) -> bool {
+		let value = series.tag(&self.tag).unwrap_or("");
+		match self.operator {
+			Operator::Equal => value == self.value,
+			Operator::NotEqual => value != self.value,
+			Operator::Match(ref regex) => regex.is_match(value),
+			Operator::NotMatch(ref regex) => !regex.is_match(value)
+		}
+	}
+
+	// Whether it only matches series that have the tag, graphite wants one such
+	fn is_positive(&self) -> bool {
+		match self.operator {
+			Operator::Equal => !self.value.is_empty(),
+			Operator::Match(ref regex) => !regex.is_match(""),
+			_ => false
+		}
+	}
+}
+
+// The tagged series written so far, since their files are named by a digest
+// they can't be found by. Kept in memory and, when opened from a file, one
+// normalized name per line appended as series are added.
+pub struct TagIndex {
+	inner: Mutex<Inner>
+}
+
+struct Inner {
+	series: BTreeSet<String>,
+	file: Option<File>
+}
+
+impl Default for TagIndex {
+	fn default() -> TagIndex {
+		TagIndex::new()
+	}
+}
+
+impl TagIndex {
+	pub fn new() -> TagIndex {
+		TagIndex { inner: Mutex::new(Inner { series: BTreeSet::new(), file: None }) }
+	}
+
+	pub fn open<P>(path: P) -> io::Result<TagIndex>
+		where P: AsRef<Path> {
+		let mut series = BTreeSet::new();
+		if path.as_ref().is_file() {
+			for line in BufReader::new(try!( File::open(&path) )).lines() {
+				let line = try!( line );
+				if !line.is_empty() {
+					series.insert(line);
+				}
+			}
+		}
+		let file = try!( OpenOptions::new().append(true).create(true).open(&path) );
+		Ok(TagIndex { inner: Mutex::new(Inner { series: series, file: Some(file) }) })
+	}
+
+	// Whether the series is new to the index
+	pub fn add(&self, series: &TaggedSeries) -> io::Result<bool> {
+		let path = series.path();
+		let mut inner = self.inner.lock().unwrap();
+		if inner.series.contains(&path) {
+			return Ok(false);
+		}
+		if let Some(ref mut file) = inner.file {
+			try!( writeln!(file, "{}", path) );
+		}
+		inner.series.insert(path);
+		Ok(true)
+	}
+
+	pub fn len(&self) -> usize {
+		self.inner.lock().unwrap().series.len()
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.len() == 0
+	}
+
+	// Normalized names, sorted
+	pub fn series(&self) -> Vec<String> {
+		self.inner.lock().unwrap().series.iter().cloned().collect()
+	}
+
+	// Every tag some series has, `name` included, sorted
+	pub fn tags(&self) -> Vec<String> {
+		let mut tags = BTreeSet::new();
+		tags.insert("name".to_string());
+		for series in self.parsed_series() {
+			tags.extend(series.tags.into_iter().map(|tag| tag.0));
+		}
+		tags.into_iter().collect()
+	}
+
+	// The values `tag` has across the series, sorted
+	pub fn values(&self, tag: &str) -> Vec<String> {
+		let values : BTreeSet<String> = self.parsed_series().iter().filter_map(|series| series.tag(tag).map(|value| value.to_string())).collect();
+		values.into_iter().collect()
+	}
+
+	// Same as graphite's seriesByTag: the series matching every expression, at
+	// least one of which has to match nothing but series having its tag
+	pub fn find(&self, expressions: &[&str]) -> Result<Vec<String>, String> {
+		let mut parsed = vec![];
+		for expression in expressions {
+			parsed.push(try!( TagExpression::parse(expression) ));
+		}
+		if !parsed.iter().any(|expression| expression.is_positive()) {
+			return Err("at least one expression has to match a non-empty value".to_string());
+		}
+
+		Ok(self.parsed_series().iter()
+			.filter(|series| parsed.iter().all(|expression| expression.matches(series)))
+			.map(|series| series.path())
+			.collect())
+	}
+
+	fn parsed_series(&self) -> Vec<TaggedSeries> {
+		self.inner.lock().unwrap().series.iter().filter_map(|path| TaggedSeries::parse(path).ok()).collect()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use whisper::temp_path;
+	use std::fs;
+
+	fn index() -> TagIndex {
+		let index = TagIndex::new();
+		for series in &["disk.used;datacenter=dc1;server=web01", "disk.used;datacenter=dc2;server=web02", "disk.free;datacenter=dc1;server=db01", "cpu;server=web01"] {
+			index.add(&TaggedSeries::parse(series).unwrap()).unwrap();
+		}
+		index
+	}
+
+	#[test]
+	fn test_find() {
+		let index = index();
+		assert_eq!(index.find(&["datacenter=dc1"]).unwrap(), vec!["disk.free;datacenter=dc1;server=db01", "disk.used;datacenter=dc1;server=web01"]);
+		assert_eq!(index.find(&["name=disk.used", "server!=web01"]).unwrap(), vec!["disk.used;datacenter=dc2;server=web02"]);
+		assert_eq!(index.find(&["server=~web", "datacenter!=~dc2"]).unwrap(), vec!["cpu;server=web01", "disk.used;datacenter=dc1;server=web01"]);
+		assert_eq!(index.find(&["server=web01", "datacenter="]).unwrap(), vec!["cpu;server=web01"]);
+		assert!(index.find(&["datacenter!=dc1"]).is_err());
+		assert!(index.find(&["server=~("]).is_err());
+		assert!(index.find(&["server"]).is_err());
+
+		assert_eq!(index.tags(), vec!["datacenter", "name", "server"]);
+		assert_eq!(index.values("datacenter"), vec!["dc1", "dc2"]);
+	}
+
+	#[test]
+	fn test_open() {
+		let path = temp_path("test_tag_index");
+		fs::remove_file(&path).ok();
+		{
+			let index = TagIndex::open(&path).unwrap();
+			assert!(index.is_empty());
+			assert!(index.add(&TaggedSeries::parse("cpu;server=web01").unwrap()).unwrap());
+			assert!(!index.add(&TaggedSeries::parse("cpu;server=web01").unwrap()).unwrap());
+		}
+		let index = TagIndex::open(&path).unwrap();
+		assert_eq!(index.series(), vec!["cpu;server=web01"]);
+		assert_eq!(index.len(), 1);
+	}
+}
@@ -0,0 +1,119 @@
+// Graphite's tagged series, `name;tag1=value1;tag2=value2`. Files are stored
+// the way carbon does with TAG_HASH_FILENAMES, its default: by the SHA-256 of
+// the normalized name under `_tagged`, since a name can't be made into a path.
+
+use std::path::PathBuf;
+
+pub mod sha256;
+mod index;
+
+pub use self::index::{ TagIndex, TagExpression };
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct TaggedSeries {
+	pub name: String,
+	// Sorted by tag, `name` left out
+	pub tags: Vec<(String, String)>
+}
+
+impl TaggedSeries {
+	#[inline]
+	pub fn is_tagged(metric_name: &str) -> bool {
+		metric_name.contains(';')
+	}
+
+	// Same checks as carbon's TaggedSeries.parse. Tags may come in any order,
+	// a later value of a tag replaces an earlier one.
+	pub fn parse(metric_name: &str) -> Result<TaggedSeries, String> {
+		let mut parts = metric_name.split(';');
+		let name = parts.next().unwrap_or("");
+		if name.is_empty() {
+			return Err(format!("tagged series `{}` has no name", metric_name));
+		}
+
+		let mut series = TaggedSeries { name: name.to_string(), tags: vec![] };
+		for tag in parts {
+			let (key, value) = match tag.find('=') {
+				Some(index) => (&tag[..index], &tag[index+1..]),
+				None => return Err(format!("tag `{}` of `{}` has no value", tag, metric_name))
+			};
+			if key.is_empty() || key.contains(|c| c == '!' || c == '^' || c == '=') {
+				return Err(format!("invalid tag `{}` in `{}`", key, metric_name));
+			}
+			if value.is_empty() || value.starts_with('~') {
+				return Err(format!("invalid value `{}` of tag `{}` in `{}`", value, key, metric_name));
+			}
+			series.set_tag(key, value);
+		}
+		series.tags.sort();
+		Ok(series)
+	}
+
+	// The normalized name, tags sorted
+	pub fn path(&self) -> String {
+		let mut path = self.name.clone();
+		for &(ref key, ref value) in &self.tags {
+			path.push(';');
+			path.push_str(key);
+			path.push('=');
+			path.push_str(value);
+		}
+		path
+	}
+
+	// `name` for the name
+	pub fn tag(&self, key: &str) -> Option<&str> {
+		if key == "name" {
+			return Some(&self.name);
+		}
+		self.tags.iter().find(|tag| tag.0 == key).map(|tag| &tag.1[..])
+	}
+
+	// `_tagged/<first 3 hex digits>/<next 3>/<digest>.wsp` of the normalized name
+	pub fn rel_path(&self) -> PathBuf {
+		TaggedSeries::rel_path_for_normalized(&self.path())
+	}
+
+	pub fn rel_path_for_normalized(path: &str) -> PathBuf {
+		let digest = sha256::hex_digest(path.as_bytes());
+		PathBuf::from(format!("_tagged/{}/{}/{}.wsp", &digest[0..3], &digest[3..6], digest))
+	}
+
+	fn set_tag(&mut self, key: &str, value: &str) {
+		if key == "name" {
+			self.name = value.to_string();
+			return;
+		}
+		match self.tags.iter().position(|tag| tag.0 == key) {
+			Some(index) => self.tags[index].1 = value.to_string(),
+			None => self.tags.push((key.to_string(), value.to_string()))
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_parse() {
+		let series = TaggedSeries::parse("disk.used;server=web01;datacenter=dc1;rack=a1;server=web02").unwrap();
+		assert_eq!(series.name, "disk.used");
+		assert_eq!(series.path(), "disk.used;datacenter=dc1;rack=a1;server=web02");
+		assert_eq!(series.tag("name"), Some("disk.used"));
+		assert_eq!(series.tag("rack"), Some("a1"));
+		assert_eq!(series.tag("row"), None);
+
+		assert_eq!(TaggedSeries::parse("disk.used;name=disk.free").unwrap().path(), "disk.free");
+		for invalid in &[";a=b", "disk.used;a", "disk.used;=b", "disk.used;a!=b", "disk.used;a=", "disk.used;a=~b", "disk.used;"] {
+			assert!(TaggedSeries::parse(invalid).is_err(), "{}", invalid);
+		}
+	}
+
+	#[test]
+	fn test_rel_path() {
+		// The path carbon gives the series
+		let series = TaggedSeries::parse("disk.used;rack=a1;datacenter=dc1;server=web01").unwrap();
+		assert_eq!(series.rel_path(), PathBuf::from("_tagged/e9a/90f/e9a90f9ab08ad206ecfb30c241e0285a7c36479ca423d74260b2614cb98c1eec.wsp"));
+	}
+}
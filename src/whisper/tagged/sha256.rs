@@ -0,0 +1,78 @@
+// SHA-256 (FIPS 180-4), only for the file names of tagged series, which
+// carbon takes from the hex digest of their name
+const CONSTANTS : [u32; 64] = [
+	0x428a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5, 0x3956c25b, 0x59f111f1, 0x923f82a4, 0xab1c5ed5,
+	0xd807aa98, 0x12835b01, 0x243185be, 0x550c7dc3, 0x72be5d74, 0x80deb1fe, 0x9bdc06a7, 0xc19bf174,
+	0xe49b69c1, 0xefbe4786, 0x0fc19dc6, 0x240ca1cc, 0x2de92c6f, 0x4a7484aa, 0x5cb0a9dc, 0x76f988da,
+	0x983e5152, 0xa831c66d, 0xb00327c8, 0xbf597fc7, 0xc6e00bf3, 0xd5a79147, 0x06ca6351, 0x14292967,
+	0x27b70a85, 0x2e1b2138, 0x4d2c6dfc, 0x53380d13, 0x650a7354, 0x766a0abb, 0x81c2c92e, 0x92722c85,
+	0xa2bfe8a1, 0xa81a664b, 0xc24b8b70, 0xc76c51a3, 0xd192e819, 0xd6990624, 0xf40e3585, 0x106aa070,
+	0x19a4c116, 0x1e376c08, 0x2748774c, 0x34b0bcb5, 0x391c0cb3, 0x4ed8aa4a, 0x5b9cca4f, 0x682e6ff3,
+	0x748f82ee, 0x78a5636f, 0x84c87814, 0x8cc70208, 0x90befffa, 0xa4506ceb, 0xbef9a3f7, 0xc67178f2
+];
+
+pub fn digest(input: &[u8]) -> [u8; 32] {
+	let mut message = input.to_vec();
+	message.push(0x80);
+	while message.len() % 64 != 56 {
+		message.push(0);
+	}
+	let bit_length = (input.len() as u64).wrapping_mul(8);
+	for i in (0..8).rev() {
+		message.push((bit_length >> (8 * i)) as u8);
+	}
+
+	let mut state : [u32; 8] = [0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f, 0x9b05688c, 0x1f83d9ab, 0x5be0cd19];
+	for block in message.chunks(64) {
+		let mut words = [0u32; 64];
+		for i in 0..16 {
+			words[i] = (block[i * 4] as u32) << 24 | (block[i * 4 + 1] as u32) << 16 | (block[i * 4 + 2] as u32) << 8 | (block[i * 4 + 3] as u32);
+		}
+		for i in 16..64 {
+			let s0 = words[i - 15].rotate_right(7) ^ words[i - 15].rotate_right(18) ^ (words[i - 15] >> 3);
+			let s1 = words[i - 2].rotate_right(17) ^ words[i - 2].rotate_right(19) ^ (words[i - 2] >> 10);
+			words[i] = words[i - 16].wrapping_add(s0).wrapping_add(words[i - 7]).wrapping_add(s1);
+		}
+
+		let mut working = state;
+		for i in 0..64 {
+			let (a, b, c, d, e, f, g, h) = (working[0], working[1], working[2], working[3], working[4], working[5], working[6], working[7]);
+			let s1 = e.rotate_right(6) ^ e.rotate_right(11) ^ e.rotate_right(25);
+			let choice = (e & f) ^ (!e & g);
+			let temp1 = h.wrapping_add(s1).wrapping_add(choice).wrapping_add(CONSTANTS[i]).wrapping_add(words[i]);
+			let s0 = a.rotate_right(2) ^ a.rotate_right(13) ^ a.rotate_right(22);
+			let majority = (a & b) ^ (a & c) ^ (b & c);
+			let temp2 = s0.wrapping_add(majority);
+			working = [temp1.wrapping_add(temp2), a, b, c, d.wrapping_add(temp1), e, f, g];
+		}
+
+		for i in 0..8 {
+			state[i] = state[i].wrapping_add(working[i]);
+		}
+	}
+
+	let mut digest = [0u8; 32];
+	for (i, word) in state.iter().enumerate() {
+		for j in 0..4 {
+			digest[i * 4 + j] = (word >> (24 - 8 * j)) as u8;
+		}
+	}
+	digest
+}
+
+pub fn hex_digest(input: &[u8]) -> String {
+	digest(input).iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_digest() {
+		// From FIPS 180-4's examples
+		assert_eq!(hex_digest(b""), "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855");
+		assert_eq!(hex_digest(b"abc"), "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad");
+		assert_eq!(hex_digest(b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq"), "248d6a61d20638b8e5c026930c3e6039a33ce45964ff2167f6ecedd419db06c1");
+	}
+}
@@ -0,0 +1,153 @@
+use std::io::{ self, Write };
+use std::slice;
+
+use whisper::{ Point, AggregationType };
+
+// The result of a fetch: `values[i]` is the value stored for the interval
+// `from + i*step`, or None when nothing was written there. Same as the
+// (timeInfo, values) tuple of whisper.py, never 0.0 for missing data.
+#[derive(Debug, PartialEq, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct TimeSeries {
+	pub from: u32,
+	pub until: u32,
+	pub step: u32,
+	pub values: Vec<Option<f64>>
+}
+
+// Every (timestamp, value) pair of a TimeSeries, missing values included
+pub struct Iter<'a> {
+	values: slice::Iter<'a, Option<f64>>,
+	timestamp: u32,
+	step: u32
+}
+
+impl<'a> Iterator for Iter<'a> {
+	type Item = (u32, Option<f64>);
+
+	fn next(&mut self) -> Option<(u32, Option<f64>)> {
+		self.values.next().map(|value| {
+			let timestamp = self.timestamp;
+			self.timestamp = self.timestamp + self.step;
+			(timestamp, *value)
+		})
+	}
+
+	fn size_hint(&self) -> (usize, Option<usize>) {
+		self.values.size_hint()
+	}
+}
+
+impl TimeSeries {
+	#[inline]
+	pub fn len(&self) -> usize {
+		self.values.len()
+	}
+
+	#[inline]
+	pub fn is_empty(&self) -> bool {
+		self.values.is_empty()
+	}
+
+	pub fn iter(&self) -> Iter {
+		Iter { values: self.values.iter(), timestamp: self.from, step: self.step }
+	}
+
+	// Only the intervals that have a value
+	pub fn points(&self) -> Vec<Point> {
+		self.iter().filter_map(|(timestamp, value)| value.map(|value| Point(timestamp, value))).collect()
+	}
+
+	// Same as graphite-web's consolidateBy: when there are more than `max_points`
+	// values, every run of values_per_point of them is rolled up into one with
+	// `function`, the last run possibly shorter. A max_points of 0 means no limit.
+	pub fn consolidate(&self, max_points: usize, function: &AggregationType) -> TimeSeries {
+		if max_points == 0 || self.values.len() <= max_points {
+			return self.clone();
+		}
+
+		let values_per_point = (self.values.len() + max_points - 1) / max_points;
+		let values : Vec<Option<f64>> = self.values.chunks(values_per_point).map(|chunk| {
+			let known_values : Vec<f64> = chunk.iter().filter_map(|value| *value).collect();
+			function.aggregate(&known_values, chunk.len())
+		}).collect();
+
+		let step = self.step * values_per_point as u32;
+		TimeSeries {
+			from: self.from,
+			until: self.from + step * values.len() as u32,
+			step: step,
+			values: values
+		}
+	}
+
+	// The value of the interval holding `timestamp`
+	pub fn value_at(&self, timestamp: u32) -> Option<f64> {
+		if self.step == 0 || timestamp < self.from {
+			return None;
+		}
+		self.values.get(((timestamp - self.from) / self.step) as usize).and_then(|value| *value)
+	}
+
+	// A `timestamp,value` header and a row per interval, missing values left empty
+	pub fn to_csv<W>(&self, writer: &mut W) -> io::Result<()>
+		where W: Write {
+		self.to_csv_with_null(writer, "")
+	}
+
+	// Same as to_csv, writing `null` for missing values, e.g. `NaN` for pandas
+	pub fn to_csv_with_null<W>(&self, writer: &mut W, null: &str) -> io::Result<()>
+		where W: Write {
+		try!( writeln!(writer, "timestamp,value") );
+		for (timestamp, value) in self.iter() {
+			match value {
+				Some(value) => try!( writeln!(writer, "{},{}", timestamp, value) ),
+				None => try!( writeln!(writer, "{},{}", timestamp, null) )
+			}
+		}
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_time_series() {
+		let series = TimeSeries { from: 100, until: 130, step: 10, values: vec![Some(1.0), None, Some(3.0)] };
+
+		assert_eq!(series.len(), 3);
+		assert_eq!(series.iter().collect::<Vec<_>>(), vec![(100, Some(1.0)), (110, None), (120, Some(3.0))]);
+		assert_eq!(series.points(), vec![Point(100, 1.0), Point(120, 3.0)]);
+		assert_eq!(series.value_at(125), Some(3.0));
+		assert_eq!(series.value_at(110), None);
+		assert_eq!(series.value_at(130), None);
+		assert_eq!(series.value_at(99), None);
+	}
+
+	#[test]
+	fn test_consolidate() {
+		let series = TimeSeries { from: 100, until: 150, step: 10, values: vec![Some(1.0), Some(5.0), None, None, Some(3.0)] };
+
+		assert_eq!(series.consolidate(0, &AggregationType::Sum), series);
+		assert_eq!(series.consolidate(5, &AggregationType::Sum), series);
+		assert_eq!(series.consolidate(3, &AggregationType::Sum), TimeSeries { from: 100, until: 160, step: 20, values: vec![Some(6.0), None, Some(3.0)] });
+		assert_eq!(series.consolidate(2, &AggregationType::Average).values, vec![Some(3.0), Some(3.0)]);
+		assert_eq!(series.consolidate(2, &AggregationType::Max).values, vec![Some(5.0), Some(3.0)]);
+		assert_eq!(series.consolidate(1, &AggregationType::Last).values, vec![Some(3.0)]);
+	}
+
+	#[test]
+	fn test_to_csv() {
+		let series = TimeSeries { from: 100, until: 130, step: 10, values: vec![Some(1.5), None, Some(-3.0)] };
+
+		let mut csv = vec![];
+		series.to_csv(&mut csv).unwrap();
+		assert_eq!(String::from_utf8(csv).unwrap(), "timestamp,value\n100,1.5\n110,\n120,-3\n");
+
+		let mut csv = vec![];
+		series.to_csv_with_null(&mut csv, "NaN").unwrap();
+		assert_eq!(String::from_utf8(csv).unwrap(), "timestamp,value\n100,1.5\n110,NaN\n120,-3\n");
+	}
+}
@@ -0,0 +1,109 @@
+use std::io;
+use std::path::PathBuf;
+
+use whisper::{ WhisperFile, FileDefect, SchemaResolver };
+use super::WhisperTree;
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct FileAudit {
+	pub metric_name: String,
+	pub path: PathBuf,
+	pub defects: Vec<FileDefect>
+}
+
+#[derive(Debug, Default, PartialEq, Clone)]
+pub struct AuditReport {
+	pub scanned: usize,
+	// Only the files with something wrong, sorted by name
+	pub defective: Vec<FileAudit>
+}
+
+impl AuditReport {
+	pub fn is_healthy(&self) -> bool {
+		self.defective.is_empty()
+	}
+}
+
+impl WhisperTree {
+	// Every file through WhisperFile::audit, for a health check after a disk
+	// incident. Nothing is written to.
+	pub fn audit(&self) -> io::Result<AuditReport> {
+		self.audit_with(None)
+	}
+
+	// Same as audit, sound files also checked against the schema and
+	// aggregation `resolver` would create them with. Metrics it has no schema
+	// for aren't.
+	pub fn audit_against<R>(&self, resolver: &R) -> io::Result<AuditReport>
+		where R: SchemaResolver {
+		self.audit_with(Some(resolver))
+	}
+
+	fn audit_with(&self, resolver: Option<&dyn SchemaResolver>) -> io::Result<AuditReport> {
+		let mut report = AuditReport::default();
+		for entry in self.walk() {
+			let entry = try!( entry );
+			report.scanned = report.scanned + 1;
+
+			let mut defects = WhisperFile::audit(&entry.path);
+			if let (true, Some(resolver)) = (defects.is_empty(), resolver) {
+				if let Some(schema) = resolver.schema_for(&entry.metric_name) {
+					let (aggregation_type, x_files_factor) = resolver.aggregation_for(&entry.metric_name);
+					match WhisperFile::open_readonly(&entry.path) {
+						Ok(file) => {
+							let diff = file.matches_schema(&schema, aggregation_type, x_files_factor);
+							if !diff.matches() {
+								defects.push(FileDefect::SchemaMismatch(diff));
+							}
+						},
+						Err(err) => defects.push(FileDefect::Unreadable(err.to_string()))
+					}
+				}
+			}
+
+			if !defects.is_empty() {
+				report.defective.push(FileAudit { metric_name: entry.metric_name, path: entry.path, defects: defects });
+			}
+		}
+		Ok(report)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use whisper::{ Schema, SchemaRules, temp_path };
+	use std::fs::{ self, OpenOptions };
+
+	#[test]
+	fn test_audit() {
+		let root = temp_path("test_whisper_tree_audit");
+		fs::remove_dir_all(&root).ok();
+		fs::create_dir_all(&root).unwrap();
+		let tree = WhisperTree::open(&root).unwrap();
+		for metric in &["servers.web01.load", "servers.web02.load", "carbon.agents.a.cpu"] {
+			let path = tree.path_for(metric).unwrap();
+			fs::create_dir_all(path.parent().unwrap()).unwrap();
+			WhisperFile::new(&path, &Schema::parse("10s:60s").unwrap()).unwrap();
+		}
+		assert_eq!(tree.audit().unwrap(), AuditReport { scanned: 3, defective: vec![] });
+
+		let truncated = tree.path_for("servers.web02.load").unwrap();
+		OpenOptions::new().write(true).open(&truncated).unwrap().set_len(40).unwrap();
+		let report = tree.audit().unwrap();
+		assert!(!report.is_healthy());
+		assert_eq!(report.defective, vec![FileAudit {
+			metric_name: "servers.web02.load".to_string(),
+			path: truncated,
+			defects: vec![FileDefect::Truncated { size: 40, expected: 100 }]
+		}]);
+
+		let rules = SchemaRules::parse("[carbon]\npattern = ^carbon\\.\nretentions = 60:90d").unwrap();
+		let report = tree.audit_against(&rules).unwrap();
+		assert_eq!(report.defective.iter().map(|audit| audit.metric_name.as_str()).collect::<Vec<_>>(), vec!["carbon.agents.a.cpu", "servers.web02.load"]);
+		match report.defective[0].defects.as_slice() {
+			[FileDefect::SchemaMismatch(ref diff)] => assert!(diff.needs_resize()),
+			other => panic!("expected a schema mismatch, got {:?}", other)
+		}
+	}
+}
@@ -0,0 +1,113 @@
+use std::collections::HashSet;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+
+use super::WhisperTree;
+
+// What a delete removed, or would have on a dry run
+#[derive(Debug, Default, PartialEq, Clone)]
+pub struct DeleteReport {
+	pub dry_run: bool,
+	// Sorted by name
+	pub metrics: Vec<String>,
+	// Left empty by the files going, deepest first
+	pub directories: Vec<PathBuf>,
+	// Apparent size of the files
+	pub bytes: u64
+}
+
+impl WhisperTree {
+	// Removes the files of the metrics `pattern` names, a metric or a graphite
+	// glob like find takes, then the directories they leave empty. Branches
+	// it names are left alone, only leaves go.
+	pub fn delete(&self, pattern: &str) -> io::Result<DeleteReport> {
+		self.delete_with(pattern, false)
+	}
+
+	// Same report as delete, nothing removed
+	pub fn delete_dry_run(&self, pattern: &str) -> io::Result<DeleteReport> {
+		self.delete_with(pattern, true)
+	}
+
+	fn delete_with(&self, pattern: &str, dry_run: bool) -> io::Result<DeleteReport> {
+		let mut report = DeleteReport { dry_run: dry_run, .. DeleteReport::default() };
+		let mut removed = HashSet::new();
+		for node in try!( self.find(pattern) ).into_iter().filter(|node| node.is_leaf) {
+			let path = try!( self.path_for(&node.path) );
+			report.bytes = report.bytes + try!( fs::metadata(&path) ).len();
+			report.metrics.push(node.path);
+			removed.insert(path);
+		}
+
+		// Deepest first, a directory is emptied when all it holds goes
+		let mut candidates : Vec<PathBuf> = removed.iter().flat_map(|path| path.ancestors().skip(1).map(|dir| dir.to_path_buf()).collect::<Vec<_>>())
+			.filter(|dir| dir.starts_with(self.root()) && *dir != self.root())
+			.collect::<HashSet<_>>().into_iter().collect();
+		candidates.sort_by(|a, b| b.components().count().cmp(&a.components().count()).then(a.cmp(b)));
+		for directory in candidates {
+			let mut emptied = true;
+			for entry in try!( fs::read_dir(&directory) ) {
+				if !removed.contains(&try!( entry ).path()) {
+					emptied = false;
+					break;
+				}
+			}
+			if emptied {
+				removed.insert(directory.clone());
+				report.directories.push(directory);
+			}
+		}
+
+		if !dry_run {
+			for metric in &report.metrics {
+				try!( fs::remove_file(try!( self.path_for(metric) )) );
+				self.index_removed(metric);
+			}
+			for directory in &report.directories {
+				try!( fs::remove_dir(directory) );
+			}
+		}
+		Ok(report)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use whisper::temp_path;
+	use std::fs::File;
+	use std::io::Write;
+
+	#[test]
+	fn test_delete() {
+		let root = PathBuf::from(temp_path("test_whisper_tree_delete"));
+		fs::remove_dir_all(&root).ok();
+		for dir in &["servers/web01/cpu", "servers/web02/cpu", "servers/db01"] {
+			fs::create_dir_all(root.join(dir)).unwrap();
+		}
+		for file in &["servers/web01/cpu/user.wsp", "servers/web01/cpu/system.wsp", "servers/web02/cpu/user.wsp", "servers/web02/notes.txt", "servers/db01/load.wsp"] {
+			File::create(root.join(file)).unwrap().write_all(b"0123456789").unwrap();
+		}
+		let tree = WhisperTree::open(&root).unwrap();
+
+		let dry_run = tree.delete_dry_run("servers.web0*.cpu.*").unwrap();
+		assert_eq!(dry_run, DeleteReport {
+			dry_run: true,
+			metrics: vec!["servers.web01.cpu.system".to_string(), "servers.web01.cpu.user".to_string(), "servers.web02.cpu.user".to_string()],
+			directories: vec![root.join("servers/web01/cpu"), root.join("servers/web02/cpu"), root.join("servers/web01")],
+			bytes: 30
+		});
+		assert_eq!(tree.metrics().unwrap().len(), 4);
+
+		let report = tree.delete("servers.web0*.cpu.*").unwrap();
+		assert_eq!(report, DeleteReport { dry_run: false, .. dry_run });
+		assert_eq!(tree.metrics().unwrap(), vec!["servers.db01.load"]);
+		assert!(root.join("servers/web02/notes.txt").is_file());
+		assert!(!root.join("servers/web01").exists());
+
+		// A branch isn't a metric
+		assert_eq!(tree.delete("servers.db01").unwrap().metrics.len(), 0);
+		assert_eq!(tree.delete("servers.db01.load").unwrap().directories, vec![root.join("servers/db01")]);
+	}
+}
@@ -0,0 +1,246 @@
+use std::collections::BTreeMap;
+use std::io;
+use std::sync::RwLock;
+
+use whisper::{ MetricPattern, MetricNode, TaggedSeries };
+use super::WhisperTree;
+
+// The metric names of a tree held in memory as a trie, a node per dotted
+// node, so a find goes down the branches that match instead of reading
+// directories. Built from the tree once, then kept up to date by the
+// WhisperCache creating files and the WhisperTree removing or renaming them,
+// both given the same index. Tagged series are left to the TagIndex.
+#[derive(Debug)]
+pub struct MetricIndex {
+	inner: RwLock<Inner>
+}
+
+#[derive(Debug)]
+struct Inner {
+	root: Node,
+	metrics: usize
+}
+
+#[derive(Debug, Default)]
+struct Node {
+	children: BTreeMap<String, Node>,
+	// A metric ends here, whether or not others go on below
+	is_leaf: bool
+}
+
+impl Default for MetricIndex {
+	fn default() -> MetricIndex {
+		MetricIndex::new()
+	}
+}
+
+impl MetricIndex {
+	pub fn new() -> MetricIndex {
+		MetricIndex { inner: RwLock::new(Inner { root: Node::default(), metrics: 0 }) }
+	}
+
+	// Every metric `tree` has now
+	pub fn build(tree: &WhisperTree) -> io::Result<MetricIndex> {
+		let index = MetricIndex::new();
+		for entry in tree.walk() {
+			index.add(&try!( entry ).metric_name);
+		}
+		Ok(index)
+	}
+
+	// Whether the metric is new to the index
+	pub fn add(&self, metric_name: &str) -> bool {
+		if metric_name.split('.').any(|name| name.is_empty()) || TaggedSeries::is_tagged(metric_name) {
+			return false;
+		}
+		// The cache asks for every file it creates, most of them known already
+		if self.contains(metric_name) {
+			return false;
+		}
+		let mut inner = self.inner.write().unwrap();
+		let leaf = metric_name.split('.').fold(&mut inner.root, |node, name| node.children.entry(name.to_string()).or_insert_with(Node::default));
+		if leaf.is_leaf {
+			return false;
+		}
+		leaf.is_leaf = true;
+		inner.metrics = inner.metrics + 1;
+		true
+	}
+
+	// Whether the index had the metric. Branches left with nothing under them go too.
+	pub fn remove(&self, metric_name: &str) -> bool {
+		let mut inner = self.inner.write().unwrap();
+		let names : Vec<&str> = metric_name.split('.').collect();
+		if !remove(&mut inner.root, &names) {
+			return false;
+		}
+		inner.metrics = inner.metrics - 1;
+		true
+	}
+
+	pub fn contains(&self, metric_name: &str) -> bool {
+		let inner = self.inner.read().unwrap();
+		let mut node = &inner.root;
+		for name in metric_name.split('.') {
+			node = match node.children.get(name) {
+				Some(child) => child,
+				None => return false
+			};
+		}
+		node.is_leaf
+	}
+
+	pub fn len(&self) -> usize {
+		self.inner.read().unwrap().metrics
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.len() == 0
+	}
+
+	// The same branches and leaves MetricPattern::find would read off the tree
+	pub fn find(&self, pattern: &MetricPattern) -> Vec<MetricNode> {
+		let inner = self.inner.read().unwrap();
+		let mut branches = vec![(&inner.root, String::new())];
+		let mut found = vec![];
+
+		for index in 0..pattern.depth() {
+			let last = index == pattern.depth() - 1;
+			let mut next_branches = vec![];
+			for (node, path) in branches {
+				let children : Vec<(&String, &Node)> = match pattern.exact_node(index) {
+					Some(name) => node.children.get_key_value(name).into_iter().collect(),
+					None => node.children.iter().filter(|&(name, _)| pattern.matches_node(index, name)).collect()
+				};
+				for (name, child) in children {
+					let metric_path = if path.is_empty() { name.clone() } else { format!("{}.{}", path, name) };
+					if !last {
+						next_branches.push((child, metric_path));
+						continue;
+					}
+					if !child.children.is_empty() {
+						found.push(MetricNode { path: metric_path.clone(), is_leaf: false });
+					}
+					if child.is_leaf {
+						found.push(MetricNode { path: metric_path, is_leaf: true });
+					}
+				}
+			}
+			branches = next_branches;
+		}
+
+		found.sort();
+		found
+	}
+
+	// Every metric, in the order WhisperTree::walk goes
+	pub fn metrics(&self) -> Vec<String> {
+		let inner = self.inner.read().unwrap();
+		let mut metrics = Vec::with_capacity(inner.metrics);
+		collect(&inner.root, "", &mut metrics);
+		metrics
+	}
+}
+
+fn remove(node: &mut Node, names: &[&str]) -> bool {
+	let (name, rest) = match names.split_first() {
+		Some(split) => split,
+		None => {
+			let was_leaf = node.is_leaf;
+			node.is_leaf = false;
+			return was_leaf;
+		}
+	};
+	let (removed, emptied) = match node.children.get_mut(*name) {
+		Some(child) => {
+			let removed = remove(child, rest);
+			(removed, !child.is_leaf && child.children.is_empty())
+		},
+		None => return false
+	};
+	if emptied {
+		node.children.remove(*name);
+	}
+	removed
+}
+
+fn collect(node: &Node, prefix: &str, metrics: &mut Vec<String>) {
+	for (name, child) in &node.children {
+		let path = if prefix.is_empty() { name.clone() } else { format!("{}.{}", prefix, name) };
+		if child.is_leaf {
+			metrics.push(path.clone());
+		}
+		collect(child, &path, metrics);
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use whisper::{ WhisperCache, NamedPoint, Schema, current_time, temp_path };
+	use std::fs::{ self, File };
+	use std::sync::Arc;
+
+	fn node(path: &str, is_leaf: bool) -> MetricNode {
+		MetricNode { path: path.to_string(), is_leaf: is_leaf }
+	}
+
+	fn find(index: &MetricIndex, pattern: &str) -> Vec<MetricNode> {
+		index.find(&MetricPattern::parse(pattern).unwrap())
+	}
+
+	#[test]
+	fn test_index() {
+		let index = MetricIndex::new();
+		for metric in &["servers.web01.cpu.user", "servers.web01.cpu.system", "servers.web01", "servers.web02.load", "servers.web010.load", "load"] {
+			assert!(index.add(metric));
+		}
+		assert!(!index.add("servers.web01"));
+		assert!(!index.add("servers..load"));
+		assert!(!index.add("disk.used;server=web01"));
+		assert_eq!(index.len(), 6);
+
+		assert_eq!(find(&index, "*"), vec![node("load", true), node("servers", false)]);
+		assert_eq!(find(&index, "servers.web0[1-2]"), vec![node("servers.web01", false), node("servers.web01", true), node("servers.web02", false)]);
+		assert_eq!(find(&index, "servers.web01.cpu.{user,idle}"), vec![node("servers.web01.cpu.user", true)]);
+		assert_eq!(find(&index, "servers.*.load"), vec![node("servers.web010.load", true), node("servers.web02.load", true)]);
+		assert_eq!(find(&index, "servers.nothing.*"), vec![]);
+		assert!(index.contains("servers.web01") && !index.contains("servers.web01.cpu"));
+
+		assert!(index.remove("servers.web01.cpu.user"));
+		assert!(index.remove("servers.web01.cpu.system"));
+		assert!(!index.remove("servers.web01.cpu"));
+		assert_eq!(find(&index, "servers.web01"), vec![node("servers.web01", true)]);
+		assert_eq!(index.metrics(), vec!["load", "servers.web01", "servers.web010.load", "servers.web02.load"]);
+		assert!(index.remove("servers.web01"));
+		assert_eq!(find(&index, "servers.*"), vec![node("servers.web010", false), node("servers.web02", false)]);
+		assert_eq!(index.len(), 3);
+	}
+
+	#[test]
+	fn test_tree_index() {
+		let root = temp_path("test_whisper_tree_index");
+		fs::remove_dir_all(&root).ok();
+		for dir in &["servers/web01/cpu", "servers/web02"] {
+			fs::create_dir_all(format!("{}/{}", root, dir)).unwrap();
+		}
+		for file in &["servers/web01/cpu/user.wsp", "servers/web01/cpu/system.wsp", "servers/web02/load.wsp"] {
+			File::create(format!("{}/{}", root, file)).unwrap();
+		}
+		let tree = WhisperTree::open(&root).unwrap();
+		let index = Arc::new(MetricIndex::build(&tree).unwrap());
+		assert_eq!(index.metrics(), tree.metrics().unwrap());
+		let tree = tree.with_metric_index(index.clone());
+
+		let cache = WhisperCache::new(&root, 10, Schema::parse("10s:60s").unwrap());
+		cache.set_metric_index(Some(index.clone()));
+		let now = current_time();
+		cache.write(NamedPoint::new("servers.web03.load".to_string(), now, 1.0)).unwrap();
+		assert_eq!(tree.find("servers.*.load").unwrap(), vec![node("servers.web02.load", true), node("servers.web03.load", true)]);
+
+		tree.rename("servers.web02.load", "servers.db01.load").unwrap();
+		tree.delete("servers.web01.cpu.*").unwrap();
+		assert_eq!(tree.find("servers.*").unwrap(), vec![node("servers.db01", false), node("servers.web03", false)]);
+		assert_eq!(index.metrics(), tree.metrics().unwrap());
+	}
+}
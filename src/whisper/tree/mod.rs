@@ -0,0 +1,512 @@
+use std::fs;
+use std::io;
+use std::path::{ Path, PathBuf };
+use std::sync::Arc;
+
+use whisper::{ MetricPattern, MetricNode, PathMapping, PathLayout, DataRoot, WhisperError, WhisperCache };
+use whisper::find::hashed_metrics;
+use whisper::path_mapping::TAGGED_DIRECTORY;
+
+mod rename;
+mod delete;
+mod usage;
+mod stale;
+mod audit;
+mod provision;
+mod index;
+#[cfg(all(feature = "watch", target_os = "linux"))]
+mod watch;
+pub use self::delete::DeleteReport;
+pub use self::usage::{ DiskUsage, SubtreeUsage };
+pub use self::stale::{ StalePolicy, StaleAction, StaleMetric, StaleReport };
+pub use self::audit::{ AuditReport, FileAudit };
+pub use self::provision::{ ProvisionProgress, ProvisionReport };
+pub use self::index::MetricIndex;
+#[cfg(all(feature = "watch", target_os = "linux"))]
+pub use self::watch::{ TreeWatcher, WatchStats };
+
+// A whisper file under a tree, with the metric it holds
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone)]
+pub struct TreeEntry {
+	pub metric_name: String,
+	pub path: PathBuf
+}
+
+// A data root laid out the way carbon and WhisperCache write it, a directory
+// per node and a `.wsp` file per metric unless its PathMapping hashes them. Hidden entries and the hashed files of tagged series are no
+// part of it, neither is what symlinks out of its DataRoot lead to.
+#[derive(Debug, Clone)]
+pub struct WhisperTree {
+	data_root: DataRoot,
+	index: Option< Arc<MetricIndex> >,
+	cache: Option< Arc<WhisperCache> >
+}
+
+impl WhisperTree {
+	pub fn open<P>(root: P) -> io::Result<WhisperTree>
+		where P: AsRef<Path> {
+		let root = root.as_ref().to_path_buf();
+		if !root.is_dir() {
+			return Err(io::Error::new(io::ErrorKind::NotFound, format!("{} is not a directory", root.display())));
+		}
+		Ok(WhisperTree { data_root: DataRoot::new(root), index: None, cache: None })
+	}
+
+	// The tree of `cache.data_root()`, resolving paths the way the cache does
+	pub fn from_data_root(data_root: DataRoot) -> io::Result<WhisperTree> {
+		try!( WhisperTree::open(data_root.path()) );
+		Ok(WhisperTree { data_root: data_root, index: None, cache: None })
+	}
+
+	// The mapping the cache writing the tree has, PathMapping::default() otherwise
+	pub fn with_path_mapping(mut self, mapping: PathMapping) -> WhisperTree {
+		self.data_root = self.data_root.with_path_mapping(mapping);
+		self
+	}
+
+	// Where symlinks under the root may lead besides the root itself
+	pub fn allow_symlinks_into<P>(mut self, directory: P) -> WhisperTree
+		where P: AsRef<Path> {
+		self.data_root = self.data_root.allow_symlinks_into(directory);
+		self
+	}
+
+	// Finds answered from `index` instead of the directories, and the files
+	// removed, renamed or provisioned through the tree kept in it. It has to
+	// be built from the tree, `MetricIndex::build`, and given to the cache
+	// writing to it too.
+	pub fn with_metric_index(mut self, index: Arc<MetricIndex>) -> WhisperTree {
+		self.index = Some(index);
+		self
+	}
+
+	pub fn metric_index(&self) -> Option<&Arc<MetricIndex>> {
+		self.index.as_ref()
+	}
+
+	// The files removed or renamed through the tree closed in `cache`, the
+	// one writing to it, so it doesn't keep writing to them where they went
+	pub fn with_cache(mut self, cache: Arc<WhisperCache>) -> WhisperTree {
+		self.cache = Some(cache);
+		self
+	}
+
+	pub fn root(&self) -> &Path {
+		self.data_root.path()
+	}
+
+	pub fn path_mapping(&self) -> &PathMapping {
+		self.data_root.path_mapping()
+	}
+
+	pub fn data_root(&self) -> &DataRoot {
+		&self.data_root
+	}
+
+	// Where the file of `metric_name` is, whether or not there's one.
+	// InvalidInput for a name the mapping refuses, PermissionDenied for a path
+	// symlinked out of the data root.
+	pub fn path_for(&self, metric_name: &str) -> io::Result<PathBuf> {
+		match self.data_root.resolve(metric_name) {
+			Ok(path) => Ok(path),
+			Err(WhisperError::Io(err)) => Err(err),
+			Err(err @ WhisperError::OutsideDataRoot(_)) => Err(io::Error::new(io::ErrorKind::PermissionDenied, err.to_string())),
+			Err(err) => Err(io::Error::new(io::ErrorKind::InvalidInput, err.to_string()))
+		}
+	}
+
+	// The metric a `.wsp` file under the root holds. None for anything else,
+	// tagged series included since their names can't be told from the path.
+	pub fn metric_for<P>(&self, path: P) -> Option<String>
+		where P: AsRef<Path> {
+		match path.as_ref().strip_prefix(self.root()) {
+			Ok(relative) => self.path_mapping().metric_for(relative),
+			Err(_) => None
+		}
+	}
+
+	// Every metric, sorted by name
+	pub fn walk(&self) -> Walk {
+		let mut walk = Walk::new(self.data_root.clone(), true);
+		walk.enter(self.root().to_path_buf(), String::new());
+		walk
+	}
+
+	// The metrics below the dotted `prefix`, and the one named by it if there is
+	pub fn walk_prefix(&self, prefix: &str) -> Walk {
+		let mut walk = Walk::new(self.data_root.clone(), false);
+		if let (Ok(directory), Ok(prefix)) = (self.data_root.resolve_dir(prefix), self.path_mapping().normalize(prefix)) {
+			walk.enter(directory, prefix.clone());
+			match self.path_for(&prefix) {
+				Ok(ref leaf) if leaf.is_file() => walk.stack.push(vec![(leaf.clone(), format!("{}.wsp", prefix))]),
+				_ => ()
+			}
+		}
+		walk
+	}
+
+	pub fn metrics(&self) -> io::Result<Vec<String>> {
+		self.walk().map(|entry| entry.map(|entry| entry.metric_name)).collect()
+	}
+
+	// The metric index, if there's one, told a file was created or removed
+	fn index_created(&self, metric_name: &str) {
+		if let (Some(index), Ok(metric_name)) = (self.index.as_ref(), self.path_mapping().normalize(metric_name)) {
+			index.add(&metric_name);
+		}
+	}
+
+	// The cache, if there's one, also closes the file
+	fn index_removed(&self, metric_name: &str) {
+		if let (Some(index), Ok(metric_name)) = (self.index.as_ref(), self.path_mapping().normalize(metric_name)) {
+			index.remove(&metric_name);
+		}
+		self.invalidate_cached(metric_name);
+	}
+
+	// Results the cache kept of `metric_name` dropped, and its file closed
+	// when it isn't the one at its path anymore
+	fn invalidate_cached(&self, metric_name: &str) {
+		if let Some(Err(err)) = self.cache.as_ref().map(|cache| cache.invalidate(metric_name)) {
+			debug!("could not invalidate {}: {}", metric_name, err);
+		}
+	}
+
+	// Removes `directory` if it's empty, then its parents that are left empty,
+	// up to the root. Returns how many were.
+	fn prune_empty_directories(&self, directory: &Path) -> io::Result<usize> {
+		let mut pruned = 0;
+		let mut directory = directory.to_path_buf();
+		while directory.starts_with(self.root()) && directory != self.root() {
+			let empty = try!( fs::read_dir(&directory) ).next().is_none();
+			if !empty {
+				break;
+			}
+			try!( fs::remove_dir(&directory) );
+			pruned = pruned + 1;
+			if !directory.pop() {
+				break;
+			}
+		}
+		Ok(pruned)
+	}
+
+	// The branches and leaves a graphite target like `servers.*.disk.{used,free}`
+	// names, sorted. On top of MetricPattern's globs, alternatives can span
+	// nodes, `{web.cpu,db.io}`, the way graphite-web expands them first.
+	// Nodes symlinked out of the data root are left out.
+	pub fn find(&self, pattern: &str) -> io::Result<Vec<MetricNode>> {
+		let mut found = vec![];
+		for expanded in expand_braces(pattern) {
+			let pattern = try!( MetricPattern::parse(&expanded).map_err(|reason| io::Error::new(io::ErrorKind::InvalidInput, reason)) );
+			if let Some(ref index) = self.index {
+				found.extend(index.find(&pattern).into_iter().filter(|node| node.path.split('.').next() != Some(TAGGED_DIRECTORY)));
+				continue;
+			}
+			let nodes = try!( self.data_root.find(&pattern) ).into_iter()
+				.filter(|node| node.path.split('.').next() != Some(TAGGED_DIRECTORY));
+			found.extend(nodes);
+		}
+		found.sort();
+		found.dedup();
+		Ok(found)
+	}
+}
+
+// One pattern per alternative of the `{...}` holding a dot, the others are
+// left for MetricPattern to match within their node
+fn expand_braces(pattern: &str) -> Vec<String> {
+	let open = match pattern.find('{') {
+		Some(open) => open,
+		None => return vec![pattern.to_string()]
+	};
+	let mut depth = 0;
+	let mut close = None;
+	for (index, c) in pattern[open..].char_indices() {
+		match c {
+			'{' => depth = depth + 1,
+			'}' => {
+				depth = depth - 1;
+				if depth == 0 {
+					close = Some(open + index);
+					break;
+				}
+			},
+			_ => ()
+		}
+	}
+	let close = match close {
+		Some(close) => close,
+		// Unbalanced, for MetricPattern to refuse
+		None => return vec![pattern.to_string()]
+	};
+
+	let (head, group, tail) = (&pattern[..open], &pattern[open+1..close], &pattern[close+1..]);
+	if !group.contains('.') {
+		return expand_braces(tail).into_iter().map(|tail| format!("{}{{{}}}{}", head, group, tail)).collect();
+	}
+
+	let mut alternatives = vec![];
+	let (mut depth, mut start) = (0, 0);
+	for (index, c) in group.char_indices() {
+		match c {
+			'{' => depth = depth + 1,
+			'}' => depth = depth - 1,
+			',' if depth == 0 => {
+				alternatives.push(&group[start..index]);
+				start = index + 1;
+			},
+			_ => ()
+		}
+	}
+	alternatives.push(&group[start..]);
+	alternatives.iter().flat_map(|alternative| expand_braces(&format!("{}{}{}", head, alternative, tail))).collect()
+}
+
+// Depth first through a tree, each directory's entries in name order, leaves
+// before branches of the same name. Symlinked directories are followed.
+pub struct Walk {
+	// What's left of each directory entered, in reverse so the next one pops
+	// off: (path, dotted name), files keeping their extension
+	stack: Vec< Vec<(PathBuf, String)> >,
+	// Skips the tagged series at the top of the tree
+	top: bool,
+	data_root: DataRoot
+}
+
+impl Walk {
+	fn new(data_root: DataRoot, top: bool) -> Walk {
+		Walk { stack: vec![], top: top, data_root: data_root }
+	}
+
+	fn enter(&mut self, directory: PathBuf, prefix: String) {
+		if directory.is_dir() {
+			self.stack.push(vec![(directory, prefix)]);
+		}
+	}
+
+	fn read_directory(&mut self, directory: &Path, prefix: &str) -> io::Result<()> {
+		if let PathLayout::Hashed(_) = self.data_root.path_mapping().layout {
+			return self.read_hashed(directory, prefix);
+		}
+		let mut entries = vec![];
+		for entry in try!( fs::read_dir(directory) ) {
+			let entry = try!( entry );
+			let file_name = match entry.file_name().to_str() {
+				Some(file_name) => file_name.to_string(),
+				None => continue
+			};
+			if self.top && file_name == TAGGED_DIRECTORY {
+				continue;
+			}
+			let path = entry.path();
+			if try!( entry.file_type() ).is_symlink() && !self.data_root.holds(&path) {
+				continue;
+			}
+			let mapping = self.data_root.path_mapping();
+			let name = if path.is_dir() {
+				mapping.node_for(&file_name)
+			} else if file_name.ends_with(".wsp") {
+				mapping.node_for(&file_name[..file_name.len() - 4]).map(|node| format!("{}.wsp", node))
+			} else {
+				None
+			};
+			if let Some(name) = name {
+				entries.push((path, format!("{}{}", prefix, name)));
+			}
+		}
+		self.top = false;
+		self.push_sorted(entries);
+		Ok(())
+	}
+
+	// The whole of a hashed root at once, there's no telling which of its
+	// directories hold the metrics under `prefix`
+	fn read_hashed(&mut self, directory: &Path, prefix: &str) -> io::Result<()> {
+		let mut entries = vec![];
+		for (path, metric_name) in try!( hashed_metrics(directory, self.data_root.path_mapping()) ) {
+			if metric_name.starts_with(prefix) && self.data_root.resolve(&metric_name).is_ok() {
+				entries.push((path, format!("{}.wsp", metric_name)));
+			}
+		}
+		self.push_sorted(entries);
+		Ok(())
+	}
+
+	fn push_sorted(&mut self, mut entries: Vec<(PathBuf, String)>) {
+		// foo.wsp sorts before foo.bar, the same as `foo` would
+		entries.sort_by(|a, b| sort_key(&a.1).cmp(&sort_key(&b.1)));
+		entries.reverse();
+		self.stack.push(entries);
+	}
+}
+
+fn sort_key(name: &str) -> String {
+	if name.ends_with(".wsp") { name[..name.len() - 4].to_string() } else { format!("{}\u{0}", name) }
+}
+
+impl Iterator for Walk {
+	type Item = io::Result<TreeEntry>;
+
+	fn next(&mut self) -> Option<io::Result<TreeEntry>> {
+		loop {
+			let (path, name) = match self.stack.last_mut() {
+				Some(entries) => match entries.pop() {
+					Some(entry) => entry,
+					None => {
+						self.stack.pop();
+						continue;
+					}
+				},
+				None => return None
+			};
+
+			if path.is_dir() {
+				let prefix = if name.is_empty() { name } else { format!("{}.", name) };
+				if let Err(err) = self.read_directory(&path, &prefix) {
+					return Some(Err(err));
+				}
+			} else if name.ends_with(".wsp") {
+				return Some(Ok(TreeEntry { metric_name: name[..name.len() - 4].to_string(), path: path }));
+			}
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use whisper::temp_path;
+	use std::fs::File;
+
+	fn tree(name: &str) -> WhisperTree {
+		let root = PathBuf::from(temp_path(name));
+		fs::remove_dir_all(&root).ok();
+		for dir in &["servers/web01/cpu", "servers/web02", "servers/.git", "_tagged/e9a/90f"] {
+			fs::create_dir_all(root.join(dir)).unwrap();
+		}
+		for file in &["servers/web01/cpu/user.wsp", "servers/web01/cpu/system.wsp", "servers/web01.wsp", "servers/web02/load.wsp", "servers/notes.txt", "servers/.git/HEAD.wsp", "_tagged/e9a/90f/e9a90f.wsp", "load.wsp"] {
+			File::create(root.join(file)).unwrap();
+		}
+		WhisperTree::open(&root).unwrap()
+	}
+
+	#[test]
+	fn test_walk() {
+		let tree = tree("test_whisper_tree_walk");
+		assert_eq!(tree.metrics().unwrap(), vec!["load", "servers.web01", "servers.web01.cpu.system", "servers.web01.cpu.user", "servers.web02.load"]);
+		assert_eq!(tree.walk().next().unwrap().unwrap().path, tree.root().join("load.wsp"));
+
+		let under : Vec<String> = tree.walk_prefix("servers.web01").map(|entry| entry.unwrap().metric_name).collect();
+		assert_eq!(under, vec!["servers.web01", "servers.web01.cpu.system", "servers.web01.cpu.user"]);
+		assert_eq!(tree.walk_prefix("servers.nothing").count(), 0);
+
+		let filtered : Vec<String> = tree.walk().map(|entry| entry.unwrap().metric_name).filter(|name| name.ends_with(".user")).collect();
+		assert_eq!(filtered, vec!["servers.web01.cpu.user"]);
+
+		assert!(WhisperTree::open(tree.root().join("load.wsp")).is_err());
+	}
+
+	fn node(path: &str, is_leaf: bool) -> MetricNode {
+		MetricNode { path: path.to_string(), is_leaf: is_leaf }
+	}
+
+	#[test]
+	fn test_find() {
+		let tree = tree("test_whisper_tree_find");
+		assert_eq!(tree.find("*").unwrap(), vec![node("load", true), node("servers", false)]);
+		assert_eq!(tree.find("servers.web0[1-2]").unwrap(), vec![node("servers.web01", false), node("servers.web01", true), node("servers.web02", false)]);
+		assert_eq!(tree.find("servers.web01.cpu.{user,system,idle}").unwrap(), vec![node("servers.web01.cpu.system", true), node("servers.web01.cpu.user", true)]);
+		assert_eq!(tree.find("servers.{web01.cpu.u*,web02.load}").unwrap(), vec![node("servers.web01.cpu.user", true), node("servers.web02.load", true)]);
+		assert_eq!(tree.find("{load,servers.{web01.cpu,web02}.*}").unwrap(),
+			vec![node("load", true), node("servers.web01.cpu.system", true), node("servers.web01.cpu.user", true), node("servers.web02.load", true)]);
+		assert_eq!(tree.find("servers.*.nothing").unwrap(), vec![]);
+		assert_eq!(tree.find("servers.{web01").unwrap_err().kind(), io::ErrorKind::InvalidInput);
+	}
+
+	#[test]
+	fn test_escaping_tree() {
+		let root = PathBuf::from(temp_path("test_whisper_tree_escaping"));
+		fs::remove_dir_all(&root).ok();
+		fs::create_dir_all(root.join("requests/a%2Fb")).unwrap();
+		File::create(root.join("requests/a%2Fb/50%25.wsp")).unwrap();
+		let tree = WhisperTree::open(&root).unwrap().with_path_mapping(PathMapping::escaping());
+
+		assert_eq!(tree.metrics().unwrap(), vec!["requests.a/b.50%"]);
+		assert_eq!(tree.find("requests.*.50%").unwrap(), vec![node("requests.a/b.50%", true)]);
+		assert_eq!(tree.path_for("requests..a/b.50%").unwrap(), root.join("requests/a%2Fb/50%25.wsp"));
+		assert_eq!(tree.walk_prefix("requests..a/b").map(|entry| entry.unwrap().metric_name).collect::<Vec<_>>(), vec!["requests.a/b.50%"]);
+
+		tree.rename("requests.a/b.50%", "requests.a/b.p50").unwrap();
+		assert!(root.join("requests/a%2Fb/p50.wsp").is_file());
+		// Read as is, the name can't be told
+		assert_eq!(WhisperTree::open(&root).unwrap().metrics().unwrap(), vec!["requests.a%2Fb.p50"]);
+	}
+
+	#[test]
+	fn test_hashed_tree() {
+		let root = PathBuf::from(temp_path("test_whisper_tree_hashed"));
+		fs::remove_dir_all(&root).ok();
+		fs::create_dir_all(&root).unwrap();
+		let tree = WhisperTree::open(&root).unwrap().with_path_mapping(PathMapping::hashed());
+		for metric in &["servers.web01.cpu.user", "servers.web01.cpu.system", "servers.web01", "servers.web02.load", "load"] {
+			let path = tree.path_for(metric).unwrap();
+			fs::create_dir_all(path.parent().unwrap()).unwrap();
+			File::create(path).unwrap();
+		}
+		File::create(root.join("notes.txt")).unwrap();
+
+		assert_eq!(tree.metrics().unwrap(), vec!["load", "servers.web01", "servers.web01.cpu.system", "servers.web01.cpu.user", "servers.web02.load"]);
+		let under : Vec<String> = tree.walk_prefix("servers.web01").map(|entry| entry.unwrap().metric_name).collect();
+		assert_eq!(under, vec!["servers.web01", "servers.web01.cpu.system", "servers.web01.cpu.user"]);
+		assert_eq!(tree.find("servers.web0[1-2]").unwrap(), vec![node("servers.web01", false), node("servers.web01", true), node("servers.web02", false)]);
+		assert_eq!(tree.find("servers.{web01.cpu.u*,web02.load}").unwrap(), vec![node("servers.web01.cpu.user", true), node("servers.web02.load", true)]);
+		assert_eq!(tree.metric_for(tree.path_for("servers.web02.load").unwrap()), Some("servers.web02.load".to_string()));
+		assert!(!root.join("servers").exists());
+
+		tree.rename("servers.web02.load", "servers.web03.load").unwrap();
+		assert_eq!(tree.find("servers.*.load").unwrap(), vec![node("servers.web03.load", true)]);
+		assert_eq!(tree.delete("servers.web01.cpu.*").unwrap().metrics, vec!["servers.web01.cpu.system", "servers.web01.cpu.user"]);
+		assert_eq!(tree.metrics().unwrap(), vec!["load", "servers.web01", "servers.web03.load"]);
+	}
+
+	#[test]
+	#[cfg(unix)]
+	fn test_symlinks_out_of_data_root() {
+		let tree = tree("test_whisper_tree_data_root");
+		let elsewhere = PathBuf::from(temp_path("test_whisper_tree_data_root_elsewhere"));
+		fs::remove_dir_all(&elsewhere).ok();
+		fs::create_dir_all(elsewhere.join("web03")).unwrap();
+		File::create(elsewhere.join("web03/load.wsp")).unwrap();
+		::std::os::unix::fs::symlink(elsewhere.join("web03"), tree.root().join("servers/web03")).unwrap();
+		::std::os::unix::fs::symlink(tree.root().join("servers/web02"), tree.root().join("servers/web04")).unwrap();
+
+		assert_eq!(tree.metrics().unwrap(), vec!["load", "servers.web01", "servers.web01.cpu.system", "servers.web01.cpu.user", "servers.web02.load", "servers.web04.load"]);
+		assert_eq!(tree.find("servers.*.load").unwrap(), vec![node("servers.web02.load", true), node("servers.web04.load", true)]);
+		assert_eq!(tree.path_for("servers.web03.load").unwrap_err().kind(), io::ErrorKind::PermissionDenied);
+
+		let tree = tree.allow_symlinks_into(&elsewhere);
+		assert_eq!(tree.find("servers.web03.*").unwrap(), vec![node("servers.web03.load", true)]);
+		assert_eq!(tree.walk_prefix("servers.web03").count(), 1);
+	}
+
+	#[test]
+	fn test_expand_braces() {
+		assert_eq!(expand_braces("a.{b,c}.d"), vec!["a.{b,c}.d"]);
+		assert_eq!(expand_braces("a.{b.c,d}.{e,f.g}"), vec!["a.b.c.e", "a.b.c.f.g", "a.d.e", "a.d.f.g"]);
+		assert_eq!(expand_braces("{x,y}.{a.b,c}"), vec!["{x,y}.a.b", "{x,y}.c"]);
+	}
+
+	#[test]
+	fn test_paths() {
+		let tree = tree("test_whisper_tree_paths");
+		assert_eq!(tree.path_for("servers.web01.cpu.user").unwrap(), tree.root().join("servers/web01/cpu/user.wsp"));
+		assert_eq!(tree.path_for("servers..load").unwrap_err().kind(), io::ErrorKind::InvalidInput);
+		assert_eq!(tree.metric_for(tree.root().join("servers/web01/cpu/user.wsp")), Some("servers.web01.cpu.user".to_string()));
+		assert_eq!(tree.metric_for(tree.root().join("servers/notes.txt")), None);
+		assert_eq!(tree.metric_for(tree.root().join("servers/.git/HEAD.wsp")), None);
+		assert_eq!(tree.metric_for(tree.root().join("_tagged/e9a/90f/e9a90f.wsp")), None);
+		assert_eq!(tree.metric_for(tree.root().join("../load.wsp")), None);
+		assert_eq!(tree.metric_for("/elsewhere/load.wsp"), None);
+	}
+}
@@ -0,0 +1,152 @@
+use std::cmp;
+use std::collections::BTreeSet;
+use std::fs;
+use std::io;
+use std::sync::Mutex;
+use std::thread;
+
+use whisper::{ WhisperBuilder, WhisperError, SchemaResolver };
+use super::WhisperTree;
+
+// How far a provision has got, handed to its progress callback after each metric
+#[derive(Debug, Default, PartialEq, Clone)]
+pub struct ProvisionProgress {
+	// Metrics looked at, out of `total`
+	pub done: usize,
+	pub total: usize,
+	pub created: usize,
+	pub failed: usize
+}
+
+#[derive(Debug, Default, PartialEq, Clone)]
+pub struct ProvisionReport {
+	// Sorted by name, like the lists below
+	pub created: Vec<String>,
+	// Already had a file, left as it is
+	pub existing: Vec<String>,
+	// The resolver had no schema for them
+	pub unmatched: Vec<String>,
+	// With what went wrong, invalid names included
+	pub failed: Vec<(String, String)>
+}
+
+impl WhisperTree {
+	// Creates the files `metrics` are missing ahead of a migration cutover, laid
+	// out the way `resolver` says, on up to `concurrency` threads. Duplicate
+	// names are created once. `progress` is called from those threads one at a
+	// time, so it's best kept cheap.
+	pub fn provision<I, R, F>(&self, metrics: I, resolver: &R, concurrency: usize, progress: F) -> ProvisionReport
+		where I: IntoIterator<Item=String>, R: SchemaResolver, F: Fn(&ProvisionProgress) + Sync {
+		let metrics : BTreeSet<String> = metrics.into_iter().collect();
+		let total = metrics.len();
+		let queue = Mutex::new(metrics.into_iter());
+		let state = Mutex::new((ProvisionReport::default(), ProvisionProgress { total: total, .. ProvisionProgress::default() }));
+
+		thread::scope(|scope| {
+			for _ in 0..cmp::max(concurrency, 1) {
+				scope.spawn(|| loop {
+					let metric = match queue.lock().unwrap().next() {
+						Some(metric) => metric,
+						None => return
+					};
+					let outcome = self.provision_metric(&metric, resolver);
+
+					let mut state = state.lock().unwrap();
+					let (ref mut report, ref mut counts) = *state;
+					counts.done = counts.done + 1;
+					match outcome {
+						Ok(Some(true)) => {
+							counts.created = counts.created + 1;
+							report.created.push(metric);
+						},
+						Ok(Some(false)) => report.existing.push(metric),
+						Ok(None) => report.unmatched.push(metric),
+						Err(reason) => {
+							counts.failed = counts.failed + 1;
+							report.failed.push((metric, reason));
+						}
+					}
+					progress(counts);
+				});
+			}
+		});
+
+		let (mut report, _) = state.into_inner().unwrap();
+		report.created.sort();
+		report.existing.sort();
+		report.unmatched.sort();
+		report.failed.sort();
+		report
+	}
+
+	// Whether a file was created, None when it has no schema
+	fn provision_metric<R>(&self, metric: &str, resolver: &R) -> Result<Option<bool>, String>
+		where R: SchemaResolver {
+		let path = try!( self.path_for(metric).map_err(|err| err.to_string()) );
+		// Saves resolving the schema, the file could still show up before it's created
+		if path.exists() {
+			return Ok(Some(false));
+		}
+		let schema = match resolver.schema_for(metric) {
+			Some(schema) => schema,
+			None => return Ok(None)
+		};
+		let (aggregation_type, x_files_factor) = resolver.aggregation_for(metric);
+
+		if let Some(parent) = path.parent() {
+			try!( fs::create_dir_all(parent).map_err(|err| err.to_string()) );
+		}
+		match WhisperBuilder::new(schema).aggregation(aggregation_type).x_files_factor(x_files_factor).create_new(true).create(&path) {
+			Ok(_) => (),
+			// A cache or another provisioner got there first, its file is kept
+			Err(WhisperError::Io(ref err)) if err.kind() == io::ErrorKind::AlreadyExists => return Ok(Some(false)),
+			Err(err) => return Err(err.to_string())
+		}
+		self.index_created(metric);
+		Ok(Some(true))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use whisper::{ WhisperFile, Schema, AggregationType, AggregationRules, SchemaRules, StorageRules, temp_path };
+
+	#[test]
+	fn test_provision() {
+		let root = temp_path("test_whisper_tree_provision");
+		fs::remove_dir_all(&root).ok();
+		fs::create_dir_all(&root).unwrap();
+		let tree = WhisperTree::open(&root).unwrap();
+		let rules = StorageRules::new(
+			SchemaRules::parse("[servers]\npattern = ^servers\\.\nretentions = 10s:10m").unwrap(),
+			AggregationRules::parse("[sum]\npattern = \\.count$\naggregationMethod = sum").unwrap()
+		);
+		fs::create_dir_all(tree.root().join("servers/web00")).unwrap();
+		WhisperFile::new(tree.path_for("servers.web00.load").unwrap(), &Schema::parse("1m:1h").unwrap()).unwrap();
+
+		let mut metrics : Vec<String> = (0..20).map(|host| format!("servers.web{:02}.load", host)).collect();
+		metrics.push("servers.web01.requests.count".to_string());
+		metrics.push("servers.web01.load".to_string());
+		metrics.push("apps.shop.requests".to_string());
+		metrics.push("servers..load".to_string());
+
+		let calls = Mutex::new(vec![]);
+		let report = tree.provision(metrics, &rules, 4, |progress| calls.lock().unwrap().push(progress.clone()));
+		assert_eq!(report.created.len(), 20);
+		assert_eq!(report.existing, vec!["servers.web00.load"]);
+		assert_eq!(report.unmatched, vec!["apps.shop.requests"]);
+		assert_eq!(report.failed, vec![("servers..load".to_string(), "invalid metric name 'servers..load': empty node".to_string())]);
+
+		let calls = calls.into_inner().unwrap();
+		assert_eq!(calls.len(), 23);
+		assert_eq!(calls[22], ProvisionProgress { done: 23, total: 23, created: 20, failed: 1 });
+		assert!(calls.windows(2).all(|pair| pair[0].done + 1 == pair[1].done));
+
+		let file = WhisperFile::open(tree.path_for("servers.web01.requests.count").unwrap()).unwrap();
+		assert_eq!(file.info().aggregation_type, AggregationType::Sum);
+		assert_eq!(tree.metrics().unwrap().len(), 21);
+		// Unchanged
+		assert_eq!(WhisperFile::open(tree.path_for("servers.web00.load").unwrap()).unwrap().info().max_retention, 3600);
+	}
+}
@@ -0,0 +1,147 @@
+use std::fs;
+use std::io;
+
+use whisper::{ WhisperFile, current_time };
+use whisper::error::{ Result, WhisperError };
+use super::WhisperTree;
+
+impl WhisperTree {
+	// Moves the file of `old_metric` to where `new_metric`'s goes, making the
+	// directories it needs, and removes the directories it leaves empty. Fails
+	// when `new_metric` already has a file. A cache holding the old file open
+	// keeps writing to it under its new name, unless it's the tree's
+	// `with_cache`. Points it still has pending for `old_metric` go to a new
+	// file of that name.
+	pub fn rename(&self, old_metric: &str, new_metric: &str) -> Result<()> {
+		self.rename_with(current_time(), old_metric, new_metric, false)
+	}
+
+	// Same as rename, but a file `new_metric` already has is kept and the old
+	// one merged into it, whisper-merge.py style, its points winning
+	pub fn rename_merging(&self, old_metric: &str, new_metric: &str) -> Result<()> {
+		self.rename_merging_at(current_time(), old_metric, new_metric)
+	}
+
+	pub fn rename_merging_at(&self, now: u32, old_metric: &str, new_metric: &str) -> Result<()> {
+		self.rename_with(now, old_metric, new_metric, true)
+	}
+
+	fn rename_with(&self, now: u32, old_metric: &str, new_metric: &str, merge: bool) -> Result<()> {
+		let (source, destination) = (try!( self.path_for(old_metric) ), try!( self.path_for(new_metric) ));
+		if !source.is_file() {
+			return Err(WhisperError::Io(io::Error::new(io::ErrorKind::NotFound, format!("no file for {}", old_metric))));
+		}
+		if source == destination {
+			return Ok(());
+		}
+
+		if destination.exists() {
+			if !merge {
+				return Err(WhisperError::Io(io::Error::new(io::ErrorKind::AlreadyExists, format!("{} already has a file", new_metric))));
+			}
+			{
+				let old_file = try!( WhisperFile::open_readonly(&source) );
+				let mut new_file = try!( WhisperFile::open(&destination) );
+				try!( new_file.merge_from_at(now, &old_file, 0, now) );
+			}
+			try!( fs::remove_file(&source) );
+			self.index_removed(old_metric);
+			self.invalidate_cached(new_metric);
+		} else {
+			if let Some(parent) = destination.parent() {
+				try!( fs::create_dir_all(parent) );
+			}
+			try!( fs::rename(&source, &destination) );
+			self.index_removed(old_metric);
+			self.index_created(new_metric);
+		}
+
+		if let Some(parent) = source.parent() {
+			try!( self.prune_empty_directories(parent) );
+		}
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use whisper::{ WhisperTree, WhisperFile, WhisperError, NamedPoint, Schema, Point, temp_path, temp_cache };
+	use std::fs;
+	use std::io;
+	use std::sync::Arc;
+
+	fn tree(name: &str) -> WhisperTree {
+		let root = temp_path(name);
+		fs::remove_dir_all(&root).ok();
+		fs::create_dir_all(&root).unwrap();
+		WhisperTree::open(&root).unwrap()
+	}
+
+	fn create(tree: &WhisperTree, metric: &str, point: Point) {
+		let path = tree.path_for(metric).unwrap();
+		fs::create_dir_all(path.parent().unwrap()).unwrap();
+		let mut file = WhisperFile::new(&path, &Schema::parse("10s:10m").unwrap()).unwrap();
+		file.write_at(point.0, &point).unwrap();
+	}
+
+	#[test]
+	fn test_rename() {
+		let tree = tree("test_whisper_tree_rename");
+		let now = 1440392100;
+		create(&tree, "servers.web01.cpu.user", Point(now, 1.0));
+		create(&tree, "servers.web01.load", Point(now, 0.1));
+
+		tree.rename("servers.web01.cpu.user", "hosts.web01.cpu.user").unwrap();
+		assert!(tree.path_for("hosts.web01.cpu.user").unwrap().is_file());
+		// Emptied, up to the first directory still holding something
+		assert!(!tree.root().join("servers/web01/cpu").exists());
+		assert!(tree.root().join("servers/web01").is_dir());
+
+		tree.rename("servers.web01.load", "hosts.web01.load").unwrap();
+		assert!(!tree.root().join("servers").exists());
+		assert_eq!(tree.metrics().unwrap(), vec!["hosts.web01.cpu.user", "hosts.web01.load"]);
+
+		match tree.rename("servers.web01.load", "hosts.web01.load") {
+			Err(WhisperError::Io(ref err)) if err.kind() == io::ErrorKind::NotFound => (),
+			other => panic!("expected NotFound, got {:?}", other)
+		}
+		match tree.rename("hosts.web01.load", "hosts..load") {
+			Err(WhisperError::Io(ref err)) if err.kind() == io::ErrorKind::InvalidInput => (),
+			other => panic!("expected InvalidInput, got {:?}", other)
+		}
+	}
+
+	#[test]
+	fn test_rename_merging() {
+		let tree = tree("test_whisper_tree_rename_merging");
+		let now = 1440392100;
+		create(&tree, "old.load", Point(now, 1.0));
+		create(&tree, "new.load", Point(now - 10, 2.0));
+
+		match tree.rename("old.load", "new.load") {
+			Err(WhisperError::Io(ref err)) if err.kind() == io::ErrorKind::AlreadyExists => (),
+			other => panic!("expected AlreadyExists, got {:?}", other)
+		}
+		assert!(tree.path_for("old.load").unwrap().is_file());
+
+		tree.rename_merging_at(now, "old.load", "new.load").unwrap();
+		assert!(!tree.root().join("old").exists());
+		let file = WhisperFile::open(tree.path_for("new.load").unwrap()).unwrap();
+		assert_eq!(file.fetch_at(now, now - 30, now).unwrap().values, vec![None, Some(2.0), Some(1.0)]);
+	}
+
+	#[test]
+	fn test_rename_with_cache() {
+		let (cache, now) = temp_cache("test_whisper_tree_rename_with_cache");
+		let cache = Arc::new(cache);
+		cache.write(NamedPoint::new("old.load".to_string(), now, 1.0)).unwrap();
+		let tree = WhisperTree::open(&cache.base_path).unwrap().with_cache(cache.clone());
+
+		tree.rename("old.load", "new.load").unwrap();
+		cache.write(NamedPoint::new("old.load".to_string(), now, 2.0)).unwrap();
+		// Written to a file of its own, not the one that moved
+		assert!(tree.path_for("old.load").unwrap().is_file());
+		assert!(cache.fetch("new.load", now - 10, now + 10).unwrap().values.contains(&Some(1.0)));
+		assert!(!cache.fetch("new.load", now - 10, now + 10).unwrap().values.contains(&Some(2.0)));
+	}
+}
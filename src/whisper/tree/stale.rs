@@ -0,0 +1,239 @@
+use std::fs::{ self, File };
+use std::io;
+use std::path::{ Path, PathBuf };
+use std::process;
+use std::time::UNIX_EPOCH;
+
+use libc;
+
+use whisper::{ WhisperFile, MetricPattern, current_time };
+use super::WhisperTree;
+
+const SECONDS_PER_DAY : u32 = 86400;
+
+// What happens to the file of a metric gone stale
+#[derive(Debug, PartialEq, Clone)]
+pub enum StaleAction {
+	Delete,
+	// Moved under this directory at the same relative path, replacing what's there
+	Archive(PathBuf)
+}
+
+// Which metrics count as dead: nothing written for `max_age` seconds, and not
+// matching any of the `exclude` globs
+#[derive(Debug, Clone)]
+pub struct StalePolicy {
+	pub max_age: u32,
+	pub action: StaleAction,
+	pub exclude: Vec<MetricPattern>
+}
+
+impl StalePolicy {
+	// Fails for more days than a u32 of seconds holds, some 136 years
+	pub fn days(days: u32, action: StaleAction) -> Result<StalePolicy, String> {
+		let max_age = try!( days.checked_mul(SECONDS_PER_DAY).ok_or_else(|| format!("{} days is too long an age", days)) );
+		Ok(StalePolicy { max_age: max_age, action: action, exclude: vec![] })
+	}
+
+	pub fn exclude(mut self, pattern: MetricPattern) -> StalePolicy {
+		self.exclude.push(pattern);
+		self
+	}
+
+	fn excludes(&self, metric_name: &str) -> bool {
+		self.exclude.iter().any(|pattern| pattern.matches(metric_name))
+	}
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct StaleMetric {
+	pub metric_name: String,
+	// None for a file never written to, gone stale by its modification time
+	pub last_update: Option<u32>
+}
+
+// What a scan removed or archived, or would have on a dry run
+#[derive(Debug, Default, PartialEq, Clone)]
+pub struct StaleReport {
+	pub dry_run: bool,
+	// Files looked at, excluded ones aside
+	pub scanned: usize,
+	// Sorted by name
+	pub stale: Vec<StaleMetric>,
+	// Apparent size of the stale files
+	pub bytes: u64,
+	// Files that couldn't be opened as whisper files, left where they are
+	pub unreadable: Vec<String>
+}
+
+impl WhisperTree {
+	// Deletes or archives the files of the metrics `policy` finds stale, and
+	// the directories they leave empty
+	pub fn remove_stale(&self, policy: &StalePolicy) -> io::Result<StaleReport> {
+		self.remove_stale_at(current_time(), policy, false)
+	}
+
+	// Same report as remove_stale, nothing touched
+	pub fn remove_stale_dry_run(&self, policy: &StalePolicy) -> io::Result<StaleReport> {
+		self.remove_stale_at(current_time(), policy, true)
+	}
+
+	pub fn remove_stale_at(&self, now: u32, policy: &StalePolicy, dry_run: bool) -> io::Result<StaleReport> {
+		let mut report = StaleReport { dry_run: dry_run, .. StaleReport::default() };
+		let oldest_update = now.saturating_sub(policy.max_age);
+
+		let mut stale = vec![];
+		for entry in self.walk() {
+			let entry = try!( entry );
+			if policy.excludes(&entry.metric_name) {
+				continue;
+			}
+			report.scanned = report.scanned + 1;
+
+			let stats = match WhisperFile::open_readonly(&entry.path).and_then(|file| file.stats_at(now)) {
+				Ok(stats) => stats,
+				Err(err) => {
+					warn!("could not read {}: {}", entry.path.display(), err);
+					report.unreadable.push(entry.metric_name);
+					continue;
+				}
+			};
+			let last_update = match stats.last_update {
+				Some(last_update) => last_update,
+				None => try!( modified_time(&entry.path) )
+			};
+			if last_update < oldest_update {
+				report.bytes = report.bytes + stats.size_on_disk;
+				report.stale.push(StaleMetric { metric_name: entry.metric_name.clone(), last_update: stats.last_update });
+				stale.push(entry);
+			}
+		}
+
+		if !dry_run {
+			for entry in &stale {
+				match policy.action {
+					StaleAction::Delete => try!( fs::remove_file(&entry.path) ),
+					StaleAction::Archive(ref archive) => {
+						let destination = archive.join(entry.path.strip_prefix(self.root()).unwrap());
+						if let Some(parent) = destination.parent() {
+							try!( fs::create_dir_all(parent) );
+						}
+						try!( move_file(&entry.path, &destination) );
+					}
+				}
+				self.index_removed(&entry.metric_name);
+				if let Some(parent) = entry.path.parent() {
+					try!( self.prune_empty_directories(parent) );
+				}
+			}
+		}
+		Ok(report)
+	}
+}
+
+// A rename, or a copy synced to disk before the original goes when the archive
+// is on another filesystem
+fn move_file(from: &Path, to: &Path) -> io::Result<()> {
+	match fs::rename(from, to) {
+		Err(ref err) if err.raw_os_error() == Some(libc::EXDEV) => (),
+		renamed => return renamed
+	}
+	// Copied beside the destination first, so a crash doesn't leave half a file there
+	let file_name = to.file_name().map(|name| name.to_string_lossy().into_owned()).unwrap_or_default();
+	let copy_path = to.with_file_name(format!(".{}.{}.tmp", file_name, process::id()));
+	let copied = fs::copy(from, &copy_path)
+		.and_then(|_| File::open(&copy_path))
+		.and_then(|copy| copy.sync_all())
+		.and_then(|_| fs::rename(&copy_path, to));
+	if let Err(err) = copied {
+		fs::remove_file(&copy_path).ok();
+		return Err(err);
+	}
+	fs::remove_file(from)
+}
+
+fn modified_time(path: &Path) -> io::Result<u32> {
+	let modified = try!( try!( fs::metadata(path) ).modified() );
+	Ok(modified.duration_since(UNIX_EPOCH).map(|elapsed| elapsed.as_secs() as u32).unwrap_or(0))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use whisper::{ Schema, Point, temp_path };
+	use std::fs::File;
+	use std::io::Write;
+
+	fn create(tree: &WhisperTree, metric: &str, timestamp: u32) {
+		let path = tree.path_for(metric).unwrap();
+		fs::create_dir_all(path.parent().unwrap()).unwrap();
+		let mut file = WhisperFile::new(&path, &Schema::parse("1h:1y").unwrap()).unwrap();
+		file.write_at(timestamp, &Point(timestamp, 1.0)).unwrap();
+	}
+
+	#[test]
+	fn test_remove_stale() {
+		let root = temp_path("test_whisper_tree_remove_stale");
+		let archive = PathBuf::from(temp_path("test_whisper_tree_remove_stale_archive"));
+		fs::remove_dir_all(&root).ok();
+		fs::remove_dir_all(&archive).ok();
+		fs::create_dir_all(&root).unwrap();
+		let tree = WhisperTree::open(&root).unwrap();
+
+		// On the hour, where the points land
+		let now = 1440392400;
+		create(&tree, "servers.web01.load", now - 40 * SECONDS_PER_DAY);
+		create(&tree, "servers.web02.load", now - 3600);
+		create(&tree, "servers.old.load", now - 60 * SECONDS_PER_DAY);
+		create(&tree, "keep.forever.load", now - 90 * SECONDS_PER_DAY);
+		fs::create_dir_all(tree.root().join("servers/broken")).unwrap();
+		File::create(tree.path_for("servers.broken.load").unwrap()).unwrap().write_all(b"not whisper").unwrap();
+
+		let policy = StalePolicy::days(30, StaleAction::Archive(archive.clone())).unwrap().exclude(MetricPattern::parse("keep.*.*").unwrap());
+		let dry_run = tree.remove_stale_at(now, &policy, true).unwrap();
+		assert_eq!(dry_run.scanned, 4);
+		assert_eq!(dry_run.stale, vec![
+			StaleMetric { metric_name: "servers.old.load".to_string(), last_update: Some(now - 60 * SECONDS_PER_DAY) },
+			StaleMetric { metric_name: "servers.web01.load".to_string(), last_update: Some(now - 40 * SECONDS_PER_DAY) }
+		]);
+		assert_eq!(dry_run.unreadable, vec!["servers.broken.load"]);
+		assert_eq!(tree.metrics().unwrap().len(), 5);
+
+		let report = tree.remove_stale_at(now, &policy, false).unwrap();
+		assert_eq!(report, StaleReport { dry_run: false, .. dry_run });
+		assert_eq!(tree.metrics().unwrap(), vec!["keep.forever.load", "servers.broken.load", "servers.web02.load"]);
+		assert!(!tree.root().join("servers/old").exists());
+		assert!(archive.join("servers/web01/load.wsp").is_file());
+
+		let policy = StalePolicy::days(30, StaleAction::Delete).unwrap();
+		assert_eq!(tree.remove_stale_at(now, &policy, false).unwrap().stale.len(), 1);
+		assert_eq!(tree.metrics().unwrap(), vec!["servers.broken.load", "servers.web02.load"]);
+		assert!(StalePolicy::days(50000, StaleAction::Delete).is_err());
+	}
+
+	// Needs /dev/shm on a filesystem of its own, as it usually is
+	#[test]
+	#[cfg(target_os = "linux")]
+	fn test_archive_across_filesystems() {
+		use std::os::unix::fs::MetadataExt;
+
+		let root = temp_path("test_whisper_tree_archive_across");
+		let archive = PathBuf::from("/dev/shm/test_whisper_tree_archive_across");
+		fs::remove_dir_all(&root).ok();
+		fs::remove_dir_all(&archive).ok();
+		fs::create_dir_all(&root).unwrap();
+		if fs::create_dir_all(&archive).is_err() || fs::metadata(&archive).unwrap().dev() == fs::metadata(&root).unwrap().dev() {
+			return;
+		}
+		let tree = WhisperTree::open(&root).unwrap();
+		let now = 1440392400;
+		create(&tree, "servers.web01.load", now - 40 * SECONDS_PER_DAY);
+
+		let policy = StalePolicy::days(30, StaleAction::Archive(archive.clone())).unwrap();
+		assert_eq!(tree.remove_stale_at(now, &policy, false).unwrap().stale.len(), 1);
+		assert!(tree.metrics().unwrap().is_empty());
+		assert_eq!(WhisperFile::open_readonly(archive.join("servers/web01/load.wsp")).unwrap().stats_at(now).unwrap().last_update, Some(now - 40 * SECONDS_PER_DAY));
+		assert_eq!(fs::read_dir(archive.join("servers/web01")).unwrap().count(), 1);
+		fs::remove_dir_all(&archive).ok();
+	}
+}
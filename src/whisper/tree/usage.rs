@@ -0,0 +1,113 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+#[cfg(unix)]
+use std::os::unix::fs::MetadataExt;
+
+use super::WhisperTree;
+
+// The whisper files under one prefix and what they take up. Allocated bytes
+// are the blocks actually used, less than the apparent size for sparse files.
+// Off unix they are taken to be the apparent size.
+#[derive(Debug, Default, PartialEq, Clone)]
+pub struct SubtreeUsage {
+	pub prefix: String,
+	pub files: usize,
+	pub apparent_bytes: u64,
+	pub allocated_bytes: u64
+}
+
+#[derive(Debug, Default, PartialEq, Clone)]
+pub struct DiskUsage {
+	// Sorted by prefix
+	pub prefixes: Vec<SubtreeUsage>,
+	pub files: usize,
+	pub apparent_bytes: u64,
+	pub allocated_bytes: u64
+}
+
+impl DiskUsage {
+	// The prefixes taking up the most disk first
+	pub fn largest(&self) -> Vec<&SubtreeUsage> {
+		let mut prefixes : Vec<&SubtreeUsage> = self.prefixes.iter().collect();
+		prefixes.sort_by(|a, b| b.allocated_bytes.cmp(&a.allocated_bytes).then(a.prefix.cmp(&b.prefix)));
+		prefixes
+	}
+}
+
+impl WhisperTree {
+	// What the files of the metrics under `prefix`, "" for all of them, take
+	// up on disk, grouped by their first `depth` nodes the way
+	// SchemaRules::plan_disk_usage groups its estimate
+	pub fn disk_usage(&self, prefix: &str, depth: usize) -> io::Result<DiskUsage> {
+		let walk = if prefix.is_empty() { self.walk() } else { self.walk_prefix(prefix) };
+		let mut prefixes : BTreeMap<String, SubtreeUsage> = BTreeMap::new();
+		let mut usage = DiskUsage::default();
+
+		for entry in walk {
+			let entry = try!( entry );
+			let metadata = try!( fs::metadata(&entry.path) );
+			let (apparent_bytes, allocated_bytes) = (metadata.len(), allocated_bytes(&metadata));
+
+			let group = entry.metric_name.split('.').take(depth).collect::<Vec<&str>>().join(".");
+			let subtree = prefixes.entry(group.clone()).or_insert(SubtreeUsage { prefix: group, .. SubtreeUsage::default() });
+			subtree.files = subtree.files + 1;
+			subtree.apparent_bytes = subtree.apparent_bytes + apparent_bytes;
+			subtree.allocated_bytes = subtree.allocated_bytes + allocated_bytes;
+			usage.files = usage.files + 1;
+			usage.apparent_bytes = usage.apparent_bytes + apparent_bytes;
+			usage.allocated_bytes = usage.allocated_bytes + allocated_bytes;
+		}
+
+		usage.prefixes = prefixes.into_iter().map(|(_, subtree)| subtree).collect();
+		Ok(usage)
+	}
+}
+
+#[cfg(unix)]
+fn allocated_bytes(metadata: &fs::Metadata) -> u64 {
+	metadata.blocks() * 512
+}
+
+#[cfg(not(unix))]
+fn allocated_bytes(metadata: &fs::Metadata) -> u64 {
+	metadata.len()
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use whisper::temp_path;
+	use std::fs::File;
+	use std::io::Write;
+	use std::path::PathBuf;
+
+	#[test]
+	fn test_disk_usage() {
+		let root = PathBuf::from(temp_path("test_whisper_tree_disk_usage"));
+		fs::remove_dir_all(&root).ok();
+		for dir in &["teams/search/web01", "teams/search/web02", "teams/ads/web01"] {
+			fs::create_dir_all(root.join(dir)).unwrap();
+		}
+		for file in &["teams/search/web01/load.wsp", "teams/search/web02/load.wsp"] {
+			File::create(root.join(file)).unwrap().write_all(&[1u8; 8192]).unwrap();
+		}
+		// Sparse, nothing written
+		File::create(root.join("teams/ads/web01/load.wsp")).unwrap().set_len(1 << 20).unwrap();
+		let tree = WhisperTree::open(&root).unwrap();
+
+		let usage = tree.disk_usage("", 2).unwrap();
+		assert_eq!(usage.files, 3);
+		assert_eq!(usage.apparent_bytes, 2 * 8192 + (1 << 20));
+		assert_eq!(usage.prefixes.iter().map(|subtree| (subtree.prefix.as_str(), subtree.files)).collect::<Vec<_>>(), vec![("teams.ads", 1), ("teams.search", 2)]);
+		let (ads, search) = (&usage.prefixes[0], &usage.prefixes[1]);
+		// Sparse files only count their blocks where blocks are known
+		assert!(ads.allocated_bytes < ads.apparent_bytes || cfg!(not(unix)));
+		assert!(search.allocated_bytes >= search.apparent_bytes);
+		assert_eq!(usage.largest()[0].prefix, "teams.search");
+
+		let usage = tree.disk_usage("teams.search", 3).unwrap();
+		assert_eq!(usage.prefixes.iter().map(|subtree| subtree.prefix.as_str()).collect::<Vec<_>>(), vec!["teams.search.web01", "teams.search.web02"]);
+		assert_eq!(tree.disk_usage("teams.nothing", 1).unwrap(), DiskUsage::default());
+	}
+}
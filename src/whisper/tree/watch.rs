@@ -0,0 +1,355 @@
+use std::collections::{ HashMap, HashSet };
+use std::ffi::CString;
+use std::fs;
+use std::io;
+use std::mem;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{ Path, PathBuf };
+use std::ptr;
+use std::sync::{ Arc, Mutex };
+use std::sync::atomic::{ AtomicBool, Ordering };
+use std::thread::{ self, JoinHandle };
+
+use libc::{ self, c_int };
+
+use whisper::WhisperCache;
+use whisper::path_mapping::TAGGED_DIRECTORY;
+use super::WhisperTree;
+
+// How long a poll waits, and so how long stopping can take
+const POLL_TIMEOUT_MS : c_int = 100;
+
+const WATCHED_EVENTS : u32 = libc::IN_CREATE | libc::IN_DELETE | libc::IN_MOVED_FROM | libc::IN_MOVED_TO | libc::IN_CLOSE_WRITE | libc::IN_ONLYDIR;
+
+#[derive(Debug, Default, PartialEq, Clone)]
+pub struct WatchStats {
+	pub watched_directories: usize,
+	// Files of metrics that showed up, moved in or created in place
+	pub created: u64,
+	pub removed: u64,
+	// Files the cache had open that another one was put in place of, closed
+	// to be opened again
+	pub replaced: u64,
+	// Times the kernel dropped events and the whole tree was read again
+	pub overflows: u64
+}
+
+struct Shared {
+	stats: Mutex<WatchStats>,
+	stopped: AtomicBool
+}
+
+// Follows what other tools do to a tree, whisper-resize.py or rsync say, with
+// inotify on every directory of it: files that show up or go are added to or
+// removed from the tree's MetricIndex, and the cache forgets what it holds of
+// them. Directories come and go with their files. inotify has no recursive
+// watch, so max_user_watches has to allow for a watch per directory.
+pub struct TreeWatcher {
+	shared: Arc<Shared>,
+	thread: Option< JoinHandle<()> >
+}
+
+impl TreeWatcher {
+	// Once the watches are in place the index is brought up to date with the
+	// tree, for whatever changed while it was being built
+	pub fn start(tree: WhisperTree, cache: Option< Arc<WhisperCache> >) -> io::Result<TreeWatcher> {
+		let mut watcher = try!( Watcher::new(tree, cache) );
+		try!( watcher.resync() );
+		let shared = Arc::new(Shared { stats: Mutex::new(WatchStats::default()), stopped: AtomicBool::new(false) });
+		watcher.record(&shared, |_| WatchStats::default());
+		let thread = {
+			let shared = shared.clone();
+			thread::spawn(move || watcher.run(&shared))
+		};
+		Ok(TreeWatcher { shared: shared, thread: Some(thread) })
+	}
+
+	pub fn stats(&self) -> WatchStats {
+		self.shared.stats.lock().unwrap().clone()
+	}
+}
+
+impl Drop for TreeWatcher {
+	fn drop(&mut self) {
+		self.shared.stopped.store(true, Ordering::SeqCst);
+		if let Some(thread) = self.thread.take() {
+			thread.join().ok();
+		}
+	}
+}
+
+struct Watcher {
+	tree: WhisperTree,
+	cache: Option< Arc<WhisperCache> >,
+	fd: c_int,
+	// The directory of each watch descriptor
+	directories: HashMap<c_int, PathBuf>
+}
+
+impl Watcher {
+	fn new(tree: WhisperTree, cache: Option< Arc<WhisperCache> >) -> io::Result<Watcher> {
+		let fd = unsafe { libc::inotify_init1(libc::IN_NONBLOCK | libc::IN_CLOEXEC) };
+		if fd < 0 {
+			return Err(io::Error::last_os_error());
+		}
+		Ok(Watcher { tree: tree, cache: cache, fd: fd, directories: HashMap::new() })
+	}
+
+	fn run(&mut self, shared: &Shared) {
+		let mut buffer = vec![0u8; 64 * 1024];
+		while !shared.stopped.load(Ordering::SeqCst) {
+			let mut poll_fd = libc::pollfd { fd: self.fd, events: libc::POLLIN, revents: 0 };
+			if unsafe { libc::poll(&mut poll_fd, 1, POLL_TIMEOUT_MS) } <= 0 {
+				continue;
+			}
+			let read = unsafe { libc::read(self.fd, buffer.as_mut_ptr() as *mut libc::c_void, buffer.len()) };
+			if read < 0 {
+				let err = io::Error::last_os_error();
+				if err.kind() == io::ErrorKind::WouldBlock || err.kind() == io::ErrorKind::Interrupted {
+					continue;
+				}
+				warn!("stopped watching {}: {}", self.tree.root().display(), err);
+				return;
+			}
+
+			let mut offset = 0;
+			while offset + mem::size_of::<libc::inotify_event>() <= read as usize {
+				let event : libc::inotify_event = unsafe { ptr::read_unaligned(buffer[offset..].as_ptr() as *const libc::inotify_event) };
+				let name_start = offset + mem::size_of::<libc::inotify_event>();
+				let name = &buffer[name_start..name_start + event.len as usize];
+				let name = &name[..name.iter().position(|&byte| byte == 0).unwrap_or(name.len())];
+				offset = name_start + event.len as usize;
+
+				let directory = self.directories.get(&event.wd).cloned();
+				let path = directory.map(|directory| if name.is_empty() { directory } else { directory.join(::std::ffi::OsStr::from_bytes(name)) });
+				self.record(shared, |watcher| watcher.handle(event.wd, event.mask, path));
+			}
+		}
+	}
+
+	// Runs `f` and counts what it did in the stats
+	fn record<F>(&mut self, shared: &Shared, f: F)
+		where F: FnOnce(&mut Watcher) -> WatchStats {
+		let counts = f(self);
+		let mut stats = shared.stats.lock().unwrap();
+		stats.watched_directories = self.directories.len();
+		stats.created = stats.created + counts.created;
+		stats.removed = stats.removed + counts.removed;
+		stats.replaced = stats.replaced + counts.replaced;
+		stats.overflows = stats.overflows + counts.overflows;
+	}
+
+	fn handle(&mut self, wd: c_int, mask: u32, path: Option<PathBuf>) -> WatchStats {
+		let mut counts = WatchStats::default();
+		if mask & libc::IN_Q_OVERFLOW != 0 {
+			warn!("inotify dropped events, reading {} again", self.tree.root().display());
+			counts.overflows = 1;
+			if let Err(err) = self.resync() {
+				warn!("could not read {}: {}", self.tree.root().display(), err);
+			}
+			return counts;
+		}
+		if mask & libc::IN_IGNORED != 0 {
+			self.directories.remove(&wd);
+			return counts;
+		}
+		let path = match path {
+			Some(path) => path,
+			None => return counts
+		};
+
+		if mask & libc::IN_ISDIR != 0 {
+			if mask & (libc::IN_CREATE | libc::IN_MOVED_TO) != 0 {
+				match self.watch(&path) {
+					Ok(files) => for file in files {
+						if let Some(metric_name) = self.tree.metric_for(&file) {
+							self.created(&metric_name, &mut counts);
+						}
+					},
+					Err(err) => warn!("could not watch {}: {}", path.display(), err)
+				}
+			} else if mask & libc::IN_MOVED_FROM != 0 {
+				self.unwatch(&path);
+				// Whatever was under it is elsewhere now
+				let metrics = self.tree.metric_index().map_or(vec![], |index| index.metrics());
+				for metric_name in metrics {
+					if self.tree.path_for(&metric_name).map(|file| file.starts_with(&path)).unwrap_or(false) {
+						self.removed(&metric_name, &mut counts);
+					}
+				}
+			}
+			return counts;
+		}
+
+		let metric_name = match self.tree.metric_for(&path) {
+			Some(metric_name) => metric_name,
+			None => return counts
+		};
+		if mask & (libc::IN_DELETE | libc::IN_MOVED_FROM) != 0 {
+			self.removed(&metric_name, &mut counts);
+		} else if mask & libc::IN_CREATE != 0 {
+			self.created(&metric_name, &mut counts);
+		} else if mask & (libc::IN_MOVED_TO | libc::IN_CLOSE_WRITE) != 0 {
+			// Renamed over an older file, the way tools replace them, or written
+			// in place, by the cache itself too
+			let known = self.tree.metric_index().map_or(false, |index| index.contains(&metric_name));
+			if !known && mask & libc::IN_MOVED_TO != 0 {
+				self.created(&metric_name, &mut counts);
+			} else if self.invalidate(&metric_name) {
+				counts.replaced = counts.replaced + 1;
+			}
+		}
+		counts
+	}
+
+	fn created(&self, metric_name: &str, counts: &mut WatchStats) {
+		let added = self.tree.metric_index().map_or(false, |index| index.add(metric_name));
+		if added {
+			counts.created = counts.created + 1;
+		}
+		self.invalidate(metric_name);
+	}
+
+	fn removed(&self, metric_name: &str, counts: &mut WatchStats) {
+		let removed = self.tree.metric_index().map_or(false, |index| index.remove(metric_name));
+		if removed {
+			counts.removed = counts.removed + 1;
+		}
+		self.invalidate(metric_name);
+	}
+
+	// Whether the cache closed the file
+	fn invalidate(&self, metric_name: &str) -> bool {
+		match self.cache.as_ref().map(|cache| cache.invalidate(metric_name)) {
+			Some(Ok(closed)) => closed,
+			Some(Err(err)) => {
+				debug!("could not invalidate {}: {}", metric_name, err);
+				false
+			},
+			None => false
+		}
+	}
+
+	// Watches `directory` and what's below it, returning the files found there
+	fn watch(&mut self, directory: &Path) -> io::Result<Vec<PathBuf>> {
+		let mut files = vec![];
+		let mut directories = vec![directory.to_path_buf()];
+		while let Some(directory) = directories.pop() {
+			let name = try!( CString::new(directory.as_os_str().as_bytes()).map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err)) );
+			let wd = unsafe { libc::inotify_add_watch(self.fd, name.as_ptr(), WATCHED_EVENTS) };
+			if wd < 0 {
+				return Err(io::Error::last_os_error());
+			}
+			self.directories.insert(wd, directory.clone());
+
+			let entries = match fs::read_dir(&directory) {
+				Ok(entries) => entries,
+				// Gone again already
+				Err(ref err) if err.kind() == io::ErrorKind::NotFound => continue,
+				Err(err) => return Err(err)
+			};
+			for entry in entries {
+				let entry = try!( entry );
+				let path = entry.path();
+				let skipped = entry.file_name().to_str().map_or(true, |name| name.starts_with('.') || directory == self.tree.root() && name == TAGGED_DIRECTORY);
+				if skipped || try!( entry.file_type() ).is_symlink() && !self.tree.data_root().holds(&path) {
+					continue;
+				}
+				if path.is_dir() {
+					directories.push(path);
+				} else {
+					files.push(path);
+				}
+			}
+		}
+		Ok(files)
+	}
+
+	fn unwatch(&mut self, directory: &Path) {
+		let watches : Vec<c_int> = self.directories.iter().filter(|&(_, path)| path.starts_with(directory)).map(|(&wd, _)| wd).collect();
+		for wd in watches {
+			unsafe { libc::inotify_rm_watch(self.fd, wd) };
+			self.directories.remove(&wd);
+		}
+	}
+
+	// Watches every directory, and makes the index hold the metrics the tree
+	// has, no more
+	fn resync(&mut self) -> io::Result<()> {
+		let root = self.tree.root().to_path_buf();
+		try!( self.watch(&root) );
+		let index = match self.tree.metric_index() {
+			Some(index) => index.clone(),
+			None => return Ok(())
+		};
+		let metrics : HashSet<String> = try!( self.tree.metrics() ).into_iter().collect();
+		for metric_name in index.metrics() {
+			if !metrics.contains(&metric_name) {
+				index.remove(&metric_name);
+				self.invalidate(&metric_name);
+			}
+		}
+		for metric_name in &metrics {
+			if index.add(metric_name) {
+				self.invalidate(metric_name);
+			}
+		}
+		Ok(())
+	}
+}
+
+impl Drop for Watcher {
+	fn drop(&mut self) {
+		unsafe { libc::close(self.fd) };
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use whisper::{ WhisperFile, MetricIndex, NamedPoint, Schema, Point, current_time, temp_path };
+	use std::time::Duration;
+
+	fn wait_for<F>(condition: F) where F: Fn() -> bool {
+		for _ in 0..500 {
+			if condition() {
+				return;
+			}
+			thread::sleep(Duration::from_millis(10));
+		}
+		panic!("timed out");
+	}
+
+	#[test]
+	fn test_watch() {
+		let root = PathBuf::from(temp_path("test_whisper_tree_watch"));
+		fs::remove_dir_all(&root).ok();
+		fs::create_dir_all(root.join("servers/web01")).unwrap();
+		let schema = Schema::parse("10s:60s").unwrap();
+		WhisperFile::new(root.join("servers/web01/load.wsp"), &schema).unwrap();
+		let tree = WhisperTree::open(&root).unwrap();
+		let index = Arc::new(MetricIndex::new());
+		let tree = tree.with_metric_index(index.clone());
+		let cache = Arc::new(WhisperCache::new(&root, 10, schema.clone()));
+		let watcher = TreeWatcher::start(tree, Some(cache.clone())).unwrap();
+		assert_eq!(index.metrics(), vec!["servers.web01.load"]);
+
+		fs::create_dir_all(root.join("servers/web02/cpu")).unwrap();
+		WhisperFile::new(root.join("servers/web02/cpu/user.wsp"), &schema).unwrap();
+		wait_for(|| index.contains("servers.web02.cpu.user"));
+		fs::remove_file(root.join("servers/web01/load.wsp")).unwrap();
+		wait_for(|| !index.contains("servers.web01.load"));
+		fs::rename(root.join("servers/web02"), root.join("servers/web03")).unwrap();
+		wait_for(|| index.metrics() == vec!["servers.web03.cpu.user"]);
+
+		// Put over the file the cache has open
+		let now = current_time();
+		cache.write(NamedPoint::new("servers.web03.cpu.user".to_string(), now, 1.0)).unwrap();
+		let mut replacement = WhisperFile::new(root.join("user.wsp.tmp"), &schema).unwrap();
+		replacement.write(&Point(now, 2.0)).unwrap();
+		fs::rename(root.join("user.wsp.tmp"), root.join("servers/web03/cpu/user.wsp")).unwrap();
+		wait_for(|| watcher.stats().replaced == 1);
+		assert_eq!(cache.fetch("servers.web03.cpu.user", now - 10, now).unwrap().values.last(), Some(&Some(2.0)));
+		assert_eq!(watcher.stats(), WatchStats { watched_directories: 5, created: 2, removed: 2, replaced: 1, overflows: 0 });
+	}
+}